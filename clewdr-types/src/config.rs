@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -7,28 +9,118 @@ pub struct ConfigApi {
     #[serde(default)]
     pub port: u16,
     #[serde(default)]
+    pub request_timeout: u64,
+    #[serde(default)]
+    pub admin_request_timeout: u64,
+    #[serde(default)]
+    pub max_body_bytes: usize,
+    #[serde(default)]
+    pub cors_allow_headers: Vec<String>,
+    #[serde(default)]
+    pub cors_expose_headers: Vec<String>,
+    #[serde(default)]
+    pub wait_for_dependencies: bool,
+    #[serde(default)]
+    pub dependency_wait_timeout: u64,
+    #[serde(default)]
+    pub dependency_poll_interval: u64,
+    #[serde(default)]
+    pub shutdown_drain_timeout: u64,
+    #[serde(default)]
     pub check_update: bool,
     #[serde(default)]
     pub auto_update: bool,
     #[serde(default)]
+    pub metrics_require_auth: bool,
+    #[serde(default)]
+    pub error_log_file: Option<String>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub log_bodies: bool,
+    #[serde(default)]
+    pub log_body_max_bytes: usize,
+    #[serde(default)]
     pub password: String,
     #[serde(default)]
     pub admin_password: String,
     pub proxy: Option<String>,
+    #[serde(default)]
+    pub claude_web_proxy: Option<String>,
+    #[serde(default)]
+    pub claude_code_proxy: Option<String>,
     pub rproxy: Option<String>,
     #[serde(default)]
+    pub max_proxy_hops: u32,
+    #[serde(default)]
+    pub upstream_headers: HashMap<String, String>,
+    #[serde(default)]
+    pub upstream_headers_override: bool,
+    #[serde(default)]
     pub max_retries: usize,
     #[serde(default)]
+    pub conversation_retries: usize,
+    #[serde(default)]
+    pub stream_idle_timeout: u64,
+    #[serde(default)]
+    pub non_stream_timeout: u64,
+    #[serde(default)]
+    pub client_timeout_min: u64,
+    #[serde(default)]
+    pub client_timeout_max: u64,
+    #[serde(default)]
+    pub max_concurrent_streams: usize,
+    #[serde(default)]
+    pub per_cookie_rpm: u32,
+    #[serde(default)]
+    pub per_cookie_concurrency: usize,
+    #[serde(default)]
+    pub cookie_cooldown_wait: u64,
+    #[serde(default)]
+    pub coalesce_streams: bool,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    #[serde(default)]
     pub preserve_chats: bool,
     #[serde(default)]
     pub web_search: bool,
     #[serde(default)]
+    pub image_decode_concurrency: usize,
+    #[serde(default)]
     pub enable_web_count_tokens: bool,
     #[serde(default)]
+    pub web_paste_max_chars: usize,
+    #[serde(default)]
+    pub fetch_remote_images: bool,
+    #[serde(default)]
+    pub remote_image_max_bytes: usize,
+    #[serde(default)]
+    pub remote_image_types: Vec<String>,
+    #[serde(default)]
     pub sanitize_messages: bool,
     #[serde(default)]
+    pub merge_consecutive_roles: bool,
+    #[serde(default)]
+    pub detect_request_format: bool,
+    #[serde(default)]
+    pub default_params: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    pub model_aliases: HashMap<String, String>,
+    #[serde(default)]
+    pub models_cache_ttl: u64,
+    #[serde(default)]
+    pub strict_validation: bool,
+    #[serde(default)]
+    pub max_tool_rounds: usize,
+    #[serde(default)]
+    pub anti_truncation_attempts: usize,
+    #[serde(default)]
+    pub anti_truncation_max_tokens: u64,
+    #[serde(default)]
     pub skip_first_warning: bool,
     #[serde(default)]
+    pub min_healthy_cookies: usize,
+    #[serde(default)]
     pub skip_second_warning: bool,
     #[serde(default)]
     pub skip_restricted: bool,
@@ -46,4 +138,124 @@ pub struct ConfigApi {
     pub custom_prompt: String,
     pub claude_code_client_id: Option<String>,
     pub custom_system: Option<String>,
+    #[serde(default)]
+    pub anthropic_version: String,
+    #[serde(default)]
+    pub unsupported_block_policy: UnsupportedBlockPolicy,
+    #[serde(default)]
+    pub stop_sequence_flush: StopSequenceFlush,
+    #[serde(default)]
+    pub stop_sequence_precedence: StopSequencePrecedence,
+    #[serde(default)]
+    pub stop_sequence_regex: bool,
+    #[serde(default)]
+    pub stop_case_insensitive: bool,
+    #[serde(default)]
+    pub stream_chunk_mode: StreamChunkMode,
+    #[serde(default)]
+    pub stream_chunk_bytes: usize,
+    #[serde(default)]
+    pub stream_chunk_window_ms: u64,
+    #[serde(default)]
+    pub response_rewrites: Vec<ResponseRewrite>,
+    #[serde(default)]
+    pub echo_requested_model: bool,
+    #[serde(default)]
+    pub hide_thinking: bool,
+    #[serde(default)]
+    pub allow_dry_run: bool,
+    #[serde(default)]
+    pub validate_tool_input: bool,
+}
+
+/// An extra admin credential, labeled so actions can be attributed to it
+/// and it can be revoked on its own
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdminToken {
+    pub token: String,
+    #[serde(default)]
+    pub label: String,
+}
+
+/// What to do with content blocks the target backend cannot accept
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnsupportedBlockPolicy {
+    /// Drop the block and log it
+    #[default]
+    Strip,
+    /// Fail the request with a 400 error
+    Reject,
+}
+
+/// How eagerly streamed text held back for stop sequence matching is released
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StopSequenceFlush {
+    /// Hold back everything that may still become a stop sequence
+    #[default]
+    Conservative,
+    /// Also release held back leading whitespace
+    Balanced,
+    /// Release held back text up to its last whitespace or punctuation
+    Eager,
+}
+
+/// Which stop sequence is reported when several match at the same place
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StopSequencePrecedence {
+    /// Stop as soon as any sequence completes, so `stop` beats `stopping`
+    #[default]
+    Shortest,
+    /// Wait until no longer sequence starting at the same place can complete
+    Longest,
+}
+
+/// Requests per minute allowed for each route group, `0` means unlimited
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimits {
+    /// `/v1/messages` and `/v1/chat/completions`
+    #[serde(default)]
+    pub claude_web: u32,
+    /// `/code/v1/messages` and `/code/v1/chat/completions`
+    #[serde(default)]
+    pub claude_code: u32,
+    /// `/code/v1/messages/count_tokens`
+    #[serde(default)]
+    pub count_tokens: u32,
+}
+
+/// How log lines are written to stdout
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with an access log entry per request
+    Json,
+}
+
+/// How streamed response chunks are framed before they are sent
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamChunkMode {
+    /// Forward chunks as they arrive
+    #[default]
+    Passthrough,
+    /// Merge chunks up to `stream_chunk_bytes` or `stream_chunk_window_ms`
+    Coalesce,
+    /// Split text deltas larger than `stream_chunk_bytes` into several events
+    Split,
+}
+
+/// A regex replace rule applied to response text
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResponseRewrite {
+    /// Regex matched against text output, empty matches are ignored
+    pub pattern: String,
+    /// Replacement, may reference capture groups like `$1`
+    #[serde(default)]
+    pub replacement: String,
 }
@@ -2,7 +2,10 @@ mod config;
 mod reason;
 mod usage;
 
-pub use config::ConfigApi;
+pub use config::{
+    AdminToken, ConfigApi, LogFormat, RateLimits, ResponseRewrite, StopSequenceFlush,
+    StopSequencePrecedence, StreamChunkMode, UnsupportedBlockPolicy,
+};
 pub use reason::Reason;
 use serde::{Deserialize, Serialize};
 pub use usage::UsageBreakdown;
@@ -15,6 +18,18 @@ pub struct CookieStatusApi {
     #[serde(default)]
     pub count_tokens_allowed: Option<bool>,
     #[serde(default)]
+    pub proxy: Option<String>,
+    #[serde(default)]
+    pub is_pro: Option<bool>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub request_count: u64,
+    #[serde(default)]
+    pub token_count: u64,
+    #[serde(default)]
+    pub last_used: Option<i64>,
+    #[serde(default)]
     pub session_usage: UsageBreakdown,
     #[serde(default)]
     pub weekly_usage: UsageBreakdown,
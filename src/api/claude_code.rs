@@ -1,6 +1,10 @@
 use std::sync::Arc;
 
-use axum::{Extension, extract::State, response::Response};
+use axum::{
+    Extension, Json,
+    extract::State,
+    response::{IntoResponse, Response},
+};
 
 use crate::{
     error::ClewdrError,
@@ -14,11 +18,16 @@ use crate::{
 pub async fn api_claude_code(
     State(provider): State<Arc<ClaudeCodeProvider>>,
     ClaudeCodePreprocess(params, context): ClaudeCodePreprocess,
-) -> Result<(Extension<ClaudeContext>, Response), ClewdrError> {
+) -> Result<Response, ClewdrError> {
+    if context.dry_run() {
+        return Ok(Json(provider.dry_run(params)?).into_response());
+    }
+    #[cfg(feature = "metrics")]
+    crate::services::metrics::record_request("claude_code", &params.model);
     let ClaudeProviderResponse { context, response } = provider
         .invoke(ClaudeInvocation::messages(params, context.clone()))
         .await?;
-    Ok((Extension(context), response))
+    Ok((Extension(context), response).into_response())
 }
 
 pub async fn api_claude_code_count_tokens(
@@ -1,14 +1,19 @@
 use std::sync::Arc;
 
-use axum::{Extension, extract::State, response::Response};
+use axum::{
+    Extension, Json,
+    extract::State,
+    response::{IntoResponse, Response},
+};
 
 use crate::{
     error::ClewdrError,
-    middleware::claude::{ClaudeContext, ClaudeWebPreprocess},
+    middleware::claude::{ClaudeContext, ClaudeWebPreprocess, CountTokensRequest},
     providers::{
         LLMProvider,
         claude::{ClaudeInvocation, ClaudeProviderResponse, ClaudeWebProvider},
     },
+    types::claude::CountMessageTokensResponse,
 };
 /// Axum handler for the API messages
 /// Main API endpoint for handling message requests to Claude
@@ -24,9 +29,27 @@ use crate::{
 pub async fn api_claude_web(
     State(provider): State<Arc<ClaudeWebProvider>>,
     ClaudeWebPreprocess(params, context): ClaudeWebPreprocess,
-) -> Result<(Extension<ClaudeContext>, Response), ClewdrError> {
+) -> Result<Response, ClewdrError> {
+    if context.dry_run() {
+        return Ok(Json(provider.dry_run(params)?).into_response());
+    }
+    #[cfg(feature = "metrics")]
+    crate::services::metrics::record_request("claude_web", &params.model);
     let ClaudeProviderResponse { context, response } = provider
         .invoke(ClaudeInvocation::messages(params, context.clone()))
         .await?;
-    Ok((Extension(context), response))
+    Ok((Extension(context), response).into_response())
+}
+
+/// Counts the input tokens of a request without sending it, so clients can
+/// budget their context
+///
+/// Uses the same estimate as usage accounting and accepts Claude and OpenAI
+/// format bodies alike.
+pub async fn api_claude_web_count_tokens(
+    CountTokensRequest(params): CountTokensRequest,
+) -> Json<CountMessageTokensResponse> {
+    Json(CountMessageTokensResponse {
+        input_tokens: params.count_tokens(),
+    })
 }
@@ -1,10 +1,19 @@
-use axum::Json;
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::Query,
+    http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
 use axum_auth::AuthBearer;
 use clewdr_types::ConfigApi;
-use serde_json::json;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::info;
 
 use super::error::ApiError;
-use crate::config::{CLEWDR_CONFIG, ClewdrConfig};
+use crate::config::{AdminToken, CLEWDR_CONFIG, ClewdrConfig, REDACTED, random_token};
 
 pub async fn api_get_config(AuthBearer(t): AuthBearer) -> Result<Json<ConfigApi>, ApiError> {
     if !CLEWDR_CONFIG.load().admin_auth(&t) {
@@ -19,22 +28,225 @@ pub async fn api_post_config(
     AuthBearer(t): AuthBearer,
     Json(c): Json<ConfigApi>,
 ) -> Result<Json<serde_json::Value>, ApiError> {
-    if !CLEWDR_CONFIG.load().admin_auth(&t) {
+    let Some(label) = CLEWDR_CONFIG.load().admin_label(&t).map(str::to_owned) else {
         return Err(ApiError::unauthorized());
-    }
+    };
     let c: ClewdrConfig = ClewdrConfig::from(c).validate();
     CLEWDR_CONFIG.rcu(|old_c| {
         let mut new_c = ClewdrConfig::clone(&c);
         new_c.cookie_array = old_c.cookie_array.to_owned();
         new_c.wasted_cookie = old_c.wasted_cookie.to_owned();
+        new_c.admin_tokens = old_c.admin_tokens.to_owned();
         new_c
     });
     if let Err(e) = CLEWDR_CONFIG.load().save().await {
         return Err(ApiError::internal(format!("Failed to save config: {}", e)));
     }
+    info!("Config updated by admin `{}`", label);
 
     Ok(Json(json!({
         "message": "Config updated successfully",
         "config": ConfigApi::from(&c)
     })))
 }
+
+/// Query parameters for the config download endpoint
+#[derive(Deserialize)]
+pub struct ConfigDownloadQuery {
+    /// Replace passwords, tokens and cookies with a placeholder
+    #[serde(default)]
+    redact: bool,
+}
+
+/// API endpoint to download the current config as a TOML attachment
+///
+/// # Arguments
+/// * `t` - Auth bearer token for admin authentication
+/// * `query` - Whether to redact secrets, a redacted file cannot be restored
+///
+/// # Returns
+/// * `Response` - The config file as `clewdr.toml`
+pub async fn api_download_config(
+    AuthBearer(t): AuthBearer,
+    Query(query): Query<ConfigDownloadQuery>,
+) -> Result<Response, ApiError> {
+    let Some(label) = CLEWDR_CONFIG.load().admin_label(&t).map(str::to_owned) else {
+        return Err(ApiError::unauthorized());
+    };
+    let data = CLEWDR_CONFIG
+        .load()
+        .export_toml(query.redact)
+        .map_err(|e| ApiError::internal(format!("Failed to serialize config: {}", e)))?;
+    info!(
+        "Config downloaded by admin `{}`{}",
+        label,
+        if query.redact { " (redacted)" } else { "" }
+    );
+    Ok((
+        [
+            (CONTENT_TYPE, "application/toml"),
+            (CONTENT_DISPOSITION, "attachment; filename=\"clewdr.toml\""),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+/// API endpoint to restore the config from an uploaded TOML file
+///
+/// The file is parsed and validated before it replaces the current config.
+/// Cookies and admin tokens are kept as they are, since they are managed
+/// through their own endpoints.
+///
+/// # Arguments
+/// * `t` - Auth bearer token for admin authentication
+/// * `body` - Contents of a config file, as downloaded from `/api/config/download`
+pub async fn api_upload_config(
+    AuthBearer(t): AuthBearer,
+    body: String,
+) -> Result<Json<Value>, ApiError> {
+    let Some(label) = CLEWDR_CONFIG.load().admin_label(&t).map(str::to_owned) else {
+        return Err(ApiError::unauthorized());
+    };
+    let c: ClewdrConfig = toml::from_str(&body)
+        .map_err(|e| ApiError::bad_request(format!("Invalid config file: {}", e)))?;
+    if c.password() == REDACTED || c.admin_password() == REDACTED {
+        return Err(ApiError::bad_request(
+            "Config file is redacted, download it without `redact` to restore it",
+        ));
+    }
+    let c = c.validate();
+    CLEWDR_CONFIG.rcu(|old_c| {
+        let mut new_c = ClewdrConfig::clone(&c);
+        new_c.cookie_array = old_c.cookie_array.to_owned();
+        new_c.wasted_cookie = old_c.wasted_cookie.to_owned();
+        new_c.admin_tokens = old_c.admin_tokens.to_owned();
+        new_c
+    });
+    if let Err(e) = CLEWDR_CONFIG.load().save().await {
+        return Err(ApiError::internal(format!("Failed to save config: {}", e)));
+    }
+    info!("Config restored from upload by admin `{}`", label);
+
+    Ok(Json(json!({
+        "message": "Config restored successfully",
+        "config": ConfigApi::from(&c)
+    })))
+}
+
+/// Request body to add an admin token
+#[derive(Deserialize)]
+pub struct NewAdminToken {
+    label: String,
+    /// Token to accept, a random one is generated when absent
+    #[serde(default)]
+    token: Option<String>,
+}
+
+/// Request body to revoke an admin token
+#[derive(Deserialize)]
+pub struct RevokeAdminToken {
+    label: String,
+}
+
+/// Shows enough of a token to tell it apart without revealing it
+fn mask_token(token: &str) -> String {
+    let prefix = token.chars().take(4).collect::<String>();
+    format!("{prefix}***")
+}
+
+/// API endpoint to list the labeled admin tokens, with the tokens masked
+pub async fn api_get_admin_tokens(AuthBearer(t): AuthBearer) -> Result<Json<Value>, ApiError> {
+    if !CLEWDR_CONFIG.load().admin_auth(&t) {
+        return Err(ApiError::unauthorized());
+    }
+    let tokens = CLEWDR_CONFIG
+        .load()
+        .admin_tokens
+        .iter()
+        .map(|t| json!({ "label": t.label, "token": mask_token(&t.token) }))
+        .collect::<Vec<_>>();
+    Ok(Json(json!({ "tokens": tokens })))
+}
+
+/// API endpoint to add a labeled admin token
+///
+/// # Returns
+/// * `Json<Value>` - The new token, only ever shown in this response
+pub async fn api_post_admin_token(
+    AuthBearer(t): AuthBearer,
+    Json(new): Json<NewAdminToken>,
+) -> Result<Json<Value>, ApiError> {
+    let Some(actor) = CLEWDR_CONFIG.load().admin_label(&t).map(str::to_owned) else {
+        return Err(ApiError::unauthorized());
+    };
+    let label = new.label.trim().to_string();
+    if label.is_empty() {
+        return Err(ApiError::bad_request("Label must not be empty"));
+    }
+    let token = match new.token {
+        Some(token) if token.trim().is_empty() => {
+            return Err(ApiError::bad_request("Token must not be empty"));
+        }
+        Some(token) => token,
+        None => random_token(),
+    };
+    let entry = AdminToken {
+        token: token.to_owned(),
+        label: label.to_owned(),
+    };
+    // checked against the config being replaced, so concurrent additions
+    // cannot both take the same label or token
+    let mut conflict = None;
+    CLEWDR_CONFIG.rcu(|old_c| {
+        conflict = old_c.admin_token_conflict(&label, &token);
+        if conflict.is_some() {
+            return Arc::clone(old_c);
+        }
+        let mut new_c = ClewdrConfig::clone(old_c);
+        new_c.admin_tokens.push(entry.to_owned());
+        Arc::new(new_c)
+    });
+    if let Some(conflict) = conflict {
+        return Err(ApiError::bad_request(conflict));
+    }
+    if let Err(e) = CLEWDR_CONFIG.load().save().await {
+        return Err(ApiError::internal(format!("Failed to save config: {}", e)));
+    }
+    info!("Admin token `{}` added by admin `{}`", label, actor);
+    Ok(Json(json!({ "label": label, "token": token })))
+}
+
+/// API endpoint to revoke a labeled admin token
+pub async fn api_delete_admin_token(
+    AuthBearer(t): AuthBearer,
+    Json(revoke): Json<RevokeAdminToken>,
+) -> Result<Json<Value>, ApiError> {
+    let Some(actor) = CLEWDR_CONFIG.load().admin_label(&t).map(str::to_owned) else {
+        return Err(ApiError::unauthorized());
+    };
+    let mut found = false;
+    CLEWDR_CONFIG.rcu(|old_c| {
+        found = old_c.admin_tokens.iter().any(|t| t.label == revoke.label);
+        if !found {
+            return Arc::clone(old_c);
+        }
+        let mut new_c = ClewdrConfig::clone(old_c);
+        new_c.admin_tokens.retain(|t| t.label != revoke.label);
+        Arc::new(new_c)
+    });
+    if !found {
+        return Err(ApiError::bad_request(format!(
+            "Admin token `{}` not found",
+            revoke.label
+        )));
+    }
+    if let Err(e) = CLEWDR_CONFIG.load().save().await {
+        return Err(ApiError::internal(format!("Failed to save config: {}", e)));
+    }
+    info!(
+        "Admin token `{}` revoked by admin `{}`",
+        revoke.label, actor
+    );
+    Ok(Json(json!({ "message": "Admin token revoked" })))
+}
@@ -1,27 +1,41 @@
 use std::{
-    sync::LazyLock,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    collections::HashMap,
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use arc_swap::ArcSwapOption;
 use axum::{
     Json,
     extract::{Query, State},
     http::HeaderMap,
 };
+#[cfg(feature = "metrics")]
+use axum::{http::header::CONTENT_TYPE, response::IntoResponse};
 use axum_auth::AuthBearer;
 use moka::sync::Cache;
 use serde::Deserialize;
 use serde_json::{Value, json};
+use tokio::sync::Semaphore;
 use tracing::{error, info, warn};
-use wreq::StatusCode;
+use url::Url;
+use wreq::{Proxy, StatusCode};
 
 use super::error::ApiError;
+#[cfg(feature = "metrics")]
+use crate::middleware::RequireAdminAuth;
 use crate::{
     VERSION_INFO,
     claude_code_state::ClaudeCodeState,
     claude_web_state::ClaudeWebState,
-    config::{CLEWDR_CONFIG, CookieStatus},
-    services::cookie_actor::CookieActorHandle,
+    config::{CLEWDR_CONFIG, CookieStatus, Reason, parse_proxy, redact_proxy},
+    error::ClewdrError,
+    services::{
+        cookie_actor::{CookieActorHandle, cookie_pool_low},
+        rate_limiter::current_rates,
+        stream_limiter::open_streams,
+    },
+    utils::build_http_client,
 };
 
 /// Cache entry for cookie status responses
@@ -64,11 +78,14 @@ pub async fn api_post_cookie(
     AuthBearer(t): AuthBearer,
     Json(mut c): Json<CookieStatus>,
 ) -> Result<StatusCode, ApiError> {
-    if !CLEWDR_CONFIG.load().admin_auth(&t) {
+    let Some(label) = CLEWDR_CONFIG.load().admin_label(&t).map(str::to_owned) else {
         return Err(ApiError::unauthorized());
-    }
+    };
     c.reset_time = None;
-    info!("Cookie accepted: {}", c.cookie);
+    if let Some(proxy) = c.proxy.as_deref().filter(|p| !p.trim().is_empty()) {
+        parse_proxy(proxy).map_err(|e| ApiError::bad_request(e.to_string()))?;
+    }
+    info!("Cookie accepted from admin `{}`: {}", label, c.cookie);
     match s.submit(c).await {
         Ok(_) => {
             info!("Cookie submitted successfully");
@@ -193,13 +210,13 @@ pub async fn api_delete_cookie(
     AuthBearer(t): AuthBearer,
     Json(c): Json<CookieStatus>,
 ) -> Result<StatusCode, ApiError> {
-    if !CLEWDR_CONFIG.load().admin_auth(&t) {
+    let Some(label) = CLEWDR_CONFIG.load().admin_label(&t).map(str::to_owned) else {
         return Err(ApiError::unauthorized());
-    }
+    };
 
     match s.delete_cookie(c.to_owned()).await {
         Ok(_) => {
-            info!("Cookie deleted successfully: {}", c.cookie);
+            info!("Cookie deleted by admin `{}`: {}", label, c.cookie);
             // Clear cache to ensure fresh data on next request
             COOKIES_CACHE.invalidate(COOKIE_STATUS_CACHE_KEY);
             info!("Cookie status cache invalidated");
@@ -215,6 +232,118 @@ pub async fn api_delete_cookie(
     }
 }
 
+/// API endpoint to zero the request and token counters of every cookie
+///
+/// # Arguments
+/// * `s` - Application state containing event sender
+/// * `t` - Auth bearer token for admin authentication
+///
+/// # Returns
+/// * `Result<StatusCode, ApiError>` - Success status or error
+pub async fn api_reset_cookie_usage(
+    State(s): State<CookieActorHandle>,
+    AuthBearer(t): AuthBearer,
+) -> Result<StatusCode, ApiError> {
+    let Some(label) = CLEWDR_CONFIG.load().admin_label(&t).map(str::to_owned) else {
+        return Err(ApiError::unauthorized());
+    };
+    s.reset_use()
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to reset cookie usage: {}", e)))?;
+    info!("Cookie usage counters reset by admin `{}`", label);
+    COOKIES_CACHE.invalidate(COOKIE_STATUS_CACHE_KEY);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Most cookies checked against claude.ai at once by the validate endpoint
+const VALIDATE_CONCURRENCY: usize = 5;
+
+/// What checking one cookie found
+enum CookieCheck {
+    Valid { pro: bool },
+    Invalid,
+    Exhausted,
+    Failed,
+}
+
+/// Checks one cookie with a bootstrap call, handing a rejected one back to
+/// the pool with its reason
+async fn validate_cookie(handle: CookieActorHandle, cookie: CookieStatus) -> CookieCheck {
+    let result = match ClaudeWebState::from_cookie(handle.clone(), cookie.clone()) {
+        Ok(mut state) => state.check_cookie().await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(pro) => CookieCheck::Valid { pro },
+        Err(ClewdrError::InvalidCookie { reason }) => {
+            info!("Cookie {} failed validation: {}", cookie.cookie, reason);
+            let parked = matches!(reason, Reason::TooManyRequest(_) | Reason::Restricted(_));
+            handle
+                .return_cookie(cookie, Some(reason))
+                .await
+                .unwrap_or_else(|e| error!("Failed to return cookie: {}", e));
+            if parked {
+                CookieCheck::Exhausted
+            } else {
+                CookieCheck::Invalid
+            }
+        }
+        Err(e) => {
+            warn!("Could not validate cookie {}: {}", cookie.cookie, e);
+            CookieCheck::Failed
+        }
+    }
+}
+
+/// API endpoint to check every valid cookie against claude.ai
+/// Records whether each account is pro and moves dead cookies out of the
+/// pool, a few at a time
+///
+/// # Arguments
+/// * `s` - Application state containing event sender
+/// * `t` - Auth bearer token for admin authentication
+///
+/// # Returns
+/// * `Result<Json<Value>, ApiError>` - How many cookies were valid, pro,
+///   invalid, rate limited or could not be checked
+pub async fn api_validate_cookies(
+    State(s): State<CookieActorHandle>,
+    AuthBearer(t): AuthBearer,
+) -> Result<Json<Value>, ApiError> {
+    let Some(label) = CLEWDR_CONFIG.load().admin_label(&t).map(str::to_owned) else {
+        return Err(ApiError::unauthorized());
+    };
+    let status = s
+        .get_status()
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get cookie status: {}", e)))?;
+    info!(
+        "Cookie validation of {} cookies started by admin `{}`",
+        status.valid.len(),
+        label
+    );
+    let permits = &Semaphore::new(VALIDATE_CONCURRENCY);
+    let checks = join_all(status.valid.into_iter().map(|cookie| {
+        let handle = s.clone();
+        async move {
+            let _permit = permits.acquire().await;
+            validate_cookie(handle, cookie).await
+        }
+    }))
+    .await;
+    COOKIES_CACHE.invalidate(COOKIE_STATUS_CACHE_KEY);
+
+    let count = |f: fn(&CookieCheck) -> bool| checks.iter().filter(|c| f(c)).count();
+    Ok(Json(json!({
+        "checked": checks.len(),
+        "valid": count(|c| matches!(c, CookieCheck::Valid { .. })),
+        "pro": count(|c| matches!(c, CookieCheck::Valid { pro: true })),
+        "invalid": count(|c| matches!(c, CookieCheck::Invalid)),
+        "exhausted": count(|c| matches!(c, CookieCheck::Exhausted)),
+        "failed": count(|c| matches!(c, CookieCheck::Failed)),
+    })))
+}
+
 /// API endpoint to get the application version information
 ///
 /// # Returns
@@ -223,6 +352,174 @@ pub async fn api_version() -> String {
     VERSION_INFO.to_string()
 }
 
+/// Longest the cookie actor may take to answer a readiness probe
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Liveness probe, succeeds as soon as the server is serving
+pub async fn api_health() -> &'static str {
+    "OK"
+}
+
+/// Status and body of a readiness probe from the check result of each
+/// subsystem
+fn readiness(checks: Vec<(&str, Result<(), String>)>) -> (StatusCode, Value) {
+    let down = checks
+        .into_iter()
+        .filter_map(|(name, result)| Some((name.to_string(), result.err()?.into())))
+        .collect::<serde_json::Map<_, _>>();
+    let status = if down.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, json!({ "ready": down.is_empty(), "down": down }))
+}
+
+/// Readiness probe, answering 503 with the failing subsystems while
+/// requests cannot be served
+///
+/// # Returns
+/// * `(StatusCode, Json<Value>)` - Whether ClewdR is ready and what is down
+pub async fn api_ready(State(s): State<CookieActorHandle>) -> (StatusCode, Json<Value>) {
+    let cookie_actor = match tokio::time::timeout(READY_TIMEOUT, s.get_status()).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("no answer in time".to_string()),
+    };
+    let (status, body) = readiness(vec![("cookie_actor", cookie_actor)]);
+    (status, Json(body))
+}
+
+/// Prometheus metrics endpoint, needing the admin password when
+/// `metrics_require_auth` is enabled
+///
+/// # Returns
+/// * `Response` - Request counts, cookie pool sizes and open streams in the
+///   Prometheus text format
+#[cfg(feature = "metrics")]
+pub async fn api_metrics(
+    State(s): State<CookieActorHandle>,
+    auth: Result<RequireAdminAuth, ClewdrError>,
+) -> Result<impl IntoResponse, ClewdrError> {
+    if CLEWDR_CONFIG.load().metrics_require_auth {
+        auth?;
+    }
+    let cookies = s
+        .get_status()
+        .await
+        .inspect_err(|e| warn!("Cookie pool left out of metrics: {}", e))
+        .ok();
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        crate::services::metrics::export(cookies.as_ref()),
+    ))
+}
+
+/// API endpoint to retrieve runtime statistics
+///
+/// # Returns
+/// * `Json<Value>` - Open streaming responses, request rates and their limits
+pub async fn api_get_stats() -> Json<Value> {
+    let config = CLEWDR_CONFIG.load();
+    Json(json!({
+        "open_streams": open_streams(),
+        "max_concurrent_streams": config.max_concurrent_streams,
+        "rate_limits": current_rates(&config.rate_limits),
+        "min_healthy_cookies": config.min_healthy_cookies,
+        "cookie_pool_low": cookie_pool_low(),
+    }))
+}
+
+/// Time allowed for a proxy test request
+const PROXY_TEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Request body for the proxy test endpoint
+#[derive(Deserialize, Default)]
+pub struct ProxyTestRequest {
+    /// Proxy URL to test, the configured proxy is used when absent
+    #[serde(default)]
+    proxy: Option<String>,
+}
+
+/// API endpoint to test a proxy without sending a chat
+/// Connects to the upstream endpoint through the proxy and reports whether
+/// it succeeded, the latency and the egress IP seen by Cloudflare
+///
+/// # Arguments
+/// * `body` - Optional proxy URL, HTTP(S) and SOCKS5 are supported
+///
+/// # Returns
+/// * `Json<Value>` - Structured result of the connection attempt
+pub async fn api_test_proxy(body: Option<Json<ProxyTestRequest>>) -> Result<Json<Value>, ApiError> {
+    let raw = body
+        .and_then(|Json(b)| b.proxy)
+        .filter(|p| !p.trim().is_empty())
+        .or_else(|| CLEWDR_CONFIG.load().proxy.to_owned());
+    let proxy = raw
+        .as_deref()
+        .map(parse_proxy)
+        .transpose()
+        .map_err(|e| ApiError::bad_request(e.to_string()))?;
+    let target = CLEWDR_CONFIG
+        .load()
+        .endpoint()
+        .join("cdn-cgi/trace")
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    probe_proxy(raw.as_deref(), proxy.as_ref(), &target)
+        .await
+        .map(Json)
+}
+
+/// Fetches `target` through `proxy`, the proxy only counts as working when
+/// the target answers with a success status
+async fn probe_proxy(
+    raw: Option<&str>,
+    proxy: Option<&Proxy>,
+    target: &Url,
+) -> Result<Value, ApiError> {
+    let client = build_http_client(proxy)
+        .map_err(|e| ApiError::internal(format!("Failed to build client: {e}")))?;
+    let stopwatch = Instant::now();
+    let result = client
+        .get(target.as_str())
+        .timeout(PROXY_TEST_TIMEOUT)
+        .send()
+        .await;
+    let latency_ms = stopwatch.elapsed().as_millis() as u64;
+    let proxy = raw.map(redact_proxy);
+    let res = match result {
+        Ok(res) => res,
+        Err(e) => {
+            warn!("Proxy test failed: {}", e);
+            return Ok(json!({
+                "ok": false,
+                "proxy": proxy,
+                "target": target.as_str(),
+                "latency_ms": latency_ms,
+                "error": e.to_string(),
+            }));
+        }
+    };
+    let status = res.status();
+    if !status.is_success() {
+        warn!("Proxy test answered {}", status);
+    }
+    // Cloudflare's trace page lists the client address as `ip=...`
+    let egress_ip = res.text().await.ok().and_then(|text| {
+        text.lines()
+            .find_map(|line| line.strip_prefix("ip="))
+            .map(str::to_string)
+    });
+    Ok(json!({
+        "ok": status.is_success(),
+        "proxy": proxy,
+        "target": target.as_str(),
+        "status": status.as_u16(),
+        "latency_ms": latency_ms,
+        "egress_ip": egress_ip,
+    }))
+}
+
 /// API endpoint to verify authentication
 /// Checks if the provided token is valid for admin access
 ///
@@ -268,30 +565,73 @@ const MODEL_LIST: [&str; 26] = [
     "claude-opus-4-6-1M-thinking",
 ];
 
-/// API endpoint to get the list of available models
-/// Retrieves the list of models from the configuration
-pub async fn api_get_models() -> Json<Value> {
+/// Entry of the model listing
+fn model_entry(id: &str) -> Value {
+    json!({
+        "id": id,
+        "object": "model",
+        "created": 0,
+        "owned_by": "clewdr",
+    })
+}
+
+/// Model listing handed to polling clients, with the aliases it was built from
+struct ModelsSnapshot {
+    built: Instant,
+    aliases: HashMap<String, String>,
+    listing: Arc<Value>,
+}
+
+/// Last model listing built, rebuilt once it is older than
+/// `models_cache_ttl` or the configured aliases changed
+static MODELS_CACHE: ArcSwapOption<ModelsSnapshot> = ArcSwapOption::const_empty();
+
+/// Builds the listing from [`MODEL_LIST`] followed by the configured aliases
+fn build_models(aliases: &HashMap<String, String>) -> Value {
+    let mut names = aliases.keys().collect::<Vec<_>>();
+    names.sort();
     let data: Vec<Value> = MODEL_LIST
         .iter()
-        .map(|model| {
-            json!({
-                "id": model,
-                "object": "model",
-                "created": 0,
-                "owned_by": "clewdr",
-            })
-        })
-        .collect::<Vec<_>>();
-    Json(json!({
+        .copied()
+        .chain(names.into_iter().map(String::as_str))
+        .map(model_entry)
+        .collect();
+    json!({
         "object": "list",
         "data": data,
-    }))
+    })
+}
+
+/// Serves the cached listing while it is fresh, otherwise builds and caches
+/// a new one
+fn models_listing() -> Arc<Value> {
+    let config = CLEWDR_CONFIG.load();
+    let ttl = Duration::from_secs(config.models_cache_ttl);
+    if let Some(snapshot) = MODELS_CACHE.load().as_ref()
+        && snapshot.built.elapsed() < ttl
+        && snapshot.aliases == config.model_aliases
+    {
+        return snapshot.listing.to_owned();
+    }
+    let listing = Arc::new(build_models(&config.model_aliases));
+    MODELS_CACHE.store(Some(Arc::new(ModelsSnapshot {
+        built: Instant::now(),
+        aliases: config.model_aliases.to_owned(),
+        listing: listing.to_owned(),
+    })));
+    listing
+}
+
+/// API endpoint to get the list of available models
+/// Served from a short-lived cache, so clients polling it cost next to nothing
+pub async fn api_get_models() -> Json<Value> {
+    Json(Value::clone(&models_listing()))
 }
 
 // ------------------------------
 // Ephemeral org usage enrichment
 // ------------------------------
-use futures::{StreamExt, TryFutureExt, stream};
+use futures::{StreamExt, TryFutureExt, future::join_all, stream};
 use http::HeaderValue;
 
 async fn augment_utilization(cookies: Vec<CookieStatus>, handle: CookieActorHandle) -> Vec<Value> {
@@ -432,3 +772,59 @@ fn extract_usage_fields(usage: &serde_json::Value) -> Usage {
         sonnet_reset,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, routing::get};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn readiness_reports_what_is_down() {
+        let (status, body) = readiness(vec![("cookie_actor", Ok(()))]);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "ready": true, "down": {} }));
+
+        let (status, body) = readiness(vec![
+            ("cookie_actor", Err("actor stopped".to_string())),
+            ("other", Ok(())),
+        ]);
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body,
+            json!({ "ready": false, "down": { "cookie_actor": "actor stopped" } })
+        );
+    }
+
+    #[tokio::test]
+    async fn proxy_test_requires_a_success_status() {
+        let router = Router::new()
+            .route("/cdn-cgi/trace", get(|| async { "fl=1\nip=203.0.113.7\n" }))
+            .route("/blocked", get(|| async { StatusCode::FORBIDDEN }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let base = Url::parse(&format!("http://{addr}/")).unwrap();
+
+        let res = probe_proxy(None, None, &base.join("cdn-cgi/trace").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res["ok"], true);
+        assert_eq!(res["status"], 200);
+        assert_eq!(res["egress_ip"], "203.0.113.7");
+
+        let res = probe_proxy(None, None, &base.join("blocked").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res["ok"], false);
+        assert_eq!(res["status"], 403);
+    }
+
+    #[test]
+    fn rapid_model_listings_are_built_once() {
+        let first = models_listing();
+        let second = models_listing();
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+}
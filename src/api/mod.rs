@@ -5,12 +5,19 @@ mod error;
 mod misc;
 pub use claude_code::{api_claude_code, api_claude_code_count_tokens};
 /// Message handling endpoints for creating and managing chat conversations
-pub use claude_web::api_claude_web;
+pub use claude_web::{api_claude_web, api_claude_web_count_tokens};
 /// Configuration related endpoints for retrieving and updating Clewdr settings
-pub use config::{api_get_config, api_post_config};
+pub use config::{
+    api_delete_admin_token, api_download_config, api_get_admin_tokens, api_get_config,
+    api_post_admin_token, api_post_config, api_upload_config,
+};
 pub use error::ApiError;
 /// Miscellaneous endpoints for authentication, cookies, and version information
+#[cfg(feature = "metrics")]
+pub use misc::api_metrics;
 pub use misc::{
-    api_auth, api_delete_cookie, api_get_cookies, api_get_models, api_post_cookie, api_version,
+    api_auth, api_delete_cookie, api_get_cookies, api_get_models, api_get_stats, api_health,
+    api_post_cookie, api_ready, api_reset_cookie_usage, api_test_proxy, api_validate_cookies,
+    api_version,
 };
 // merged above
@@ -3,24 +3,31 @@ use axum::{
     response::{IntoResponse, Sse, sse::Event as SseEvent},
 };
 use colored::Colorize;
-use eventsource_stream::Eventsource;
-use futures::TryStreamExt;
-use http::header::{ACCEPT, USER_AGENT};
+use eventsource_stream::{Event, EventStreamError, Eventsource};
+use futures::{Stream, StreamExt, TryStreamExt};
+use http::{
+    HeaderMap, StatusCode,
+    header::{ACCEPT, CONTENT_LENGTH, USER_AGENT},
+};
+use serde_json::Value;
 use snafu::{GenerateImplicitData, ResultExt};
 use tracing::{Instrument, error, info, warn};
 use wreq::Method;
 
 use crate::{
-    claude_code_state::{ClaudeCodeState, TokenStatus},
-    config::{CLAUDE_CODE_USER_AGENT, CLEWDR_CONFIG, ModelFamily},
+    claude_code_state::{
+        ClaudeCodeState, TokenStatus,
+        continuation::{ContinueBudget, StreamStitcher, continuation_params, merge_response},
+    },
+    config::{CLAUDE_CODE_USER_AGENT, CLEWDR_CONFIG, CookieNeed, ModelFamily},
     error::{CheckClaudeErr, ClewdrError, WreqSnafu},
-    services::cookie_actor::CookieActorHandle,
+    services::cookie_actor::{CookieActorHandle, hold_lease},
     types::claude::{CountMessageTokensResponse, CreateMessageParams},
+    utils::{retry_attempts, with_idle_timeout, with_proxy_hops, with_total_timeout},
 };
 
 pub(super) const CLAUDE_BETA_BASE: &str = "oauth-2025-04-20";
 const CLAUDE_USAGE_URL: &str = "https://api.anthropic.com/api/oauth/usage";
-pub(super) const CLAUDE_API_VERSION: &str = "2023-06-01";
 
 impl ClaudeCodeState {
     /// Attempts to send a chat message to Claude API with retry mechanism
@@ -45,79 +52,89 @@ impl ClaudeCodeState {
         &mut self,
         p: CreateMessageParams,
     ) -> Result<axum::response::Response, ClewdrError> {
-        for i in 0..CLEWDR_CONFIG.load().max_retries + 1 {
-            if i > 0 {
-                info!("[RETRY] attempt: {}", i.to_string().green());
-            }
-            let mut state = self.to_owned();
-            let p = p.to_owned();
-
-            let cookie = state.request_cookie().await?;
-            let retry = async {
-                match state.check_token() {
-                    TokenStatus::None => {
-                        info!("No token found, requesting new token");
-                        let org = state.get_organization().await?;
-                        let code_res = state.exchange_code(&org).await?;
-                        state.exchange_token(code_res).await?;
-                        state.return_cookie(None).await;
-                    }
-                    TokenStatus::Expired => {
-                        info!("Token expired, refreshing token");
-                        state.refresh_token().await?;
-                        state.return_cookie(None).await;
-                    }
-                    TokenStatus::Valid => {
-                        info!("Token is valid, proceeding with request");
+        retry_attempts(
+            CLEWDR_CONFIG.load().max_retries,
+            self.client_timeout.is_some(),
+            |_| {
+                let mut state = self.to_owned();
+                let p = p.to_owned();
+                async move {
+                    let cookie = match state.request_cookie(CookieNeed::for_model(&p.model)).await {
+                        Ok(cookie) => cookie,
+                        Err(e) => return (state, Err(e)),
+                    };
+                    let res = async {
+                        match state.check_token() {
+                            TokenStatus::None => {
+                                info!("No token found, requesting new token");
+                                let org = state.get_organization().await?;
+                                let code_res = state.exchange_code(&org).await?;
+                                state.exchange_token(code_res).await?;
+                                state.return_cookie(None).await;
+                            }
+                            TokenStatus::Expired => {
+                                info!("Token expired, refreshing token");
+                                state.refresh_token().await?;
+                                state.return_cookie(None).await;
+                            }
+                            TokenStatus::Valid => {
+                                info!("Token is valid, proceeding with request");
+                            }
+                        }
+                        let Some(access_token) =
+                            state.cookie.as_ref().and_then(|c| c.token.to_owned())
+                        else {
+                            return Err(ClewdrError::UnexpectedNone {
+                                msg: "No access token found in cookie",
+                            });
+                        };
+                        let limit = CLEWDR_CONFIG
+                            .load()
+                            .upstream_duration(state.stream, state.client_timeout);
+                        with_total_timeout(
+                            state.send_chat(access_token.access_token.to_owned(), p),
+                            limit,
+                        )
+                        .await
                     }
-                }
-                let Some(access_token) = state.cookie.as_ref().and_then(|c| c.token.to_owned())
-                else {
-                    return Err(ClewdrError::UnexpectedNone {
-                        msg: "No access token found in cookie",
-                    });
-                };
-                state
-                    .send_chat(access_token.access_token.to_owned(), p)
+                    .instrument(tracing::info_span!(
+                        "claude_code",
+                        "cookie" = cookie.cookie.mask()
+                    ))
                     .await
-            }
-            .instrument(tracing::info_span!(
-                "claude_code",
-                "cookie" = cookie.cookie.mask()
-            ));
-            match retry.await {
-                Ok(res) => {
-                    return Ok(res);
-                }
-                Err(e) => {
-                    error!(
-                        "[{}] {}",
-                        state.cookie.as_ref().unwrap().cookie.mask().green(),
-                        e
-                    );
-                    // 429 error
-                    if let ClewdrError::InvalidCookie { reason } = e {
-                        state.return_cookie(Some(reason.to_owned())).await;
-                        continue;
+                    .map(|r| hold_lease(state.lease.clone(), r));
+                    if let Err(e) = &res {
+                        error!("[{}] {}", cookie.cookie.mask().green(), e);
                     }
-                    return Err(e);
+                    (state, res)
                 }
-            }
-        }
-        Err(ClewdrError::TooManyRetries)
+            },
+            |state, reason| async move { state.return_cookie(reason).await },
+        )
+        .await
     }
 
     pub async fn send_chat(
         &mut self,
         access_token: String,
-        mut p: CreateMessageParams,
+        p: CreateMessageParams,
     ) -> Result<axum::response::Response, ClewdrError> {
+        let p = Self::upstream_params(p);
+        let model_family = Self::classify_model(&p.model);
+        let response = self.execute_claude_request(&access_token, &p).await?;
+        let budget = ContinueBudget::for_params(&p, &CLEWDR_CONFIG.load());
+        let continuation = budget.map(|budget| (budget, access_token, p));
+        self.handle_success_response(response, model_family, continuation)
+            .await
+    }
+
+    /// Request body as sent to the Messages API, without ClewdR's own
+    /// model suffixes
+    pub fn upstream_params(mut p: CreateMessageParams) -> CreateMessageParams {
         if let Some(stripped) = p.model.strip_suffix("-1M") {
             p.model = stripped.to_string();
         }
-        let model_family = Self::classify_model(&p.model);
-        let response = self.execute_claude_request(&access_token, &p).await?;
-        self.handle_success_response(response, model_family).await
+        p
     }
 
     async fn execute_claude_request(
@@ -126,20 +143,23 @@ impl ClaudeCodeState {
         body: &CreateMessageParams,
     ) -> Result<wreq::Response, ClewdrError> {
         let beta_header = Self::build_beta_header(self.anthropic_beta_header.as_deref());
-        self.client
-            .post(
-                self.endpoint
-                    .join("v1/messages")
-                    .map_err(|e| ClewdrError::Whatever {
-                        message: format!("Parse URL error: {e}"),
-                        source: Some(Box::new(e)),
-                    })?
-                    .to_string(),
-            )
+        let url = self
+            .endpoint
+            .join("v1/messages")
+            .map_err(|e| ClewdrError::Whatever {
+                message: format!("Parse URL error: {e}"),
+                source: Some(Box::new(e)),
+            })?
+            .to_string();
+        let req = self
+            .client
+            .post(&url)
             .bearer_auth(access_token)
             .header(USER_AGENT, CLAUDE_CODE_USER_AGENT)
             .header("anthropic-beta", beta_header)
-            .header("anthropic-version", CLAUDE_API_VERSION)
+            .header("anthropic-version", &self.anthropic_version);
+        with_proxy_hops(req, &url, self.proxy_hops)
+            .headers(self.upstream_headers.clone())
             .json(body)
             .send()
             .await
@@ -218,7 +238,7 @@ impl ClaudeCodeState {
             let mut state = self.to_owned();
             let p = p.to_owned();
 
-            let cookie = state.request_cookie().await?;
+            let cookie = state.request_cookie(CookieNeed::Any).await?;
             let web_attempt_allowed = CLEWDR_CONFIG.load().enable_web_count_tokens;
             let cookie_disallows = matches!(cookie.count_tokens_allowed, Some(false));
             if cookie_disallows || (for_web && !web_attempt_allowed) {
@@ -312,19 +332,31 @@ impl ClaudeCodeState {
         }
     }
 
+    /// Forwards a successful response, continuing it when it was cut off at
+    /// `max_tokens` and `continuation` holds the budget, token and request
     async fn handle_success_response(
         &mut self,
         response: wreq::Response,
         model_family: ModelFamily,
+        continuation: Option<(ContinueBudget, String, CreateMessageParams)>,
     ) -> Result<axum::response::Response, ClewdrError> {
         if !self.stream {
-            let (resp, usage_pair) = Self::materialize_non_stream_response(response).await?;
+            let (resp, usage_pair) = match continuation {
+                Some((budget, access_token, p)) => {
+                    self.materialize_with_continuations(response, budget, &access_token, &p)
+                        .await?
+                }
+                None => Self::materialize_non_stream_response(response).await?,
+            };
             let (input, output) = usage_pair.unwrap_or((self.usage.input_tokens as u64, 0));
             self.persist_usage_totals(input, output, model_family).await;
+            self.record_use(input + output).await;
             Ok(resp)
         } else {
             // Stream pass-through while accumulating output token usage from message_delta events
-            return self.forward_stream_with_usage(response, model_family).await;
+            return self
+                .forward_stream_with_usage(response, model_family, continuation)
+                .await;
         }
     }
 
@@ -343,10 +375,79 @@ impl ClaudeCodeState {
         }
     }
 
+    /// Counts the request against its cookie, once it has succeeded
+    async fn record_use(&self, tokens: u64) {
+        let Some(cookie) = self.cookie.as_ref() else {
+            return;
+        };
+        if let Err(err) = self
+            .cookie_actor_handle
+            .record_use(cookie.cookie.to_owned(), tokens)
+            .await
+        {
+            warn!("Failed to record cookie use: {}", err);
+        }
+    }
+
+    /// Events of `response` followed by those of its continuations, stitched
+    /// into a single message
+    ///
+    /// The next continuation is only requested once the previous stream has
+    /// ended, so the client sees a pause where a response was cut off. If a
+    /// continuation fails the message ends as it was, cut off at `max_tokens`.
+    fn stitch_continuations(
+        &self,
+        response: wreq::Response,
+        budget: ContinueBudget,
+        access_token: String,
+        p: CreateMessageParams,
+    ) -> impl Stream<Item = Result<Event, EventStreamError<wreq::Error>>> + Send + 'static {
+        let mut state = self.to_owned();
+        async_stream::stream! {
+            let mut stitcher = StreamStitcher::new(budget);
+            let mut response = response;
+            loop {
+                let mut events = std::pin::pin!(response.bytes_stream().eventsource());
+                while let Some(event) = events.next().await {
+                    match event {
+                        Ok(event) => {
+                            for event in stitcher.observe(event) {
+                                yield Ok(event);
+                            }
+                        }
+                        Err(e) => {
+                            yield Err(e);
+                            return;
+                        }
+                    }
+                }
+                let Some(prefill) = stitcher.take_continuation() else {
+                    break;
+                };
+                info!("Response cut off at max_tokens, continuing");
+                let body = continuation_params(&p, prefill);
+                match state.execute_claude_request(&access_token, &body).await {
+                    Ok(next) => {
+                        stitcher.resume();
+                        response = next;
+                    }
+                    Err(e) => {
+                        warn!("Failed to continue truncated response: {}", e);
+                        break;
+                    }
+                }
+            }
+            for event in stitcher.finish() {
+                yield Ok(event);
+            }
+        }
+    }
+
     async fn forward_stream_with_usage(
         &mut self,
         response: wreq::Response,
         family: ModelFamily,
+        continuation: Option<(ContinueBudget, String, CreateMessageParams)>,
     ) -> Result<axum::response::Response, ClewdrError> {
         use std::sync::{
             Arc,
@@ -357,9 +458,17 @@ impl ClaudeCodeState {
         let output_sum = Arc::new(AtomicU64::new(0));
         let handle = self.cookie_actor_handle.clone();
         let cookie = self.cookie.clone();
+        let timeout_handle = handle.clone();
+        let timeout_cookie = cookie.clone();
 
         let osum = output_sum.clone();
-        let stream = response.bytes_stream().eventsource().map_ok(move |event| {
+        let events = match continuation {
+            Some((budget, access_token, p)) => self
+                .stitch_continuations(response, budget, access_token, p)
+                .boxed(),
+            None => response.bytes_stream().eventsource().boxed(),
+        };
+        let stream = events.map_ok(move |event| {
             // accumulate output tokens from message_delta usage if present
             if let Ok(parsed) =
                 serde_json::from_str::<crate::types::claude::StreamEvent>(&event.data)
@@ -378,7 +487,9 @@ impl ClaudeCodeState {
                                 ClaudeCodeState::update_cookie_boundaries_if_due(&mut c, &handle)
                                     .await;
                                 c.add_and_bucket_usage(input_tokens, total_out, family);
+                                let used = c.cookie.to_owned();
                                 let _ = handle.return_cookie(c, None).await;
+                                let _ = handle.record_use(used, input_tokens + total_out).await;
                             });
                         }
                     }
@@ -394,20 +505,87 @@ impl ClaudeCodeState {
             };
             e.data(event.data)
         });
+        let stream = with_idle_timeout(
+            stream,
+            CLEWDR_CONFIG.load().stream_idle_duration(),
+            move || {
+                if let Some(cookie) = timeout_cookie {
+                    tokio::spawn(async move {
+                        let _ = timeout_handle.return_cookie(cookie, None).await;
+                    });
+                }
+            },
+        );
 
         Ok(Sse::new(stream)
             .keep_alive(Default::default())
             .into_response())
     }
 
-    async fn materialize_non_stream_response(
+    async fn read_body(
         response: wreq::Response,
-    ) -> Result<(axum::response::Response, Option<(u64, u64)>), ClewdrError> {
+    ) -> Result<(StatusCode, HeaderMap, bytes::Bytes), ClewdrError> {
         let status = response.status();
         let headers = response.headers().clone();
         let bytes = response.bytes().await.context(WreqSnafu {
             msg: "Failed to read Claude response body",
         })?;
+        Ok((status, headers, bytes))
+    }
+
+    async fn materialize_non_stream_response(
+        response: wreq::Response,
+    ) -> Result<(axum::response::Response, Option<(u64, u64)>), ClewdrError> {
+        let (status, headers, bytes) = Self::read_body(response).await?;
+        Self::build_response(status, headers, bytes)
+    }
+
+    /// Reads a non-stream response, requesting continuations while it is cut
+    /// off at `max_tokens` and merging them into one message
+    ///
+    /// A failed continuation leaves the response as far as it got.
+    async fn materialize_with_continuations(
+        &mut self,
+        response: wreq::Response,
+        mut budget: ContinueBudget,
+        access_token: &str,
+        p: &CreateMessageParams,
+    ) -> Result<(axum::response::Response, Option<(u64, u64)>), ClewdrError> {
+        let (status, mut headers, bytes) = Self::read_body(response).await?;
+        let Ok(mut merged) = serde_json::from_slice::<Value>(&bytes) else {
+            return Self::build_response(status, headers, bytes);
+        };
+        let mut continued = false;
+        while let Some(prefill) = budget.continue_response(&merged) {
+            info!("Response cut off at max_tokens, continuing");
+            let body = continuation_params(p, prefill);
+            let next = match self.execute_claude_request(access_token, &body).await {
+                Ok(next) => next.json::<Value>().await.context(WreqSnafu {
+                    msg: "Failed to read Claude continuation",
+                }),
+                Err(e) => Err(e),
+            };
+            match next {
+                Ok(next) => merge_response(&mut merged, next),
+                Err(e) => {
+                    warn!("Failed to continue truncated response: {}", e);
+                    break;
+                }
+            }
+            continued = true;
+        }
+        if !continued {
+            return Self::build_response(status, headers, bytes);
+        }
+        headers.remove(CONTENT_LENGTH);
+        Self::build_response(status, headers, serde_json::to_vec(&merged)?.into())
+    }
+
+    fn build_response(
+        status: StatusCode,
+        headers: HeaderMap,
+        bytes: bytes::Bytes,
+    ) -> Result<(axum::response::Response, Option<(u64, u64)>), ClewdrError> {
         let usage = Self::extract_usage_from_bytes(&bytes);
 
         let mut builder = http::Response::builder().status(status);
@@ -457,20 +635,23 @@ impl ClaudeCodeState {
         body: &CreateMessageParams,
     ) -> Result<wreq::Response, ClewdrError> {
         let beta_header = Self::build_beta_header(self.anthropic_beta_header.as_deref());
-        self.client
-            .post(
-                self.endpoint
-                    .join("v1/messages/count_tokens")
-                    .map_err(|e| ClewdrError::Whatever {
-                        message: format!("Parse URL error: {e}"),
-                        source: Some(Box::new(e)),
-                    })?
-                    .to_string(),
-            )
+        let url = self
+            .endpoint
+            .join("v1/messages/count_tokens")
+            .map_err(|e| ClewdrError::Whatever {
+                message: format!("Parse URL error: {e}"),
+                source: Some(Box::new(e)),
+            })?
+            .to_string();
+        let req = self
+            .client
+            .post(&url)
             .bearer_auth(access_token)
             .header(USER_AGENT, CLAUDE_CODE_USER_AGENT)
             .header("anthropic-beta", beta_header)
-            .header("anthropic-version", CLAUDE_API_VERSION)
+            .header("anthropic-version", &self.anthropic_version);
+        with_proxy_hops(req, &url, self.proxy_hops)
+            .headers(self.upstream_headers.clone())
             .json(body)
             .send()
             .await
@@ -481,14 +662,13 @@ impl ClaudeCodeState {
             .await
     }
 
+    /// The OAuth beta followed by the client's own betas, each token once
     fn build_beta_header(extra: Option<&str>) -> String {
-        let mut parts = vec![CLAUDE_BETA_BASE.to_string()];
-        if let Some(extra) = extra {
-            for token in extra.split(',') {
-                let t = token.trim();
-                if !t.is_empty() {
-                    parts.push(t.to_string());
-                }
+        let mut parts = vec![CLAUDE_BETA_BASE];
+        for token in extra.unwrap_or_default().split(',') {
+            let t = token.trim();
+            if !t.is_empty() && !parts.contains(&t) {
+                parts.push(t);
             }
         }
         parts.join(",")
@@ -645,3 +825,89 @@ impl ClaudeCodeState {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Json, Router,
+        body::{self, Body},
+        extract::{FromRequest, Request},
+        routing::post,
+    };
+    use http::{HeaderMap, header::CONTENT_TYPE};
+    use serde_json::{Value, json};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{middleware::claude::ClaudeCodePreprocess, providers::claude::build_providers};
+
+    #[tokio::test]
+    async fn count_tokens_answers_json_when_stream_is_requested() {
+        let params = serde_json::from_value::<CreateMessageParams>(json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 16,
+            "stream": true,
+            "messages": [{"role": "user", "content": "Hello there"}],
+        }))
+        .unwrap();
+        let resp = ClaudeCodeState::local_count_tokens_response(&params);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+        let bytes = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let value = serde_json::from_slice::<Value>(&bytes).unwrap();
+        assert!(value["input_tokens"].as_u64().is_some_and(|n| n > 0));
+    }
+
+    #[test]
+    fn client_betas_are_merged_with_the_oauth_beta() {
+        assert_eq!(ClaudeCodeState::build_beta_header(None), CLAUDE_BETA_BASE);
+        assert_eq!(
+            ClaudeCodeState::build_beta_header(Some(
+                "prompt-caching-2024-07-31, oauth-2025-04-20,,context-1m-2025-08-07,prompt-caching-2024-07-31"
+            )),
+            "oauth-2025-04-20,prompt-caching-2024-07-31,context-1m-2025-08-07"
+        );
+    }
+
+    #[tokio::test]
+    async fn client_anthropic_version_overrides_the_configured_one() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route(
+            "/v1/messages",
+            post(|headers: HeaderMap| async move {
+                Json(json!({ "version": headers["anthropic-version"].to_str().unwrap() }))
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let providers = build_providers(CookieActorHandle::start().await.unwrap());
+        let body = json!({
+            "model": "claude-sonnet-4-6",
+            "max_tokens": 16,
+            "messages": [{"role": "user", "content": "Hello there"}],
+        });
+        // what goes upstream for a client request carrying `version`
+        let sent = async |version: Option<&str>| {
+            let mut req =
+                Request::post("/code/v1/messages").header(CONTENT_TYPE, "application/json");
+            if let Some(version) = version {
+                req = req.header("anthropic-version", version);
+            }
+            let req = req.body(Body::from(body.to_string())).unwrap();
+            let ClaudeCodePreprocess(params, context) =
+                ClaudeCodePreprocess::from_request(req, &()).await.unwrap();
+            let mut state = providers.code().state(&context);
+            state.endpoint = url::Url::parse(&format!("http://{addr}/")).unwrap();
+            let res = state
+                .execute_claude_request("token", &params)
+                .await
+                .unwrap();
+            res.json::<Value>().await.unwrap()["version"].to_owned()
+        };
+        assert_eq!(
+            sent(None).await,
+            CLEWDR_CONFIG.load().anthropic_version.as_str()
+        );
+        assert_eq!(sent(Some("2099-01-01")).await, "2099-01-01");
+    }
+}
@@ -0,0 +1,514 @@
+use std::collections::HashMap;
+
+use eventsource_stream::Event;
+use serde_json::{Value, json};
+
+use crate::{
+    config::ClewdrConfig,
+    types::claude::{CreateMessageParams, Message, Role, Thinking},
+};
+
+/// Continuations left for a response cut off at `max_tokens`
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ContinueBudget {
+    attempts: usize,
+    max_output: u64,
+}
+
+impl ContinueBudget {
+    /// Budget for a request, `None` when anti-truncation is off or the
+    /// request cannot be continued by prefilling the assistant turn
+    ///
+    /// Extended thinking does not allow prefill, and a request that already
+    /// ends with an assistant turn has its own prefill.
+    pub(super) fn for_params(params: &CreateMessageParams, config: &ClewdrConfig) -> Option<Self> {
+        if config.anti_truncation_attempts == 0
+            || matches!(
+                params.thinking,
+                Some(Thinking::Enabled { .. } | Thinking::Adaptive { .. })
+            )
+            || params
+                .messages
+                .last()
+                .is_some_and(|m| m.role == Role::Assistant)
+        {
+            return None;
+        }
+        Some(Self {
+            attempts: config.anti_truncation_attempts,
+            max_output: config.anti_truncation_max_tokens,
+        })
+    }
+
+    /// Whether a response with `output` tokens so far may be continued
+    fn allows(&self, output: u64) -> bool {
+        self.attempts > 0 && (self.max_output == 0 || output < self.max_output)
+    }
+
+    /// Prefill continuing a non-stream response, when it was cut off at
+    /// `max_tokens`, holds only text and the budget allows another attempt
+    pub(super) fn continue_response(&mut self, resp: &Value) -> Option<String> {
+        if resp["stop_reason"] != "max_tokens" {
+            return None;
+        }
+        let output = resp["usage"]["output_tokens"].as_u64().unwrap_or_default();
+        if !self.allows(output) {
+            return None;
+        }
+        let mut text = String::new();
+        for block in resp["content"].as_array()? {
+            if block["type"] != "text" {
+                return None;
+            }
+            text.push_str(block["text"].as_str().unwrap_or_default());
+        }
+        let text = text.trim_end();
+        if text.is_empty() {
+            return None;
+        }
+        self.attempts -= 1;
+        Some(text.to_string())
+    }
+}
+
+/// Request continuing `params` after the assistant text `prefill`
+///
+/// The prefill must not end in whitespace, so callers trim it and the model
+/// writes the trimmed whitespace again.
+pub(super) fn continuation_params(
+    params: &CreateMessageParams,
+    prefill: String,
+) -> CreateMessageParams {
+    let mut params = params.to_owned();
+    params
+        .messages
+        .push(Message::new_text(Role::Assistant, prefill));
+    params
+}
+
+/// Appends a continuation to a non-stream response
+///
+/// The continuation's first text block extends the last text block, its
+/// stop reason replaces the truncated one and output tokens add up.
+pub(super) fn merge_response(merged: &mut Value, next: Value) {
+    let mut blocks = next["content"].as_array().cloned().unwrap_or_default();
+    if let Some(content) = merged["content"].as_array_mut() {
+        let last_text = content.iter_mut().rev().find(|b| b["type"] == "text");
+        if let Some(last) = last_text
+            && blocks.first().is_some_and(|b| b["type"] == "text")
+        {
+            let first = blocks.remove(0);
+            let text = last["text"]
+                .as_str()
+                .unwrap_or_default()
+                .trim_end()
+                .to_string();
+            last["text"] = json!(text + first["text"].as_str().unwrap_or_default());
+        }
+        content.extend(blocks);
+    }
+    merged["stop_reason"] = next["stop_reason"].to_owned();
+    merged["stop_sequence"] = next["stop_sequence"].to_owned();
+    let output = merged["usage"]["output_tokens"]
+        .as_u64()
+        .unwrap_or_default()
+        + next["usage"]["output_tokens"].as_u64().unwrap_or_default();
+    if merged["usage"].is_object() {
+        merged["usage"]["output_tokens"] = json!(output);
+    }
+}
+
+/// Joins the event streams of a response and its continuations into one
+///
+/// Events go through as they arrive, except for what a continuation may
+/// still change:
+/// - the trailing whitespace of the text, which the model writes again after
+///   the trimmed prefill
+/// - the `content_block_stop` of the last block, which a continuation's
+///   text extends
+/// - the `message_delta` and `message_stop` of a truncated response
+///
+/// A continuation's `message_start` is dropped and its block indices follow
+/// on from the ones already sent. The text sent so far is kept as the
+/// prefill of the next continuation.
+pub(super) struct StreamStitcher {
+    budget: ContinueBudget,
+    /// Client index of each upstream block of the current response
+    indices: HashMap<u64, u64>,
+    next_index: u64,
+    /// Client index of the last text block, which a continuation extends
+    last_text: Option<u64>,
+    /// Assistant text so far, without the held whitespace
+    text: String,
+    /// Trailing whitespace of the text not sent yet
+    held_space: String,
+    held_stop: Option<Event>,
+    held_delta: Option<Event>,
+    held_end: Option<Event>,
+    output_tokens: u64,
+    only_text: bool,
+    continuing: bool,
+    truncated: bool,
+}
+
+/// Event with its data replaced
+fn with_data(event: Event, data: Value) -> Event {
+    Event {
+        data: data.to_string(),
+        ..event
+    }
+}
+
+impl StreamStitcher {
+    pub(super) fn new(budget: ContinueBudget) -> Self {
+        Self {
+            budget,
+            indices: HashMap::new(),
+            next_index: 0,
+            last_text: None,
+            text: String::new(),
+            held_space: String::new(),
+            held_stop: None,
+            held_delta: None,
+            held_end: None,
+            output_tokens: 0,
+            only_text: true,
+            continuing: false,
+            truncated: false,
+        }
+    }
+
+    /// Sends the held whitespace and end of the last block
+    fn flush_block(&mut self) -> Vec<Event> {
+        let mut out = vec![];
+        if !self.held_space.is_empty()
+            && let Some(index) = self.last_text
+        {
+            let data = json!({
+                "type": "content_block_delta",
+                "index": index,
+                "delta": {"type": "text_delta", "text": std::mem::take(&mut self.held_space)},
+            });
+            out.push(Event {
+                event: "content_block_delta".to_string(),
+                data: data.to_string(),
+                ..Default::default()
+            });
+        }
+        out.extend(self.held_stop.take());
+        out
+    }
+
+    /// Client index of an upstream block
+    fn remap(&self, data: &mut Value) {
+        if let Some(index) = data["index"].as_u64().and_then(|i| self.indices.get(&i)) {
+            data["index"] = json!(index);
+        }
+    }
+
+    /// Events to send the client for an upstream event
+    pub(super) fn observe(&mut self, event: Event) -> Vec<Event> {
+        let Ok(mut data) = serde_json::from_str::<Value>(&event.data) else {
+            return vec![event];
+        };
+        match data["type"].as_str().unwrap_or_default() {
+            "message_start" if self.continuing => vec![],
+            "content_block_start" => {
+                let upstream = data["index"].as_u64().unwrap_or_default();
+                let is_text = data["content_block"]["type"] == "text";
+                self.only_text &= is_text;
+                if self.continuing
+                    && is_text
+                    && self.indices.is_empty()
+                    && let Some(index) = self.last_text
+                {
+                    // the continuation extends the block left open, and ends it
+                    self.indices.insert(upstream, index);
+                    self.held_stop = None;
+                    return vec![];
+                }
+                let mut out = self.flush_block();
+                let index = self.next_index;
+                self.next_index += 1;
+                self.indices.insert(upstream, index);
+                if is_text {
+                    self.last_text = Some(index);
+                }
+                data["index"] = json!(index);
+                out.push(with_data(event, data));
+                out
+            }
+            "content_block_delta" => {
+                self.remap(&mut data);
+                if data["delta"]["type"] == "text_delta" {
+                    let text = std::mem::take(&mut self.held_space)
+                        + data["delta"]["text"].as_str().unwrap_or_default();
+                    let sent = text.trim_end();
+                    self.held_space = text[sent.len()..].to_string();
+                    if sent.is_empty() {
+                        return vec![];
+                    }
+                    self.text.push_str(sent);
+                    data["delta"]["text"] = json!(sent);
+                }
+                vec![with_data(event, data)]
+            }
+            "content_block_stop" => {
+                // the block's held whitespace goes out with it, once the next
+                // block starts or the message ends
+                self.remap(&mut data);
+                self.held_stop = Some(with_data(event, data));
+                vec![]
+            }
+            "message_delta" => {
+                self.output_tokens += data["usage"]["output_tokens"].as_u64().unwrap_or_default();
+                if data["usage"].is_object() {
+                    data["usage"]["output_tokens"] = json!(self.output_tokens);
+                }
+                let cut_off = data["delta"]["stop_reason"] == "max_tokens";
+                let event = with_data(event, data);
+                if cut_off
+                    && self.only_text
+                    && !self.text.is_empty()
+                    && self.budget.allows(self.output_tokens)
+                {
+                    self.truncated = true;
+                    self.held_delta = Some(event);
+                    return vec![];
+                }
+                let mut out = self.flush_block();
+                out.push(event);
+                out
+            }
+            "message_stop" if self.truncated => {
+                self.held_end = Some(event);
+                vec![]
+            }
+            _ => vec![event],
+        }
+    }
+
+    /// Prefill of a continuation, when the response was cut off and the
+    /// budget allows another attempt
+    pub(super) fn take_continuation(&mut self) -> Option<String> {
+        if !std::mem::take(&mut self.truncated) {
+            return None;
+        }
+        self.budget.attempts -= 1;
+        Some(self.text.to_owned())
+    }
+
+    /// Drops what the continuation that was just sent replaces
+    pub(super) fn resume(&mut self) {
+        self.continuing = true;
+        self.indices.clear();
+        self.held_space.clear();
+        self.held_delta = None;
+        self.held_end = None;
+    }
+
+    /// Held events, once no continuation follows
+    pub(super) fn finish(&mut self) -> Vec<Event> {
+        let mut out = self.flush_block();
+        out.extend(self.held_delta.take());
+        out.extend(self.held_end.take());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(attempts: usize, max_output: u64) -> ContinueBudget {
+        ContinueBudget {
+            attempts,
+            max_output,
+        }
+    }
+
+    fn event(data: Value) -> Event {
+        Event {
+            event: data["type"].as_str().unwrap().to_string(),
+            data: data.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Upstream events of a text response
+    fn response(deltas: &[&str], stop_reason: &str, output_tokens: u64) -> Vec<Event> {
+        let mut events = vec![
+            event(json!({"type": "message_start", "message": {"id": "msg_1"}})),
+            event(
+                json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}}),
+            ),
+        ];
+        for text in deltas {
+            events.push(event(json!({
+                "type": "content_block_delta",
+                "index": 0,
+                "delta": {"type": "text_delta", "text": text},
+            })));
+        }
+        events.extend([
+            event(json!({"type": "content_block_stop", "index": 0})),
+            event(json!({
+                "type": "message_delta",
+                "delta": {"stop_reason": stop_reason, "stop_sequence": null},
+                "usage": {"output_tokens": output_tokens},
+            })),
+            event(json!({"type": "message_stop"})),
+        ]);
+        events
+    }
+
+    /// Mock upstream: the first request is cut off, the continuation completes
+    fn mock_upstream(prefill: Option<&str>) -> Vec<Event> {
+        match prefill {
+            None => response(&["Once upon", " a "], "max_tokens", 3),
+            Some("Once upon a") => response(&[" time."], "end_turn", 2),
+            Some(other) => panic!("unexpected prefill {other:?}"),
+        }
+    }
+
+    #[test]
+    fn truncated_stream_is_stitched_to_its_continuation() {
+        let mut stitcher = StreamStitcher::new(budget(2, 0));
+        let mut sent = vec![];
+        let mut upstream = mock_upstream(None);
+        let mut prefills = vec![];
+        loop {
+            for event in upstream {
+                sent.extend(stitcher.observe(event));
+            }
+            let Some(prefill) = stitcher.take_continuation() else {
+                break;
+            };
+            upstream = mock_upstream(Some(&prefill));
+            prefills.push(prefill);
+            stitcher.resume();
+        }
+        sent.extend(stitcher.finish());
+
+        assert_eq!(prefills, ["Once upon a"]);
+        let data = sent
+            .iter()
+            .map(|e| serde_json::from_str::<Value>(&e.data).unwrap())
+            .collect::<Vec<_>>();
+        let types = data
+            .iter()
+            .map(|d| d["type"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            [
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop",
+            ]
+        );
+        let text = data
+            .iter()
+            .filter_map(|d| d["delta"]["text"].as_str())
+            .collect::<String>();
+        assert_eq!(text, "Once upon a time.");
+        assert!(data[1..6].iter().all(|d| d["index"] == 0));
+        assert_eq!(data[6]["delta"]["stop_reason"], "end_turn");
+        assert_eq!(data[6]["usage"]["output_tokens"], 5);
+    }
+
+    #[test]
+    fn stream_is_left_truncated_without_budget() {
+        let mut stitcher = StreamStitcher::new(budget(2, 3));
+        let mut sent = vec![];
+        for event in mock_upstream(None) {
+            sent.extend(stitcher.observe(event));
+        }
+        assert!(stitcher.take_continuation().is_none());
+        sent.extend(stitcher.finish());
+        assert_eq!(sent.len(), 8);
+        assert!(sent[6].data.contains(r#""stop_reason":"max_tokens""#));
+
+        // a failed continuation still ends the stream
+        let mut stitcher = StreamStitcher::new(budget(1, 0));
+        let mut sent = vec![];
+        for event in mock_upstream(None) {
+            sent.extend(stitcher.observe(event));
+        }
+        assert!(stitcher.take_continuation().is_some());
+        sent.extend(stitcher.finish());
+        assert_eq!(sent.len(), 8);
+        assert!(sent[4].data.contains(r#""text":" ""#));
+        assert_eq!(sent[7].event, "message_stop");
+    }
+
+    #[test]
+    fn truncated_response_is_merged_with_its_continuation() {
+        let mock_upstream = |prefill: Option<&str>| match prefill {
+            None => json!({
+                "content": [{"type": "text", "text": "Once upon a "}],
+                "stop_reason": "max_tokens",
+                "stop_sequence": null,
+                "usage": {"input_tokens": 10, "output_tokens": 3},
+            }),
+            Some("Once upon a") => json!({
+                "content": [{"type": "text", "text": " time."}],
+                "stop_reason": "end_turn",
+                "stop_sequence": null,
+                "usage": {"input_tokens": 13, "output_tokens": 2},
+            }),
+            Some(other) => panic!("unexpected prefill {other:?}"),
+        };
+        let mut budget = budget(3, 0);
+        let mut merged = mock_upstream(None);
+        while let Some(prefill) = budget.continue_response(&merged) {
+            merge_response(&mut merged, mock_upstream(Some(&prefill)));
+        }
+        assert_eq!(budget.attempts, 2);
+        assert_eq!(
+            merged,
+            json!({
+                "content": [{"type": "text", "text": "Once upon a time."}],
+                "stop_reason": "end_turn",
+                "stop_sequence": null,
+                "usage": {"input_tokens": 10, "output_tokens": 5},
+            })
+        );
+    }
+
+    #[test]
+    fn only_prefillable_requests_are_continued() {
+        let mut config = ClewdrConfig::default();
+        let params = |v: Value| serde_json::from_value::<CreateMessageParams>(v).unwrap();
+        let user = params(json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 16,
+            "messages": [{"role": "user", "content": "Hi"}],
+        }));
+        assert!(ContinueBudget::for_params(&user, &config).is_none());
+
+        config.anti_truncation_attempts = 1;
+        assert!(ContinueBudget::for_params(&user, &config).is_some());
+        let thinking = params(json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 16,
+            "thinking": {"type": "enabled", "budget_tokens": 1024},
+            "messages": [{"role": "user", "content": "Hi"}],
+        }));
+        assert!(ContinueBudget::for_params(&thinking, &config).is_none());
+        let prefilled = params(json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 16,
+            "messages": [
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "Hello"},
+            ],
+        }));
+        assert!(ContinueBudget::for_params(&prefilled, &config).is_none());
+    }
+}
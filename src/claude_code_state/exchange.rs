@@ -18,12 +18,12 @@ use serde_json::Value;
 use snafu::{OptionExt, ResultExt};
 use url::Url;
 
-use super::chat::{CLAUDE_API_VERSION, CLAUDE_BETA_BASE};
+use super::chat::CLAUDE_BETA_BASE;
 use crate::{
     claude_code_state::ClaudeCodeState,
     config::{
-        CC_REDIRECT_URI, CC_TOKEN_URL, CLAUDE_CODE_USER_AGENT, CLEWDR_CONFIG, CookieStatus,
-        TokenInfo,
+        CC_REDIRECT_URI, CC_TOKEN_URL, CLAUDE_API_VERSION, CLAUDE_CODE_USER_AGENT, CLEWDR_CONFIG,
+        CookieStatus, TokenInfo,
     },
     error::{CheckClaudeErr, ClewdrError, UnexpectedNoneSnafu, UrlSnafu, WreqSnafu},
 };
@@ -1,8 +1,12 @@
 mod chat;
+mod continuation;
 mod exchange;
 mod organization;
+
+use std::{sync::Arc, time::Duration};
+
 use http::{
-    HeaderValue, Method,
+    HeaderMap, HeaderValue, Method,
     header::{COOKIE, ORIGIN, REFERER, USER_AGENT},
 };
 use snafu::ResultExt;
@@ -11,26 +15,37 @@ use wreq::RequestBuilder;
 
 use crate::{
     claude_web_state::SUPER_CLIENT,
-    config::{CLAUDE_CODE_USER_AGENT, CLAUDE_ENDPOINT, CLEWDR_CONFIG, CookieStatus, Reason},
+    config::{
+        CLAUDE_CODE_USER_AGENT, CLAUDE_ENDPOINT, CLEWDR_CONFIG, CookieNeed, CookieStatus,
+        ProxyBackend, Reason,
+    },
     error::{ClewdrError, WreqSnafu},
     middleware::claude::ClaudeApiFormat,
-    services::cookie_actor::CookieActorHandle,
+    services::cookie_actor::{CookieActorHandle, CookieLease},
     types::claude::Usage,
-    utils::build_http_client,
+    utils::{build_http_client, with_proxy_hops},
 };
 
 #[derive(Clone)]
 pub struct ClaudeCodeState {
     pub cookie_actor_handle: CookieActorHandle,
     pub cookie: Option<CookieStatus>,
+    /// Counts this request as in flight on `cookie`
+    pub lease: Option<Arc<CookieLease>>,
     pub cookie_header_value: HeaderValue,
     pub proxy: Option<wreq::Proxy>,
     pub endpoint: url::Url,
+    /// Configured `upstream_headers`, added to every request
+    pub upstream_headers: HeaderMap,
     pub client: wreq::Client,
     pub api_format: ClaudeApiFormat,
     pub stream: bool,
     pub system_prompt_hash: Option<u64>,
     pub anthropic_beta_header: Option<String>,
+    /// `anthropic-version` sent upstream, the client's or the configured one
+    pub anthropic_version: String,
+    pub proxy_hops: u32,
+    pub client_timeout: Option<Duration>,
     pub usage: Usage,
 }
 
@@ -40,14 +55,19 @@ impl ClaudeCodeState {
         ClaudeCodeState {
             cookie_actor_handle,
             cookie: None,
+            lease: None,
             cookie_header_value: HeaderValue::from_static(""),
-            proxy: CLEWDR_CONFIG.load().wreq_proxy.to_owned(),
+            proxy: CLEWDR_CONFIG.load().backend_proxy(ProxyBackend::ClaudeCode),
             endpoint: CLEWDR_CONFIG.load().endpoint(),
+            upstream_headers: CLEWDR_CONFIG.load().upstream_header_map.clone(),
             client: SUPER_CLIENT.to_owned(),
             api_format: ClaudeApiFormat::Claude,
             stream: false,
             system_prompt_hash: None,
             anthropic_beta_header: None,
+            anthropic_version: CLEWDR_CONFIG.load().anthropic_version.clone(),
+            proxy_hops: 0,
+            client_timeout: None,
             usage: Usage::default(),
         }
     }
@@ -58,6 +78,7 @@ impl ClaudeCodeState {
         cookie: CookieStatus,
     ) -> Result<Self, ClewdrError> {
         let mut state = Self::new(cookie_actor_handle);
+        state.proxy = cookie.effective_proxy(ProxyBackend::ClaudeCode);
         state.cookie = Some(cookie);
         let cookie_value = state
             .cookie
@@ -92,12 +113,14 @@ impl ClaudeCodeState {
     /// Build a request with the current cookie and proxy settings
     pub fn build_request(&self, method: Method, url: impl ToString) -> RequestBuilder {
         // let r = SUPER_CLIENT.cloned();
-        let mut req = self
+        let url = url.to_string();
+        let req = self
             .client
-            .request(method, url.to_string())
+            .request(method, &url)
             .header(ORIGIN, CLAUDE_ENDPOINT)
             .header(REFERER, format!("{CLAUDE_ENDPOINT}new"))
             .header(USER_AGENT, CLAUDE_CODE_USER_AGENT);
+        let mut req = with_proxy_hops(req, &url, self.proxy_hops);
         if !self.cookie_header_value.as_bytes().is_empty() {
             req = req.header(COOKIE, self.cookie_header_value.clone());
         }
@@ -111,16 +134,18 @@ impl ClaudeCodeState {
 
     /// Requests a new cookie from the cookie manager
     /// Updates the internal state with the new cookie and proxy configuration
-    pub async fn request_cookie(&mut self) -> Result<CookieStatus, ClewdrError> {
-        let res = self
+    pub async fn request_cookie(&mut self, need: CookieNeed) -> Result<CookieStatus, ClewdrError> {
+        let (res, lease) = self
             .cookie_actor_handle
-            .request(self.system_prompt_hash)
+            .request(self.system_prompt_hash, need)
             .await?;
+        self.lease = Some(Arc::new(lease));
         self.cookie = Some(res.to_owned());
         self.cookie_header_value = HeaderValue::from_str(res.cookie.to_string().as_str())?;
         // Always pull latest proxy/endpoint before building the client
-        self.proxy = CLEWDR_CONFIG.load().wreq_proxy.to_owned();
+        self.proxy = res.effective_proxy(ProxyBackend::ClaudeCode);
         self.endpoint = CLEWDR_CONFIG.load().endpoint();
+        self.upstream_headers = CLEWDR_CONFIG.load().upstream_header_map.clone();
         self.client = build_http_client(self.proxy.as_ref()).context(WreqSnafu {
             msg: "Failed to build client with new cookie",
         })?;
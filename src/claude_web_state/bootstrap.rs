@@ -137,6 +137,7 @@ impl ClaudeWebState {
                     msg: "Failed to find UUID in organization response",
                 })?;
         self.org_uuid = Some(u.to_string());
+        self.record_capabilities().await;
         Ok(())
     }
 
@@ -216,3 +217,81 @@ impl ClaudeWebState {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{Json, Router, http::StatusCode, routing::get};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+    use url::Url;
+
+    use super::*;
+    use crate::{config::CookieStatus, services::cookie_actor::CookieActorHandle};
+
+    /// Serves `router` on a local port, giving its base URL
+    async fn serve(router: Router) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        Url::parse(&format!("http://{addr}/")).unwrap()
+    }
+
+    async fn check(handle: &CookieActorHandle, endpoint: Url) -> Result<bool, ClewdrError> {
+        let cookie = CookieStatus::new(&format!("{}-ABCDEFAA", "a".repeat(86)), None).unwrap();
+        let mut state = ClaudeWebState::from_cookie(handle.clone(), cookie).unwrap();
+        state.endpoint = endpoint;
+        state.check_cookie().await
+    }
+
+    #[tokio::test]
+    async fn cookies_are_classified_by_the_org_endpoint() {
+        let handle = CookieActorHandle::start().await.unwrap();
+        let pro = serve(
+            Router::new()
+                .route(
+                    "/api/bootstrap",
+                    get(|| async {
+                        Json(json!({
+                            "account": {
+                                "email_address": "pro@example.com",
+                                "memberships": [{
+                                    "organization": { "capabilities": ["chat", "claude_pro"] }
+                                }]
+                            }
+                        }))
+                    }),
+                )
+                .route(
+                    "/api/organizations",
+                    get(|| async {
+                        Json(json!([
+                            { "uuid": "api-org", "capabilities": ["api"] },
+                            { "uuid": "chat-org", "capabilities": ["chat", "claude_pro"] }
+                        ]))
+                    }),
+                ),
+        )
+        .await;
+        assert!(check(&handle, pro).await.unwrap());
+
+        let dead = serve(Router::new().route(
+            "/api/bootstrap",
+            get(|| async {
+                (
+                    StatusCode::UNAUTHORIZED,
+                    Json(json!({
+                        "type": "error",
+                        "error": { "type": "authentication_error", "message": "Invalid authorization" }
+                    })),
+                )
+            }),
+        ))
+        .await;
+        assert!(matches!(
+            check(&handle, dead).await,
+            Err(ClewdrError::InvalidCookie {
+                reason: Reason::Null
+            })
+        ));
+    }
+}
@@ -1,18 +1,24 @@
+use std::time::Duration;
+
 use colored::Colorize;
 use futures::TryFutureExt;
 use serde_json::json;
 use snafu::ResultExt;
-use tracing::{Instrument, debug, error, info, info_span};
-use wreq::{Method, Response, header::ACCEPT};
+use tracing::{Instrument, debug, error, info_span, warn};
+use wreq::{Method, Response, StatusCode, header::ACCEPT};
 
 use super::ClaudeWebState;
 use crate::{
-    config::CLEWDR_CONFIG,
+    config::{CLEWDR_CONFIG, CookieNeed},
     error::{CheckClaudeErr, ClewdrError, WreqSnafu},
+    services::cookie_actor::hold_lease,
     types::claude::CreateMessageParams,
-    utils::print_out_json,
+    utils::{print_out_json, retry_attempts, with_total_timeout},
 };
 
+/// Wait before the first repeated attempt at creating a conversation
+const CONVERSATION_RETRY_DELAY: Duration = Duration::from_millis(500);
+
 impl ClaudeWebState {
     /// Attempts to send a chat message to Claude API with retry mechanism
     ///
@@ -36,40 +42,43 @@ impl ClaudeWebState {
         &mut self,
         p: CreateMessageParams,
     ) -> Result<axum::response::Response, ClewdrError> {
-        for i in 0..CLEWDR_CONFIG.load().max_retries + 1 {
-            if i > 0 {
-                info!("[RETRY] attempt: {}", i.to_string().green());
-            }
-            let mut state = self.to_owned();
-            let p = p.to_owned();
-
-            let cookie = state.request_cookie().await?;
-            // check if request is successful
-            let web_res = async {
-                state.bootstrap().await?;
-                state.send_chat(p).await
-            };
-            let transform_res = web_res
-                .and_then(async |r| self.transform_response(r).await)
-                .instrument(info_span!("claude_web", "cookie" = cookie.cookie.mask()));
-
-            match transform_res.await {
-                Ok(b) => {
-                    return Ok(b);
-                }
-                Err(e) => {
-                    error!("{e}");
-                    // 429 error
-                    if let ClewdrError::InvalidCookie { reason } = e {
-                        state.return_cookie(Some(reason.to_owned())).await;
-                        continue;
+        let this = &*self;
+        retry_attempts(
+            CLEWDR_CONFIG.load().max_retries,
+            self.client_timeout.is_some(),
+            |_| {
+                let mut state = this.to_owned();
+                // responses are transformed by the state the request came in with
+                let mut origin = this.to_owned();
+                let p = p.to_owned();
+                async move {
+                    let cookie = match state.request_cookie(CookieNeed::for_model(&p.model)).await {
+                        Ok(cookie) => cookie,
+                        Err(e) => return (state, Err(e)),
+                    };
+                    // check if request is successful
+                    let web_res = async {
+                        state.bootstrap().await?;
+                        state.send_chat(p).await
+                    };
+                    let limit = CLEWDR_CONFIG
+                        .load()
+                        .upstream_duration(this.stream, this.client_timeout);
+                    let transform_res = web_res
+                        .and_then(async |r| origin.transform_response(r).await)
+                        .instrument(info_span!("claude_web", "cookie" = cookie.cookie.mask()));
+                    let res = with_total_timeout(transform_res, limit)
+                        .await
+                        .map(|r| hold_lease(state.lease.clone(), r));
+                    if let Err(e) = &res {
+                        error!("{e}");
                     }
-                    return Err(e);
+                    (state, res)
                 }
-            }
-        }
-        error!("Max retries exceeded");
-        Err(ClewdrError::TooManyRetries)
+            },
+            |state, reason| async move { state.return_cookie(reason).await },
+        )
+        .await
     }
 
     /// Sends a message to the Claude API by creating a new conversation and processing the request
@@ -98,49 +107,12 @@ impl ClaudeWebState {
                 msg: "Organization UUID is not set",
             })?;
 
-        // Create a new conversation
-        let new_uuid = uuid::Uuid::new_v4().to_string();
-        let endpoint = self
-            .endpoint
-            .join(&format!(
-                "api/organizations/{}/chat_conversations",
-                org_uuid
-            ))
-            .map_err(|e| ClewdrError::Whatever {
-                message: format!("Parse URL error: {e}"),
-                source: Some(Box::new(e)),
-            })?;
-        let is_temporary = !CLEWDR_CONFIG.load().preserve_chats;
-        let body = json!({
-            "uuid": new_uuid,
-            "name": if is_temporary { "".to_string() } else { format!("ClewdR-{}", chrono::Utc::now().format("%Y-%m-%d %H:%M:%S")) },
-            "is_temporary": is_temporary,
-        });
-
-        let referer = if is_temporary {
-            self.endpoint
-                .join("new?incognito")
-                .map(|u| u.to_string())
-                .unwrap_or_else(|_| format!("{}new?incognito", crate::config::CLAUDE_ENDPOINT))
-        } else {
-            self.endpoint
-                .join("new")
-                .map(|u| u.to_string())
-                .unwrap_or_else(|_| format!("{}new", crate::config::CLAUDE_ENDPOINT))
-        };
-
-        self.build_request(Method::POST, endpoint)
-            .header(wreq::header::REFERER, referer)
-            .json(&body)
-            .send()
-            .await
-            .context(WreqSnafu {
-                msg: "Failed to create new conversation",
-            })?
-            .check_claude()
-            .await?;
+        let retries = CLEWDR_CONFIG.load().conversation_retries;
+        let new_uuid = retry_transient(retries, CONVERSATION_RETRY_DELAY, || {
+            self.create_conversation(&org_uuid)
+        })
+        .await?;
         self.conv_uuid = Some(new_uuid.to_string());
-        debug!("New conversation created: {}", new_uuid);
 
         // preserve original params for possible post-call token accounting
         self.last_params = Some(p.clone());
@@ -179,6 +151,12 @@ impl ClaudeWebState {
         // upload images
         let files = self.upload_images(images).await;
         body.files = files;
+        let chars = body.prompt.chars().count()
+            + body
+                .attachments
+                .iter()
+                .map(|a| a.char_count())
+                .sum::<usize>();
 
         // send the request
         print_out_json(&body, "claude_web_clewdr_req.json");
@@ -200,5 +178,295 @@ impl ClaudeWebState {
             })?
             .check_claude()
             .await
+            .map_err(|e| {
+                if is_prompt_too_large(&e) {
+                    ClewdrError::WebPromptTooLarge { chars }
+                } else {
+                    e
+                }
+            })
+    }
+
+    /// Creates a new conversation, returning its UUID
+    ///
+    /// A failed attempt may still have left a conversation behind on
+    /// claude.ai, so it is deleted before the error is returned.
+    async fn create_conversation(&self, org_uuid: &str) -> Result<String, ClewdrError> {
+        let new_uuid = uuid::Uuid::new_v4().to_string();
+        let endpoint = self
+            .endpoint
+            .join(&format!(
+                "api/organizations/{}/chat_conversations",
+                org_uuid
+            ))
+            .map_err(|e| ClewdrError::Whatever {
+                message: format!("Parse URL error: {e}"),
+                source: Some(Box::new(e)),
+            })?;
+        let is_temporary = !CLEWDR_CONFIG.load().preserve_chats;
+        let body = json!({
+            "uuid": new_uuid,
+            "name": if is_temporary { "".to_string() } else { format!("ClewdR-{}", chrono::Utc::now().format("%Y-%m-%d %H:%M:%S")) },
+            "is_temporary": is_temporary,
+        });
+
+        let referer = if is_temporary {
+            self.endpoint
+                .join("new?incognito")
+                .map(|u| u.to_string())
+                .unwrap_or_else(|_| format!("{}new?incognito", crate::config::CLAUDE_ENDPOINT))
+        } else {
+            self.endpoint
+                .join("new")
+                .map(|u| u.to_string())
+                .unwrap_or_else(|_| format!("{}new", crate::config::CLAUDE_ENDPOINT))
+        };
+
+        let res = async {
+            self.build_request(Method::POST, endpoint)
+                .header(wreq::header::REFERER, referer)
+                .json(&body)
+                .send()
+                .await
+                .context(WreqSnafu {
+                    msg: "Failed to create new conversation",
+                })?
+                .check_claude()
+                .await
+        }
+        .await;
+        if let Err(e) = res {
+            self.delete_conversation(org_uuid, &new_uuid).await;
+            return Err(e);
+        }
+        debug!("New conversation created: {}", new_uuid);
+        Ok(new_uuid)
+    }
+
+    /// Deletes a conversation, failures are only logged
+    async fn delete_conversation(&self, org_uuid: &str, uuid: &str) {
+        let Ok(endpoint) = self.endpoint.join(&format!(
+            "api/organizations/{}/chat_conversations/{}",
+            org_uuid, uuid
+        )) else {
+            return;
+        };
+        match self.build_request(Method::DELETE, endpoint).send().await {
+            Ok(res) if res.status().is_success() => {
+                debug!("Deleted partially created conversation: {}", uuid)
+            }
+            Ok(res) => debug!(
+                "Conversation {} not deleted, status: {}",
+                uuid,
+                res.status()
+            ),
+            Err(e) => debug!("Failed to delete conversation {}: {}", uuid, e),
+        }
+    }
+}
+
+/// Whether a failed upstream call is worth repeating with the same cookie
+fn is_transient(err: &ClewdrError) -> bool {
+    match err {
+        ClewdrError::ClaudeHttpError { code, .. } => code.is_server_error(),
+        _ => err.is_connection_error(),
+    }
+}
+
+/// Whether claude.ai rejected the completion for the size of the prompt,
+/// which the API would have accepted
+fn is_prompt_too_large(err: &ClewdrError) -> bool {
+    let ClewdrError::ClaudeHttpError { code, inner } = err else {
+        return false;
+    };
+    if *code == StatusCode::PAYLOAD_TOO_LARGE {
+        return true;
+    }
+    let message = inner
+        .message
+        .as_str()
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_else(|| inner.message.to_string().to_ascii_lowercase());
+    *code == StatusCode::BAD_REQUEST
+        && ["too long", "too large", "exceeds the maximum"]
+            .iter()
+            .any(|phrase| message.contains(phrase))
+}
+
+/// Runs `op` until it succeeds, fails with a non-transient error or uses up
+/// `retries` extra attempts, doubling the wait between attempts from `delay`
+async fn retry_transient<T, F, Fut>(
+    retries: usize,
+    delay: Duration,
+    mut op: F,
+) -> Result<T, ClewdrError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ClewdrError>>,
+{
+    let mut delay = delay;
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                attempt += 1;
+                warn!(
+                    "[RETRY] conversation creation failed, attempt {}: {}",
+                    attempt.to_string().yellow(),
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::error::ClaudeErrorBody;
+
+    fn server_error() -> ClewdrError {
+        ClewdrError::ClaudeHttpError {
+            code: StatusCode::SERVICE_UNAVAILABLE,
+            inner: ClaudeErrorBody {
+                message: json!("Service Unavailable"),
+                r#type: "error".to_string(),
+                code: Some(503),
+            },
+        }
+    }
+
+    #[test]
+    fn size_rejections_get_an_actionable_error() {
+        let rejection = |code: StatusCode, message: &str| ClewdrError::ClaudeHttpError {
+            code,
+            inner: ClaudeErrorBody {
+                message: json!(message),
+                r#type: "invalid_request_error".to_string(),
+                code: Some(code.as_u16()),
+            },
+        };
+        assert!(is_prompt_too_large(&rejection(
+            StatusCode::BAD_REQUEST,
+            "Prompt is too long"
+        )));
+        assert!(is_prompt_too_large(&rejection(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Request Entity Too Large"
+        )));
+        assert!(!is_prompt_too_large(&rejection(
+            StatusCode::BAD_REQUEST,
+            "Invalid model"
+        )));
+        assert!(!is_prompt_too_large(&server_error()));
+
+        let message = ClewdrError::WebPromptTooLarge { chars: 900_000 }.to_string();
+        assert!(message.contains("900000 characters"));
+        assert!(message.contains("Claude Code endpoint"));
+    }
+
+    #[tokio::test]
+    async fn conversation_creation_is_retried_after_transient_failure() {
+        let calls = &AtomicUsize::new(0);
+        let res = retry_transient(2, Duration::from_millis(1), || async move {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(server_error()),
+                _ => Ok("conversation-uuid"),
+            }
+        })
+        .await;
+        assert_eq!(res.unwrap(), "conversation-uuid");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_conversation_is_deleted_before_the_retry() {
+        use std::sync::{Arc, Mutex};
+
+        use axum::{
+            Json, Router,
+            extract::Path,
+            routing::{delete, post},
+        };
+        use serde_json::Value;
+        use tokio::net::TcpListener;
+        use url::Url;
+
+        use crate::{config::CookieStatus, services::cookie_actor::CookieActorHandle};
+
+        let events = Arc::new(Mutex::new(Vec::<String>::new()));
+        let create = {
+            let events = events.clone();
+            move |Json(body): Json<Value>| async move {
+                let uuid = body["uuid"].as_str().unwrap_or_default().to_string();
+                let first = {
+                    let mut events = events.lock().unwrap();
+                    events.push(format!("create {uuid}"));
+                    events.len() == 1
+                };
+                if first {
+                    let error = json!({
+                        "type": "error",
+                        "error": { "type": "overloaded_error", "message": "Overloaded" }
+                    });
+                    (axum::http::StatusCode::SERVICE_UNAVAILABLE, Json(error))
+                } else {
+                    (axum::http::StatusCode::OK, Json(body))
+                }
+            }
+        };
+        let remove = {
+            let events = events.clone();
+            move |Path(uuid): Path<String>| async move {
+                events.lock().unwrap().push(format!("delete {uuid}"));
+            }
+        };
+        let router = Router::new()
+            .route("/api/organizations/org/chat_conversations", post(create))
+            .route(
+                "/api/organizations/org/chat_conversations/{uuid}",
+                delete(remove),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let handle = CookieActorHandle::start().await.unwrap();
+        let cookie = CookieStatus::new(&format!("{}-ABCDEFAA", "a".repeat(86)), None).unwrap();
+        let mut state = ClaudeWebState::from_cookie(handle, cookie).unwrap();
+        state.endpoint = Url::parse(&format!("http://{addr}/")).unwrap();
+        let uuid = retry_transient(2, Duration::from_millis(1), || {
+            state.create_conversation("org")
+        })
+        .await
+        .unwrap();
+
+        let events = events.lock().unwrap().clone();
+        let [first, deleted, second] = events.as_slice() else {
+            panic!("unexpected requests: {events:?}");
+        };
+        let orphan = first.strip_prefix("create ").unwrap();
+        assert_ne!(orphan, uuid);
+        assert_eq!(deleted, &format!("delete {orphan}"));
+        assert_eq!(second, &format!("create {uuid}"));
+    }
+
+    #[tokio::test]
+    async fn non_transient_errors_are_not_retried() {
+        let calls = &AtomicUsize::new(0);
+        let res = retry_transient(2, Duration::from_millis(1), || async move {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(ClewdrError::BadRequest { msg: "bad" })
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
     }
 }
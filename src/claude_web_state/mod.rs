@@ -1,6 +1,9 @@
-use std::sync::LazyLock;
+use std::{
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
 
-use axum::http::{HeaderValue, header::COOKIE};
+use axum::http::{HeaderMap, HeaderValue, header::COOKIE};
 use serde_json::Value;
 use snafu::ResultExt;
 use tracing::{error, warn};
@@ -11,12 +14,15 @@ use wreq::{
 };
 
 use crate::{
-    config::{CLAUDE_ENDPOINT, CLEWDR_CONFIG, CookieStatus, Reason},
+    config::{
+        CLAUDE_ENDPOINT, CLEWDR_CONFIG, CookieNeed, CookieStatus, ProxyBackend, Reason,
+        is_pro_capabilities,
+    },
     error::{ClewdrError, WreqSnafu},
     middleware::claude::ClaudeApiFormat,
-    services::cookie_actor::CookieActorHandle,
+    services::cookie_actor::{CookieActorHandle, CookieLease},
     types::claude::{CreateMessageParams, Usage},
-    utils::build_http_client,
+    utils::{build_http_client, with_proxy_hops},
 };
 
 pub mod bootstrap;
@@ -29,15 +35,21 @@ pub static SUPER_CLIENT: LazyLock<Client> = LazyLock::new(Client::new);
 #[derive(Clone)]
 pub struct ClaudeWebState {
     pub cookie: Option<CookieStatus>,
+    /// Counts this request as in flight on `cookie`
+    pub lease: Option<Arc<CookieLease>>,
     cookie_header_value: HeaderValue,
     pub cookie_actor_handle: CookieActorHandle,
     pub org_uuid: Option<String>,
     pub conv_uuid: Option<String>,
     pub capabilities: Vec<String>,
     pub endpoint: Url,
+    /// Configured `upstream_headers`, added to every request
+    pub upstream_headers: HeaderMap,
     pub proxy: Option<Proxy>,
     pub api_format: ClaudeApiFormat,
     pub stream: bool,
+    pub proxy_hops: u32,
+    pub client_timeout: Option<Duration>,
     pub client: Client,
     pub key: Option<(u64, usize)>,
     pub usage: Usage,
@@ -51,14 +63,18 @@ impl ClaudeWebState {
         ClaudeWebState {
             cookie_actor_handle,
             cookie: None,
+            lease: None,
             org_uuid: None,
             conv_uuid: None,
             cookie_header_value: HeaderValue::from_static(""),
             capabilities: Vec::new(),
             endpoint: CLEWDR_CONFIG.load().endpoint(),
-            proxy: CLEWDR_CONFIG.load().wreq_proxy.to_owned(),
+            upstream_headers: CLEWDR_CONFIG.load().upstream_header_map.clone(),
+            proxy: CLEWDR_CONFIG.load().backend_proxy(ProxyBackend::ClaudeWeb),
             api_format: ClaudeApiFormat::Claude,
             stream: false,
+            proxy_hops: 0,
+            client_timeout: None,
             client: SUPER_CLIENT.to_owned(),
             key: None,
             usage: Usage::default(),
@@ -83,51 +99,46 @@ impl ClaudeWebState {
     /// Build a request with the current cookie and proxy settings
     pub fn build_request(&self, method: Method, url: impl ToString) -> RequestBuilder {
         // let r = SUPER_CLIENT.cloned();
-        let mut req = self
+        let url = url.to_string();
+        let req = self
             .client
-            .request(method, url.to_string())
+            .request(method, &url)
             .header(ORIGIN, CLAUDE_ENDPOINT);
+        let mut req = with_proxy_hops(req, &url, self.proxy_hops);
         if !self.cookie_header_value.as_bytes().is_empty() {
             req = req.header(COOKIE, self.cookie_header_value.clone());
         }
-        if let Some(uuid) = self.conv_uuid.to_owned() {
-            req.header(
-                REFERER,
-                self.endpoint
-                    .join(&format!("chat/{uuid}"))
-                    .map(|u| u.into())
-                    .unwrap_or_else(|_| format!("{CLAUDE_ENDPOINT}chat/{uuid}")),
-            )
+        let referer = if let Some(uuid) = self.conv_uuid.to_owned() {
+            self.endpoint
+                .join(&format!("chat/{uuid}"))
+                .map(|u| u.into())
+                .unwrap_or_else(|_| format!("{CLAUDE_ENDPOINT}chat/{uuid}"))
         } else {
-            req.header(
-                REFERER,
-                self.endpoint
-                    .join("new")
-                    .map(|u| u.into())
-                    .unwrap_or_else(|_| format!("{CLAUDE_ENDPOINT}new")),
-            )
-        }
+            self.endpoint
+                .join("new")
+                .map(|u| u.into())
+                .unwrap_or_else(|_| format!("{CLAUDE_ENDPOINT}new"))
+        };
+        req.header(REFERER, referer)
+            .headers(self.upstream_headers.clone())
     }
 
     /// Checks if the current user has pro capabilities
     /// Returns true if any capability contains "pro", "enterprise", "raven", or "max"
     pub fn is_pro(&self) -> bool {
-        self.capabilities.iter().any(|c| {
-            c.contains("pro")
-                || c.contains("enterprise")
-                || c.contains("raven")
-                || c.contains("max")
-        })
+        is_pro_capabilities(&self.capabilities)
     }
 
     /// Requests a new cookie from the cookie manager
     /// Updates the internal state with the new cookie and proxy configuration
-    pub async fn request_cookie(&mut self) -> Result<CookieStatus, ClewdrError> {
-        let res = self.cookie_actor_handle.request(None).await?;
+    pub async fn request_cookie(&mut self, need: CookieNeed) -> Result<CookieStatus, ClewdrError> {
+        let (res, lease) = self.cookie_actor_handle.request(None, need).await?;
+        self.lease = Some(Arc::new(lease));
         self.cookie = Some(res.to_owned());
         // Always pull latest proxy/endpoint before building the client
-        self.proxy = CLEWDR_CONFIG.load().wreq_proxy.to_owned();
+        self.proxy = res.effective_proxy(ProxyBackend::ClaudeWeb);
         self.endpoint = CLEWDR_CONFIG.load().endpoint();
+        self.upstream_headers = CLEWDR_CONFIG.load().upstream_header_map.clone();
         self.client = Self::build_client(self.proxy.as_ref()).context(WreqSnafu {
             msg: "Failed to build client with new cookie",
         })?;
@@ -135,6 +146,26 @@ impl ClaudeWebState {
         Ok(res)
     }
 
+    /// Stores the capabilities found by bootstrap on the cookie when they changed,
+    /// so dispatch can tell pro cookies from free ones
+    async fn record_capabilities(&mut self) {
+        let Some(cookie) = self.cookie.as_mut() else {
+            return;
+        };
+        if cookie.capabilities == self.capabilities {
+            return;
+        }
+        cookie.capabilities = self.capabilities.clone();
+        cookie.is_pro = Some(is_pro_capabilities(&self.capabilities));
+        if let Err(e) = self
+            .cookie_actor_handle
+            .set_capabilities(cookie.cookie.clone(), self.capabilities.clone())
+            .await
+        {
+            warn!("Failed to record cookie capabilities: {}", e);
+        }
+    }
+
     /// Returns the current cookie to the cookie manager
     /// Optionally provides a reason for returning the cookie (e.g., invalid, banned)
     pub async fn return_cookie(&self, reason: Option<Reason>) {
@@ -178,16 +209,53 @@ impl ClaudeWebState {
         }
     }
 
+    /// Counts the request against its cookie, once it has succeeded
+    pub async fn record_use(&self, tokens: u64) {
+        let Some(cookie) = self.cookie.as_ref() else {
+            return;
+        };
+        if let Err(err) = self
+            .cookie_actor_handle
+            .record_use(cookie.cookie.to_owned(), tokens)
+            .await
+        {
+            warn!("Failed to record cookie use: {}", err);
+        }
+    }
+
+    /// Build a ClaudeWebState using a given cookie instead of one from the pool
+    pub fn from_cookie(
+        cookie_actor_handle: CookieActorHandle,
+        cookie: CookieStatus,
+    ) -> Result<Self, ClewdrError> {
+        let mut state = ClaudeWebState::new(cookie_actor_handle);
+        state.proxy = cookie.effective_proxy(ProxyBackend::ClaudeWeb);
+        state.client = Self::build_client(state.proxy.as_ref()).context(WreqSnafu {
+            msg: "Failed to build client for cookie",
+        })?;
+        state.cookie_header_value = HeaderValue::from_str(cookie.cookie.to_string().as_str())?;
+        state.cookie = Some(cookie);
+        Ok(state)
+    }
+
+    /// Checks the cookie with a bootstrap call, returning whether its account is pro
+    ///
+    /// A `NormalPro` skip still counts as a working cookie, as the pool
+    /// ignores it too.
+    pub async fn check_cookie(&mut self) -> Result<bool, ClewdrError> {
+        match self.bootstrap().await {
+            Ok(())
+            | Err(ClewdrError::InvalidCookie {
+                reason: Reason::NormalPro,
+            }) => Ok(self.is_pro()),
+            Err(e) => Err(e),
+        }
+    }
+
     /// Fetch usage data via the claude.ai web endpoint.
     /// Used as a fallback when the OAuth usage endpoint is not available (e.g. Without Claude Code Access).
     pub async fn fetch_web_usage(handle: CookieActorHandle, cookie: CookieStatus) -> Option<Value> {
-        let mut state = ClaudeWebState::new(handle);
-        state.cookie = Some(cookie.clone());
-        state.proxy = CLEWDR_CONFIG.load().wreq_proxy.to_owned();
-        state.endpoint = CLEWDR_CONFIG.load().endpoint();
-        state.client = Self::build_client(state.proxy.as_ref()).ok()?;
-        state.cookie_header_value =
-            HeaderValue::from_str(cookie.cookie.to_string().as_str()).ok()?;
+        let mut state = ClaudeWebState::from_cookie(handle, cookie.clone()).ok()?;
 
         if let Err(e) = state.bootstrap().await {
             warn!(
@@ -223,3 +291,41 @@ impl ClaudeWebState {
             .ok()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, routing::get};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn configured_upstream_headers_are_sent() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route(
+            "/echo",
+            get(|headers: HeaderMap| async move {
+                headers
+                    .get("cf-access-client-id")
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_owned()
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let handle = CookieActorHandle::start().await.unwrap();
+        let mut state = ClaudeWebState::new(handle);
+        state.upstream_headers.insert(
+            "cf-access-client-id",
+            HeaderValue::from_static("gateway.access"),
+        );
+        let res = state
+            .build_request(Method::GET, format!("http://{addr}/echo"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.text().await.unwrap(), "gateway.access");
+    }
+}
@@ -30,7 +30,10 @@ impl ClaudeWebState {
         }
         Some(WebRequestBody {
             max_tokens_to_sample: value.max_tokens,
-            attachments: vec![Attachment::new(merged.paste)],
+            attachments: split_paste(merged.paste, CLEWDR_CONFIG.load().web_paste_max_chars)
+                .into_iter()
+                .map(Attachment::new)
+                .collect(),
             files: vec![],
             model: if self.is_pro() {
                 Some(value.model)
@@ -51,31 +54,10 @@ impl ClaudeWebState {
 
     /// Upload images to the Claude.ai
     pub async fn upload_images(&self, imgs: Vec<ImageSource>) -> Vec<String> {
+        let decoded = decode_images(imgs, CLEWDR_CONFIG.load().image_decode_concurrency).await;
         // upload images
-        stream::iter(imgs)
-            .filter_map(async |img| {
-                let ImageSource::Base64 { media_type, data } = img else {
-                    warn!("Image type is not base64");
-                    return None;
-                };
-                // decode the image
-                let bytes = BASE64_STANDARD
-                    .decode(data)
-                    .inspect_err(|e| {
-                        warn!("Failed to decode image: {}", e);
-                    })
-                    .ok()?;
-                // choose the file name based on the media type (extract main type before any params)
-                let main_type = media_type.split(';').next().unwrap_or(&media_type);
-                let file_name = match main_type.to_lowercase().as_str() {
-                    "image/png" => "image.png",
-                    "image/jpeg" => "image.jpg",
-                    "image/jpg" => "image.jpg",
-                    "image/gif" => "image.gif",
-                    "image/webp" => "image.webp",
-                    "application/pdf" => "document.pdf",
-                    _ => "file",
-                };
+        stream::iter(decoded.into_iter().flatten())
+            .filter_map(async |(bytes, file_name)| {
                 // create the part and form
                 let part = Part::bytes(bytes).file_name(file_name);
                 let form = Form::new().part("file", part);
@@ -113,6 +95,85 @@ impl ClaudeWebState {
     }
 }
 
+/// Requests with fewer images than this are decoded inline, as handing them
+/// to the blocking pool costs more than it saves
+const PARALLEL_DECODE_THRESHOLD: usize = 2;
+
+/// Decodes a base64 image and picks an upload file name for it
+fn decode_image(img: ImageSource) -> Option<(Vec<u8>, &'static str)> {
+    let ImageSource::Base64 { media_type, data } = img else {
+        warn!("Image type is not base64");
+        return None;
+    };
+    // decode the image
+    let bytes = BASE64_STANDARD
+        .decode(data)
+        .inspect_err(|e| {
+            warn!("Failed to decode image: {}", e);
+        })
+        .ok()?;
+    // choose the file name based on the media type (extract main type before any params)
+    let main_type = media_type.split(';').next().unwrap_or(&media_type);
+    let file_name = match main_type.to_lowercase().as_str() {
+        "image/png" => "image.png",
+        "image/jpeg" => "image.jpg",
+        "image/jpg" => "image.jpg",
+        "image/gif" => "image.gif",
+        "image/webp" => "image.webp",
+        "application/pdf" => "document.pdf",
+        _ => "file",
+    };
+    Some((bytes, file_name))
+}
+
+/// Decodes images on the blocking pool, at most `concurrency` at once,
+/// keeping them in their original order
+async fn decode_images(
+    imgs: Vec<ImageSource>,
+    concurrency: usize,
+) -> Vec<Option<(Vec<u8>, &'static str)>> {
+    if concurrency <= 1 || imgs.len() < PARALLEL_DECODE_THRESHOLD {
+        return imgs.into_iter().map(decode_image).collect();
+    }
+    stream::iter(imgs)
+        .map(|img| async move {
+            tokio::task::spawn_blocking(move || decode_image(img))
+                .await
+                .inspect_err(|e| {
+                    warn!("Image decoding task failed: {}", e);
+                })
+                .ok()
+                .flatten()
+        })
+        .buffered(concurrency)
+        .collect()
+        .await
+}
+
+/// Splits the prompt into pieces of at most `max_chars` characters, breaking
+/// after the last newline of a piece when there is one, 0 keeps it whole
+fn split_paste(paste: String, max_chars: usize) -> Vec<String> {
+    if max_chars == 0 || paste.chars().count() <= max_chars {
+        return vec![paste];
+    }
+    let mut pieces = vec![];
+    let mut rest = paste.as_str();
+    while !rest.is_empty() {
+        let end = rest
+            .char_indices()
+            .nth(max_chars)
+            .map_or(rest.len(), |(i, _)| i);
+        let end = match rest[..end].rfind('\n') {
+            Some(newline) if end < rest.len() => newline + 1,
+            _ => end,
+        };
+        let (piece, tail) = rest.split_at(end);
+        pieces.push(piece.to_string());
+        rest = tail;
+    }
+    pieces
+}
+
 /// Merged messages and images
 #[derive(Default, Debug)]
 struct Merged {
@@ -264,3 +325,49 @@ fn merge_system(sys: Value) -> String {
         _ => String::new(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_paste_is_split_on_line_breaks() {
+        let paste = "aaaa\nbbbb\ncccccccc".to_string();
+        assert_eq!(split_paste(paste.clone(), 0), vec![paste.clone()]);
+        let pieces = split_paste(paste.clone(), 7);
+        assert_eq!(pieces, vec!["aaaa\n", "bbbb\n", "ccccccc", "c"]);
+        assert_eq!(pieces.concat(), paste);
+    }
+
+    #[tokio::test]
+    async fn parallel_decoding_keeps_image_order() {
+        let payloads = (0..8u8).map(|i| vec![i; 64]).collect::<Vec<_>>();
+        let mut imgs = payloads
+            .iter()
+            .map(|bytes| ImageSource::Base64 {
+                media_type: "image/png".to_string(),
+                data: BASE64_STANDARD.encode(bytes),
+            })
+            .collect::<Vec<_>>();
+        imgs.insert(
+            3,
+            ImageSource::Base64 {
+                media_type: "image/png".to_string(),
+                data: "not base64!".to_string(),
+            },
+        );
+
+        let decoded = decode_images(imgs.to_owned(), 4).await;
+        assert_eq!(decoded.len(), 9);
+        assert!(decoded[3].is_none());
+        let valid = decoded.into_iter().flatten().collect::<Vec<_>>();
+        assert_eq!(valid.len(), payloads.len());
+        for ((bytes, file_name), expected) in valid.iter().zip(&payloads) {
+            assert_eq!(bytes, expected);
+            assert_eq!(*file_name, "image.png");
+        }
+
+        let sequential = decode_images(imgs, 1).await;
+        assert_eq!(sequential.into_iter().flatten().collect::<Vec<_>>(), valid);
+    }
+}
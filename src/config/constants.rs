@@ -15,12 +15,50 @@ pub const CLAUDE_ENDPOINT: &str = "https://api.anthropic.com/";
 #[allow(dead_code)]
 pub const CLAUDE_CONSOLE_ENDPOINT: &str = "https://console.anthropic.com/";
 pub const GEMINI_ENDPOINT: &str = "https://generativelanguage.googleapis.com/";
+/// Label of the `admin_password`, reserved so no admin token can take it
+pub const ADMIN_PASSWORD_LABEL: &str = "admin";
 pub const CC_CLIENT_ID: &str = "9d1c250a-e61b-44d9-88ed-5944d1962f5e";
 pub const CC_TOKEN_URL: &str = "https://api.anthropic.com/v1/oauth/token";
 pub const CC_REDIRECT_URI: &str = "https://console.anthropic.com/oauth/code/callback";
 pub const CLAUDE_CODE_VERSION: &str = "2.1.76";
 pub const CLAUDE_CODE_USER_AGENT: &str = "claude-code/2.1.76";
 pub const CLAUDE_CODE_BILLING_SALT: &str = "59cf53e54c78";
+/// `anthropic-version` sent upstream unless the config or the client asks for
+/// another, the event schema stop sequence and usage synthesis expect
+pub const CLAUDE_API_VERSION: &str = "2023-06-01";
+/// Client `anthropic-version` values responses are valid for
+///
+/// Only 2023-06-01 exists for the Messages API, so no translation is needed
+/// yet. A new version gets listed here together with the response changes
+/// it needs.
+pub const SUPPORTED_ANTHROPIC_VERSIONS: &[&str] = &[CLAUDE_API_VERSION];
+/// Headers ClewdR sets on upstream requests itself, `upstream_headers` only
+/// replaces them with `upstream_headers_override`
+pub const PROTECTED_UPSTREAM_HEADERS: &[&str] = &[
+    "authorization",
+    "x-api-key",
+    "cookie",
+    "anthropic-version",
+    "anthropic-beta",
+    "content-type",
+    "content-length",
+    "host",
+];
+
+/// Header counting the ClewdR instances a request went through, so that an
+/// upstream pointing back at a ClewdR can't loop forever
+pub const PROXY_HOPS_HEADER: &str = "x-clewdr-hops";
+
+/// Anthropic's own hosts, which never get to see ClewdR specific headers
+pub const OFFICIAL_HOSTS: &[&str] = &["api.anthropic.com", "claude.ai", "console.anthropic.com"];
+
+/// Whether `endpoint` is one of Anthropic's own hosts rather than a custom
+/// endpoint or reverse proxy
+pub fn is_official_endpoint(endpoint: &Url) -> bool {
+    endpoint
+        .host_str()
+        .is_some_and(|host| OFFICIAL_HOSTS.contains(&host))
+}
 
 pub static ENDPOINT_URL: LazyLock<Url> = LazyLock::new(|| {
     Url::parse(CLAUDE_ENDPOINT).unwrap_or_else(|_| {
@@ -100,6 +138,14 @@ pub const fn default_max_retries() -> usize {
     5
 }
 
+/// Default number of extra attempts at creating a claude.ai conversation
+///
+/// # Returns
+/// * `usize` - The default value of 2
+pub const fn default_conversation_retries() -> usize {
+    2
+}
+
 /// Default IP address for the server to bind to
 ///
 /// # Returns
@@ -131,6 +177,169 @@ pub const fn default_use_real_roles() -> bool {
 pub const fn default_check_update() -> bool {
     true
 }
+/// Default maximum gap between streamed chunks, in seconds
+///
+/// # Returns
+/// * `u64` - The default value of 300
+pub const fn default_stream_idle_timeout() -> u64 {
+    300
+}
+
+/// Default total time allowed for a non-streaming response, in seconds
+///
+/// # Returns
+/// * `u64` - The default value of 600
+pub const fn default_non_stream_timeout() -> u64 {
+    600
+}
+
+/// Default wall-clock limit for a message request until its response is
+/// ready, including retries, in seconds
+///
+/// # Returns
+/// * `u64` - The default value of 1200
+pub const fn default_request_timeout() -> u64 {
+    1200
+}
+
+/// Default wall-clock limit for admin API requests, in seconds
+///
+/// # Returns
+/// * `u64` - The default value of 60
+pub const fn default_admin_request_timeout() -> u64 {
+    60
+}
+
+/// Default limit on request bodies, generous enough for inlined images
+///
+/// # Returns
+/// * `usize` - The default value of 32 MiB
+pub const fn default_max_body_bytes() -> usize {
+    32 * 1024 * 1024
+}
+
+/// Default number of ClewdR instances a request may pass through
+///
+/// # Returns
+/// * `u32` - The default value of 3
+pub const fn default_max_proxy_hops() -> u32 {
+    3
+}
+
+/// Default longest wait for a cookie to leave its cooldown, in seconds
+///
+/// # Returns
+/// * `u64` - The default value of 10
+pub const fn default_cookie_cooldown_wait() -> u64 {
+    10
+}
+
+/// Default shortest upstream timeout a client may ask for, in seconds
+///
+/// # Returns
+/// * `u64` - The default value of 5
+pub const fn default_client_timeout_min() -> u64 {
+    5
+}
+
+/// Default longest upstream timeout a client may ask for, in seconds
+///
+/// # Returns
+/// * `u64` - The default value of 600
+pub const fn default_client_timeout_max() -> u64 {
+    600
+}
+
+/// Default longest wait for dependencies at startup, in seconds
+///
+/// # Returns
+/// * `u64` - The default value of 60
+pub const fn default_dependency_wait_timeout() -> u64 {
+    60
+}
+
+/// Default pause between dependency checks at startup, in seconds
+///
+/// # Returns
+/// * `u64` - The default value of 2
+pub const fn default_dependency_poll_interval() -> u64 {
+    2
+}
+
+/// Default number of bytes of a body kept in the body log
+///
+/// # Returns
+/// * `usize` - The default value of 4096
+pub const fn default_log_body_max_bytes() -> usize {
+    4096
+}
+
+/// Default time open requests get to finish on shutdown, in seconds
+///
+/// # Returns
+/// * `u64` - The default value of 30
+pub const fn default_shutdown_drain_timeout() -> u64 {
+    30
+}
+
+/// Default time `/v1/models` serves the same listing
+///
+/// # Returns
+/// * `u64` - The default value of 10 seconds
+pub const fn default_models_cache_ttl() -> u64 {
+    10
+}
+
+/// Default frame size for the `coalesce` and `split` stream chunk modes
+///
+/// # Returns
+/// * `usize` - The default value of 1024 bytes
+pub const fn default_stream_chunk_bytes() -> usize {
+    1024
+}
+
+/// Default time a chunk may be held back in the `coalesce` stream chunk mode
+///
+/// # Returns
+/// * `u64` - The default value of 50 milliseconds
+pub const fn default_stream_chunk_window_ms() -> u64 {
+    50
+}
+
+/// Default number of images decoded at once for a claude.ai web request
+///
+/// # Returns
+/// * `usize` - The default value of 4
+pub const fn default_image_decode_concurrency() -> usize {
+    4
+}
+
+/// Default size limit of a remote image, matching what Claude accepts
+///
+/// # Returns
+/// * `usize` - The default value of 5 MiB
+pub const fn default_remote_image_max_bytes() -> usize {
+    5 * 1024 * 1024
+}
+
+/// Default media types a remote image may have, the ones Claude accepts
+///
+/// # Returns
+/// * `Vec<String>` - JPEG, PNG, GIF and WebP
+pub fn default_remote_image_types() -> Vec<String> {
+    ["image/jpeg", "image/png", "image/gif", "image/webp"]
+        .map(String::from)
+        .to_vec()
+}
+
+/// Default `anthropic-version` sent to the Claude API
+///
+/// # Returns
+/// * `String` - [`CLAUDE_API_VERSION`]
+pub fn default_anthropic_version() -> String {
+    CLAUDE_API_VERSION.to_string()
+}
+
 /// Default setting for skipping cool down cookies
 ///
 /// # Returns
@@ -3,10 +3,12 @@ mod clewdr_config;
 mod constants;
 mod cookie;
 mod reason;
+mod rewrite_rule;
 mod token;
 
 pub use clewdr_config::*;
 pub use constants::*;
 pub use cookie::*;
 pub use reason::*;
+pub use rewrite_rule::*;
 pub use token::*;
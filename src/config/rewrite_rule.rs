@@ -0,0 +1,109 @@
+use regex::Regex;
+use regex_automata::{
+    Anchored, Input,
+    hybrid::dfa::{Cache, DFA},
+};
+
+use crate::error::ClewdrError;
+
+/// A compiled response rewrite rule
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    regex: Regex,
+    /// Lazy DFA of the same pattern, used to tell whether a match starting at
+    /// some offset may still complete once more text arrives
+    dfa: DFA,
+    replacement: String,
+}
+
+impl RewriteRule {
+    pub fn new(pattern: &str, replacement: &str) -> Result<Self, ClewdrError> {
+        let regex = Regex::new(pattern).map_err(|e| ClewdrError::Whatever {
+            message: format!("Invalid regex: {e}"),
+            source: Some(Box::new(e)),
+        })?;
+        let dfa = DFA::builder()
+            .configure(DFA::config().unicode_word_boundary(true))
+            .build(pattern)
+            .map_err(|e| ClewdrError::Whatever {
+                message: format!("Unsupported regex: {e}"),
+                source: Some(Box::new(e)),
+            })?;
+        Ok(Self {
+            regex,
+            dfa,
+            replacement: replacement.to_owned(),
+        })
+    }
+
+    /// Scratch space for [`Self::is_live`]
+    pub(crate) fn create_cache(&self) -> Cache {
+        self.dfa.create_cache()
+    }
+
+    /// Rewrites a complete piece of text
+    pub fn apply(&self, text: &str) -> String {
+        let mut out = String::new();
+        let end = self.rewrite(text, 0, text.len(), &mut out);
+        out.push_str(&text[end..]);
+        out
+    }
+
+    /// Replaces non-empty matches in `hay` that start in `from..until`,
+    /// appending to `out` and returning where the unwritten text begins
+    pub(crate) fn rewrite(&self, hay: &str, from: usize, until: usize, out: &mut String) -> usize {
+        let mut last = from;
+        let mut pos = from;
+        while pos <= until {
+            let Some(caps) = self.regex.captures_at(hay, pos) else {
+                break;
+            };
+            let m = caps.get(0).expect("group 0 always matches");
+            if m.start() >= until {
+                break;
+            }
+            if m.is_empty() {
+                pos = hay[m.start()..]
+                    .chars()
+                    .next()
+                    .map_or(hay.len() + 1, |c| m.start() + c.len_utf8());
+                continue;
+            }
+            out.push_str(&hay[last..m.start()]);
+            caps.expand(&self.replacement, out);
+            last = m.end();
+            pos = m.end();
+        }
+        last
+    }
+
+    /// Whether a match anchored at `start` may still complete or grow once
+    /// more text is appended to `hay`
+    pub(crate) fn is_live(&self, cache: &mut Cache, hay: &str, start: usize) -> bool {
+        let input = Input::new(hay).range(start..).anchored(Anchored::Yes);
+        let Ok(mut sid) = self.dfa.start_state_forward(cache, &input) else {
+            return true;
+        };
+        for &byte in &hay.as_bytes()[start..] {
+            if sid.is_dead() {
+                return false;
+            }
+            if sid.is_quit() {
+                return true;
+            }
+            let Ok(next) = self.dfa.next_state(cache, sid, byte) else {
+                return true;
+            };
+            sid = next;
+        }
+        if sid.is_dead() {
+            return false;
+        }
+        // a finished match is not live when no byte can extend it
+        (0..=u8::MAX).any(|byte| {
+            self.dfa
+                .next_state(cache, sid, byte)
+                .is_ok_and(|next| !next.is_dead())
+        })
+    }
+}
@@ -14,10 +14,16 @@ use snafu::Location;
 use strum::IntoStaticStr;
 use tokio::sync::oneshot;
 use tracing::{debug, error};
-use wreq::{Response, StatusCode, header::InvalidHeaderValue};
+use wreq::{
+    Response, StatusCode,
+    header::{HeaderValue, InvalidHeaderValue, RETRY_AFTER},
+};
 
 use crate::{config::Reason, types::claude::Message};
 
+/// Seconds a client should wait before retrying when streams are saturated
+const STREAM_RETRY_AFTER_SECS: &str = "5";
+
 #[derive(Debug, IntoStaticStr, snafu::Snafu)]
 #[snafu(visibility(pub(crate)))]
 #[strum(serialize_all = "snake_case")]
@@ -86,8 +92,48 @@ pub enum ClewdrError {
     InvalidHeaderValue { source: InvalidHeaderValue },
     #[snafu(display("Bad request: {}", msg))]
     BadRequest { msg: &'static str },
+    #[snafu(display("Invalid `{}`: {}", field, msg))]
+    InvalidRequest { field: String, msg: String },
+    #[snafu(display("Content block `{}` is not supported by {}", block, backend))]
+    UnsupportedContentBlock {
+        backend: &'static str,
+        block: String,
+    },
+    #[snafu(display(
+        "Prompt of {} characters is too large for claude.ai, send it to the Claude Code endpoint or set `web_paste_max_chars` to split it",
+        chars
+    ))]
+    WebPromptTooLarge { chars: usize },
+    #[snafu(display(
+        "Request body is larger than the limit of {} bytes, raise `max_body_bytes` to accept it",
+        limit
+    ))]
+    BodyTooLarge { limit: usize },
+    #[snafu(display(
+        "Request went through {} ClewdR proxies, more than the limit of {}, check that the upstream endpoint doesn't point back at ClewdR",
+        hops,
+        max
+    ))]
+    ProxyLoop { hops: u32, max: u32 },
     #[snafu(display("Retries exceeded"))]
     TooManyRetries,
+    #[snafu(display("Upstream did not respond within {}s", secs))]
+    UpstreamTimeout { secs: u64 },
+    #[snafu(display("Too many concurrent streams, limit is {}", max))]
+    TooManyStreams { max: usize },
+    #[snafu(display("All cookies are cooling down, the next one is free in {}ms", wait_ms))]
+    AllCookiesCoolingDown { wait_ms: u64 },
+    #[snafu(display(
+        "All cookies are rate limited, the first one resets at {}",
+        chrono::DateTime::from_timestamp(*reset_time, 0).map(|t| t.to_rfc3339()).unwrap_or_default()
+    ))]
+    AllCookiesRateLimited { reset_time: i64 },
+    #[snafu(display("Rate limit of {} requests per minute exceeded for {}", limit, group))]
+    RateLimited {
+        group: &'static str,
+        limit: u32,
+        retry_after: u64,
+    },
     #[snafu(display("EventSource error: {}", source))]
     #[snafu(context(false))]
     EventSourceAxumError {
@@ -113,6 +159,8 @@ pub enum ClewdrError {
     CookieDispatchError { source: oneshot::error::RecvError },
     #[snafu(display("No cookie available"))]
     NoCookieAvailable,
+    #[snafu(display("No cookie with a paid plan is available for this model"))]
+    NoCapableCookie,
     #[snafu(display("Invalid Cookie: {}", reason))]
     #[snafu(context(false))]
     InvalidCookie {
@@ -166,8 +214,84 @@ pub enum ClewdrError {
     },
 }
 
+impl ClewdrError {
+    /// Whether upstream could not be reached or the connection broke, as
+    /// opposed to an error upstream answered with
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            ClewdrError::WreqError { source, .. } => {
+                source.is_connect() || source.is_timeout() || has_connection_io_error(source)
+            }
+            ClewdrError::IoError { source, .. } => has_connection_io_error(source),
+            _ => false,
+        }
+    }
+}
+
+/// Looks for an IO error caused by the network anywhere in the source chain
+fn has_connection_io_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    use std::io::ErrorKind::*;
+
+    let mut next = Some(err);
+    while let Some(err) = next {
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            if matches!(
+                io.kind(),
+                ConnectionRefused
+                    | ConnectionReset
+                    | ConnectionAborted
+                    | NotConnected
+                    | BrokenPipe
+                    | TimedOut
+                    | UnexpectedEof
+                    | HostUnreachable
+                    | NetworkUnreachable
+            ) {
+                return true;
+            }
+            // `source` of an IO error skips the error it wraps
+            if let Some(inner) = io.get_ref() {
+                next = Some(inner);
+                continue;
+            }
+        }
+        next = err.source();
+    }
+    false
+}
+
+/// Why a request failed, attached to error responses for the rejection log
+#[derive(Debug, Clone)]
+pub struct ErrorReason(pub String);
+
 impl IntoResponse for ClewdrError {
     fn into_response(self) -> axum::response::Response {
+        let reason = ErrorReason(self.to_string());
+        let mut res = self.into_error_response();
+        res.extensions_mut().insert(reason);
+        res
+    }
+}
+
+impl ClewdrError {
+    fn into_error_response(self) -> axum::response::Response {
+        let retry_after = match self {
+            ClewdrError::TooManyStreams { .. } => {
+                Some(HeaderValue::from_static(STREAM_RETRY_AFTER_SECS))
+            }
+            ClewdrError::RateLimited { retry_after, .. } => Some(HeaderValue::from(retry_after)),
+            ClewdrError::AllCookiesCoolingDown { wait_ms } => {
+                Some(HeaderValue::from(wait_ms.div_ceil(1000)))
+            }
+            ClewdrError::AllCookiesRateLimited { reset_time } => Some(HeaderValue::from(
+                (reset_time - Utc::now().timestamp()).max(1),
+            )),
+            _ => None,
+        };
+        let reset_time = match self {
+            ClewdrError::AllCookiesRateLimited { reset_time } => Some(reset_time),
+            _ => None,
+        };
         let (status, msg) = match self {
             ClewdrError::UrlError {
                 loc,
@@ -203,10 +327,34 @@ impl IntoResponse for ClewdrError {
                 (source.status(), json!(source.body_text()))
             }
             ClewdrError::TooManyRetries => (StatusCode::GATEWAY_TIMEOUT, json!(self.to_string())),
+            ClewdrError::UpstreamTimeout { .. } => {
+                (StatusCode::GATEWAY_TIMEOUT, json!(self.to_string()))
+            }
+            ClewdrError::TooManyStreams { .. } | ClewdrError::NoCapableCookie => {
+                (StatusCode::SERVICE_UNAVAILABLE, json!(self.to_string()))
+            }
+            ClewdrError::RateLimited { .. }
+            | ClewdrError::AllCookiesCoolingDown { .. }
+            | ClewdrError::AllCookiesRateLimited { .. } => {
+                (StatusCode::TOO_MANY_REQUESTS, json!(self.to_string()))
+            }
             ClewdrError::InvalidCookie { .. } => (StatusCode::BAD_REQUEST, json!(self.to_string())),
             ClewdrError::PathNotFound { .. } => (StatusCode::NOT_FOUND, json!(self.to_string())),
             ClewdrError::InvalidAuth => (StatusCode::UNAUTHORIZED, json!(self.to_string())),
             ClewdrError::BadRequest { .. } => (StatusCode::BAD_REQUEST, json!(self.to_string())),
+            ClewdrError::InvalidRequest { .. } => {
+                (StatusCode::BAD_REQUEST, json!(self.to_string()))
+            }
+            ClewdrError::UnsupportedContentBlock { .. } => {
+                (StatusCode::BAD_REQUEST, json!(self.to_string()))
+            }
+            ClewdrError::ProxyLoop { .. } => (StatusCode::LOOP_DETECTED, json!(self.to_string())),
+            ClewdrError::WebPromptTooLarge { .. } => {
+                (StatusCode::PAYLOAD_TOO_LARGE, json!(self.to_string()))
+            }
+            ClewdrError::BodyTooLarge { .. } => {
+                (StatusCode::PAYLOAD_TOO_LARGE, json!(self.to_string()))
+            }
             ClewdrError::InvalidHeaderValue { .. } => {
                 (StatusCode::BAD_REQUEST, json!(self.to_string()))
             }
@@ -220,7 +368,19 @@ impl IntoResponse for ClewdrError {
                 code: Some(status.as_u16()),
             },
         };
-        (status, Json(err)).into_response()
+        let mut res = match reset_time {
+            // unix time the first parked cookie comes back, for clients to back off until
+            Some(reset_time) => {
+                let mut body = json!(err);
+                body["error"]["reset_time"] = json!(reset_time);
+                (status, Json(body)).into_response()
+            }
+            None => (status, Json(err)).into_response(),
+        };
+        if let Some(retry_after) = retry_after {
+            res.headers_mut().insert(RETRY_AFTER, retry_after);
+        }
+        res
     }
 }
 
@@ -407,3 +567,36 @@ impl CheckClaudeErr for Response {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn connection_errors_are_told_apart_from_http_errors() {
+        let reset = ClewdrError::from(io::Error::new(
+            io::ErrorKind::ConnectionReset,
+            "connection reset by peer",
+        ));
+        assert!(reset.is_connection_error());
+
+        // a reset wrapped by another error is still found
+        let wrapped = io::Error::other(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert!(has_connection_io_error(&wrapped));
+
+        let denied = ClewdrError::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(!denied.is_connection_error());
+
+        let bad_request = ClewdrError::ClaudeHttpError {
+            code: StatusCode::BAD_REQUEST,
+            inner: ClaudeErrorBody {
+                message: json!("invalid request"),
+                r#type: "invalid_request_error".to_string(),
+                code: Some(400),
+            },
+        };
+        assert!(!bad_request.is_connection_error());
+    }
+}
@@ -69,7 +69,7 @@ pub const FIG: &str = r#"
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
-    #[cfg(feature = "portable")]
+    #[cfg(all(feature = "portable", not(feature = "no-self-update")))]
     #[arg(short, long)]
     /// Force update of the application
     pub update: bool,
@@ -2,8 +2,10 @@ use std::io::IsTerminal;
 
 use clewdr::{
     self, FIG, IS_DEBUG,
-    config::{CLEWDR_CONFIG, CONFIG_PATH, LOG_DIR},
+    config::{CLEWDR_CONFIG, CONFIG_PATH, LOG_DIR, LogFormat},
     error::ClewdrError,
+    middleware::{BODIES_TARGET, REJECTIONS_TARGET, json_log_layer},
+    services::shutdown,
     version_info_colored,
 };
 use colored::Colorize;
@@ -11,6 +13,7 @@ use mimalloc::MiMalloc;
 use tracing::Subscriber;
 use tracing_subscriber::{
     Layer, Registry,
+    filter::{LevelFilter, Targets},
     fmt::{self, time::ChronoLocal},
     layer::SubscriberExt,
     registry::LookupSpan,
@@ -64,40 +67,99 @@ async fn main() -> Result<(), ClewdrError> {
 
     // detect if stdout is a TTY and disable colors if not
     let stdout_is_tty = std::io::stdout().is_terminal();
-    colored::control::set_override(stdout_is_tty);
+    // JSON log lines must stay free of color codes
+    let json_logs = CLEWDR_CONFIG.load().log_format == LogFormat::Json;
+    colored::control::set_override(stdout_is_tty && !json_logs);
 
     // set up logging time format
     let timer = ChronoLocal::new("%H:%M:%S%.3f".to_string());
     // set up logging
     let filter = if IS_DEBUG {
-        tracing_subscriber::filter::LevelFilter::DEBUG
+        LevelFilter::DEBUG
     } else {
-        tracing_subscriber::filter::LevelFilter::INFO
+        LevelFilter::INFO
     };
-    let env_filter = tracing_subscriber::EnvFilter::builder()
-        .with_default_directive(filter.into())
-        .from_env_lossy();
-    let subscriber = Registry::default().with(
-        fmt::Layer::default()
+    // rejected requests get their own file when configured, not the main log
+    let error_log_file = CLEWDR_CONFIG
+        .load()
+        .error_log_file
+        .to_owned()
+        .filter(|_| !CLEWDR_CONFIG.load().no_fs);
+    // logged bodies only ever go to their own file
+    let log_bodies = CLEWDR_CONFIG.load().log_bodies && !CLEWDR_CONFIG.load().no_fs;
+    let env_filter = || {
+        let env_filter = tracing_subscriber::EnvFilter::builder()
+            .with_default_directive(filter.into())
+            .from_env_lossy()
+            .add_directive(
+                format!("{BODIES_TARGET}=off")
+                    .parse()
+                    .expect("Failed to parse filter"),
+            );
+        if error_log_file.is_some() {
+            env_filter.add_directive(
+                format!("{REJECTIONS_TARGET}=off")
+                    .parse()
+                    .expect("Failed to parse filter"),
+            )
+        } else {
+            env_filter
+        }
+    };
+    let (error_layer, _error_guard) = match error_log_file.as_deref() {
+        Some(file_name) => {
+            std::fs::create_dir_all(LOG_DIR.as_path()).expect("Failed to create log directory");
+            let file_appender = tracing_appender::rolling::daily(LOG_DIR.as_path(), file_name);
+            let (file_writer, guard) = tracing_appender::non_blocking(file_appender);
+            let layer = fmt::Layer::default()
+                .with_writer(file_writer)
+                .with_timer(timer.to_owned())
+                .with_ansi(false)
+                .with_filter(Targets::new().with_target(REJECTIONS_TARGET, LevelFilter::WARN));
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+    let (bodies_layer, _bodies_guard) = if log_bodies {
+        std::fs::create_dir_all(LOG_DIR.as_path()).expect("Failed to create log directory");
+        let file_appender = tracing_appender::rolling::daily(LOG_DIR.as_path(), "requests.log");
+        let (file_writer, guard) = tracing_appender::non_blocking(file_appender);
+        let layer = fmt::Layer::default()
+            .with_writer(file_writer)
+            .with_timer(timer.to_owned())
+            .with_ansi(false)
+            .with_filter(Targets::new().with_target(BODIES_TARGET, LevelFilter::DEBUG));
+        (Some(layer), Some(guard))
+    } else {
+        (None, None)
+    };
+    let (text_layer, json_layer) = if json_logs {
+        let layer = json_log_layer(std::io::stdout).with_filter(env_filter());
+        (None, Some(layer))
+    } else {
+        let layer = fmt::Layer::default()
             .with_writer(std::io::stdout)
             .with_timer(timer.to_owned())
             .with_ansi(stdout_is_tty)
             .with_ansi_sanitization(false)
-            .with_filter(env_filter),
-    );
+            .with_filter(env_filter());
+        (Some(layer), None)
+    };
+    let subscriber = Registry::default()
+        .with(text_layer)
+        .with(json_layer)
+        .with(error_layer)
+        .with(bodies_layer);
     let _guard = if !CLEWDR_CONFIG.load().no_fs && CLEWDR_CONFIG.load().log_to_file {
         std::fs::create_dir_all(LOG_DIR.as_path()).expect("Failed to create log directory");
         let file_appender = tracing_appender::rolling::daily(LOG_DIR.as_path(), "clewdr.log");
         let (file_writer, guard) = tracing_appender::non_blocking(file_appender);
-        let filter = tracing_subscriber::EnvFilter::builder()
-            .with_default_directive(filter.into())
-            .from_env_lossy();
         let subscriber = subscriber.with(
             fmt::Layer::default()
                 .with_writer(file_writer)
                 .with_timer(timer)
                 .with_ansi(false) // disable ANSI colors for file logging
-                .with_filter(filter),
+                .with_filter(env_filter()),
         );
         setup_subscriber(subscriber);
         Some(guard)
@@ -106,9 +168,17 @@ async fn main() -> Result<(), ClewdrError> {
         None
     };
 
-    println!("{}\n{}", FIG, version_info_colored());
+    // keep stdout to JSON lines, the banner goes to stderr then
+    let banner = |text: String| {
+        if json_logs {
+            eprintln!("{text}");
+        } else {
+            println!("{text}");
+        }
+    };
+    banner(format!("{}\n{}", FIG, version_info_colored()));
 
-    #[cfg(feature = "portable")]
+    #[cfg(all(feature = "portable", not(feature = "no-self-update")))]
     {
         use tracing::warn;
         let updater = clewdr::services::update::ClewdrUpdater::new()?;
@@ -118,23 +188,26 @@ async fn main() -> Result<(), ClewdrError> {
     }
 
     // print info
-    println!("Config dir: {}", CONFIG_PATH.display().to_string().blue());
-    println!("{}", *CLEWDR_CONFIG);
+    banner(format!(
+        "Config dir: {}",
+        CONFIG_PATH.display().to_string().blue()
+    ));
+    banner(CLEWDR_CONFIG.to_string());
+
+    clewdr::services::dependencies::wait_for_dependencies().await;
+    clewdr::services::dependencies::warn_on_endpoint_loop().await;
 
     // build axum router
     // create a TCP listener
     let addr = CLEWDR_CONFIG.load().address();
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    let router = clewdr::router::RouterBuilder::new()
+    let builder = clewdr::router::RouterBuilder::new()
         .await
-        .with_default_setup()
-        .build();
+        .with_default_setup();
+    let cookie_actor = builder.cookie_actor_handle();
+    let router = builder.build();
     // serve the application
-    Ok(axum::serve(listener, router)
-        .with_graceful_shutdown(async {
-            tokio::signal::ctrl_c()
-                .await
-                .expect("Failed to install Ctrl-C handler");
-        })
-        .await?)
+    shutdown::serve_until_shutdown(listener, router).await?;
+    shutdown::flush_state(cookie_actor).await;
+    Ok(())
 }
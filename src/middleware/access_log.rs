@@ -0,0 +1,136 @@
+use std::time::Instant;
+
+use axum::{extract::Request, middleware::Next, response::Response};
+use tracing::{Instrument, Subscriber, field::Empty, info, info_span};
+use tracing_subscriber::{
+    fmt::{
+        self, MakeWriter,
+        format::{Format, Json, JsonFields},
+    },
+    registry::LookupSpan,
+};
+
+/// Tracing target of the per-request access log entries
+pub const ACCESS_TARGET: &str = "clewdr::access";
+
+/// Provider serving a route, from its path
+fn provider(path: &str) -> &'static str {
+    if path.starts_with("/code/") {
+        "claude_code"
+    } else if path.starts_with("/v1/") {
+        "claude_web"
+    } else if path.starts_with("/api/") {
+        "admin"
+    } else {
+        "other"
+    }
+}
+
+/// Runs each request inside a span carrying its id, method, path and
+/// provider, then logs its status and latency under [`ACCESS_TARGET`]
+///
+/// The model is recorded on the span once the request body is parsed.
+pub async fn log_access(req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    let span = info_span!(
+        target: ACCESS_TARGET,
+        "request",
+        id = %uuid::Uuid::new_v4(),
+        method = %req.method(),
+        path,
+        provider = provider(path),
+        model = Empty,
+    );
+    let start = Instant::now();
+    let resp = next.run(req).instrument(span.clone()).await;
+    span.in_scope(|| {
+        info!(
+            target: ACCESS_TARGET,
+            status = resp.status().as_u16(),
+            latency_ms = start.elapsed().as_millis() as u64,
+            "request finished"
+        )
+    });
+    resp
+}
+
+/// Log layer writing one JSON object per line, with the fields of the
+/// current span, for `log_format = "json"`
+pub fn json_log_layer<S, W>(writer: W) -> fmt::Layer<S, JsonFields, Format<Json>, W>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    fmt::Layer::default()
+        .json()
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_writer(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{Router, body::Body, middleware::from_fn, routing::post};
+    use serde_json::Value;
+    use tower::ServiceExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    /// Writer appending to a shared buffer
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_emit_a_json_access_line() {
+        let buf = SharedBuf::default();
+        let writer = buf.clone();
+        let subscriber =
+            tracing_subscriber::registry().with(json_log_layer(move || writer.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let app = Router::new()
+            .route(
+                "/v1/messages",
+                post(|| async {
+                    tracing::Span::current().record("model", "claude-sonnet-4-6");
+                    "ok"
+                }),
+            )
+            .layer(from_fn(log_access));
+        let req = http::Request::post("/v1/messages")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.oneshot(req).await.unwrap().status(), 200);
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let line = out
+            .lines()
+            .map(|l| serde_json::from_str::<Value>(l).expect("log line is JSON"))
+            .find(|l| l["fields"]["message"] == "request finished")
+            .expect("access line logged");
+        assert_eq!(line["target"], ACCESS_TARGET);
+        assert_eq!(line["fields"]["status"], 200);
+        assert!(line["fields"]["latency_ms"].is_u64());
+        assert_eq!(line["span"]["path"], "/v1/messages");
+        assert_eq!(line["span"]["provider"], "claude_web");
+        assert_eq!(line["span"]["model"], "claude-sonnet-4-6");
+        assert!(line["span"]["id"].is_string());
+    }
+}
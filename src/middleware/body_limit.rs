@@ -0,0 +1,87 @@
+use axum::{
+    Router,
+    extract::{DefaultBodyLimit, Request, State},
+    middleware::{Next, from_fn_with_state},
+    response::Response,
+};
+use http::header::CONTENT_LENGTH;
+
+use crate::error::ClewdrError;
+
+/// Rejects a request whose declared length is past `limit` before any of
+/// its body is read
+async fn check_content_length(
+    State(limit): State<usize>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ClewdrError> {
+    let len = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+    if len.is_some_and(|len| len > limit) {
+        return Err(ClewdrError::BodyTooLarge { limit });
+    }
+    Ok(next.run(req).await)
+}
+
+/// Caps request bodies at `limit` bytes, 0 lifts the cap
+///
+/// Bodies with a `Content-Length` are turned away up front, chunked ones
+/// stop being buffered once past the limit. Both end in a 413.
+pub fn with_body_limit(router: Router, limit: usize) -> Router {
+    if limit == 0 {
+        return router.layer(DefaultBodyLimit::disable());
+    }
+    router
+        .layer(from_fn_with_state(limit, check_content_length))
+        .layer(DefaultBodyLimit::max(limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Json, body::Body, routing::post};
+    use futures::stream;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn oversized_bodies_get_413() {
+        let app = with_body_limit(
+            Router::new().route(
+                "/v1/messages",
+                post(|Json(v): Json<Value>| async { Json(v) }),
+            ),
+            64,
+        );
+        let send = async |body: Body| {
+            let req = http::Request::post("/v1/messages")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(body)
+                .unwrap();
+            app.clone().oneshot(req).await.unwrap()
+        };
+        let small = r#"{"model":"claude-sonnet-4-6"}"#;
+        assert_eq!(send(Body::from(small)).await.status(), 200);
+
+        let big = format!(r#"{{"prompt":"{}"}}"#, "x".repeat(128));
+        let res = send(Body::from(big.clone())).await;
+        assert_eq!(res.status(), 413);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let err: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(err["error"]["code"], 413);
+
+        // no Content-Length, cut off while buffering
+        let chunks = big
+            .into_bytes()
+            .chunks(16)
+            .map(|c| Ok::<_, std::io::Error>(c.to_vec()))
+            .collect::<Vec<_>>();
+        let res = send(Body::from_stream(stream::iter(chunks))).await;
+        assert_eq!(res.status(), 413);
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::LazyLock;
+
+use axum::{body::Body, extract::Request, middleware::Next, response::Response};
+use futures::StreamExt;
+use regex::Regex;
+use tracing::debug;
+
+use crate::config::CLEWDR_CONFIG;
+
+/// Tracing target of logged bodies, written to `requests.log` instead of the
+/// main log when `log_bodies` is enabled
+pub const BODIES_TARGET: &str = "clewdr::bodies";
+
+/// String values of JSON fields holding credentials, the closing quote may
+/// be cut off by truncation
+static SECRET_FIELDS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"(?i)("(?:[a-z_-]*password|[a-z_-]*token|[a-z_-]*api[_-]?key|authorization|cookie|secret)"\s*:\s*")[^"]*"#,
+    )
+    .expect("Failed to compile secret field pattern")
+});
+
+/// Claude cookies and API keys wherever they appear
+static SECRET_VALUES: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"sk-ant-[A-Za-z0-9_-]+").expect("Failed to compile secret value pattern")
+});
+
+/// Masks credentials in logged text
+fn redact(text: &str) -> String {
+    let text = SECRET_FIELDS.replace_all(text, "${1}***");
+    SECRET_VALUES.replace_all(&text, "sk-ant-***").into_owned()
+}
+
+/// Start of a body passing through, logged when the body is done with
+struct BodyTap {
+    label: String,
+    max: usize,
+    head: Vec<u8>,
+    total: usize,
+}
+
+impl BodyTap {
+    fn observe(&mut self, chunk: &[u8]) {
+        let room = self.max.saturating_sub(self.head.len());
+        self.head.extend_from_slice(&chunk[..room.min(chunk.len())]);
+        self.total += chunk.len();
+    }
+
+    fn summary(&self) -> String {
+        let mut text = redact(&String::from_utf8_lossy(&self.head));
+        if self.total > self.head.len() {
+            text.push_str(&format!("… ({} bytes in total)", self.total));
+        }
+        format!("{}: {}", self.label, text)
+    }
+}
+
+impl Drop for BodyTap {
+    fn drop(&mut self) {
+        debug!(target: BODIES_TARGET, "{}", self.summary());
+    }
+}
+
+/// Passes `body` through, keeping only its first `max` bytes for the log so
+/// large payloads such as images are never held twice
+fn tap(body: Body, label: String, max: usize) -> Body {
+    let mut tap = BodyTap {
+        label,
+        max,
+        head: vec![],
+        total: 0,
+    };
+    Body::from_stream(body.into_data_stream().map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            tap.observe(chunk);
+        }
+        chunk
+    }))
+}
+
+/// Logs the start of request and response bodies under [`BODIES_TARGET`],
+/// when `log_bodies` is enabled
+///
+/// Streamed responses are logged once they end, up to
+/// `log_body_max_bytes` of their events.
+pub async fn log_bodies(req: Request, next: Next) -> Response {
+    let config = CLEWDR_CONFIG.load();
+    if !config.log_bodies {
+        return next.run(req).await;
+    }
+    let max = config.log_body_max_bytes;
+    let name = format!("{} {}", req.method(), req.uri().path());
+    let req = req.map(|body| tap(body, format!("{name} request"), max));
+    let resp = next.run(req).await;
+    let label = format!("{name} response {}", resp.status().as_u16());
+    resp.map(|body| tap(body, label, max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_are_redacted() {
+        let text = redact(
+            r#"{"api_key": "abc", "admin_password":"hunter2", "cookie": "sk-ant-sid01-xyz", "model": "claude"}"#,
+        );
+        assert_eq!(
+            text,
+            r#"{"api_key": "***", "admin_password":"***", "cookie": "***", "model": "claude"}"#
+        );
+
+        // a value cut off by truncation is still masked
+        assert_eq!(redact(r#"{"token": "eyJhbGc"#), r#"{"token": "***"#);
+        assert_eq!(
+            redact("x-api-key sk-ant-api03-AbC_d-e1 sent"),
+            "x-api-key sk-ant-*** sent"
+        );
+    }
+
+    #[test]
+    fn only_the_head_of_a_body_is_kept() {
+        let mut tap = BodyTap {
+            label: "POST /v1/messages request".to_string(),
+            max: 4,
+            head: vec![],
+            total: 0,
+        };
+        tap.observe(b"ab");
+        tap.observe(b"cdef");
+        tap.observe(b"gh");
+        assert_eq!(tap.head, b"abcd");
+        assert_eq!(
+            tap.summary(),
+            "POST /v1/messages request: abcd… (8 bytes in total)"
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use std::time::Duration;
+
+use axum::{body::Body, response::Response};
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt, pin_mut, stream};
+use http::header::CONTENT_TYPE;
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::config::{CLEWDR_CONFIG, StreamChunkMode};
+
+/// Merges streamed chunks until `target` bytes are buffered or `window` has
+/// passed since the first buffered chunk, whichever comes first
+fn coalesce_chunks<S, E>(
+    stream: S,
+    target: usize,
+    window: Duration,
+) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    async_stream::stream! {
+        pin_mut!(stream);
+        let mut buf = BytesMut::new();
+        let mut deadline = None;
+        loop {
+            let next = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, stream.next()).await,
+                None => Ok(stream.next().await),
+            };
+            match next {
+                // the window closed before the target size was reached
+                Err(_) => {
+                    deadline = None;
+                    yield Ok(buf.split().freeze());
+                }
+                Ok(Some(Ok(chunk))) => {
+                    if buf.is_empty() {
+                        deadline = Some(Instant::now() + window);
+                    }
+                    buf.extend_from_slice(&chunk);
+                    if buf.len() >= target {
+                        deadline = None;
+                        yield Ok(buf.split().freeze());
+                    }
+                }
+                Ok(Some(Err(e))) => {
+                    if !buf.is_empty() {
+                        yield Ok(buf.split().freeze());
+                    }
+                    yield Err(e);
+                    break;
+                }
+                Ok(None) => {
+                    if !buf.is_empty() {
+                        yield Ok(buf.split().freeze());
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// End of the first complete SSE event in `buf`, after its blank line
+fn event_end(buf: &[u8]) -> Option<usize> {
+    let lf = buf.windows(2).position(|w| w == b"\n\n").map(|i| i + 2);
+    let crlf = buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4);
+    match (lf, crlf) {
+        (Some(lf), Some(crlf)) => Some(lf.min(crlf)),
+        (lf, crlf) => lf.or(crlf),
+    }
+}
+
+/// Regroups streamed chunks into one chunk per complete SSE event
+fn sse_events<S, E>(stream: S) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    async_stream::stream! {
+        pin_mut!(stream);
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    buf.extend_from_slice(&chunk);
+                    while let Some(end) = event_end(&buf) {
+                        yield Ok(buf.split_to(end).freeze());
+                    }
+                }
+                Err(e) => {
+                    yield Err(e);
+                    break;
+                }
+            }
+        }
+        if !buf.is_empty() {
+            yield Ok(buf.freeze());
+        }
+    }
+}
+
+/// Cuts `text` into pieces of at most `max` bytes, without splitting UTF-8
+/// characters
+fn split_text(mut text: &str, max: usize) -> Vec<&str> {
+    let max = max.max(1);
+    let mut pieces = vec![];
+    while text.len() > max {
+        let at = (1..=max)
+            .rev()
+            .find(|at| text.is_char_boundary(*at))
+            .or_else(|| (max + 1..text.len()).find(|at| text.is_char_boundary(*at)))
+            .unwrap_or(text.len());
+        let (piece, rest) = text.split_at(at);
+        pieces.push(piece);
+        text = rest;
+    }
+    if !text.is_empty() {
+        pieces.push(text);
+    }
+    pieces
+}
+
+/// Where the text of a streamed delta sits, in a Claude `text_delta` or an
+/// OpenAI chunk
+fn delta_text_pointer(value: &Value) -> Option<&'static str> {
+    if value["type"] == "content_block_delta" && value["delta"]["type"] == "text_delta" {
+        return Some("/delta/text");
+    }
+    (value["choices"].as_array()?.len() == 1 && value["choices"][0]["delta"]["content"].is_string())
+        .then_some("/choices/0/delta/content")
+}
+
+/// Splits an event carrying more than `max` bytes of delta text into several
+/// complete events of the same kind, any other event is left whole
+fn split_event(event: Bytes, max: usize) -> Vec<Bytes> {
+    let Ok(text) = std::str::from_utf8(&event) else {
+        return vec![event];
+    };
+    let data = text
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let Ok(mut value) = serde_json::from_str::<Value>(&data) else {
+        return vec![event];
+    };
+    let Some(pointer) = delta_text_pointer(&value) else {
+        return vec![event];
+    };
+    let delta = match value.pointer(pointer).and_then(Value::as_str) {
+        Some(delta) if delta.len() > max => delta.to_owned(),
+        _ => return vec![event],
+    };
+    // `event:`, `id:` and the like are repeated on every piece
+    let head = text
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with("data:"))
+        .map(|line| format!("{line}\n"))
+        .collect::<String>();
+    split_text(&delta, max)
+        .into_iter()
+        .map(|piece| {
+            if let Some(text) = value.pointer_mut(pointer) {
+                *text = Value::from(piece);
+            }
+            Bytes::from(format!("{head}data: {value}\n\n"))
+        })
+        .collect()
+}
+
+/// Splits events whose delta text is larger than `max` bytes into several
+/// smaller events, every frame sent stays a complete event
+fn split_chunks<S, E>(stream: S, max: usize) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    sse_events(stream).flat_map(move |item| {
+        let frames = match item {
+            Ok(event) => split_event(event, max).into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e)],
+        };
+        stream::iter(frames)
+    })
+}
+
+/// Re-frames streamed responses according to `stream_chunk_mode`
+///
+/// Coalescing only changes the framing of the body, splitting cuts large
+/// text deltas into several events so that no event is ever cut in half.
+pub async fn apply_stream_chunk_mode(resp: Response) -> Response {
+    let config = CLEWDR_CONFIG.load();
+    let is_event_stream = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+    if config.stream_chunk_mode == StreamChunkMode::Passthrough || !is_event_stream {
+        return resp;
+    }
+    let (parts, body) = resp.into_parts();
+    let stream = body.into_data_stream();
+    let body = match config.stream_chunk_mode {
+        StreamChunkMode::Coalesce => Body::from_stream(coalesce_chunks(
+            stream,
+            config.stream_chunk_bytes,
+            Duration::from_millis(config.stream_chunk_window_ms),
+        )),
+        StreamChunkMode::Split => {
+            Body::from_stream(split_chunks(stream, config.stream_chunk_bytes))
+        }
+        StreamChunkMode::Passthrough => Body::from_stream(stream),
+    };
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::*;
+
+    fn chunks(frames: &[&'static str]) -> impl Stream<Item = Result<Bytes, Infallible>> {
+        stream::iter(
+            frames
+                .iter()
+                .map(|f| Ok(Bytes::from_static(f.as_bytes())))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    async fn collect(stream: impl Stream<Item = Result<Bytes, Infallible>>) -> Vec<Bytes> {
+        stream.map(Result::unwrap).collect().await
+    }
+
+    #[tokio::test]
+    async fn coalesce_merges_small_frames_up_to_target() {
+        let frames = chunks(&["data: a\n\n", "data: b\n\n", "data: c\n\n", "data: d\n\n"]);
+        let out = collect(coalesce_chunks(frames, 18, Duration::from_secs(10))).await;
+        assert_eq!(out, ["data: a\n\ndata: b\n\n", "data: c\n\ndata: d\n\n"]);
+    }
+
+    #[tokio::test]
+    async fn coalesce_flushes_when_window_closes() {
+        let slow = chunks(&["data: a\n\n", "data: b\n\n"]).then(async |chunk| {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            chunk
+        });
+        let out = collect(coalesce_chunks(slow, 1024, Duration::from_millis(10))).await;
+        assert_eq!(out, ["data: a\n\n", "data: b\n\n"]);
+    }
+
+    #[tokio::test]
+    async fn split_sends_only_complete_events() {
+        let delta = serde_json::json!({
+            "type": "content_block_delta",
+            "index": 0,
+            "delta": {"type": "text_delta", "text": "Hello, wörld!"},
+        });
+        let event = format!("event: content_block_delta\ndata: {delta}\n\n");
+        // the event arrives cut at arbitrary points
+        let (a, b) = event.split_at(20);
+        let (b, c) = b.split_at(40);
+        let stop = "event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n";
+        let input = stream::iter(
+            [a, b, c, ": ping\n\n", stop].map(|f| Ok::<_, Infallible>(Bytes::from(f.to_owned()))),
+        );
+        let out = collect(split_chunks(input, 5)).await;
+
+        let mut text = String::new();
+        for frame in &out {
+            let frame = std::str::from_utf8(frame).unwrap();
+            assert!(frame.ends_with("\n\n"), "incomplete frame {frame:?}");
+            assert_eq!(frame.matches("\n\n").count(), 1, "merged frames {frame:?}");
+            if let Some(data) = frame.strip_prefix("event: content_block_delta\ndata: ") {
+                let value = serde_json::from_str::<Value>(data.trim_end()).unwrap();
+                let piece = value["delta"]["text"].as_str().unwrap();
+                assert!(piece.len() <= 5);
+                text.push_str(piece);
+            }
+        }
+        assert_eq!(text, "Hello, wörld!");
+        assert_eq!(out[out.len() - 2], ": ping\n\n");
+        assert_eq!(out[out.len() - 1], stop);
+
+        // OpenAI chunks are split the same way
+        let chunk = r#"data: {"choices":[{"index":0,"delta":{"content":"abcdefgh"}}]}"#;
+        let out = collect(split_chunks(chunks(&[chunk, "\n\n"]), 4)).await;
+        let pieces = out
+            .iter()
+            .map(|frame| {
+                let data = std::str::from_utf8(frame)
+                    .unwrap()
+                    .strip_prefix("data: ")
+                    .unwrap();
+                let value = serde_json::from_str::<Value>(data.trim_end()).unwrap();
+                value["choices"][0]["delta"]["content"].to_owned()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(pieces, ["abcd", "efgh"]);
+    }
+
+    #[test]
+    fn text_is_never_cut_inside_a_character() {
+        assert_eq!(split_text("aé€", 2), ["a", "é", "€"]);
+        assert_eq!(split_text("abc", 0), ["a", "b", "c"]);
+    }
+}
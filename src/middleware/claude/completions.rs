@@ -0,0 +1,219 @@
+use axum::{
+    body::{self, Body},
+    extract::Request,
+    middleware::Next,
+    response::{IntoResponse, Response, Sse, sse::Event},
+};
+use eventsource_stream::Eventsource;
+use futures::TryStreamExt;
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use serde_json::{Value, json};
+
+use super::ClaudeContext;
+use crate::{config::CLEWDR_CONFIG, error::ClewdrError};
+
+/// Whether `path` is a legacy OpenAI text completions endpoint
+fn is_legacy_completions(path: &str) -> bool {
+    path.ends_with("/v1/completions")
+}
+
+fn invalid_prompt(msg: &str) -> ClewdrError {
+    ClewdrError::InvalidRequest {
+        field: "prompt".to_string(),
+        msg: msg.to_string(),
+    }
+}
+
+/// Turns a text completions body into a chat completions one, its `prompt`
+/// becoming a single user message
+///
+/// A one element prompt array is taken as its string, batches are rejected
+/// as each prompt would need its own upstream request.
+fn prompt_to_messages(body: &mut Value) -> Result<(), ClewdrError> {
+    let Some(obj) = body.as_object_mut() else {
+        return Err(ClewdrError::BadRequest {
+            msg: "Invalid request body",
+        });
+    };
+    let prompt = match obj.remove("prompt") {
+        Some(Value::String(prompt)) => prompt,
+        Some(Value::Array(prompts)) => match <[Value; 1]>::try_from(prompts) {
+            Ok([Value::String(prompt)]) => prompt,
+            Ok(_) => return Err(invalid_prompt("must be a string")),
+            Err(_) => {
+                return Err(invalid_prompt(
+                    "batched prompts are not supported, send one prompt per request",
+                ));
+            }
+        },
+        Some(_) => return Err(invalid_prompt("must be a string")),
+        None => return Err(invalid_prompt("is required")),
+    };
+    obj.insert(
+        "messages".to_string(),
+        json!([{"role": "user", "content": prompt}]),
+    );
+    Ok(())
+}
+
+/// Reshapes a chat completion, or a chunk of one, into a text completion
+///
+/// Reasoning deltas have no place in a text completion, chunks made only of
+/// them come out with an empty `text`.
+fn to_text_completion(mut chat: Value) -> Value {
+    let choices = chat["choices"]
+        .as_array()
+        .map(|choices| {
+            choices
+                .iter()
+                .enumerate()
+                .map(|(i, choice)| {
+                    let text = choice["message"]["content"]
+                        .as_str()
+                        .or(choice["delta"]["content"].as_str())
+                        .unwrap_or_default();
+                    json!({
+                        "index": choice.get("index").cloned().unwrap_or(json!(i)),
+                        "text": text,
+                        "logprobs": null,
+                        "finish_reason": choice.get("finish_reason").cloned().unwrap_or(Value::Null),
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    chat["object"] = json!("text_completion");
+    chat["choices"] = json!(choices);
+    chat
+}
+
+/// Serves the legacy `/v1/completions` endpoint on top of chat completions
+///
+/// The `prompt` is handed to the chat pipeline as a user message, and its
+/// answer, whole or streamed, is reshaped into `choices[].text`.
+pub async fn legacy_completions(req: Request, next: Next) -> Result<Response, ClewdrError> {
+    if !is_legacy_completions(req.uri().path()) {
+        return Ok(next.run(req).await);
+    }
+    let limit = match CLEWDR_CONFIG.load().max_body_bytes {
+        0 => usize::MAX,
+        limit => limit,
+    };
+    let (mut parts, body) = req.into_parts();
+    let bytes = body::to_bytes(body, limit)
+        .await
+        .map_err(|_| ClewdrError::BodyTooLarge { limit })?;
+    let mut value =
+        serde_json::from_slice::<Value>(&bytes).map_err(|_| ClewdrError::BadRequest {
+            msg: "Invalid request body",
+        })?;
+    prompt_to_messages(&mut value)?;
+    parts.headers.remove(CONTENT_LENGTH);
+    let req = Request::from_parts(parts, Body::from(value.to_string()));
+
+    let resp = next.run(req).await;
+    if !resp.status().is_success() {
+        return Ok(resp);
+    }
+    let is_event_stream = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+    let cx = resp.extensions().get::<ClaudeContext>().cloned();
+    let mut resp = if is_event_stream {
+        let stream = resp
+            .into_body()
+            .into_data_stream()
+            .eventsource()
+            .map_ok(|event| {
+                let data = match serde_json::from_str::<Value>(&event.data) {
+                    Ok(chunk) => to_text_completion(chunk).to_string(),
+                    // `[DONE]` and anything else unexpected pass through
+                    Err(_) => event.data,
+                };
+                Event::default().data(data)
+            });
+        Sse::new(stream)
+            .keep_alive(Default::default())
+            .into_response()
+    } else {
+        let bytes = body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap_or_default();
+        match serde_json::from_slice::<Value>(&bytes) {
+            Ok(chat) => axum::Json(to_text_completion(chat)).into_response(),
+            Err(_) => ([(CONTENT_TYPE, "application/json")], bytes).into_response(),
+        }
+    };
+    if let Some(cx) = cx {
+        resp.extensions_mut().insert(cx);
+    }
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::oai::CreateMessageParams as OaiCreateMessageParams;
+
+    #[test]
+    fn prompt_becomes_a_user_message() {
+        let mut body = json!({
+            "model": "claude-sonnet-4-6",
+            "prompt": "Once upon a time",
+            "max_tokens": 32,
+            "stream": true,
+        });
+        prompt_to_messages(&mut body).unwrap();
+        assert!(body.get("prompt").is_none());
+        assert_eq!(
+            body["messages"],
+            json!([{"role": "user", "content": "Once upon a time"}])
+        );
+        let params = serde_json::from_value::<OaiCreateMessageParams>(body);
+        assert!(params.is_ok());
+
+        let mut single = json!({"model": "m", "prompt": ["Hello"]});
+        prompt_to_messages(&mut single).unwrap();
+        assert_eq!(single["messages"][0]["content"], "Hello");
+
+        let mut batch = json!({"model": "m", "prompt": ["a", "b"]});
+        assert!(matches!(
+            prompt_to_messages(&mut batch),
+            Err(ClewdrError::InvalidRequest { .. })
+        ));
+        let mut missing = json!({"model": "m"});
+        assert!(prompt_to_messages(&mut missing).is_err());
+    }
+
+    #[test]
+    fn chat_answers_are_reshaped_into_text() {
+        let chat = json!({
+            "id": "msg_1",
+            "object": "chat.completion",
+            "model": "claude-sonnet-4-6",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "the end."},
+                "finish_reason": "stop",
+            }],
+            "usage": {"prompt_tokens": 4, "completion_tokens": 3, "total_tokens": 7},
+        });
+        let text = to_text_completion(chat);
+        assert_eq!(text["object"], "text_completion");
+        assert_eq!(text["choices"][0]["text"], "the end.");
+        assert_eq!(text["choices"][0]["finish_reason"], "stop");
+        assert!(text["choices"][0]["logprobs"].is_null());
+        assert_eq!(text["usage"]["total_tokens"], 7);
+
+        let chunk = json!({"choices": [{"delta": {"content": "Once"}}]});
+        let text = to_text_completion(chunk);
+        assert_eq!(text["choices"][0]["text"], "Once");
+        assert_eq!(text["choices"][0]["index"], 0);
+        assert!(text["choices"][0]["finish_reason"].is_null());
+
+        let reasoning = json!({"choices": [{"delta": {"reasoning_content": "hmm"}}]});
+        assert_eq!(to_text_completion(reasoning)["choices"][0]["text"], "");
+    }
+}
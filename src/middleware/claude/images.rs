@@ -0,0 +1,327 @@
+use std::{collections::HashMap, pin::pin, time::Duration};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use futures::{StreamExt, stream};
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use tracing::warn;
+use wreq::{Client, Proxy};
+
+use crate::{
+    config::{CLEWDR_CONFIG, ClewdrConfig},
+    types::claude::{ContentBlock, CreateMessageParams, ImageSource, MessageContent},
+    utils::build_http_client,
+};
+
+/// Longest a single image download may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// Images of a request downloaded at once
+const FETCH_CONCURRENCY: usize = 4;
+
+/// Builds an inline image from a downloaded body, checking it against the
+/// configured media types and size limit
+fn inline_image(
+    content_type: Option<&str>,
+    body: &[u8],
+    config: &ClewdrConfig,
+) -> Result<ImageSource, String> {
+    let media_type = content_type
+        .and_then(|t| t.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if !config
+        .remote_image_types
+        .iter()
+        .any(|t| t.eq_ignore_ascii_case(&media_type))
+    {
+        return Err(format!("media type `{media_type}` is not allowed"));
+    }
+    if body.len() > config.remote_image_max_bytes {
+        return Err(format!(
+            "{} bytes exceed the limit of {}",
+            body.len(),
+            config.remote_image_max_bytes
+        ));
+    }
+    Ok(ImageSource::Base64 {
+        media_type,
+        data: BASE64_STANDARD.encode(body),
+    })
+}
+
+/// Downloads an image, giving up as soon as it grows past the size limit
+async fn fetch_image(
+    client: &Client,
+    url: &str,
+    config: &ClewdrConfig,
+) -> Result<ImageSource, String> {
+    let resp = client
+        .get(url)
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !resp.status().is_success() {
+        return Err(format!("upstream answered {}", resp.status()));
+    }
+    let max = config.remote_image_max_bytes;
+    let too_large = || format!("image exceeds the limit of {max} bytes");
+    let header = |name| resp.headers().get(name).and_then(|v| v.to_str().ok());
+    if header(CONTENT_LENGTH)
+        .and_then(|len| len.parse::<usize>().ok())
+        .is_some_and(|len| len > max)
+    {
+        return Err(too_large());
+    }
+    let content_type = header(CONTENT_TYPE).map(str::to_owned);
+    let mut stream = pin!(resp.bytes_stream());
+    let mut body = vec![];
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk.map_err(|e| e.to_string())?);
+        if body.len() > max {
+            return Err(too_large());
+        }
+    }
+    inline_image(content_type.as_deref(), &body, config)
+}
+
+/// URL of a block that is a remote image to inline
+fn remote_url(block: &ContentBlock) -> Option<&str> {
+    let url = match block {
+        ContentBlock::Image {
+            source: ImageSource::Url { url },
+            ..
+        } => url,
+        ContentBlock::ImageUrl { image_url } => &image_url.url,
+        _ => return None,
+    };
+    Some(url.trim()).filter(|url| url.starts_with("https://"))
+}
+
+/// Every remote image URL of a request, once each
+fn remote_urls(body: &CreateMessageParams) -> Vec<String> {
+    let mut urls: Vec<String> = vec![];
+    for msg in &body.messages {
+        let MessageContent::Blocks { content } = &msg.content else {
+            continue;
+        };
+        for url in content.iter().filter_map(remote_url) {
+            if !urls.iter().any(|u| u == url) {
+                urls.push(url.to_string());
+            }
+        }
+    }
+    urls
+}
+
+/// Replaces remote images with their downloaded sources, dropping the ones
+/// that failed to download
+fn replace_remote_images(
+    body: &mut CreateMessageParams,
+    fetched: &HashMap<String, Result<ImageSource, String>>,
+) {
+    for msg in body.messages.iter_mut() {
+        let MessageContent::Blocks { content } = &mut msg.content else {
+            continue;
+        };
+        content.retain_mut(|block| {
+            let Some(result) = remote_url(block).and_then(|url| fetched.get(url)) else {
+                return true;
+            };
+            match result {
+                Ok(source) => {
+                    let cache_control = match block {
+                        ContentBlock::Image { cache_control, .. } => cache_control.take(),
+                        _ => None,
+                    };
+                    *block = ContentBlock::Image {
+                        source: source.to_owned(),
+                        cache_control,
+                    };
+                    true
+                }
+                Err(e) => {
+                    warn!("Dropping remote image that could not be inlined: {}", e);
+                    false
+                }
+            }
+        });
+    }
+}
+
+/// Downloads the `https://` images of a request and sends their data
+/// inline, when `fetch_remote_images` is enabled
+///
+/// Downloads go through `proxy` like the upstream request, a few at a
+/// time. Images that fail to download, are too large or have a media type
+/// outside `remote_image_types` are dropped from the request.
+pub(super) async fn inline_remote_images(body: &mut CreateMessageParams, proxy: Option<&Proxy>) {
+    let config = CLEWDR_CONFIG.load();
+    if !config.fetch_remote_images {
+        return;
+    }
+    let urls = remote_urls(body);
+    if urls.is_empty() {
+        return;
+    }
+    let client = build_http_client(proxy).map_err(|e| e.to_string());
+    let fetched = stream::iter(urls)
+        .map(|url| {
+            let client = &client;
+            let config = &config;
+            async move {
+                let result = match client {
+                    Ok(client) => fetch_image(client, &url, config).await,
+                    Err(e) => Err(e.to_owned()),
+                };
+                (url, result)
+            }
+        })
+        .buffer_unordered(FETCH_CONCURRENCY)
+        .collect::<HashMap<_, _>>()
+        .await;
+    replace_remote_images(body, &fetched);
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, body::Body, http::StatusCode, routing::get};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::types::claude::{ImageUrl, Message, Role};
+
+    /// Serves test images on a local port, giving its base URL
+    async fn serve_images() -> String {
+        let router = Router::new()
+            .route(
+                "/cat.png",
+                get(|| async { ([(CONTENT_TYPE, "image/png")], "png") }),
+            )
+            .route("/missing.png", get(|| async { StatusCode::NOT_FOUND }))
+            .route(
+                "/large.png",
+                get(|| async { ([(CONTENT_TYPE, "image/png")], vec![0u8; 16]) }),
+            )
+            .route(
+                "/streamed.png",
+                get(|| async {
+                    let chunks = [Ok::<_, std::io::Error>(vec![0u8; 8]), Ok(vec![0u8; 8])];
+                    (
+                        [(CONTENT_TYPE, "image/png")],
+                        Body::from_stream(stream::iter(chunks)),
+                    )
+                }),
+            )
+            .route(
+                "/page.html",
+                get(|| async { ([(CONTENT_TYPE, "text/html")], "<html>") }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn images_are_downloaded_within_limits() {
+        let base = serve_images().await;
+        let client = build_http_client(None).unwrap();
+        let mut config = ClewdrConfig::default();
+        config.remote_image_max_bytes = 8;
+        let fetch =
+            async |path: &str| fetch_image(&client, &format!("{base}{path}"), &config).await;
+
+        assert_eq!(
+            fetch("/cat.png").await.unwrap(),
+            ImageSource::Base64 {
+                media_type: "image/png".to_string(),
+                data: "cG5n".to_string(),
+            }
+        );
+        assert!(fetch("/missing.png").await.unwrap_err().contains("404"));
+        assert!(fetch("/large.png").await.unwrap_err().contains("limit"));
+        assert!(fetch("/streamed.png").await.unwrap_err().contains("limit"));
+        assert!(
+            fetch("/page.html")
+                .await
+                .unwrap_err()
+                .contains("not allowed")
+        );
+    }
+
+    #[test]
+    fn downloaded_images_are_checked() {
+        let config = ClewdrConfig::default();
+        let source = inline_image(Some("image/PNG; charset=binary"), b"png", &config).unwrap();
+        assert_eq!(
+            source,
+            ImageSource::Base64 {
+                media_type: "image/png".to_string(),
+                data: "cG5n".to_string(),
+            }
+        );
+        assert!(inline_image(Some("text/html"), b"<html>", &config).is_err());
+        assert!(inline_image(None, b"png", &config).is_err());
+
+        let mut config = ClewdrConfig::default();
+        config.remote_image_max_bytes = 2;
+        assert!(inline_image(Some("image/png"), b"png", &config).is_err());
+    }
+
+    #[test]
+    fn remote_images_are_inlined_or_dropped() {
+        let image = |url: &str| ContentBlock::Image {
+            source: ImageSource::Url {
+                url: url.to_string(),
+            },
+            cache_control: None,
+        };
+        let mut body = CreateMessageParams {
+            messages: vec![Message::new_blocks(
+                Role::User,
+                vec![
+                    ContentBlock::text("compare"),
+                    image("https://img.example/cat.png"),
+                    ContentBlock::ImageUrl {
+                        image_url: ImageUrl {
+                            url: "https://img.example/missing.png".to_string(),
+                        },
+                    },
+                    image("http://img.example/plain.png"),
+                    image("https://img.example/cat.png"),
+                ],
+            )],
+            ..Default::default()
+        };
+        assert_eq!(
+            remote_urls(&body),
+            [
+                "https://img.example/cat.png",
+                "https://img.example/missing.png"
+            ]
+        );
+
+        let config = ClewdrConfig::default();
+        let fetched = HashMap::from([
+            (
+                "https://img.example/cat.png".to_string(),
+                inline_image(Some("image/png"), b"png", &config),
+            ),
+            (
+                "https://img.example/missing.png".to_string(),
+                Err("upstream answered 404 Not Found".to_string()),
+            ),
+        ]);
+        replace_remote_images(&mut body, &fetched);
+
+        let MessageContent::Blocks { content } = &body.messages[0].content else {
+            panic!("blocks expected");
+        };
+        assert_eq!(content.len(), 4);
+        let inlined = fetched["https://img.example/cat.png"].to_owned().unwrap();
+        assert!(matches!(&content[1], ContentBlock::Image { source, .. } if *source == inlined));
+        assert_eq!(content[2], image("http://img.example/plain.png"));
+        assert!(matches!(&content[3], ContentBlock::Image { source, .. } if *source == inlined));
+    }
+}
@@ -1,13 +1,26 @@
+use std::time::Duration;
+
+mod chunking;
 mod claude2oai;
+mod completions;
+mod images;
 mod request;
 mod response;
+mod rewrite;
 mod stop_sequences;
+mod thinking;
+mod tool_input;
 
+pub use chunking::*;
 pub(crate) use claude2oai::*;
+pub use completions::*;
 pub use request::*;
 pub use response::*;
+pub use rewrite::*;
 pub use stop_sequences::*;
 use strum::Display;
+pub use thinking::*;
+pub use tool_input::*;
 
 use crate::types::claude::Usage;
 
@@ -60,6 +73,13 @@ impl ClaudeContext {
         }
     }
 
+    pub fn stop_case_insensitive(&self) -> bool {
+        match self {
+            ClaudeContext::Web(ctx) => ctx.stop_case_insensitive,
+            ClaudeContext::Code(_) => false,
+        }
+    }
+
     pub fn system_prompt_hash(&self) -> Option<u64> {
         match self {
             ClaudeContext::Web(_) => None,
@@ -67,6 +87,13 @@ impl ClaudeContext {
         }
     }
 
+    pub fn requested_model(&self) -> &str {
+        match self {
+            ClaudeContext::Web(ctx) => &ctx.requested_model,
+            ClaudeContext::Code(ctx) => &ctx.requested_model,
+        }
+    }
+
     pub fn usage(&self) -> &Usage {
         match self {
             ClaudeContext::Web(ctx) => &ctx.usage,
@@ -74,10 +101,52 @@ impl ClaudeContext {
         }
     }
 
+    pub fn hide_thinking(&self) -> bool {
+        match self {
+            ClaudeContext::Web(ctx) => ctx.hide_thinking,
+            ClaudeContext::Code(ctx) => ctx.hide_thinking,
+        }
+    }
+
+    pub fn proxy_hops(&self) -> u32 {
+        match self {
+            ClaudeContext::Web(ctx) => ctx.proxy_hops,
+            ClaudeContext::Code(ctx) => ctx.proxy_hops,
+        }
+    }
+
+    pub fn client_timeout(&self) -> Option<Duration> {
+        match self {
+            ClaudeContext::Web(ctx) => ctx.client_timeout,
+            ClaudeContext::Code(ctx) => ctx.client_timeout,
+        }
+    }
+
+    pub fn dry_run(&self) -> bool {
+        match self {
+            ClaudeContext::Web(ctx) => ctx.dry_run,
+            ClaudeContext::Code(ctx) => ctx.dry_run,
+        }
+    }
+
+    pub fn ignored_params(&self) -> &[&'static str] {
+        match self {
+            ClaudeContext::Web(ctx) => &ctx.ignored_params,
+            ClaudeContext::Code(ctx) => &ctx.ignored_params,
+        }
+    }
+
     pub fn anthropic_beta(&self) -> Option<&str> {
         match self {
             ClaudeContext::Web(_) => None,
             ClaudeContext::Code(ctx) => ctx.anthropic_beta.as_deref(),
         }
     }
+
+    pub fn anthropic_version(&self) -> Option<&str> {
+        match self {
+            ClaudeContext::Web(_) => None,
+            ClaudeContext::Code(ctx) => ctx.anthropic_version.as_deref(),
+        }
+    }
 }
@@ -1,7 +1,9 @@
 use std::{
+    collections::HashMap,
     env,
     hash::{DefaultHasher, Hash, Hasher},
     sync::LazyLock,
+    time::Duration,
     vec,
 };
 
@@ -10,13 +12,21 @@ use axum::{
     extract::{FromRequest, Request},
 };
 use http::HeaderMap;
-use serde_json::{Value, json};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
 use sha2::{Digest, Sha256};
+use tracing::{Span, warn};
 
+pub use crate::config::PROXY_HOPS_HEADER;
 use crate::{
-    config::{CLAUDE_CODE_BILLING_SALT, CLAUDE_CODE_VERSION, CLEWDR_CONFIG},
+    config::{
+        CLAUDE_API_VERSION, CLAUDE_CODE_BILLING_SALT, CLAUDE_CODE_VERSION, CLEWDR_CONFIG,
+        ClewdrConfig, ProxyBackend, SUPPORTED_ANTHROPIC_VERSIONS, UnsupportedBlockPolicy,
+    },
     error::ClewdrError,
-    middleware::claude::{ClaudeApiFormat, ClaudeContext},
+    middleware::claude::{
+        ClaudeApiFormat, ClaudeContext, images::inline_remote_images, thinking::hide_thinking,
+    },
     types::{
         claude::{
             ContentBlock, CreateMessageParams, Message, MessageContent, Role, Thinking, Usage,
@@ -54,6 +64,20 @@ pub struct ClaudeWebContext {
     pub(super) api_format: ClaudeApiFormat,
     /// The stop sequence used for the request
     pub(super) stop_sequences: Vec<String>,
+    /// Whether stop sequences match regardless of case
+    pub(super) stop_case_insensitive: bool,
+    /// Model name as sent by the client, before any suffix stripping
+    pub(super) requested_model: String,
+    /// Whether thinking blocks are stripped from the response
+    pub(super) hide_thinking: bool,
+    /// ClewdR instances the request already went through
+    pub(super) proxy_hops: u32,
+    /// Upstream timeout asked for by the client
+    pub(super) client_timeout: Option<Duration>,
+    /// Whether the upstream request body is returned instead of sent
+    pub(super) dry_run: bool,
+    /// Client parameters Claude has no equivalent for, dropped from the request
+    pub(super) ignored_params: Vec<&'static str>,
     /// User information about input and output tokens
     pub(super) usage: Usage,
 }
@@ -69,7 +93,30 @@ static TEST_MESSAGE_CLAUDE: LazyLock<Message> =
 /// Predefined test message in OpenAI format for connection testing
 static TEST_MESSAGE_OAI: LazyLock<Message> = LazyLock::new(|| Message::new_text(Role::User, "Hi"));
 
-struct NormalizeRequest(CreateMessageParams, ClaudeApiFormat);
+/// Normalized request body, its API format, the model name the client asked
+/// for and the parameters it sent that were dropped
+struct NormalizeRequest(
+    CreateMessageParams,
+    ClaudeApiFormat,
+    String,
+    Vec<&'static str>,
+);
+
+/// OpenAI sampling parameters Claude has no equivalent for
+///
+/// They are dropped either way, but the client is told so through the
+/// `x-clewdr-ignored-params` header, as eval harnesses rely on them for
+/// reproducible outputs.
+const UNSUPPORTED_PARAMS: &[&str] = &["seed", "logit_bias"];
+
+/// Unsupported parameters set in a raw request body
+fn ignored_params(value: &Value) -> Vec<&'static str> {
+    UNSUPPORTED_PARAMS
+        .iter()
+        .copied()
+        .filter(|param| value.get(param).is_some_and(|v| !v.is_null()))
+        .collect()
+}
 
 const CLAUDE_CODE_ENTRYPOINT_ENV: &str = "CLAUDE_CODE_ENTRYPOINT";
 
@@ -188,6 +235,101 @@ fn strip_ephemeral_scope_from_system(system: &mut Value) {
     }
 }
 
+/// Hashes the system blocks marked for prompt caching, so requests sharing
+/// a cached prefix can be routed to the same cookie
+///
+/// Expects the system prompt in array form, as left by `prepend_system_blocks`.
+fn system_prompt_hash(system: Option<&Value>) -> Option<u64> {
+    let cache_systems = system
+        .and_then(Value::as_array)
+        .map(|systems| {
+            systems
+                .iter()
+                .filter(|s| s["cache_control"].as_object().is_some())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    (!cache_systems.is_empty()).then(|| {
+        let mut hasher = DefaultHasher::new();
+        cache_systems.hash(&mut hasher);
+        hasher.finish()
+    })
+}
+
+/// Hops a request already made, rejecting it once past `max`
+fn proxy_hops(headers: &HeaderMap, max: u32) -> Result<u32, ClewdrError> {
+    let hops = headers
+        .get(PROXY_HOPS_HEADER)
+        .and_then(|v| v.to_str().ok()?.trim().parse().ok())
+        .unwrap_or(0);
+    if hops > max {
+        return Err(ClewdrError::ProxyLoop { hops, max });
+    }
+    Ok(hops)
+}
+
+/// Request headers a client may set its own timeout in, in seconds
+pub const CLIENT_TIMEOUT_HEADERS: &[&str] = &["x-timeout", "x-stainless-timeout"];
+
+/// Upstream timeout the client asked for, clamped to the configured bounds
+fn client_timeout(headers: &HeaderMap, config: &ClewdrConfig) -> Option<Duration> {
+    let secs = CLIENT_TIMEOUT_HEADERS
+        .iter()
+        .find_map(|name| headers.get(*name)?.to_str().ok()?.trim().parse().ok())?;
+    config.client_timeout(secs)
+}
+
+/// Request header asking for the upstream request body instead of a
+/// response, `?dry_run=1` does the same
+pub const DRY_RUN_HEADER: &str = "x-clewdr-dryrun";
+
+/// Whether the client asked for a dry run, rejected unless `allow_dry_run`
+/// is set so that a preset under test never spends quota by accident
+fn dry_run(req: &Request, allowed: bool) -> Result<bool, ClewdrError> {
+    let truthy = |v: &str| matches!(v.trim(), "1" | "true" | "yes" | "on");
+    let header = req
+        .headers()
+        .get(DRY_RUN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(truthy);
+    let query = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .any(|(k, v)| k == "dry_run" && truthy(v));
+    if (header || query) && !allowed {
+        return Err(ClewdrError::BadRequest {
+            msg: "Dry run is disabled, set allow_dry_run to use it",
+        });
+    }
+    Ok(header || query)
+}
+
+/// Warns about an `anthropic-version` whose response schema ClewdR does not
+/// expect, the version is still forwarded to Claude Code
+fn check_anthropic_version(headers: &HeaderMap) {
+    let Some(version) = extract_anthropic_version_header(headers) else {
+        return;
+    };
+    if !SUPPORTED_ANTHROPIC_VERSIONS.contains(&version.as_str()) {
+        warn!(
+            "Unsupported anthropic-version {}, response handling follows {}",
+            version, CLAUDE_API_VERSION
+        );
+    }
+}
+
+fn extract_anthropic_version_header(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("anthropic-version")
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+}
+
 fn extract_anthropic_beta_header(headers: &HeaderMap) -> Option<String> {
     let mut parts = Vec::new();
     for value in headers.get_all("anthropic-beta") {
@@ -208,6 +350,61 @@ fn extract_anthropic_beta_header(headers: &HeaderMap) -> Option<String> {
     }
 }
 
+/// Block types the claude.ai web backend can fold into its text prompt
+fn web_supports_block(block: &ContentBlock) -> bool {
+    matches!(
+        block,
+        ContentBlock::Text { .. } | ContentBlock::Image { .. } | ContentBlock::ImageUrl { .. }
+    )
+}
+
+/// The Messages API accepts every Claude block, but not OpenAI-style image urls
+fn code_supports_block(block: &ContentBlock) -> bool {
+    !matches!(block, ContentBlock::ImageUrl { .. })
+}
+
+fn content_block_type(block: &ContentBlock) -> String {
+    serde_json::to_value(block)
+        .ok()
+        .and_then(|value| value["type"].as_str().map(str::to_string))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Strips or rejects message content blocks the target backend cannot accept,
+/// so that they don't end up as an opaque upstream error or get silently lost
+fn filter_unsupported_blocks(
+    body: &mut CreateMessageParams,
+    backend: &'static str,
+    supports: fn(&ContentBlock) -> bool,
+    policy: UnsupportedBlockPolicy,
+) -> Result<(), ClewdrError> {
+    for message in body.messages.iter_mut() {
+        let MessageContent::Blocks { content } = &mut message.content else {
+            continue;
+        };
+        if policy == UnsupportedBlockPolicy::Reject
+            && let Some(block) = content.iter().find(|block| !supports(block))
+        {
+            return Err(ClewdrError::UnsupportedContentBlock {
+                backend,
+                block: content_block_type(block),
+            });
+        }
+        content.retain(|block| {
+            let keep = supports(block);
+            if !keep {
+                warn!(
+                    "Stripped `{}` content block unsupported by {}",
+                    content_block_type(block),
+                    backend
+                );
+            }
+            keep
+        });
+    }
+    Ok(())
+}
+
 fn sanitize_messages(msgs: Vec<Message>) -> Vec<Message> {
     msgs.into_iter()
         .filter_map(|m| {
@@ -248,6 +445,270 @@ fn sanitize_messages(msgs: Vec<Message>) -> Vec<Message> {
         .collect()
 }
 
+fn into_blocks(content: MessageContent) -> Vec<ContentBlock> {
+    match content {
+        MessageContent::Text { content } => vec![ContentBlock::text(content)],
+        MessageContent::Blocks { content } => content,
+    }
+}
+
+/// Merges adjacent user or assistant messages into one, keeping their blocks
+/// in order, for clients that don't alternate roles
+///
+/// System messages are never merged, so nothing is merged across them.
+fn merge_consecutive_roles(msgs: Vec<Message>) -> Vec<Message> {
+    let mut merged: Vec<Message> = Vec::with_capacity(msgs.len());
+    for msg in msgs {
+        match merged.last_mut() {
+            Some(last) if last.role == msg.role && msg.role != Role::System => {
+                let mut blocks = into_blocks(std::mem::replace(
+                    &mut last.content,
+                    MessageContent::Blocks { content: vec![] },
+                ));
+                blocks.extend(into_blocks(msg.content));
+                last.content = MessageContent::Blocks { content: blocks };
+            }
+            _ => merged.push(msg),
+        }
+    }
+    merged
+}
+
+/// Top level fields only a Claude messages request uses
+const CLAUDE_ONLY_FIELDS: &[&str] = &["system", "stop_sequences"];
+
+/// Top level fields only an OpenAI chat completions request uses
+const OAI_ONLY_FIELDS: &[&str] = &[
+    "max_completion_tokens",
+    "stop",
+    "reasoning_effort",
+    "frequency_penalty",
+    "logit_bias",
+    "functions",
+    "parallel_tool_calls",
+];
+
+/// Guesses the API format from the shape of a request body
+///
+/// Both formats share most fields, so only fields exclusive to one of them,
+/// or OpenAI style system messages, are taken as a hint.
+fn sniff_format(value: &Value) -> Option<ClaudeApiFormat> {
+    let has_any = |fields: &[&str]| fields.iter().any(|f| value.get(f).is_some());
+    let oai_system_message = value["messages"].as_array().is_some_and(|messages| {
+        messages
+            .iter()
+            .any(|m| matches!(m["role"].as_str(), Some("system" | "developer")))
+    });
+    match (
+        has_any(CLAUDE_ONLY_FIELDS),
+        has_any(OAI_ONLY_FIELDS) || oai_system_message,
+    ) {
+        (true, false) => Some(ClaudeApiFormat::Claude),
+        (false, true) => Some(ClaudeApiFormat::OpenAI),
+        _ => None,
+    }
+}
+
+fn parse_as(
+    value: &Value,
+    format: ClaudeApiFormat,
+) -> Result<CreateMessageParams, serde_json::Error> {
+    match format {
+        ClaudeApiFormat::Claude => CreateMessageParams::deserialize(value),
+        ClaudeApiFormat::OpenAI => OaiCreateMessageParams::deserialize(value).map(Into::into),
+    }
+}
+
+/// Names a Claude parameter can go by in the given format, defaults are
+/// written under the first one
+fn param_names(name: &str, format: ClaudeApiFormat) -> Vec<&str> {
+    match (format, name) {
+        (ClaudeApiFormat::OpenAI, "max_tokens") => vec!["max_tokens", "max_completion_tokens"],
+        (ClaudeApiFormat::OpenAI, "stop_sequences") => vec!["stop"],
+        _ => vec![name],
+    }
+}
+
+/// Fills in configured parameter defaults the client left out
+///
+/// A parameter counts as provided under any of its names in `format`, so
+/// an OpenAI request with `max_completion_tokens` keeps it even when a
+/// `max_tokens` default is set. Values from the client always win.
+fn apply_default_params(value: &mut Value, defaults: &Map<String, Value>, format: ClaudeApiFormat) {
+    let Some(body) = value.as_object_mut() else {
+        return;
+    };
+    for (name, default) in defaults {
+        let names = param_names(name, format);
+        if names
+            .iter()
+            .all(|n| body.get(*n).is_none_or(Value::is_null))
+        {
+            body.insert(names[0].to_string(), default.clone());
+        }
+    }
+}
+
+/// Parses a request body in the format the endpoint expects
+///
+/// With `detect` enabled, a body that fails to parse as the expected format
+/// is tried as the other one, a body valid for the endpoint is never
+/// rerouted. Errors are always reported for the expected format.
+fn parse_body(
+    value: Value,
+    expected: ClaudeApiFormat,
+    detect: bool,
+) -> Result<(CreateMessageParams, ClaudeApiFormat), ClewdrError> {
+    let other = match expected {
+        ClaudeApiFormat::Claude => ClaudeApiFormat::OpenAI,
+        ClaudeApiFormat::OpenAI => ClaudeApiFormat::Claude,
+    };
+    let order = if detect {
+        vec![expected, other]
+    } else {
+        vec![expected]
+    };
+    for format in order {
+        if let Ok(body) = parse_as(&value, format) {
+            if format != expected {
+                warn!(
+                    "{format} request body sent to a {expected} endpoint, handling it as {format}"
+                );
+            }
+            return Ok((body, format));
+        }
+    }
+    let bytes = serde_json::to_vec(&value)?;
+    let rejection = match expected {
+        ClaudeApiFormat::Claude => Json::<CreateMessageParams>::from_bytes(&bytes).err(),
+        ClaudeApiFormat::OpenAI => Json::<OaiCreateMessageParams>::from_bytes(&bytes).err(),
+    };
+    Err(rejection.map_or(
+        ClewdrError::BadRequest {
+            msg: "Invalid request body",
+        },
+        Into::into,
+    ))
+}
+
+fn invalid(field: impl Into<String>, msg: impl Into<String>) -> ClewdrError {
+    ClewdrError::InvalidRequest {
+        field: field.into(),
+        msg: msg.into(),
+    }
+}
+
+/// Tool use round trips in the history, each user turn handing back tool
+/// results counts as one
+fn tool_rounds(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .filter(|m| {
+            m.role == Role::User
+                && matches!(
+                    &m.content,
+                    MessageContent::Blocks { content }
+                        if content.iter().any(|b| matches!(b, ContentBlock::ToolResult { .. }))
+                )
+        })
+        .count()
+}
+
+/// Rejects conversations past `max` tool use round trips, 0 is unlimited
+fn check_tool_rounds(body: &CreateMessageParams, max: usize) -> Result<(), ClewdrError> {
+    let rounds = tool_rounds(&body.messages);
+    if max == 0 || rounds <= max {
+        return Ok(());
+    }
+    Err(invalid(
+        "messages",
+        format!("{rounds} tool use round trips exceed the limit of {max} per conversation"),
+    ))
+}
+
+/// `name` without `suffix`, ignoring case
+fn strip_suffix_ignore_case<'a>(name: &'a str, suffix: &str) -> Option<&'a str> {
+    let split = name.len().checked_sub(suffix.len())?;
+    name.get(split..)
+        .is_some_and(|end| end.eq_ignore_ascii_case(suffix))
+        .then(|| &name[..split])
+}
+
+/// Model ID a configured alias stands for, matched regardless of case
+///
+/// The `-1M` and `-thinking` suffixes are peeled off to find the alias and
+/// kept after the resolved ID, so `opus-thinking` still enables thinking.
+fn resolve_model_alias(model: &str, aliases: &HashMap<String, String>) -> Option<String> {
+    let mut base = model;
+    loop {
+        if let Some((_, target)) = aliases.iter().find(|(k, _)| k.eq_ignore_ascii_case(base)) {
+            return Some(format!("{target}{}", &model[base.len()..]));
+        }
+        base = ["-thinking", "-1M"]
+            .iter()
+            .find_map(|suffix| strip_suffix_ignore_case(base, suffix))?;
+    }
+}
+
+/// Resolves model aliases and turns a `-thinking` model suffix into
+/// enabled thinking
+fn resolve_model(body: &mut CreateMessageParams, aliases: &HashMap<String, String>) {
+    if let Some(model) = resolve_model_alias(&body.model, aliases) {
+        body.model = model;
+    }
+    if body.model.ends_with("-thinking") {
+        body.model = body.model.trim_end_matches("-thinking").to_string();
+        body.thinking.get_or_insert(Thinking::new(4096));
+    }
+}
+
+/// Checks semantic constraints serde cannot express, so a malformed request
+/// gets a field-specific error instead of an opaque upstream rejection
+fn validate_request(
+    body: &CreateMessageParams,
+    format: ClaudeApiFormat,
+) -> Result<(), ClewdrError> {
+    if body.max_tokens == 0 {
+        return Err(invalid("max_tokens", "must be greater than 0"));
+    }
+    if body.model.trim().is_empty() {
+        return Err(invalid("model", "must not be empty"));
+    }
+    if body.messages.is_empty() {
+        return Err(invalid("messages", "must contain at least one message"));
+    }
+    // OpenAI system messages are moved out of `messages` during conversion
+    if format != ClaudeApiFormat::Claude {
+        return Ok(());
+    }
+    let mut previous = None;
+    for (i, message) in body.messages.iter().enumerate() {
+        match (message.role, previous) {
+            (Role::System, _) => {
+                return Err(invalid(
+                    format!("messages[{i}].role"),
+                    "`system` is not a message role, use the top level `system` field",
+                ));
+            }
+            (Role::Assistant, None) => {
+                return Err(invalid(
+                    "messages[0].role",
+                    "the first message must be from `user`",
+                ));
+            }
+            (role, Some(prev)) if role == prev => {
+                return Err(invalid(
+                    format!("messages[{i}].role"),
+                    "roles must alternate between `user` and `assistant`",
+                ));
+            }
+            _ => {}
+        }
+        previous = Some(message.role);
+    }
+    Ok(())
+}
+
 impl<S> FromRequest<S> for NormalizeRequest
 where
     S: Send + Sync,
@@ -256,28 +717,45 @@ where
 
     async fn from_request(req: Request, _: &S) -> Result<Self, Self::Rejection> {
         let uri = req.uri().to_string();
-        let format = if uri.contains("chat/completions") {
+        // legacy text completions arrive already turned into chat ones
+        let expected = if uri.contains("chat/completions") || uri.contains("v1/completions") {
             ClaudeApiFormat::OpenAI
         } else {
             ClaudeApiFormat::Claude
         };
-        let Json(mut body) = match format {
-            ClaudeApiFormat::OpenAI => {
-                let Json(json) = Json::<OaiCreateMessageParams>::from_request(req, &()).await?;
-                Json(json.into())
-            }
-            ClaudeApiFormat::Claude => Json::<CreateMessageParams>::from_request(req, &()).await?,
-        };
-        if CLEWDR_CONFIG.load().sanitize_messages {
+        check_anthropic_version(req.headers());
+        let Json(mut value) = Json::<Value>::from_request(req, &()).await?;
+        let ignored = ignored_params(&value);
+        if !ignored.is_empty() {
+            warn!("Ignoring unsupported parameters: {}", ignored.join(", "));
+        }
+        let config = CLEWDR_CONFIG.load();
+        if !config.default_params.is_empty() {
+            let likely = config
+                .detect_request_format
+                .then(|| sniff_format(&value))
+                .flatten()
+                .unwrap_or(expected);
+            apply_default_params(&mut value, &config.default_params, likely);
+        }
+        let (mut body, format) = parse_body(value, expected, config.detect_request_format)?;
+        check_tool_rounds(&body, config.max_tool_rounds)?;
+        if config.sanitize_messages {
             // Trim whitespace and drop empty assistant turns when enabled.
             body.messages = sanitize_messages(body.messages);
         }
-        if body.model.ends_with("-thinking") {
-            body.model = body.model.trim_end_matches("-thinking").to_string();
-            body.thinking.get_or_insert(Thinking::new(4096));
+        if config.merge_consecutive_roles {
+            body.messages = merge_consecutive_roles(body.messages);
+        }
+        if config.strict_validation {
+            validate_request(&body, format)?;
         }
+        let requested_model = body.model.to_owned();
+        resolve_model(&mut body, &config.model_aliases);
+        // for the access log span, when there is one
+        Span::current().record("model", body.model.as_str());
         drop_empty_system(&mut body);
-        Ok(Self(body, format))
+        Ok(Self(body, format, requested_model, ignored))
     }
 }
 
@@ -288,10 +766,24 @@ where
     type Rejection = ClewdrError;
 
     async fn from_request(req: Request, _: &S) -> Result<Self, Self::Rejection> {
-        let NormalizeRequest(body, format) = NormalizeRequest::from_request(req, &()).await?;
+        let hide_thinking = hide_thinking(req.headers());
+        let proxy_hops = proxy_hops(req.headers(), CLEWDR_CONFIG.load().max_proxy_hops)?;
+        let client_timeout = client_timeout(req.headers(), &CLEWDR_CONFIG.load());
+        let dry_run = dry_run(&req, CLEWDR_CONFIG.load().allow_dry_run)?;
+        let NormalizeRequest(mut body, format, requested_model, ignored_params) =
+            NormalizeRequest::from_request(req, &()).await?;
+        let proxy = CLEWDR_CONFIG.load().backend_proxy(ProxyBackend::ClaudeWeb);
+        inline_remote_images(&mut body, proxy.as_ref()).await;
+        filter_unsupported_blocks(
+            &mut body,
+            "claude.ai web",
+            web_supports_block,
+            CLEWDR_CONFIG.load().unsupported_block_policy,
+        )?;
 
         // Check for test messages and respond appropriately
         if !body.stream.unwrap_or_default()
+            && !dry_run
             && (body.messages == vec![TEST_MESSAGE_CLAUDE.to_owned()]
                 || body.messages == vec![TEST_MESSAGE_OAI.to_owned()])
         {
@@ -307,6 +799,13 @@ where
             stream,
             api_format: format,
             stop_sequences: body.stop_sequences.to_owned().unwrap_or_default(),
+            stop_case_insensitive: CLEWDR_CONFIG.load().stop_case_insensitive,
+            requested_model,
+            hide_thinking,
+            proxy_hops,
+            client_timeout,
+            dry_run,
+            ignored_params,
             usage: Usage {
                 input_tokens,
                 output_tokens: 0, // Placeholder for output token count
@@ -317,6 +816,25 @@ where
     }
 }
 
+/// Body of a token counting request, in Claude or OpenAI format whatever
+/// the endpoint
+pub struct CountTokensRequest(pub CreateMessageParams);
+
+impl<S> FromRequest<S> for CountTokensRequest
+where
+    S: Send + Sync,
+{
+    type Rejection = ClewdrError;
+
+    async fn from_request(req: Request, _: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<Value>::from_request(req, &()).await?;
+        // the count is the same in either format, so the body's shape decides
+        let likely = sniff_format(&value).unwrap_or(ClaudeApiFormat::Claude);
+        let (body, _) = parse_body(value, likely, true)?;
+        Ok(Self(body))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ClaudeCodeContext {
     /// Whether the response should be streamed
@@ -327,12 +845,31 @@ pub struct ClaudeCodeContext {
     pub(super) system_prompt_hash: Option<u64>,
     /// Optional anthropic-beta header forwarded from client request
     pub(super) anthropic_beta: Option<String>,
+    /// Optional anthropic-version header forwarded from client request
+    pub(super) anthropic_version: Option<String>,
+    /// Model name as sent by the client, before any suffix stripping
+    pub(super) requested_model: String,
+    /// Whether thinking blocks are stripped from the response
+    pub(super) hide_thinking: bool,
+    /// ClewdR instances the request already went through
+    pub(super) proxy_hops: u32,
+    /// Upstream timeout asked for by the client
+    pub(super) client_timeout: Option<Duration>,
+    /// Whether the upstream request body is returned instead of sent
+    pub(super) dry_run: bool,
+    /// Client parameters Claude has no equivalent for, dropped from the request
+    pub(super) ignored_params: Vec<&'static str>,
     // Usage information for the request
     pub(super) usage: Usage,
 }
 
 pub struct ClaudeCodePreprocess(pub CreateMessageParams, pub ClaudeContext);
 
+/// Endpoints that always answer with plain JSON, whatever `stream` says
+fn is_non_streaming_endpoint(path: &str) -> bool {
+    path.ends_with("/count_tokens")
+}
+
 impl<S> FromRequest<S> for ClaudeCodePreprocess
 where
     S: Send + Sync,
@@ -341,7 +878,27 @@ where
 
     async fn from_request(req: Request, _: &S) -> Result<Self, Self::Rejection> {
         let anthropic_beta = extract_anthropic_beta_header(req.headers());
-        let NormalizeRequest(mut body, format) = NormalizeRequest::from_request(req, &()).await?;
+        let anthropic_version = extract_anthropic_version_header(req.headers());
+        let hide_thinking = hide_thinking(req.headers());
+        let proxy_hops = proxy_hops(req.headers(), CLEWDR_CONFIG.load().max_proxy_hops)?;
+        let client_timeout = client_timeout(req.headers(), &CLEWDR_CONFIG.load());
+        let dry_run = dry_run(&req, CLEWDR_CONFIG.load().allow_dry_run)?;
+        let non_streaming = is_non_streaming_endpoint(req.uri().path());
+        let NormalizeRequest(mut body, format, requested_model, ignored_params) =
+            NormalizeRequest::from_request(req, &()).await?;
+        if non_streaming {
+            // some clients send `stream: true` everywhere, the context must
+            // not make response middleware treat the JSON answer as SSE
+            body.stream = Some(false);
+        }
+        let proxy = CLEWDR_CONFIG.load().backend_proxy(ProxyBackend::ClaudeCode);
+        inline_remote_images(&mut body, proxy.as_ref()).await;
+        filter_unsupported_blocks(
+            &mut body,
+            "Claude Code",
+            code_supports_block,
+            CLEWDR_CONFIG.load().unsupported_block_policy,
+        )?;
         // Handle thinking mode by modifying the model name
         if body.temperature.is_some() {
             body.top_p = None; // temperature and top_p cannot be used together in Opus-4.x
@@ -349,6 +906,7 @@ where
 
         // Check for test messages and respond appropriately
         if !body.stream.unwrap_or_default()
+            && !dry_run
             && (body.messages == vec![TEST_MESSAGE_CLAUDE.to_owned()]
                 || body.messages == vec![TEST_MESSAGE_OAI.to_owned()])
         {
@@ -376,22 +934,7 @@ where
             strip_ephemeral_scope_from_system(system);
         }
 
-        let cache_systems = body
-            .system
-            .as_ref()
-            .and_then(Value::as_array)
-            .map(|systems| {
-                systems
-                    .iter()
-                    .filter(|s| s["cache_control"].as_object().is_some())
-                    .collect::<Vec<_>>()
-            })
-            .unwrap_or_default();
-        let system_prompt_hash = (!cache_systems.is_empty()).then(|| {
-            let mut hasher = DefaultHasher::new();
-            cache_systems.hash(&mut hasher);
-            hasher.finish()
-        });
+        let system_prompt_hash = system_prompt_hash(body.system.as_ref());
 
         let input_tokens = body.count_tokens();
 
@@ -400,6 +943,13 @@ where
             api_format: format,
             system_prompt_hash,
             anthropic_beta,
+            anthropic_version,
+            requested_model,
+            hide_thinking,
+            proxy_hops,
+            client_timeout,
+            dry_run,
+            ignored_params,
             usage: Usage {
                 input_tokens,
                 output_tokens: 0, // Placeholder for output token count
@@ -473,4 +1023,522 @@ mod tests {
             .collect::<Vec<_>>();
         assert_eq!(texts, vec!["billing", "custom system", "original system"]);
     }
+
+    #[test]
+    fn string_system_is_wrapped_and_hashed_like_an_array() {
+        let prefixes = || vec![ContentBlock::text("billing")];
+        let mut string_body = CreateMessageParams {
+            system: Some(json!("You are terse.")),
+            ..Default::default()
+        };
+        prepend_system_blocks(&mut string_body, prefixes());
+        assert_eq!(
+            string_body.system,
+            Some(json!([
+                { "type": "text", "text": "billing" },
+                { "type": "text", "text": "You are terse." }
+            ]))
+        );
+        // nothing is marked for caching, so there is no prefix to pin
+        assert_eq!(system_prompt_hash(string_body.system.as_ref()), None);
+
+        let cached = |text: &str| {
+            let mut body = CreateMessageParams {
+                system: Some(json!([{
+                    "type": "text",
+                    "text": text,
+                    "cache_control": { "type": "ephemeral" }
+                }])),
+                ..Default::default()
+            };
+            prepend_system_blocks(&mut body, prefixes());
+            system_prompt_hash(body.system.as_ref())
+        };
+        assert!(cached("You are terse.").is_some());
+        assert_eq!(cached("You are terse."), cached("You are terse."));
+        assert_ne!(cached("You are terse."), cached("You are verbose."));
+    }
+
+    fn document_message() -> Message {
+        Message::new_blocks(
+            Role::User,
+            vec![
+                ContentBlock::text("summarize this"),
+                ContentBlock::Document {
+                    source: json!({
+                        "type": "text",
+                        "media_type": "text/plain",
+                        "data": "hello"
+                    }),
+                    cache_control: None,
+                    citations: None,
+                    context: None,
+                    title: None,
+                },
+            ],
+        )
+    }
+
+    #[test]
+    fn strips_document_block_for_web_backend() {
+        let mut body = CreateMessageParams {
+            messages: vec![document_message()],
+            ..Default::default()
+        };
+
+        filter_unsupported_blocks(
+            &mut body,
+            "claude.ai web",
+            web_supports_block,
+            UnsupportedBlockPolicy::Strip,
+        )
+        .unwrap();
+
+        assert_eq!(
+            body.messages,
+            vec![Message::new_blocks(
+                Role::User,
+                vec![ContentBlock::text("summarize this")]
+            )]
+        );
+    }
+
+    #[test]
+    fn rejects_document_block_for_web_backend() {
+        let mut body = CreateMessageParams {
+            messages: vec![document_message()],
+            ..Default::default()
+        };
+
+        let err = filter_unsupported_blocks(
+            &mut body,
+            "claude.ai web",
+            web_supports_block,
+            UnsupportedBlockPolicy::Reject,
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            err,
+            ClewdrError::UnsupportedContentBlock { ref block, .. } if block == "document"
+        ));
+        assert_eq!(body.messages, vec![document_message()]);
+    }
+
+    #[test]
+    fn keeps_document_block_for_code_backend() {
+        let mut body = CreateMessageParams {
+            messages: vec![document_message()],
+            ..Default::default()
+        };
+
+        filter_unsupported_blocks(
+            &mut body,
+            "Claude Code",
+            code_supports_block,
+            UnsupportedBlockPolicy::Reject,
+        )
+        .unwrap();
+
+        assert_eq!(body.messages, vec![document_message()]);
+    }
+
+    #[test]
+    fn bodies_valid_for_the_endpoint_are_not_rerouted() {
+        // OpenAI shaped, but still a valid Claude body
+        let oai_like = json!({
+            "model": "claude-sonnet-4-5",
+            "max_completion_tokens": 512,
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "Hi" }
+            ]
+        });
+        let (_, format) = parse_body(oai_like, ClaudeApiFormat::Claude, true).unwrap();
+        assert_eq!(format, ClaudeApiFormat::Claude);
+
+        // Claude shaped, but still a valid OpenAI body
+        let claude_like = json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 256,
+            "system": "Be brief.",
+            "stop_sequences": ["END"],
+            "messages": [{ "role": "user", "content": "Hi" }]
+        });
+        let (params, format) = parse_body(claude_like, ClaudeApiFormat::OpenAI, true).unwrap();
+        assert_eq!(format, ClaudeApiFormat::OpenAI);
+        assert_eq!(params.system, None);
+    }
+
+    #[test]
+    fn default_params_fill_only_missing_fields() {
+        let defaults = json!({ "temperature": 0.7, "max_tokens": 2048 });
+        let defaults = defaults.as_object().unwrap();
+        let mut missing = json!({ "model": "claude-sonnet-4-5", "messages": [] });
+        apply_default_params(&mut missing, defaults, ClaudeApiFormat::Claude);
+        assert_eq!(missing["temperature"], 0.7);
+        assert_eq!(missing["max_tokens"], 2048);
+
+        let mut provided = json!({ "temperature": 0.2, "max_tokens": 64 });
+        apply_default_params(&mut provided, defaults, ClaudeApiFormat::Claude);
+        assert_eq!(provided["temperature"], 0.2);
+        assert_eq!(provided["max_tokens"], 64);
+
+        // OpenAI's newer token limit counts as the same parameter
+        let mut oai = json!({ "max_completion_tokens": 64 });
+        apply_default_params(&mut oai, defaults, ClaudeApiFormat::OpenAI);
+        assert_eq!(oai.get("max_tokens"), None);
+        assert_eq!(oai["temperature"], 0.7);
+    }
+
+    #[test]
+    fn unparseable_body_falls_back_to_other_format() {
+        // OpenAI clients may send an explicit null, which Claude's format rejects
+        let body = json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": null,
+            "messages": [{ "role": "user", "content": "Hi" }]
+        });
+        let (params, format) = parse_body(body.clone(), ClaudeApiFormat::Claude, true).unwrap();
+        assert_eq!(format, ClaudeApiFormat::OpenAI);
+        assert_eq!(params.messages.len(), 1);
+        // without detection, the Claude endpoint rejects it
+        assert!(parse_body(body, ClaudeApiFormat::Claude, false).is_err());
+
+        let invalid = json!({ "model": "claude-sonnet-4-5" });
+        assert!(matches!(
+            parse_body(invalid, ClaudeApiFormat::Claude, true),
+            Err(ClewdrError::JsonRejection { .. })
+        ));
+    }
+
+    fn validate(body: Value, format: ClaudeApiFormat) -> Result<(), String> {
+        let (params, format) = parse_body(body, format, false).unwrap();
+        validate_request(&params, format).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn strict_validation_accepts_well_formed_requests() {
+        let claude = json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 256,
+            "messages": [
+                { "role": "user", "content": "Hi" },
+                { "role": "assistant", "content": "Hello" },
+                { "role": "user", "content": "How are you?" }
+            ]
+        });
+        assert_eq!(validate(claude, ClaudeApiFormat::Claude), Ok(()));
+
+        let oai = json!({
+            "model": "claude-sonnet-4-5",
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "Hi" },
+                { "role": "user", "content": "Still there?" }
+            ]
+        });
+        assert_eq!(validate(oai, ClaudeApiFormat::OpenAI), Ok(()));
+    }
+
+    #[test]
+    fn strict_validation_reports_offending_field() {
+        let cases = [
+            (
+                json!({ "model": "m", "max_tokens": 0, "messages": [{ "role": "user", "content": "Hi" }] }),
+                "Invalid `max_tokens`: must be greater than 0",
+            ),
+            (
+                json!({ "model": "m", "max_tokens": 16, "messages": [] }),
+                "Invalid `messages`: must contain at least one message",
+            ),
+            (
+                json!({ "model": " ", "max_tokens": 16, "messages": [{ "role": "user", "content": "Hi" }] }),
+                "Invalid `model`: must not be empty",
+            ),
+            (
+                json!({ "model": "m", "max_tokens": 16, "messages": [{ "role": "assistant", "content": "Hi" }] }),
+                "Invalid `messages[0].role`: the first message must be from `user`",
+            ),
+            (
+                json!({ "model": "m", "max_tokens": 16, "messages": [
+                    { "role": "user", "content": "Hi" },
+                    { "role": "assistant", "content": "Hello" },
+                    { "role": "assistant", "content": "Anyone?" }
+                ] }),
+                "Invalid `messages[2].role`: roles must alternate between `user` and `assistant`",
+            ),
+            (
+                json!({ "model": "m", "max_tokens": 16, "messages": [
+                    { "role": "user", "content": "Hi" },
+                    { "role": "system", "content": "Be brief." }
+                ] }),
+                "Invalid `messages[1].role`: `system` is not a message role, use the top level `system` field",
+            ),
+        ];
+        for (body, expected) in cases {
+            assert_eq!(
+                validate(body, ClaudeApiFormat::Claude),
+                Err(expected.into())
+            );
+        }
+
+        let oai = json!({ "model": "m", "max_completion_tokens": 0, "messages": [{ "role": "user", "content": "Hi" }] });
+        assert_eq!(
+            validate(oai, ClaudeApiFormat::OpenAI),
+            Err("Invalid `max_tokens`: must be greater than 0".into())
+        );
+    }
+
+    #[test]
+    fn deep_proxy_chains_are_rejected() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(proxy_hops(&headers, 3).unwrap(), 0);
+        headers.insert(PROXY_HOPS_HEADER, "3".parse().unwrap());
+        assert_eq!(proxy_hops(&headers, 3).unwrap(), 3);
+        headers.insert(PROXY_HOPS_HEADER, "40".parse().unwrap());
+        let err = proxy_hops(&headers, 3).unwrap_err();
+        assert!(matches!(err, ClewdrError::ProxyLoop { hops: 40, max: 3 }));
+        assert_eq!(
+            axum::response::IntoResponse::into_response(err).status(),
+            508
+        );
+    }
+
+    #[test]
+    fn client_timeout_header_shortens_upstream_timeout() {
+        let mut config = ClewdrConfig::default();
+        let mut headers = HeaderMap::new();
+        assert_eq!(client_timeout(&headers, &config), None);
+        assert_eq!(
+            config.upstream_duration(false, None),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(
+            config.upstream_duration(true, None),
+            config.stream_idle_duration()
+        );
+
+        headers.insert("x-timeout", "30".parse().unwrap());
+        let client = client_timeout(&headers, &config);
+        assert_eq!(client, Some(Duration::from_secs(30)));
+        assert_eq!(config.upstream_duration(false, client), client);
+        assert_eq!(config.upstream_duration(true, client), client);
+
+        headers.insert("x-timeout", "0.5".parse().unwrap());
+        assert_eq!(
+            client_timeout(&headers, &config),
+            Some(Duration::from_secs(5))
+        );
+        headers.insert("x-timeout", "86400".parse().unwrap());
+        let client = client_timeout(&headers, &config);
+        assert_eq!(client, Some(Duration::from_secs(600)));
+        config.non_stream_timeout = 120;
+        assert_eq!(
+            config.upstream_duration(false, client),
+            Some(Duration::from_secs(120))
+        );
+
+        config.client_timeout_max = 0;
+        assert_eq!(client_timeout(&headers, &config), None);
+    }
+
+    #[test]
+    fn tool_rounds_are_capped() {
+        let round = |id: &str| {
+            [
+                json!({ "role": "assistant", "content": [
+                    { "type": "tool_use", "id": id, "name": "search", "input": {} }
+                ] }),
+                json!({ "role": "user", "content": [
+                    { "type": "tool_result", "tool_use_id": id, "content": "done" }
+                ] }),
+            ]
+        };
+        let mut messages = vec![json!({ "role": "user", "content": "Look it up" })];
+        messages.extend(round("a"));
+        messages.extend(round("b"));
+        let body = json!({ "model": "m", "max_tokens": 16, "messages": messages });
+        let (body, _) = parse_body(body, ClaudeApiFormat::Claude, false).unwrap();
+
+        assert_eq!(tool_rounds(&body.messages), 2);
+        assert!(check_tool_rounds(&body, 0).is_ok());
+        assert!(check_tool_rounds(&body, 2).is_ok());
+        assert_eq!(
+            check_tool_rounds(&body, 1).unwrap_err().to_string(),
+            "Invalid `messages`: 2 tool use round trips exceed the limit of 1 per conversation"
+        );
+    }
+
+    #[test]
+    fn consecutive_user_messages_are_merged() {
+        let image = ContentBlock::Image {
+            source: crate::types::claude::ImageSource::Url {
+                url: "https://example.com/a.png".to_string(),
+            },
+            cache_control: None,
+        };
+        let messages = vec![
+            Message::new_text(Role::User, "first"),
+            Message::new_blocks(
+                Role::User,
+                vec![image.to_owned(), ContentBlock::text("second")],
+            ),
+            Message::new_text(Role::Assistant, "reply"),
+            Message::new_text(Role::User, "third"),
+        ];
+
+        let merged = merge_consecutive_roles(messages);
+        assert_eq!(merged.len(), 3);
+        assert_eq!(
+            merged[0],
+            Message::new_blocks(
+                Role::User,
+                vec![
+                    ContentBlock::text("first"),
+                    image,
+                    ContentBlock::text("second")
+                ]
+            )
+        );
+        assert_eq!(merged[1], Message::new_text(Role::Assistant, "reply"));
+        assert_eq!(merged[2], Message::new_text(Role::User, "third"));
+    }
+
+    #[test]
+    fn messages_are_not_merged_across_system() {
+        let messages = vec![
+            Message::new_text(Role::User, "a"),
+            Message::new_text(Role::System, "s1"),
+            Message::new_text(Role::System, "s2"),
+            Message::new_text(Role::User, "b"),
+        ];
+        assert_eq!(merge_consecutive_roles(messages.to_owned()), messages);
+    }
+
+    #[test]
+    fn sanitizing_keeps_tool_use_beside_empty_text() {
+        let tool_use = ContentBlock::ToolUse {
+            id: "toolu_1".to_string(),
+            name: "search".to_string(),
+            input: serde_json::json!({ "query": "rust" }),
+            cache_control: None,
+            caller: None,
+        };
+        let messages = vec![
+            Message::new_text(Role::User, "find it"),
+            Message::new_blocks(
+                Role::Assistant,
+                vec![ContentBlock::text("  "), tool_use.to_owned()],
+            ),
+            Message::new_blocks(Role::Assistant, vec![ContentBlock::text("")]),
+        ];
+
+        let sanitized = sanitize_messages(messages);
+        assert_eq!(sanitized.len(), 2);
+        assert_eq!(
+            sanitized[1],
+            Message::new_blocks(Role::Assistant, vec![tool_use])
+        );
+    }
+
+    #[test]
+    fn count_tokens_ignores_stream_flag() {
+        assert!(is_non_streaming_endpoint("/code/v1/messages/count_tokens"));
+        assert!(!is_non_streaming_endpoint("/code/v1/messages"));
+        assert!(!is_non_streaming_endpoint("/code/v1/chat/completions"));
+    }
+
+    #[test]
+    fn model_aliases_resolve_before_suffixes() {
+        let aliases = HashMap::from([
+            ("opus".to_string(), "claude-opus-4-1-20250805".to_string()),
+            ("Sonnet".to_string(), "claude-sonnet-4-6".to_string()),
+        ]);
+        let resolve = |model: &str| {
+            let mut body = CreateMessageParams {
+                model: model.to_string(),
+                ..Default::default()
+            };
+            resolve_model(&mut body, &aliases);
+            (body.model, body.thinking.is_some())
+        };
+        assert_eq!(
+            resolve("OPUS"),
+            ("claude-opus-4-1-20250805".to_string(), false)
+        );
+        assert_eq!(
+            resolve("opus-thinking"),
+            ("claude-opus-4-1-20250805".to_string(), true)
+        );
+        assert_eq!(
+            resolve("sonnet-1M-thinking"),
+            ("claude-sonnet-4-6-1M".to_string(), true)
+        );
+        assert_eq!(
+            resolve("claude-opus-4-6-thinking"),
+            ("claude-opus-4-6".to_string(), true)
+        );
+        assert_eq!(resolve("opusx"), ("opusx".to_string(), false));
+    }
+
+    #[test]
+    fn dry_run_is_asked_for_by_header_or_query() {
+        let req = |uri: &str, header: Option<&str>| {
+            let mut req = Request::builder().uri(uri);
+            if let Some(value) = header {
+                req = req.header(DRY_RUN_HEADER, value);
+            }
+            req.body(axum::body::Body::empty()).unwrap()
+        };
+        assert!(dry_run(&req("/v1/messages", Some("true")), true).unwrap());
+        assert!(dry_run(&req("/v1/messages?beta=true&dry_run=1", None), true).unwrap());
+        assert!(!dry_run(&req("/v1/messages?dry_run=0", Some("off")), true).unwrap());
+        assert!(!dry_run(&req("/v1/messages", None), false).unwrap());
+        assert!(matches!(
+            dry_run(&req("/v1/messages?dry_run=1", None), false),
+            Err(ClewdrError::BadRequest { .. })
+        ));
+    }
+
+    #[test]
+    fn seed_and_logit_bias_are_reported_as_ignored() {
+        let body = json!({"model": "m", "messages": [], "seed": 42, "logit_bias": null});
+        assert_eq!(ignored_params(&body), vec!["seed"]);
+        let body = json!({"model": "m", "messages": [], "logit_bias": {"50256": -100}});
+        assert_eq!(ignored_params(&body), vec!["logit_bias"]);
+        assert!(ignored_params(&json!({"model": "m", "messages": []})).is_empty());
+    }
+
+    #[tokio::test]
+    async fn token_count_is_stable_across_formats() {
+        let count = async |body: Value| {
+            let req = Request::builder()
+                .method("POST")
+                .uri("/v1/messages/count_tokens")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap();
+            let CountTokensRequest(body) =
+                CountTokensRequest::from_request(req, &()).await.unwrap();
+            body.count_tokens()
+        };
+        let claude = count(json!({
+            "model": "claude-sonnet-4-5",
+            "max_tokens": 16,
+            "system": "You are helpful.",
+            "messages": [{"role": "user", "content": "Hello, world!"}],
+        }))
+        .await;
+        let openai = count(json!({
+            "model": "claude-sonnet-4-5",
+            "messages": [
+                {"role": "system", "content": "You are helpful."},
+                {"role": "user", "content": "Hello, world!"},
+            ],
+        }))
+        .await;
+        assert_eq!(claude, 8);
+        assert_eq!(openai, claude);
+    }
 }
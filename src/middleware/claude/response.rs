@@ -5,11 +5,12 @@ use axum::{
 };
 use eventsource_stream::Eventsource;
 use futures::TryStreamExt;
-use http::header::CONTENT_TYPE;
+use http::{HeaderValue, header::CONTENT_TYPE};
 use tracing::warn;
 
 use super::{ClaudeApiFormat, transform_stream};
 use crate::{
+    config::CLEWDR_CONFIG,
     middleware::claude::{ClaudeContext, transforms_json},
     types::claude::{CreateMessageResponse, StreamEvent},
 };
@@ -52,29 +53,36 @@ where
 ///
 /// The original or transformed response as appropriate
 pub async fn to_oai(resp: Response) -> impl IntoResponse {
-    let Some(cx) = resp.extensions().get::<ClaudeContext>() else {
+    let Some(cx) = resp.extensions().get::<ClaudeContext>().cloned() else {
         return resp;
     };
     if ClaudeApiFormat::Claude == cx.api_format() {
         return resp;
     }
-    if !cx.is_stream() {
+    let mut resp = if !cx.is_stream() {
         match parse_response::<CreateMessageResponse>(resp).await {
-            Ok(response) => return Json(transforms_json(response)).into_response(),
+            Ok(response) => Json(transforms_json(response)).into_response(),
             Err(resp) => return resp,
         }
-    }
-    let stream = resp.into_body().into_data_stream().eventsource();
-    let stream = transform_stream(stream);
-    Sse::new(stream)
-        .keep_alive(Default::default())
-        .into_response()
+    } else {
+        let stream = resp.into_body().into_data_stream().eventsource();
+        let stream = transform_stream(stream);
+        Sse::new(stream)
+            .keep_alive(Default::default())
+            .into_response()
+    };
+    resp.extensions_mut().insert(cx);
+    resp
 }
 
 pub async fn add_usage_info(resp: Response) -> impl IntoResponse {
-    let Some(cx) = resp.extensions().get::<ClaudeContext>() else {
+    let Some(cx) = resp.extensions().get::<ClaudeContext>().cloned() else {
         return resp;
     };
+    // already converted by `to_oai` when an OpenAI body hit a Claude endpoint
+    if ClaudeApiFormat::OpenAI == cx.api_format() {
+        return resp;
+    }
     let (mut usage, stream) = (cx.usage().to_owned(), cx.is_stream());
     if !stream {
         let mut response = match parse_response::<CreateMessageResponse>(resp).await {
@@ -84,7 +92,9 @@ pub async fn add_usage_info(resp: Response) -> impl IntoResponse {
         let output_tokens = response.count_tokens();
         usage.output_tokens = output_tokens;
         response.usage = Some(usage);
-        return Json(response).into_response();
+        let mut resp = Json(response).into_response();
+        resp.extensions_mut().insert(cx);
+        return resp;
     }
     let stream = resp
         .into_body()
@@ -122,9 +132,87 @@ pub async fn add_usage_info(resp: Response) -> impl IntoResponse {
             }
         });
 
-    Sse::new(stream)
+    let mut resp = Sse::new(stream)
         .keep_alive(Default::default())
-        .into_response()
+        .into_response();
+    resp.extensions_mut().insert(cx);
+    resp
+}
+
+/// Response header listing the request parameters that were dropped
+pub const IGNORED_PARAMS_HEADER: &str = "x-clewdr-ignored-params";
+
+/// Tells the client which of its parameters Claude could not honour, such as
+/// `seed`, instead of dropping them silently
+pub async fn warn_ignored_params(mut resp: Response) -> Response {
+    let Some(cx) = resp.extensions().get::<ClaudeContext>() else {
+        return resp;
+    };
+    if cx.ignored_params().is_empty() {
+        return resp;
+    }
+    if let Ok(value) = HeaderValue::from_str(&cx.ignored_params().join(", ")) {
+        resp.headers_mut().insert(IGNORED_PARAMS_HEADER, value);
+    }
+    resp
+}
+
+/// Rewrites the `model` field of responses back to the model name the client
+/// requested, when `echo_requested_model` is enabled
+///
+/// Upstream reports the model it actually served, which differs from the
+/// request once suffixes like `-thinking` have been stripped.
+pub async fn restore_requested_model(resp: Response) -> Response {
+    let echo = CLEWDR_CONFIG.load().echo_requested_model;
+    rewrite_response_model(resp, echo).await
+}
+
+async fn rewrite_response_model(resp: Response, echo: bool) -> Response {
+    if !echo || !resp.status().is_success() {
+        return resp;
+    }
+    let Some(cx) = resp.extensions().get::<ClaudeContext>().cloned() else {
+        return resp;
+    };
+    let model = cx.requested_model().to_owned();
+    let mut resp = if !cx.is_stream() {
+        match parse_response::<CreateMessageResponse>(resp).await {
+            Ok(mut response) => {
+                response.model = model;
+                Json(response).into_response()
+            }
+            Err(resp) => resp,
+        }
+    } else {
+        let stream = resp
+            .into_body()
+            .into_data_stream()
+            .eventsource()
+            .map_ok(move |event| {
+                let new_event = axum::response::sse::Event::default()
+                    .event(event.event)
+                    .id(event.id);
+                let new_event = if let Some(retry) = event.retry {
+                    new_event.retry(retry)
+                } else {
+                    new_event
+                };
+                match serde_json::from_str::<StreamEvent>(&event.data) {
+                    Ok(StreamEvent::MessageStart { mut message }) => {
+                        message.model = model.to_owned();
+                        new_event
+                            .json_data(StreamEvent::MessageStart { message })
+                            .unwrap()
+                    }
+                    _ => new_event.data(event.data),
+                }
+            });
+        Sse::new(stream)
+            .keep_alive(Default::default())
+            .into_response()
+    };
+    resp.extensions_mut().insert(cx);
+    resp
 }
 
 pub async fn check_overloaded(mut resp: Response) -> Response {
@@ -144,3 +232,113 @@ pub async fn check_overloaded(mut resp: Response) -> Response {
     }
     resp
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::{
+        middleware::claude::{ClaudeApiFormat, ClaudeWebContext},
+        types::claude::Usage,
+    };
+
+    fn thinking_context(stream: bool) -> ClaudeContext {
+        ClaudeContext::Web(ClaudeWebContext {
+            stream,
+            api_format: ClaudeApiFormat::Claude,
+            stop_sequences: vec![],
+            stop_case_insensitive: false,
+            requested_model: "claude-sonnet-4-5-thinking".to_string(),
+            hide_thinking: false,
+            proxy_hops: 0,
+            client_timeout: None,
+            dry_run: false,
+            ignored_params: vec![],
+            usage: Usage::default(),
+        })
+    }
+
+    fn with_context(body: Body, stream: bool) -> Response {
+        let mut resp = Response::new(body);
+        resp.extensions_mut().insert(thinking_context(stream));
+        resp
+    }
+
+    fn message_json() -> Value {
+        json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-5",
+            "content": [{ "type": "text", "text": "Hi" }],
+            "stop_reason": "end_turn",
+            "stop_sequence": null,
+            "usage": null
+        })
+    }
+
+    async fn body_text(resp: Response) -> String {
+        let bytes = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn non_stream_model_follows_config() {
+        let passthrough = rewrite_response_model(
+            with_context(Body::from(message_json().to_string()), false),
+            false,
+        )
+        .await;
+        let body: Value = serde_json::from_str(&body_text(passthrough).await).unwrap();
+        assert_eq!(body["model"], "claude-sonnet-4-5");
+
+        let echoed = rewrite_response_model(
+            with_context(Body::from(message_json().to_string()), false),
+            true,
+        )
+        .await;
+        assert!(echoed.extensions().get::<ClaudeContext>().is_some());
+        let body: Value = serde_json::from_str(&body_text(echoed).await).unwrap();
+        assert_eq!(body["model"], "claude-sonnet-4-5-thinking");
+    }
+
+    #[tokio::test]
+    async fn stream_message_start_model_follows_config() {
+        let mut message = message_json();
+        message["content"] = json!([]);
+        let sse = format!(
+            "event: message_start\ndata: {}\n\n",
+            json!({ "type": "message_start", "message": message })
+        );
+
+        let passthrough =
+            rewrite_response_model(with_context(Body::from(sse.clone()), true), false).await;
+        let text = body_text(passthrough).await;
+        assert!(text.contains(r#""model":"claude-sonnet-4-5""#));
+
+        let echoed = rewrite_response_model(with_context(Body::from(sse), true), true).await;
+        let text = body_text(echoed).await;
+        assert!(text.contains(r#""model":"claude-sonnet-4-5-thinking""#));
+        assert!(!text.contains(r#""model":"claude-sonnet-4-5""#));
+    }
+
+    #[tokio::test]
+    async fn dropped_params_are_reported_in_a_header() {
+        let resp = with_context(Body::from(message_json().to_string()), false);
+        let resp = warn_ignored_params(resp).await;
+        assert!(resp.headers().get(IGNORED_PARAMS_HEADER).is_none());
+
+        let ClaudeContext::Web(mut cx) = thinking_context(false) else {
+            unreachable!()
+        };
+        cx.api_format = ClaudeApiFormat::OpenAI;
+        cx.ignored_params = vec!["seed", "logit_bias"];
+        let mut resp = Response::new(Body::from(message_json().to_string()));
+        resp.extensions_mut().insert(ClaudeContext::Web(cx));
+        // the OpenAI conversion rebuilds the response, the context outlives it
+        let resp = to_oai(resp).await.into_response();
+        let resp = warn_ignored_params(resp).await;
+        assert_eq!(resp.headers()[IGNORED_PARAMS_HEADER], "seed, logit_bias");
+    }
+}
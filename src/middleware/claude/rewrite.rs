@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+
+use async_stream::try_stream;
+use axum::{
+    Json,
+    body::{self, Body},
+    response::{IntoResponse, Response, Sse, sse::Event},
+};
+use eventsource_stream::{Event as SourceEvent, Eventsource};
+use futures::Stream;
+use http::header::CONTENT_TYPE;
+use regex_automata::hybrid::dfa::Cache;
+use tracing::warn;
+
+use super::stop_sequences::text_delta_event;
+use crate::{
+    config::{CLEWDR_CONFIG, RewriteRule},
+    middleware::claude::ClaudeContext,
+    types::claude::{ContentBlock, ContentBlockDelta, CreateMessageResponse, StreamEvent},
+};
+
+type EventResult<T> = Result<T, eventsource_stream::EventStreamError<axum::Error>>;
+
+/// Held back text is released anyway once it grows past this many bytes,
+/// so a pattern like `(?s).*` cannot stall a stream until the block ends
+const MAX_HELD_BYTES: usize = 4096;
+
+/// One rule applied incrementally to streamed text
+struct RewriteStage {
+    rule: RewriteRule,
+    cache: Cache,
+    /// Last released character, kept as look-behind context, followed by
+    /// text not yet released
+    buffer: String,
+    /// Start of the unreleased text in `buffer`
+    pending: usize,
+}
+
+impl RewriteStage {
+    fn new(rule: RewriteRule) -> Self {
+        let cache = rule.create_cache();
+        Self {
+            rule,
+            cache,
+            buffer: String::new(),
+            pending: 0,
+        }
+    }
+
+    fn push(&mut self, text: &str) -> String {
+        self.buffer.push_str(text);
+        let hold = self.buffer[self.pending..]
+            .char_indices()
+            .map(|(i, _)| self.pending + i)
+            .find(|&start| self.rule.is_live(&mut self.cache, &self.buffer, start))
+            .unwrap_or(self.buffer.len());
+        let hold = if self.buffer.len() - hold > MAX_HELD_BYTES {
+            self.buffer.len()
+        } else {
+            hold
+        };
+        self.release(hold)
+    }
+
+    fn flush(&mut self) -> String {
+        let text = self.release(self.buffer.len());
+        self.buffer.clear();
+        self.pending = 0;
+        text
+    }
+
+    /// Rewrites and releases the unreleased text up to `hold`, or past it
+    /// when a match starting before `hold` extends further
+    fn release(&mut self, hold: usize) -> String {
+        let mut out = String::new();
+        let last = self
+            .rule
+            .rewrite(&self.buffer, self.pending, hold, &mut out);
+        let end = hold.max(last);
+        out.push_str(&self.buffer[last..end]);
+        let context = self.buffer[..end]
+            .char_indices()
+            .next_back()
+            .map_or(end, |(i, _)| i);
+        self.buffer.drain(..context);
+        self.pending = end - context;
+        out
+    }
+}
+
+/// Incremental response rewriter, each rule's output feeds the next rule
+///
+/// Like stop sequences, text that may still be part of a match is held back
+/// until the match either completes or breaks, so matches spanning chunk
+/// boundaries are rewritten as if the text arrived in one piece.
+pub struct ResponseRewriter {
+    stages: Vec<RewriteStage>,
+}
+
+impl ResponseRewriter {
+    pub fn new(rules: &[RewriteRule]) -> Self {
+        Self {
+            stages: rules.iter().cloned().map(RewriteStage::new).collect(),
+        }
+    }
+
+    /// Feeds a chunk of streamed text, returning what can be released
+    pub fn push(&mut self, text: &str) -> String {
+        self.stages
+            .iter_mut()
+            .fold(text.to_owned(), |text, stage| stage.push(&text))
+    }
+
+    /// Releases any held back text, e.g. at the end of a content block
+    pub fn flush(&mut self) -> String {
+        self.stages.iter_mut().fold(String::new(), |text, stage| {
+            let mut out = stage.push(&text);
+            out.push_str(&stage.flush());
+            out
+        })
+    }
+}
+
+fn rewrite_stream(
+    rules: Vec<RewriteRule>,
+    stream: impl Stream<Item = EventResult<SourceEvent>>,
+) -> impl Stream<Item = EventResult<Event>> {
+    try_stream!({
+        let mut rewriters = HashMap::<usize, ResponseRewriter>::new();
+        for await event in stream {
+            let SourceEvent {
+                data,
+                id,
+                event,
+                retry,
+            } = event?;
+            let parsed = serde_json::from_str::<StreamEvent>(&data);
+            let out = Event::default().event(&event).id(id).data(&data);
+            let out = if let Some(retry) = retry {
+                out.retry(retry)
+            } else {
+                out
+            };
+            match parsed {
+                Ok(StreamEvent::ContentBlockDelta {
+                    delta: ContentBlockDelta::TextDelta { text },
+                    index,
+                }) => {
+                    let text = rewriters
+                        .entry(index)
+                        .or_insert_with(|| ResponseRewriter::new(&rules))
+                        .push(&text);
+                    if !text.is_empty() {
+                        yield text_delta_event(&event, index, text);
+                    }
+                }
+                Ok(StreamEvent::ContentBlockStop { index }) => {
+                    if let Some(mut rewriter) = rewriters.remove(&index) {
+                        let held = rewriter.flush();
+                        if !held.is_empty() {
+                            yield text_delta_event("content_block_delta", index, held);
+                        }
+                    }
+                    yield out;
+                }
+                Ok(StreamEvent::MessageDelta { .. } | StreamEvent::MessageStop) => {
+                    for (index, mut rewriter) in rewriters.drain() {
+                        let held = rewriter.flush();
+                        if !held.is_empty() {
+                            yield text_delta_event("content_block_delta", index, held);
+                        }
+                    }
+                    yield out;
+                }
+                _ => yield out,
+            }
+        }
+        for (index, mut rewriter) in rewriters.drain() {
+            let held = rewriter.flush();
+            if !held.is_empty() {
+                yield text_delta_event("content_block_delta", index, held);
+            }
+        }
+    })
+}
+
+/// Applies the configured response rewrites to text output
+///
+/// Only successful responses are touched. Streamed text deltas are rewritten
+/// incrementally per content block, non-streaming text blocks in one go.
+pub async fn apply_response_rewrites(resp: Response) -> Response {
+    let rules = CLEWDR_CONFIG.load().rewrite_rules.to_owned();
+    if rules.is_empty() || !resp.status().is_success() {
+        return resp;
+    }
+    let Some(cx) = resp.extensions().get::<ClaudeContext>().cloned() else {
+        return resp;
+    };
+
+    let mut resp = if cx.is_stream() {
+        let stream = resp.into_body().into_data_stream().eventsource();
+        Sse::new(rewrite_stream(rules, stream))
+            .keep_alive(Default::default())
+            .into_response()
+    } else {
+        let bytes = body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .inspect_err(|err| {
+                warn!("Failed to read response body: {}", err);
+            })
+            .unwrap_or_default();
+        match serde_json::from_slice::<CreateMessageResponse>(&bytes) {
+            Ok(mut response) => {
+                for block in response.content.iter_mut() {
+                    if let ContentBlock::Text { text, .. } = block {
+                        *text = rules.iter().fold(text.to_owned(), |t, r| r.apply(&t));
+                    }
+                }
+                Json(response).into_response()
+            }
+            Err(_) => Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(bytes))
+                .unwrap(),
+        }
+    };
+    resp.extensions_mut().insert(cx);
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewriter(rules: &[(&str, &str)]) -> ResponseRewriter {
+        let rules = rules
+            .iter()
+            .map(|(p, r)| RewriteRule::new(p, r).unwrap())
+            .collect::<Vec<_>>();
+        ResponseRewriter::new(&rules)
+    }
+
+    fn run(rewriter: &mut ResponseRewriter, chunks: &[&str]) -> String {
+        let mut out = chunks.iter().map(|c| rewriter.push(c)).collect::<String>();
+        out.push_str(&rewriter.flush());
+        out
+    }
+
+    #[test]
+    fn simple_replace() {
+        let rule = RewriteRule::new(r"colou?r", "hue").unwrap();
+        assert_eq!(rule.apply("color and colour"), "hue and hue");
+
+        let mut r = rewriter(&[(r"colou?r", "hue")]);
+        assert_eq!(r.push("the color "), "the hue ");
+        assert_eq!(r.flush(), "");
+    }
+
+    #[test]
+    fn match_spanning_chunks_is_rewritten() {
+        let mut r = rewriter(&[(r"As an AI( language model)?, ", "")]);
+        assert_eq!(r.push("Sure. As an A"), "Sure. ");
+        assert_eq!(r.push("I language"), "");
+        assert_eq!(r.push(" model, I can help."), "I can help.");
+
+        let mut r = rewriter(&[(r"(\d+) apples", "$1 pears")]);
+        assert_eq!(
+            run(&mut r, &["I have 1", "2 app", "les."]),
+            "I have 12 pears."
+        );
+    }
+
+    #[test]
+    fn broken_match_is_released_unchanged() {
+        let mut r = rewriter(&[("DISCLAIMER", "")]);
+        assert_eq!(r.push("DISCLA"), "");
+        assert_eq!(r.push("IMS apply"), "DISCLAIMS apply");
+    }
+
+    #[test]
+    fn rules_are_chained_in_order() {
+        let mut r = rewriter(&[("foo", "bar"), ("bar", "baz")]);
+        assert_eq!(run(&mut r, &["fo", "o ", "ba", "r"]), "baz baz");
+    }
+}
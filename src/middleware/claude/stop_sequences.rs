@@ -1,22 +1,515 @@
+use std::collections::HashSet;
+
 use async_stream::try_stream;
-use axum::response::{IntoResponse, Response, Sse, sse::Event};
+use axum::{
+    Json,
+    body::{self, Body},
+    response::{IntoResponse, Response, Sse, sse::Event},
+};
 use eventsource_stream::{Event as SourceEvent, Eventsource};
 use futures::Stream;
+use http::header::CONTENT_TYPE;
+use regex_automata::{
+    Anchored, Input,
+    hybrid::dfa::{Cache, DFA},
+    util::syntax,
+};
+use tiktoken_rs::o200k_base;
+use tracing::warn;
 
 use crate::{
+    config::{CLEWDR_CONFIG, StopSequenceFlush, StopSequencePrecedence},
+    error::ClewdrError,
     middleware::claude::ClaudeContext,
-    types::claude::{ContentBlockDelta, MessageDeltaContent, StopReason, StreamEvent},
+    types::claude::{
+        ContentBlock, ContentBlockDelta, CreateMessageResponse, MessageDeltaContent, StopReason,
+        StreamEvent, StreamUsage,
+    },
 };
 
 type EventResult<T> = Result<T, eventsource_stream::EventStreamError<axum::Error>>;
 
+/// Outcome of feeding a chunk of text to a [`StopSequenceMatcher`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopSequenceOutcome {
+    /// No stop sequence completed, the text is safe to emit
+    Continue(String),
+    /// A stop sequence completed, `text` is what precedes it
+    Stop { text: String, sequence: String },
+}
+
+/// Text a live regex match holds back is released anyway past this many
+/// bytes, so a pattern that never completes cannot stall the stream
+const MAX_REGEX_HELD_BYTES: usize = 4096;
+
+/// Where an anchored regex match stands after the text seen so far
+#[derive(Debug, PartialEq, Eq)]
+enum RegexScan {
+    /// A non-empty match ends at this offset
+    Match(usize),
+    /// More text may still complete a match
+    Live,
+    /// No match can start here
+    Dead,
+}
+
+/// A stop sequence given as a regex, walked byte by byte with a lazy DFA
+struct RegexStop {
+    dfa: DFA,
+    cache: Cache,
+}
+
+impl RegexStop {
+    fn new(pattern: &str, case_insensitive: bool) -> Result<Self, ClewdrError> {
+        let dfa = DFA::builder()
+            .configure(DFA::config().unicode_word_boundary(true))
+            .syntax(syntax::Config::new().case_insensitive(case_insensitive))
+            .build(pattern)
+            .map_err(|e| ClewdrError::Whatever {
+                message: format!("Unsupported regex: {e}"),
+                source: Some(Box::new(e)),
+            })?;
+        let cache = dfa.create_cache();
+        Ok(Self { dfa, cache })
+    }
+
+    /// Looks for the earliest match starting at `start`
+    ///
+    /// DFA matches are reported one byte late, so a match ending at the end
+    /// of `hay` only counts once the text is `finished`.
+    fn scan(&mut self, hay: &str, start: usize, finished: bool) -> RegexScan {
+        let input = Input::new(hay).range(start..).anchored(Anchored::Yes);
+        let Ok(mut sid) = self.dfa.start_state_forward(&mut self.cache, &input) else {
+            return RegexScan::Live;
+        };
+        for (offset, &byte) in hay.as_bytes()[start..].iter().enumerate() {
+            if sid.is_dead() {
+                return RegexScan::Dead;
+            }
+            let Ok(next) = self.dfa.next_state(&mut self.cache, sid, byte) else {
+                return RegexScan::Live;
+            };
+            sid = next;
+            if sid.is_match() && offset > 0 {
+                return RegexScan::Match(start + offset);
+            }
+        }
+        if finished {
+            return match self.dfa.next_eoi_state(&mut self.cache, sid) {
+                Ok(eoi) if eoi.is_match() && hay.len() > start => RegexScan::Match(hay.len()),
+                _ => RegexScan::Dead,
+            };
+        }
+        if sid.is_dead() {
+            RegexScan::Dead
+        } else {
+            RegexScan::Live
+        }
+    }
+}
+
+/// Drops empty and duplicate stop sequences, shortest first
+pub fn normalize_stop_sequences(sequences: &[String]) -> Vec<String> {
+    let mut sequences = sequences
+        .iter()
+        .filter(|s| !s.is_empty())
+        .cloned()
+        .collect::<Vec<_>>();
+    sequences.sort_by(|a, b| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+    sequences.dedup();
+    sequences
+}
+
+/// Bytes of `text` as the trie sees them, each paired with the offset in
+/// `text` just past the character it came from
+///
+/// Case insensitive matchers see every character lowercased. Lowercasing
+/// goes one character at a time without locale rules, so e.g. Turkish `I`
+/// folds to `i` and never matches a dotless `ı`.
+fn trie_bytes(text: &str, case_insensitive: bool) -> impl Iterator<Item = (u8, usize)> + '_ {
+    text.char_indices().flat_map(move |(start, c)| {
+        let end = start + c.len_utf8();
+        // a lowercase mapping is at most three characters long
+        let mut buf = [0; 12];
+        let mut len = 0;
+        if case_insensitive {
+            for lower in c.to_lowercase() {
+                len += lower.encode_utf8(&mut buf[len..]).len();
+            }
+        } else {
+            len = c.encode_utf8(&mut buf).len();
+        }
+        buf.into_iter().take(len).map(move |byte| (byte, end))
+    })
+}
+
+/// Incremental stop sequence matcher for streamed text
+///
+/// Text is released as soon as it can no longer be part of a stop sequence,
+/// only a suffix that is still a live prefix of some stop sequence is held
+/// back. The stop sequence itself is never emitted.
+///
+/// The sequence that completes first wins. When several start at the same
+/// place, `precedence` decides whether the shortest one stops right away or
+/// the match waits for the longest one that can still complete.
+///
+/// Matchers built with [`StopSequenceMatcher::new_with_regex`] also stop on
+/// regex patterns, a regex match is reported as the text it matched and
+/// always stops as early as possible.
+///
+/// A `case_insensitive` matcher compares lowercased text, see
+/// [`trie_bytes`], while the text released and the sequence reported keep
+/// their original casing.
+pub struct StopSequenceMatcher {
+    /// Stop sequences keyed by their bytes as [`trie_bytes`] yields them
+    trie: trie_rs::map::Trie<u8, String>,
+    regexes: Vec<RegexStop>,
+    case_insensitive: bool,
+    flush: StopSequenceFlush,
+    precedence: StopSequencePrecedence,
+    /// Text not yet resolved, may still be part of a stop sequence
+    buffer: String,
+    /// Number of bytes at the start of `buffer` already released to the client
+    emitted: usize,
+}
+
+impl StopSequenceMatcher {
+    pub fn new(
+        sequences: &[String],
+        flush: StopSequenceFlush,
+        precedence: StopSequencePrecedence,
+        case_insensitive: bool,
+    ) -> Self {
+        let mut keys = HashSet::new();
+        let trie = trie_rs::map::Trie::from_iter(
+            normalize_stop_sequences(sequences)
+                .into_iter()
+                .map(|s| {
+                    let key = trie_bytes(&s, case_insensitive)
+                        .map(|(byte, _)| byte)
+                        .collect::<Vec<_>>();
+                    (key, s)
+                })
+                // sequences differing only in case share a key, keep the first
+                .filter(|(key, _)| keys.insert(key.to_owned())),
+        );
+        Self {
+            trie,
+            regexes: vec![],
+            case_insensitive,
+            flush,
+            precedence,
+            buffer: String::new(),
+            emitted: 0,
+        }
+    }
+
+    /// Builds a matcher treating every entry as a regex
+    ///
+    /// Entries without regex syntax stay on the literal trie, only actual
+    /// patterns go through the slower regex engine, so mixed lists work.
+    /// Invalid patterns are logged and skipped.
+    pub fn new_with_regex(
+        patterns: &[String],
+        flush: StopSequenceFlush,
+        precedence: StopSequencePrecedence,
+        case_insensitive: bool,
+    ) -> Self {
+        let (literals, patterns): (Vec<_>, Vec<_>) = patterns
+            .iter()
+            .filter(|p| !p.is_empty())
+            .cloned()
+            .partition(|p| regex::escape(p) == *p);
+        let mut matcher = Self::new(&literals, flush, precedence, case_insensitive);
+        for pattern in patterns {
+            match RegexStop::new(&pattern, case_insensitive) {
+                Ok(regex) => matcher.regexes.push(regex),
+                Err(e) => warn!("Ignoring invalid stop sequence regex {}: {}", pattern, e),
+            }
+        }
+        matcher
+    }
+
+    /// Feeds a chunk of streamed text, returning what can be released
+    pub fn push(&mut self, text: &str) -> StopSequenceOutcome {
+        self.buffer.push_str(text);
+        let mut deferred = None;
+        if let Some((start, sequence, literal)) = self.first_match(false) {
+            if !literal {
+                return self.stop(start, sequence);
+            }
+            match self.resolve(start, sequence, false) {
+                Some(sequence) => return self.stop(start, sequence),
+                None => deferred = Some(start),
+            }
+        }
+
+        let hold_start = self.live_prefix_start();
+        let release = hold_start + releasable(self.flush, &self.buffer[hold_start..]);
+        // never release a match waiting for a longer sequence
+        let release = deferred.map_or(release, |start| release.min(start));
+        let release = release.max(self.emitted);
+        let text = self.buffer[self.emitted..release].to_string();
+        self.buffer.drain(..hold_start);
+        self.emitted = release - hold_start;
+        StopSequenceOutcome::Continue(text)
+    }
+
+    /// Releases any held back text, e.g. at the end of a content block
+    ///
+    /// A match still waiting for a longer sequence is settled on the longest
+    /// sequence completed so far.
+    pub fn finish(&mut self) -> StopSequenceOutcome {
+        if let Some((start, sequence, literal)) = self.first_match(true) {
+            let sequence = if literal {
+                self.resolve(start, sequence, true)
+            } else {
+                Some(sequence)
+            };
+            if let Some(sequence) = sequence {
+                return self.stop(start, sequence);
+            }
+        }
+        StopSequenceOutcome::Continue(self.flush())
+    }
+
+    /// Releases any held back text without looking for matches
+    pub fn flush(&mut self) -> String {
+        let text = self.buffer.split_off(self.emitted);
+        self.buffer.clear();
+        self.emitted = 0;
+        text
+    }
+
+    fn stop(&mut self, start: usize, sequence: String) -> StopSequenceOutcome {
+        let text = self
+            .buffer
+            .get(self.emitted..start)
+            .unwrap_or_default()
+            .to_string();
+        self.buffer.clear();
+        self.emitted = 0;
+        StopSequenceOutcome::Stop { text, sequence }
+    }
+
+    /// Finds the stop sequence that completes first in the buffer,
+    /// returning its start offset and whether it is a literal sequence
+    fn first_match(&mut self, finished: bool) -> Option<(usize, String, bool)> {
+        let mut best: Option<(usize, usize, &String)> = None;
+        for (start, _) in self.buffer.char_indices() {
+            let mut search = self.trie.inc_search();
+            for (byte, end) in trie_bytes(&self.buffer[start..], self.case_insensitive) {
+                let end = start + end;
+                if best.is_some_and(|(_, best_end, _)| end >= best_end) {
+                    break;
+                }
+                match search.query(&byte) {
+                    Some(answer) if answer.is_match() => {
+                        best = search.value().map(|seq| (start, end, seq));
+                        break;
+                    }
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+        }
+        let mut best = best.map(|(start, end, seq)| (start, end, seq.to_owned(), true));
+        for regex in &mut self.regexes {
+            for (start, _) in self.buffer.char_indices() {
+                if best.as_ref().is_some_and(|(_, end, ..)| start >= *end) {
+                    break;
+                }
+                if let RegexScan::Match(end) = regex.scan(&self.buffer, start, finished)
+                    && best
+                        .as_ref()
+                        .is_none_or(|(s, e, ..)| end < *e || (end == *e && start < *s))
+                {
+                    best = Some((start, end, self.buffer[start..end].to_string(), false));
+                }
+            }
+        }
+        best.map(|(start, _, seq, literal)| (start, seq, literal))
+    }
+
+    /// Applies the precedence to a match found at `start`
+    ///
+    /// Returns `None` while a longer sequence starting there may still
+    /// complete, unless the text is `finished`.
+    fn resolve(&self, start: usize, shortest: String, finished: bool) -> Option<String> {
+        if self.precedence == StopSequencePrecedence::Shortest {
+            return Some(shortest);
+        }
+        let mut search = self.trie.inc_search();
+        let mut longest = shortest;
+        for (byte, _) in trie_bytes(&self.buffer[start..], self.case_insensitive) {
+            let Some(answer) = search.query(&byte) else {
+                return Some(longest);
+            };
+            if answer.is_match()
+                && let Some(seq) = search.value()
+            {
+                longest = seq.to_owned();
+            }
+            if !answer.is_prefix() {
+                return Some(longest);
+            }
+        }
+        finished.then_some(longest)
+    }
+
+    /// Start of the longest suffix of the buffer that may still grow into a
+    /// stop sequence, or the buffer length if there is none
+    fn live_prefix_start(&mut self) -> usize {
+        let literal = self
+            .buffer
+            .char_indices()
+            .map(|(start, _)| start)
+            .find(|&start| self.is_live_prefix(&self.buffer[start..]))
+            .unwrap_or(self.buffer.len());
+        let oldest = self.buffer.len().saturating_sub(MAX_REGEX_HELD_BYTES);
+        let mut live = literal;
+        for regex in &mut self.regexes {
+            if let Some(start) = self
+                .buffer
+                .char_indices()
+                .map(|(start, _)| start)
+                .take_while(|&start| start < live)
+                .find(|&start| {
+                    start >= oldest && regex.scan(&self.buffer, start, false) == RegexScan::Live
+                })
+            {
+                live = start;
+            }
+        }
+        live
+    }
+
+    /// Whether `text` is the start of a longer literal stop sequence
+    fn is_live_prefix(&self, text: &str) -> bool {
+        let mut search = self.trie.inc_search();
+        let mut live = false;
+        for (byte, _) in trie_bytes(text, self.case_insensitive) {
+            let Some(answer) = search.query(&byte) else {
+                return false;
+            };
+            live = answer.is_prefix();
+        }
+        live
+    }
+}
+
+/// How many bytes of a held back suffix the flush policy releases anyway
+fn releasable(flush: StopSequenceFlush, held: &str) -> usize {
+    match flush {
+        StopSequenceFlush::Conservative => 0,
+        StopSequenceFlush::Balanced => held.len() - held.trim_start().len(),
+        StopSequenceFlush::Eager => held
+            .char_indices()
+            .rev()
+            .find(|(_, c)| c.is_whitespace() || c.is_ascii_punctuation())
+            .map_or(0, |(i, c)| i + c.len_utf8()),
+    }
+}
+
+pub(super) fn text_delta_event(event: &str, index: usize, text: String) -> Event {
+    Event::default()
+        .event(event)
+        .json_data(StreamEvent::ContentBlockDelta {
+            delta: ContentBlockDelta::TextDelta { text },
+            index,
+        })
+        .unwrap()
+}
+
+/// Usage upstream reported so far and the text released since, for the
+/// `message_delta` sent when a stop sequence ends the stream early
+#[derive(Default)]
+struct UsageTracker {
+    input_tokens: u32,
+    output_tokens: u32,
+    text: String,
+}
+
+impl UsageTracker {
+    fn observe(&mut self, event: &StreamEvent) {
+        match event {
+            StreamEvent::MessageStart { message } => {
+                if let Some(usage) = &message.usage {
+                    self.input_tokens = usage.input_tokens;
+                    self.output_tokens = usage.output_tokens;
+                }
+            }
+            StreamEvent::MessageDelta {
+                usage: Some(usage), ..
+            } => {
+                self.input_tokens = self.input_tokens.max(usage.input_tokens);
+                self.output_tokens = usage.output_tokens;
+            }
+            _ => {}
+        }
+    }
+
+    /// Output tokens are counted from the released text when that is more
+    /// than upstream reported, upstream never sees where the stream was cut
+    fn usage(&self) -> StreamUsage {
+        let bpe = o200k_base().expect("Failed to get encoding");
+        let released = bpe.encode_with_special_tokens(&self.text).len() as u32;
+        StreamUsage {
+            input_tokens: self.input_tokens,
+            output_tokens: self.output_tokens.max(released),
+        }
+    }
+}
+
+/// Events to send for a matcher outcome, and whether the stream stops there
+fn outcome_events(
+    event: &str,
+    index: usize,
+    outcome: StopSequenceOutcome,
+    usage: &mut UsageTracker,
+) -> (Vec<Event>, bool) {
+    match outcome {
+        StopSequenceOutcome::Continue(text) => {
+            usage.text.push_str(&text);
+            let events = if text.is_empty() {
+                vec![]
+            } else {
+                vec![text_delta_event(event, index, text)]
+            };
+            (events, false)
+        }
+        StopSequenceOutcome::Stop { text, sequence } => {
+            usage.text.push_str(&text);
+            let mut events = vec![];
+            if !text.is_empty() {
+                events.push(text_delta_event(event, index, text));
+            }
+            let content_block_stop = StreamEvent::ContentBlockStop { index };
+            let message_delta = StreamEvent::MessageDelta {
+                delta: MessageDeltaContent {
+                    stop_reason: Some(StopReason::StopSequence),
+                    stop_sequence: Some(sequence),
+                },
+                // clients of the pinned version read `usage` off every message_delta
+                usage: Some(usage.usage()),
+            };
+            let message_stop = StreamEvent::MessageStop;
+
+            for e in [content_block_stop, message_delta, message_stop] {
+                events.push(Event::default().json_data(e).unwrap());
+            }
+            (events, true)
+        }
+    }
+}
+
 fn stop_stream(
-    sequences: Vec<String>,
+    mut matcher: StopSequenceMatcher,
     stream: impl Stream<Item = EventResult<SourceEvent>>,
 ) -> impl Stream<Item = EventResult<Event>> {
-    let trie = trie_rs::map::Trie::from_iter(sequences.into_iter().map(|s| (s.to_owned(), s)));
     try_stream!({
-        let mut searches = vec![trie.inc_search()];
+        let mut last_index = 0;
+        let mut usage = UsageTracker::default();
         for await event in stream {
             let eventsource_stream::Event {
                 data,
@@ -24,81 +517,660 @@ fn stop_stream(
                 event,
                 retry,
             } = event?;
-            let event = Event::default().event(event).id(id).data(&data);
-            let event = if let Some(retry) = retry {
-                event.retry(retry)
+            let parsed = serde_json::from_str::<StreamEvent>(&data);
+            let out = Event::default().event(&event).id(id).data(&data);
+            let out = if let Some(retry) = retry {
+                out.retry(retry)
             } else {
-                event
-            };
-            let Ok(parsed) = serde_json::from_str::<StreamEvent>(&data) else {
-                yield event;
-                continue;
-            };
-            let StreamEvent::ContentBlockDelta { delta, index } = parsed else {
-                yield event;
-                continue;
-            };
-            let ContentBlockDelta::TextDelta { text } = delta else {
-                yield event;
-                continue;
+                out
             };
-            let input = text.into_bytes();
-            for i in 0..input.len() {
-                let mut next_searches = vec![trie.inc_search()];
-                for mut s in searches.into_iter() {
-                    match s.query(&input[i]) {
-                        // match found, return
-                        Some(a) if a.is_match() => {
-                            let seq = s.value().unwrap();
-                            // stop sequence found
-                            let result = String::from_utf8_lossy(&input[..i + 1]).to_string();
-                            let event = StreamEvent::ContentBlockDelta {
-                                delta: ContentBlockDelta::TextDelta { text: result },
-                                index,
-                            };
-                            let content_block_stop = StreamEvent::ContentBlockStop { index };
-                            let message_delta = StreamEvent::MessageDelta {
-                                delta: MessageDeltaContent {
-                                    stop_reason: Some(StopReason::StopSequence),
-                                    stop_sequence: Some(seq.to_string()),
-                                },
-                                usage: None,
-                            };
-                            let message_stop = StreamEvent::MessageStop;
-
-                            for e in [event, content_block_stop, message_delta, message_stop] {
-                                let event = Event::default();
-                                let event = event.json_data(e).unwrap();
-                                yield event;
-                            }
-                            return;
-                        }
-                        // prefix found, add it to the next searches
-                        Some(a) if a.is_prefix() => next_searches.push(s),
-                        _ => (),
+            let Ok(StreamEvent::ContentBlockDelta {
+                delta: ContentBlockDelta::TextDelta { text },
+                index,
+            }) = parsed
+            else {
+                if let Ok(parsed) = &parsed {
+                    usage.observe(parsed);
+                }
+                // any other event ends the current run of text, release what was held back
+                let ends_text = matches!(&parsed, Ok(e) if !matches!(e, StreamEvent::Ping));
+                if ends_text {
+                    let (events, stopped) = outcome_events(
+                        "content_block_delta",
+                        last_index,
+                        matcher.finish(),
+                        &mut usage,
+                    );
+                    for e in events {
+                        yield e;
+                    }
+                    if stopped {
+                        return;
                     }
                 }
-                searches = next_searches;
+                yield out;
+                continue;
+            };
+            last_index = index;
+            let (events, stopped) = outcome_events(&event, index, matcher.push(&text), &mut usage);
+            for e in events {
+                yield e;
+            }
+            if stopped {
+                return;
             }
-            yield event;
+        }
+        let (events, _) = outcome_events(
+            "content_block_delta",
+            last_index,
+            matcher.finish(),
+            &mut usage,
+        );
+        for e in events {
+            yield e;
         }
     })
 }
 
+/// Cuts a complete response at the first stop sequence in its text blocks,
+/// returning whether one matched
+///
+/// Blocks after the match are dropped. Usage is left as upstream reported
+/// it, matching what streamed responses report.
+fn stop_response(mut matcher: StopSequenceMatcher, response: &mut CreateMessageResponse) -> bool {
+    let mut matched = None;
+    for (i, block) in response.content.iter_mut().enumerate() {
+        let ContentBlock::Text { text, .. } = block else {
+            continue;
+        };
+        let (kept, sequence) = match matcher.push(text) {
+            StopSequenceOutcome::Stop { text, sequence } => (text, sequence),
+            StopSequenceOutcome::Continue(head) => match matcher.finish() {
+                StopSequenceOutcome::Stop { text, sequence } => (head + &text, sequence),
+                StopSequenceOutcome::Continue(_) => continue,
+            },
+        };
+        *text = kept;
+        matched = Some((i, sequence));
+        break;
+    }
+    let Some((i, sequence)) = matched else {
+        return false;
+    };
+    response.content.truncate(i + 1);
+    response.stop_reason = Some(StopReason::StopSequence);
+    response.stop_sequence = Some(sequence);
+    true
+}
+
+/// Ends responses at the client's stop sequences, streamed ones as soon as
+/// a sequence completes, complete ones by cutting their text
+///
+/// OpenAI responses are converted later on, a stop sequence there becomes
+/// `finish_reason: "stop"`.
 pub async fn apply_stop_sequences(resp: Response) -> Response {
     let Some(f) = resp.extensions().get::<ClaudeContext>().cloned() else {
         return resp;
     };
-    if !f.is_stream() || f.stop_sequences().is_empty() {
+    if f.stop_sequences().is_empty() || !resp.status().is_success() {
         return resp;
     }
 
-    let stream = resp.into_body().into_data_stream().eventsource();
-    let stream = stop_stream(f.stop_sequences().to_owned(), stream);
-    let mut resp = Sse::new(stream)
-        .keep_alive(Default::default())
-        .into_response();
+    let config = CLEWDR_CONFIG.load();
+    let (flush, precedence) = (config.stop_sequence_flush, config.stop_sequence_precedence);
+    let case_insensitive = f.stop_case_insensitive();
+    let matcher = if config.stop_sequence_regex {
+        StopSequenceMatcher::new_with_regex(f.stop_sequences(), flush, precedence, case_insensitive)
+    } else {
+        StopSequenceMatcher::new(f.stop_sequences(), flush, precedence, case_insensitive)
+    };
+    let mut resp = if f.is_stream() {
+        let stream = resp.into_body().into_data_stream().eventsource();
+        Sse::new(stop_stream(matcher, stream))
+            .keep_alive(Default::default())
+            .into_response()
+    } else {
+        let bytes = body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .inspect_err(|err| {
+                warn!("Failed to read response body: {}", err);
+            })
+            .unwrap_or_default();
+        match serde_json::from_slice::<CreateMessageResponse>(&bytes) {
+            Ok(mut response) => {
+                stop_response(matcher, &mut response);
+                Json(response).into_response()
+            }
+            Err(_) => Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(bytes))
+                .unwrap(),
+        }
+    };
 
     resp.extensions_mut().insert(f);
     resp
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(sequences: &[&str], flush: StopSequenceFlush) -> StopSequenceMatcher {
+        precedence_matcher(sequences, flush, StopSequencePrecedence::Shortest)
+    }
+
+    fn precedence_matcher(
+        sequences: &[&str],
+        flush: StopSequenceFlush,
+        precedence: StopSequencePrecedence,
+    ) -> StopSequenceMatcher {
+        let sequences = sequences.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        StopSequenceMatcher::new(&sequences, flush, precedence, false)
+    }
+
+    #[test]
+    fn normal_text_is_never_held_back() {
+        for flush in [
+            StopSequenceFlush::Conservative,
+            StopSequenceFlush::Balanced,
+            StopSequenceFlush::Eager,
+        ] {
+            let mut m = matcher(&["\n\nHuman:", "END"], flush);
+            for chunk in ["Hello ", "world, ", "this is ", "plain text.", " 你好"] {
+                assert_eq!(
+                    m.push(chunk),
+                    StopSequenceOutcome::Continue(chunk.to_string())
+                );
+            }
+            assert_eq!(m.flush(), "");
+        }
+    }
+
+    #[test]
+    fn stop_sequence_split_across_chunks_is_not_emitted() {
+        let mut m = matcher(&["\n\nHuman:"], StopSequenceFlush::Conservative);
+        assert_eq!(
+            m.push("Sure.\n"),
+            StopSequenceOutcome::Continue("Sure.".to_string())
+        );
+        assert_eq!(
+            m.push("\nHum"),
+            StopSequenceOutcome::Continue(String::new())
+        );
+        assert_eq!(
+            m.push("an: hi"),
+            StopSequenceOutcome::Stop {
+                text: String::new(),
+                sequence: "\n\nHuman:".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn held_text_is_released_when_match_breaks() {
+        let mut m = matcher(&["END"], StopSequenceFlush::Conservative);
+        assert_eq!(
+            m.push("the EN"),
+            StopSequenceOutcome::Continue("the ".into())
+        );
+        assert_eq!(
+            m.push("D"),
+            StopSequenceOutcome::Stop {
+                text: String::new(),
+                sequence: "END".into(),
+            }
+        );
+
+        let mut m = matcher(&["END"], StopSequenceFlush::Conservative);
+        assert_eq!(
+            m.push("the EN"),
+            StopSequenceOutcome::Continue("the ".into())
+        );
+        assert_eq!(
+            m.push("ding"),
+            StopSequenceOutcome::Continue("ENding".into())
+        );
+    }
+
+    #[test]
+    fn multibyte_text_is_split_on_char_boundaries() {
+        let mut m = matcher(&["stop"], StopSequenceFlush::Conservative);
+        assert_eq!(
+            m.push("你好stop世界"),
+            StopSequenceOutcome::Stop {
+                text: "你好".to_string(),
+                sequence: "stop".to_string(),
+            }
+        );
+
+        let mut m = matcher(&["停止"], StopSequenceFlush::Eager);
+        assert_eq!(
+            m.push("你好停"),
+            StopSequenceOutcome::Continue("你好".to_string())
+        );
+        assert_eq!(
+            m.push("下🎉停"),
+            StopSequenceOutcome::Continue("停下🎉".to_string())
+        );
+        assert_eq!(
+            m.push("止"),
+            StopSequenceOutcome::Stop {
+                text: String::new(),
+                sequence: "停止".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn earliest_completed_sequence_wins() {
+        let mut m = matcher(&["abcd", "bc"], StopSequenceFlush::Conservative);
+        assert_eq!(
+            m.push("xabcd"),
+            StopSequenceOutcome::Stop {
+                text: "xa".into(),
+                sequence: "bc".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn balanced_flush_releases_held_whitespace() {
+        let mut m = matcher(&["\n\nHuman:"], StopSequenceFlush::Balanced);
+        assert_eq!(
+            m.push("Sure.\n\n"),
+            StopSequenceOutcome::Continue("Sure.\n\n".to_string())
+        );
+        assert_eq!(m.push("Hu"), StopSequenceOutcome::Continue(String::new()));
+        assert_eq!(
+            m.push("man:"),
+            StopSequenceOutcome::Stop {
+                text: String::new(),
+                sequence: "\n\nHuman:".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn eager_flush_releases_up_to_last_boundary() {
+        let mut m = matcher(&["Hello, World"], StopSequenceFlush::Eager);
+        assert_eq!(
+            m.push("Hello, Wo"),
+            StopSequenceOutcome::Continue("Hello, ".to_string())
+        );
+        assert_eq!(m.flush(), "Wo");
+    }
+
+    #[test]
+    fn duplicate_sequences_are_dropped_shortest_first() {
+        let sequences = ["stopping", "stop", "", "stop", "ab"].map(String::from);
+        assert_eq!(
+            normalize_stop_sequences(&sequences),
+            ["ab", "stop", "stopping"]
+        );
+    }
+
+    #[test]
+    fn shortest_precedence_stops_on_first_completion() {
+        let mut m = precedence_matcher(
+            &["stopping", "stop"],
+            StopSequenceFlush::Conservative,
+            StopSequencePrecedence::Shortest,
+        );
+        assert_eq!(
+            m.push("we are stopping now"),
+            StopSequenceOutcome::Stop {
+                text: "we are ".into(),
+                sequence: "stop".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn longest_precedence_waits_for_longer_sequence() {
+        let mut m = precedence_matcher(
+            &["stop", "stopping"],
+            StopSequenceFlush::Conservative,
+            StopSequencePrecedence::Longest,
+        );
+        assert_eq!(
+            m.push("we are stop"),
+            StopSequenceOutcome::Continue("we are ".into())
+        );
+        assert_eq!(m.push("pi"), StopSequenceOutcome::Continue(String::new()));
+        assert_eq!(
+            m.push("ng now"),
+            StopSequenceOutcome::Stop {
+                text: String::new(),
+                sequence: "stopping".into(),
+            }
+        );
+
+        // the longer sequence breaks off, the shorter one still stops
+        let mut m = precedence_matcher(
+            &["stop", "stopping"],
+            StopSequenceFlush::Eager,
+            StopSequencePrecedence::Longest,
+        );
+        assert_eq!(m.push("stop"), StopSequenceOutcome::Continue(String::new()));
+        assert_eq!(
+            m.push("s here"),
+            StopSequenceOutcome::Stop {
+                text: String::new(),
+                sequence: "stop".into(),
+            }
+        );
+
+        // the block ends while waiting
+        let mut m = precedence_matcher(
+            &["stop", "stopping"],
+            StopSequenceFlush::Conservative,
+            StopSequencePrecedence::Longest,
+        );
+        assert_eq!(
+            m.push("a stopp"),
+            StopSequenceOutcome::Continue("a ".into())
+        );
+        assert_eq!(
+            m.finish(),
+            StopSequenceOutcome::Stop {
+                text: String::new(),
+                sequence: "stop".into(),
+            }
+        );
+    }
+
+    fn case_insensitive_matcher(sequences: &[&str]) -> StopSequenceMatcher {
+        let sequences = sequences.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        StopSequenceMatcher::new(
+            &sequences,
+            StopSequenceFlush::Conservative,
+            StopSequencePrecedence::Shortest,
+            true,
+        )
+    }
+
+    #[test]
+    fn case_insensitive_match_reports_configured_sequence() {
+        let mut m = case_insensitive_matcher(&["STOP"]);
+        assert_eq!(
+            m.push("Then St"),
+            StopSequenceOutcome::Continue("Then ".to_string())
+        );
+        assert_eq!(
+            m.push("op here"),
+            StopSequenceOutcome::Stop {
+                text: String::new(),
+                sequence: "STOP".to_string(),
+            }
+        );
+
+        let mut m = case_insensitive_matcher(&["Ende", "ENDE"]);
+        assert_eq!(
+            m.push("Das ist das ende"),
+            StopSequenceOutcome::Stop {
+                text: "Das ist das ".to_string(),
+                sequence: "ENDE".to_string(),
+            }
+        );
+
+        // exact matching stays the default
+        let mut m = matcher(&["STOP"], StopSequenceFlush::Conservative);
+        assert_eq!(
+            m.push("stop"),
+            StopSequenceOutcome::Continue("stop".to_string())
+        );
+    }
+
+    #[test]
+    fn case_folding_is_per_character_and_locale_free() {
+        // Ä and ä fold together, the released text keeps its casing
+        let mut m = case_insensitive_matcher(&["ÄRGER"]);
+        assert_eq!(
+            m.push("Kein Ärger"),
+            StopSequenceOutcome::Stop {
+                text: "Kein ".to_string(),
+                sequence: "ÄRGER".to_string(),
+            }
+        );
+
+        // Known limitation: Turkish casing is not applied, dotted İ lowercases
+        // to i plus a combining dot and dotless ı has no uppercase peer here
+        let mut m = case_insensitive_matcher(&["İSTANBUL"]);
+        assert_eq!(
+            m.push("istanbul"),
+            StopSequenceOutcome::Continue("istanbul".to_string())
+        );
+        let mut m = case_insensitive_matcher(&["ISTANBUL"]);
+        assert_eq!(
+            m.push("ıstanbul"),
+            StopSequenceOutcome::Continue("ıstanbul".to_string())
+        );
+        // while the combining dot makes İ match a literal i̇
+        let mut m = case_insensitive_matcher(&["i\u{307}stanbul"]);
+        assert_eq!(
+            m.push("İSTANBUL"),
+            StopSequenceOutcome::Stop {
+                text: String::new(),
+                sequence: "i\u{307}stanbul".to_string(),
+            }
+        );
+    }
+
+    fn regex_matcher(patterns: &[&str]) -> StopSequenceMatcher {
+        let patterns = patterns.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        StopSequenceMatcher::new_with_regex(
+            &patterns,
+            StopSequenceFlush::Conservative,
+            StopSequencePrecedence::Shortest,
+            false,
+        )
+    }
+
+    #[test]
+    fn regex_match_split_across_chunks_is_held() {
+        let mut m = regex_matcher(&[r"\n\s+Human:"]);
+        assert_eq!(
+            m.push("Sure.\n  "),
+            StopSequenceOutcome::Continue("Sure.".into())
+        );
+        assert_eq!(m.push(" Hum"), StopSequenceOutcome::Continue(String::new()));
+        // the match is only reported once a byte past it is seen
+        assert_eq!(m.push("an:"), StopSequenceOutcome::Continue(String::new()));
+        assert_eq!(
+            m.push(" hi"),
+            StopSequenceOutcome::Stop {
+                text: String::new(),
+                sequence: "\n   Human:".into(),
+            }
+        );
+
+        // a match ending with the block is settled by `finish`
+        let mut m = regex_matcher(&[r"\n\s+Human:"]);
+        assert_eq!(
+            m.push("ok\n\nHuman:"),
+            StopSequenceOutcome::Continue("ok".into())
+        );
+        assert_eq!(
+            m.finish(),
+            StopSequenceOutcome::Stop {
+                text: String::new(),
+                sequence: "\n\nHuman:".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn regex_alternations_mix_with_literals() {
+        let mut m = regex_matcher(&["(User|Human):", "END"]);
+        assert_eq!(
+            m.push("a User: b"),
+            StopSequenceOutcome::Stop {
+                text: "a ".into(),
+                sequence: "User:".into(),
+            }
+        );
+        // the literal entry keeps matching through the trie
+        let mut m = regex_matcher(&["(User|Human):", "END"]);
+        assert_eq!(
+            m.push("done END"),
+            StopSequenceOutcome::Stop {
+                text: "done ".into(),
+                sequence: "END".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn regex_that_never_completes_is_released() {
+        let mut m = regex_matcher(&[r"<end \d+>"]);
+        assert_eq!(
+            m.push("a <end 12"),
+            StopSequenceOutcome::Continue("a ".into())
+        );
+        // proven not to match once the digits are followed by anything else
+        assert_eq!(
+            m.push("3 x"),
+            StopSequenceOutcome::Continue("<end 123 x".into())
+        );
+
+        let mut m = regex_matcher(&[r"<end \d+>"]);
+        assert_eq!(
+            m.push("<end 1"),
+            StopSequenceOutcome::Continue(String::new())
+        );
+        assert_eq!(m.finish(), StopSequenceOutcome::Continue("<end 1".into()));
+
+        // held text is capped so the stream cannot stall
+        let mut m = regex_matcher(&[r"<end \d+>"]);
+        let digits = "1".repeat(MAX_REGEX_HELD_BYTES + 10);
+        let StopSequenceOutcome::Continue(text) = m.push(&format!("<end {digits}")) else {
+            panic!("no match expected");
+        };
+        assert!(!text.is_empty());
+    }
+
+    #[test]
+    fn complete_responses_are_cut_at_stop_sequence() {
+        let body = r#"{
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-5",
+            "content": [
+                {"type": "thinking", "thinking": "plan", "signature": "sig"},
+                {"type": "text", "text": "Answer: 42\nUser: thanks"},
+                {"type": "text", "text": "more"}
+            ],
+            "stop_reason": "end_turn",
+            "stop_sequence": null,
+            "usage": {"input_tokens": 10, "output_tokens": 20}
+        }"#;
+        let mut response = serde_json::from_str::<CreateMessageResponse>(body).unwrap();
+        let m = matcher(&["\nUser:"], StopSequenceFlush::Conservative);
+        assert!(stop_response(m, &mut response));
+        assert_eq!(response.content.len(), 2);
+        assert!(
+            matches!(&response.content[1], ContentBlock::Text { text, .. } if text == "Answer: 42")
+        );
+        assert!(matches!(
+            response.stop_reason,
+            Some(StopReason::StopSequence)
+        ));
+        assert_eq!(response.stop_sequence.as_deref(), Some("\nUser:"));
+        assert_eq!(response.usage.as_ref().unwrap().output_tokens, 20);
+
+        let oai = crate::middleware::claude::transforms_json(response);
+        assert_eq!(oai["choices"][0]["finish_reason"], "stop");
+        assert_eq!(oai["choices"][0]["message"]["content"], "Answer: 42");
+
+        let mut response = serde_json::from_str::<CreateMessageResponse>(body).unwrap();
+        let m = matcher(&["STOP"], StopSequenceFlush::Conservative);
+        assert!(!stop_response(m, &mut response));
+        assert_eq!(response.content.len(), 3);
+        assert!(matches!(response.stop_reason, Some(StopReason::EndTurn)));
+    }
+
+    #[tokio::test]
+    async fn synthesized_stop_events_follow_supported_versions() {
+        use axum::body;
+        use futures::{StreamExt, stream};
+        use serde_json::Value;
+
+        use crate::config::SUPPORTED_ANTHROPIC_VERSIONS;
+
+        let upstream = [
+            r#"{"type":"message_start","message":{"id":"msg_1","type":"message","role":"assistant","content":[],"model":"claude-sonnet-4-5","stop_reason":null,"stop_sequence":null,"usage":{"input_tokens":10,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello there, here is a longer answer STOP more"}}"#,
+        ]
+        .map(|data| format!("event: message\ndata: {data}\n\n"))
+        .concat();
+        let source = stream::iter([Ok::<_, axum::Error>(upstream)]).eventsource();
+        let out = stop_stream(matcher(&["STOP"], StopSequenceFlush::Conservative), source);
+        let body = Sse::new(out).into_response().into_body();
+        let bytes = body::to_bytes(body, usize::MAX).await.unwrap();
+        let events = stream::iter([Ok::<_, axum::Error>(bytes)])
+            .eventsource()
+            .map(|e| serde_json::from_str::<Value>(&e.unwrap().data).unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        // only one Messages API version exists, responses need no translation
+        assert_eq!(SUPPORTED_ANTHROPIC_VERSIONS, ["2023-06-01"]);
+        for event in &events {
+            serde_json::from_value::<StreamEvent>(event.to_owned()).unwrap();
+        }
+        let delta = events
+            .iter()
+            .find(|e| e["type"] == "message_delta")
+            .unwrap();
+        assert_eq!(delta["delta"]["stop_reason"], "stop_sequence");
+        assert_eq!(delta["delta"]["stop_sequence"], "STOP");
+        // usage carries upstream's input and the output released before the stop
+        assert_eq!(delta["usage"]["input_tokens"], 10);
+        assert!(delta["usage"]["output_tokens"].as_u64().unwrap() > 1);
+        assert_eq!(events.last().unwrap()["type"], "message_stop");
+    }
+
+    #[tokio::test]
+    async fn tool_input_deltas_are_not_matched() {
+        use axum::body;
+        use futures::{StreamExt, stream};
+
+        let fragments = [r#"{"text": "say ST"#, r#"OP now"}"#];
+        let upstream = [
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"tool_use","id":"toolu_1","name":"echo","input":{}}}"#.to_string(),
+        ]
+        .into_iter()
+        .chain(fragments.iter().map(|partial_json| {
+            serde_json::json!({
+                "type": "content_block_delta",
+                "index": 0,
+                "delta": { "type": "input_json_delta", "partial_json": partial_json },
+            })
+            .to_string()
+        }))
+        .chain([r#"{"type":"content_block_stop","index":0}"#.to_string()])
+        .map(|data| format!("event: message\ndata: {data}\n\n"))
+        .collect::<String>();
+        let source = stream::iter([Ok::<_, axum::Error>(upstream)]).eventsource();
+        let out = stop_stream(matcher(&["STOP"], StopSequenceFlush::Conservative), source);
+        let body = Sse::new(out).into_response().into_body();
+        let bytes = body::to_bytes(body, usize::MAX).await.unwrap();
+        let input = stream::iter([Ok::<_, axum::Error>(bytes)])
+            .eventsource()
+            .filter_map(async |e| {
+                match serde_json::from_str::<StreamEvent>(&e.unwrap().data).unwrap() {
+                    StreamEvent::ContentBlockDelta {
+                        delta: ContentBlockDelta::InputJsonDelta { partial_json },
+                        ..
+                    } => Some(partial_json),
+                    _ => None,
+                }
+            })
+            .collect::<String>()
+            .await;
+        assert_eq!(input, fragments.concat());
+    }
+}
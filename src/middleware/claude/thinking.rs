@@ -0,0 +1,187 @@
+use std::collections::BTreeSet;
+
+use async_stream::try_stream;
+use axum::{
+    Json,
+    body::{self, Body},
+    response::{IntoResponse, Response, Sse, sse::Event},
+};
+use eventsource_stream::{Event as SourceEvent, Eventsource};
+use futures::Stream;
+use http::{HeaderMap, header::CONTENT_TYPE};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{config::CLEWDR_CONFIG, middleware::claude::ClaudeContext};
+
+type EventResult<T> = Result<T, eventsource_stream::EventStreamError<axum::Error>>;
+
+/// Request header overriding `hide_thinking` for a single request
+pub const HIDE_THINKING_HEADER: &str = "x-clewdr-hide-thinking";
+
+/// Whether thinking should be stripped from the response, the request header
+/// wins over the configured default
+pub(super) fn hide_thinking(headers: &HeaderMap) -> bool {
+    let header = headers
+        .get(HIDE_THINKING_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_ascii_lowercase());
+    match header.as_deref() {
+        Some("1" | "true" | "yes" | "on") => true,
+        Some("0" | "false" | "no" | "off") => false,
+        _ => CLEWDR_CONFIG.load().hide_thinking,
+    }
+}
+
+fn is_thinking_block(block: &Value) -> bool {
+    matches!(
+        block["type"].as_str(),
+        Some("thinking" | "redacted_thinking")
+    )
+}
+
+/// Drops thinking blocks from streamed events, renumbering the remaining
+/// blocks so clients still see contiguous indices
+#[derive(Default)]
+struct ThinkingFilter {
+    /// Upstream indices of the thinking blocks seen so far
+    hidden: BTreeSet<u64>,
+}
+
+impl ThinkingFilter {
+    /// Returns the event data to send, `None` if the event is dropped
+    fn filter(&mut self, data: &str) -> Option<String> {
+        let Ok(mut event) = serde_json::from_str::<Value>(data) else {
+            return Some(data.to_owned());
+        };
+        let Some(index) = event["index"].as_u64() else {
+            return Some(data.to_owned());
+        };
+        if event["type"] == "content_block_start" && is_thinking_block(&event["content_block"]) {
+            self.hidden.insert(index);
+        }
+        if self.hidden.contains(&index) {
+            return None;
+        }
+        let shift = self.hidden.range(..index).count() as u64;
+        if shift == 0 {
+            return Some(data.to_owned());
+        }
+        event["index"] = (index - shift).into();
+        Some(event.to_string())
+    }
+}
+
+fn strip_stream(
+    stream: impl Stream<Item = EventResult<SourceEvent>>,
+) -> impl Stream<Item = EventResult<Event>> {
+    try_stream!({
+        let mut filter = ThinkingFilter::default();
+        for await event in stream {
+            let SourceEvent {
+                data,
+                id,
+                event,
+                retry,
+            } = event?;
+            let Some(data) = filter.filter(&data) else {
+                continue;
+            };
+            let out = Event::default().event(&event).id(id).data(data);
+            yield if let Some(retry) = retry {
+                out.retry(retry)
+            } else {
+                out
+            };
+        }
+    })
+}
+
+/// Removes thinking content blocks from responses when the client asked to
+/// hide them
+///
+/// Thinking stays enabled upstream, only what is sent to the client changes.
+pub async fn strip_thinking(resp: Response) -> Response {
+    let Some(cx) = resp.extensions().get::<ClaudeContext>().cloned() else {
+        return resp;
+    };
+    if !cx.hide_thinking() || !resp.status().is_success() {
+        return resp;
+    }
+
+    let mut resp = if cx.is_stream() {
+        let stream = resp.into_body().into_data_stream().eventsource();
+        Sse::new(strip_stream(stream))
+            .keep_alive(Default::default())
+            .into_response()
+    } else {
+        let bytes = body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .inspect_err(|err| {
+                warn!("Failed to read response body: {}", err);
+            })
+            .unwrap_or_default();
+        match serde_json::from_slice::<Value>(&bytes) {
+            Ok(mut response) => {
+                if let Some(content) = response["content"].as_array_mut() {
+                    content.retain(|block| !is_thinking_block(block));
+                }
+                Json(response).into_response()
+            }
+            Err(_) => Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(bytes))
+                .unwrap(),
+        }
+    };
+    resp.extensions_mut().insert(cx);
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{StreamExt, stream};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn thinking_is_filtered_from_stream() {
+        let upstream = [
+            r#"{"type":"message_start","message":{"id":"msg","content":[]}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"thinking","thinking":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"hmm"}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"signature_delta","signature":"sig"}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"text_delta","text":"Hi"}}"#,
+            r#"{"type":"content_block_stop","index":1}"#,
+            r#"{"type":"message_stop"}"#,
+        ]
+        .map(|data| format!("event: message\ndata: {data}\n\n"))
+        .concat();
+        let source = stream::iter([Ok::<_, axum::Error>(upstream)]).eventsource();
+        let body = Sse::new(strip_stream(source)).into_response().into_body();
+        let bytes = body::to_bytes(body, usize::MAX).await.unwrap();
+        let events = stream::iter([Ok::<_, axum::Error>(bytes)])
+            .eventsource()
+            .map(|e| serde_json::from_str::<Value>(&e.unwrap().data).unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(events.len(), 5);
+        assert!(events.iter().all(|e| !e.to_string().contains("thinking")));
+        assert_eq!(events[1]["type"], "content_block_start");
+        assert_eq!(events[1]["index"], 0);
+        assert_eq!(events[2]["delta"]["text"], "Hi");
+        assert_eq!(events[3]["index"], 0);
+    }
+
+    #[test]
+    fn header_overrides_config() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HIDE_THINKING_HEADER, "true".parse().unwrap());
+        assert!(hide_thinking(&headers));
+        headers.insert(HIDE_THINKING_HEADER, "0".parse().unwrap());
+        assert!(!hide_thinking(&headers));
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use async_stream::try_stream;
+use axum::response::{IntoResponse, Response, Sse, sse::Event};
+use eventsource_stream::{Event as SourceEvent, Eventsource};
+use futures::Stream;
+use tracing::warn;
+
+use crate::{
+    config::CLEWDR_CONFIG,
+    middleware::claude::ClaudeContext,
+    types::claude::{ContentBlock, ContentBlockDelta, StreamEvent},
+};
+
+type EventResult<T> = Result<T, eventsource_stream::EventStreamError<axum::Error>>;
+
+/// Collects the `input_json_delta` fragments of streamed tool_use blocks
+///
+/// Fragments are partial JSON that only parses once concatenated, so they
+/// are checked as a whole when their block closes.
+#[derive(Default)]
+struct ToolInputTracker {
+    /// Concatenated input of the open tool_use blocks, by block index
+    inputs: HashMap<usize, String>,
+}
+
+impl ToolInputTracker {
+    /// Observes one event, returning the block index and parse error of a
+    /// tool input that closed malformed
+    fn observe(&mut self, data: &str) -> Option<(usize, serde_json::Error)> {
+        match serde_json::from_str::<StreamEvent>(data).ok()? {
+            StreamEvent::ContentBlockStart {
+                index,
+                content_block: ContentBlock::ToolUse { .. },
+            } => {
+                self.inputs.insert(index, String::new());
+                None
+            }
+            StreamEvent::ContentBlockDelta {
+                index,
+                delta: ContentBlockDelta::InputJsonDelta { partial_json },
+            } => {
+                if let Some(input) = self.inputs.get_mut(&index) {
+                    input.push_str(&partial_json);
+                }
+                None
+            }
+            StreamEvent::ContentBlockStop { index } => {
+                let input = self.inputs.remove(&index)?;
+                // without deltas the input given at block start stands
+                if input.is_empty() {
+                    return None;
+                }
+                serde_json::from_str::<serde_json::Value>(&input)
+                    .err()
+                    .map(|e| (index, e))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn check_stream(
+    stream: impl Stream<Item = EventResult<SourceEvent>>,
+) -> impl Stream<Item = EventResult<Event>> {
+    try_stream!({
+        let mut tracker = ToolInputTracker::default();
+        for await event in stream {
+            let SourceEvent {
+                data,
+                id,
+                event,
+                retry,
+            } = event?;
+            if let Some((index, err)) = tracker.observe(&data) {
+                warn!(
+                    "Malformed tool_use input in content block {}: {}",
+                    index, err
+                );
+            }
+            let out = Event::default().event(&event).id(id).data(data);
+            yield if let Some(retry) = retry {
+                out.retry(retry)
+            } else {
+                out
+            };
+        }
+    })
+}
+
+/// Logs streamed tool_use inputs whose JSON is malformed once complete,
+/// when `validate_tool_input` is enabled
+///
+/// Events are passed on untouched, fixing the input is left to the client.
+pub async fn check_tool_input(resp: Response) -> Response {
+    if !CLEWDR_CONFIG.load().validate_tool_input || !resp.status().is_success() {
+        return resp;
+    }
+    let Some(cx) = resp.extensions().get::<ClaudeContext>().cloned() else {
+        return resp;
+    };
+    if !cx.is_stream() {
+        return resp;
+    }
+    let stream = resp.into_body().into_data_stream().eventsource();
+    let mut resp = Sse::new(check_stream(stream))
+        .keep_alive(Default::default())
+        .into_response();
+    resp.extensions_mut().insert(cx);
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Events of a tool_use block whose input arrives in `fragments`
+    fn tool_use_events(fragments: &[&str]) -> Vec<String> {
+        let mut events = vec![
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"search","input":{}}}"#.to_string(),
+        ];
+        events.extend(fragments.iter().map(|partial_json| {
+            serde_json::json!({
+                "type": "content_block_delta",
+                "index": 1,
+                "delta": { "type": "input_json_delta", "partial_json": partial_json },
+            })
+            .to_string()
+        }));
+        events.push(r#"{"type":"content_block_stop","index":1}"#.to_string());
+        events
+    }
+
+    #[test]
+    fn tool_input_is_validated_once_complete() {
+        let mut tracker = ToolInputTracker::default();
+        let events = tool_use_events(&[r#"{"query": "#, r#""rust "#, r#"END"}"#]);
+        assert!(events.iter().all(|e| tracker.observe(e).is_none()));
+
+        let mut tracker = ToolInputTracker::default();
+        let events = tool_use_events(&[r#"{"query": "#, r#""rust"#]);
+        let errors = events
+            .iter()
+            .filter_map(|e| tracker.observe(e))
+            .collect::<Vec<_>>();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 1);
+    }
+}
@@ -6,7 +6,17 @@
 /// - Authentication: Verify API keys for different authentication methods (admin, OpenAI, Claude)
 /// - Request preprocessing: Normalize requests from different API formats
 /// - Response transformation: Convert between different response formats and handle streaming
+mod access_log;
 mod auth;
+mod body_limit;
+mod body_log;
 pub mod claude;
+mod rate_limit;
+mod rejections;
 
+pub use access_log::{ACCESS_TARGET, json_log_layer, log_access};
 pub use auth::{RequireAdminAuth, RequireBearerAuth, RequireFlexibleAuth};
+pub use body_limit::with_body_limit;
+pub use body_log::{BODIES_TARGET, log_bodies};
+pub use rate_limit::rate_limit;
+pub use rejections::{REJECTIONS_TARGET, log_rejections};
@@ -0,0 +1,37 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use tracing::warn;
+
+use crate::{
+    config::CLEWDR_CONFIG,
+    error::ClewdrError,
+    services::rate_limiter::{self, RouteGroup},
+};
+
+/// Rejects requests over the configured per-minute limit of a route group
+///
+/// Used with `from_fn_with_state`, the state naming the group to count the
+/// request against. Limits are read per request, so they hot reload.
+pub async fn rate_limit(
+    State(group): State<RouteGroup>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ClewdrError> {
+    let limit = group.limit(&CLEWDR_CONFIG.load().rate_limits);
+    if let Err(wait) = rate_limiter::try_acquire(group, limit) {
+        let group: &'static str = group.into();
+        warn!(
+            "Rate limit of {} requests per minute hit for {}",
+            limit, group
+        );
+        return Err(ClewdrError::RateLimited {
+            group,
+            limit,
+            retry_after: wait.as_secs().max(1),
+        });
+    }
+    Ok(next.run(req).await)
+}
@@ -0,0 +1,102 @@
+use axum::{extract::Request, middleware::Next, response::Response};
+use http::{Method, StatusCode, header::CONTENT_LENGTH};
+use tracing::{debug, warn};
+
+use crate::{config::CLEWDR_CONFIG, error::ErrorReason};
+
+/// Tracing target of rejected and failed requests, `error_log_file` routes
+/// it to its own file
+pub const REJECTIONS_TARGET: &str = "clewdr::rejections";
+
+/// Longest reason kept in a rejection entry
+const MAX_REASON_CHARS: usize = 300;
+
+/// One line describing a failed request
+///
+/// Only the path, the body size and the error are included, never the query
+/// string or the body, so that keys and user content stay out of the log.
+fn rejection_summary(
+    method: &Method,
+    path: &str,
+    status: StatusCode,
+    body_len: Option<u64>,
+    reason: Option<&str>,
+) -> String {
+    let mut reason = reason
+        .or(status.canonical_reason())
+        .unwrap_or("unknown")
+        .to_string();
+    if let Some((cut, _)) = reason.char_indices().nth(MAX_REASON_CHARS) {
+        reason.truncate(cut);
+        reason.push('…');
+    }
+    let body = body_len.map_or_else(|| "unknown".to_string(), |len| format!("{len} bytes"));
+    format!(
+        "{} {} -> {}, body: {}, reason: {}",
+        method,
+        path,
+        status.as_u16(),
+        body,
+        reason
+    )
+}
+
+/// Logs requests that end in an error response under [`REJECTIONS_TARGET`]
+///
+/// They are warnings when `error_log_file` collects them, otherwise debug
+/// entries of the main log. Not found responses without an error reason come
+/// from static file serving and are skipped.
+pub async fn log_rejections(req: Request, next: Next) -> Response {
+    let method = req.method().to_owned();
+    let path = req.uri().path().to_owned();
+    let body_len = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse().ok());
+    let resp = next.run(req).await;
+    let status = resp.status();
+    let reason = resp.extensions().get::<ErrorReason>().map(|r| r.0.as_str());
+    if (status.is_client_error() || status.is_server_error())
+        && !(status == StatusCode::NOT_FOUND && reason.is_none())
+    {
+        let summary = rejection_summary(&method, &path, status, body_len, reason);
+        let config = CLEWDR_CONFIG.load();
+        if config.error_log_file.is_some() && !config.no_fs {
+            warn!(target: REJECTIONS_TARGET, "{}", summary);
+        } else {
+            debug!(target: REJECTIONS_TARGET, "{}", summary);
+        }
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_keeps_user_content_out() {
+        let summary = rejection_summary(
+            &Method::POST,
+            "/v1/messages",
+            StatusCode::UNAUTHORIZED,
+            Some(42),
+            Some("Key/Password Invalid"),
+        );
+        assert_eq!(
+            summary,
+            "POST /v1/messages -> 401, body: 42 bytes, reason: Key/Password Invalid"
+        );
+
+        let long = "x".repeat(1000);
+        let summary = rejection_summary(
+            &Method::POST,
+            "/v1/messages",
+            StatusCode::BAD_GATEWAY,
+            None,
+            Some(&long),
+        );
+        assert!(summary.ends_with(&format!("{}…", "x".repeat(MAX_REASON_CHARS))));
+        assert!(summary.contains("body: unknown"));
+    }
+}
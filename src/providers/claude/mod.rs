@@ -2,19 +2,43 @@ use std::{sync::Arc, time::Instant};
 
 use axum::response::Response;
 use colored::Colorize;
+use serde_json::Value;
 use tracing::info;
 
 use super::LLMProvider;
 use crate::{
     claude_code_state::ClaudeCodeState,
     claude_web_state::ClaudeWebState,
+    config::CLEWDR_CONFIG,
     error::ClewdrError,
     middleware::claude::{ClaudeApiFormat, ClaudeContext},
-    services::cookie_actor::CookieActorHandle,
+    services::{
+        cookie_actor::CookieActorHandle,
+        stream_coalescer::{coalesce, coalesce_key},
+        stream_limiter::StreamPermit,
+    },
     types::claude::CreateMessageParams,
     utils::{enabled, print_out_json},
 };
 
+/// Takes a streaming slot for streaming requests, non-streaming ones are not limited
+fn acquire_stream_permit(stream: bool) -> Result<Option<StreamPermit>, ClewdrError> {
+    if !stream {
+        return Ok(None);
+    }
+    let max = CLEWDR_CONFIG.load().max_concurrent_streams;
+    StreamPermit::try_acquire(max)
+        .map(Some)
+        .ok_or(ClewdrError::TooManyStreams { max })
+}
+
+fn hold_stream_permit(permit: Option<StreamPermit>, response: Response) -> Response {
+    match permit {
+        Some(permit) => permit.hold(response),
+        None => response,
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum ClaudeOperation {
     Messages,
@@ -95,6 +119,20 @@ impl ClaudeWebProvider {
     fn new(shared: Arc<ClaudeSharedState>) -> Self {
         Self { shared }
     }
+
+    /// Upstream request body for a dry run, nothing is sent
+    ///
+    /// No cookie is taken, so the model is left out as for a free account
+    /// and images are not uploaded.
+    pub fn dry_run(&self, params: CreateMessageParams) -> Result<Value, ClewdrError> {
+        let state = ClaudeWebState::new(self.shared.cookie_actor_handle.clone());
+        let body = state
+            .transform_request(params)
+            .ok_or(ClewdrError::BadRequest {
+                msg: "Request body is empty",
+            })?;
+        Ok(serde_json::to_value(body)?)
+    }
 }
 
 #[async_trait::async_trait]
@@ -107,6 +145,8 @@ impl LLMProvider for ClaudeWebProvider {
         let stream = request.context.is_stream();
         state.api_format = request.context.api_format();
         state.stream = stream;
+        state.proxy_hops = request.context.proxy_hops();
+        state.client_timeout = request.context.client_timeout();
         state.usage = request.context.usage().to_owned();
         let ClaudeInvocation {
             params,
@@ -131,8 +171,15 @@ impl LLMProvider for ClaudeWebProvider {
             format_display
         );
         print_out_json(&params, "claude_web_client_req.json");
+        let permit = acquire_stream_permit(stream)?;
         let stopwatch = Instant::now();
-        let response = state.try_chat(params).await?;
+        let response = if stream && CLEWDR_CONFIG.load().coalesce_streams {
+            let key = coalesce_key("claude_web", &params);
+            coalesce(key, || state.try_chat(params)).await?
+        } else {
+            state.try_chat(params).await?
+        };
+        let response = hold_stream_permit(permit, response);
         let elapsed = stopwatch.elapsed();
         info!(
             "[FIN] elapsed: {}s",
@@ -151,6 +198,28 @@ impl ClaudeCodeProvider {
     fn new(shared: Arc<ClaudeSharedState>) -> Self {
         Self { shared }
     }
+
+    /// Upstream request body for a dry run, nothing is sent
+    pub fn dry_run(&self, params: CreateMessageParams) -> Result<Value, ClewdrError> {
+        let body = ClaudeCodeState::upstream_params(params);
+        Ok(serde_json::to_value(body)?)
+    }
+
+    /// State a request is sent with, carrying over what the client asked for
+    pub(crate) fn state(&self, context: &ClaudeContext) -> ClaudeCodeState {
+        let mut state = ClaudeCodeState::new(self.shared.cookie_actor_handle.clone());
+        state.api_format = context.api_format();
+        state.stream = context.is_stream();
+        state.system_prompt_hash = context.system_prompt_hash();
+        state.anthropic_beta_header = context.anthropic_beta().map(str::to_string);
+        if let Some(version) = context.anthropic_version() {
+            state.anthropic_version = version.to_string();
+        }
+        state.proxy_hops = context.proxy_hops();
+        state.client_timeout = context.client_timeout();
+        state.usage = context.usage().to_owned();
+        state
+    }
 }
 
 #[async_trait::async_trait]
@@ -159,12 +228,7 @@ impl LLMProvider for ClaudeCodeProvider {
     type Output = ClaudeProviderResponse;
 
     async fn invoke(&self, request: Self::Request) -> Result<Self::Output, ClewdrError> {
-        let mut state = ClaudeCodeState::new(self.shared.cookie_actor_handle.clone());
-        state.api_format = request.context.api_format();
-        state.stream = request.context.is_stream();
-        state.system_prompt_hash = request.context.system_prompt_hash();
-        state.anthropic_beta_header = request.context.anthropic_beta().map(str::to_string);
-        state.usage = request.context.usage().to_owned();
+        let mut state = self.state(&request.context);
         let ClaudeInvocation {
             params,
             context,
@@ -184,8 +248,22 @@ impl LLMProvider for ClaudeCodeProvider {
                     format_display
                 );
                 print_out_json(&params, "claude_code_client_req.json");
+                let permit = acquire_stream_permit(state.stream)?;
                 let stopwatch = Instant::now();
-                let response = state.try_chat(params).await?;
+                let response = if state.stream && CLEWDR_CONFIG.load().coalesce_streams {
+                    let key = coalesce_key(
+                        (
+                            "claude_code",
+                            state.anthropic_beta_header.to_owned(),
+                            state.anthropic_version.to_owned(),
+                        ),
+                        &params,
+                    );
+                    coalesce(key, || state.try_chat(params)).await?
+                } else {
+                    state.try_chat(params).await?
+                };
+                let response = hold_stream_permit(permit, response);
                 let elapsed = stopwatch.elapsed();
                 info!(
                     "[FIN] elapsed: {}s",
@@ -215,3 +293,34 @@ impl LLMProvider for ClaudeCodeProvider {
 pub fn build_providers(cookie_actor_handle: CookieActorHandle) -> ClaudeProviders {
     ClaudeProviders::new(cookie_actor_handle)
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn dry_run_returns_the_upstream_body() {
+        let providers = build_providers(CookieActorHandle::start().await.unwrap());
+        let params = serde_json::from_value::<CreateMessageParams>(json!({
+            "model": "claude-sonnet-4-6-1M",
+            "max_tokens": 64,
+            "system": "Stay in character.",
+            "messages": [{"role": "user", "content": "Hello there"}],
+        }))
+        .unwrap();
+
+        let code = providers.code().dry_run(params.clone()).unwrap();
+        let mut expected = serde_json::to_value(&params).unwrap();
+        expected["model"] = json!("claude-sonnet-4-6");
+        assert_eq!(code, expected);
+
+        let web = providers.web().dry_run(params).unwrap();
+        assert_eq!(web["max_tokens_to_sample"], 64);
+        assert!(web.get("model").is_none());
+        let prompt = web["prompt"].as_str().unwrap();
+        assert!(prompt.contains("Stay in character."));
+        assert!(prompt.contains("Hello there"));
+    }
+}
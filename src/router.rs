@@ -1,23 +1,79 @@
+use std::time::Duration;
+
 use axum::{
     Router,
-    extract::DefaultBodyLimit,
-    http::Method,
-    middleware::{from_extractor, map_response},
+    http::{Method, StatusCode},
+    middleware::{from_extractor, from_fn, from_fn_with_state, map_response},
     routing::{delete, get, post},
 };
+use http::header::HeaderName;
 use tower::ServiceBuilder;
-use tower_http::{compression::CompressionLayer, cors::CorsLayer};
+use tower_http::{compression::CompressionLayer, cors::CorsLayer, timeout::TimeoutLayer};
+use tracing::warn;
 
 use crate::{
     api::*,
+    config::{CLEWDR_CONFIG, LogFormat},
     middleware::{
         RequireAdminAuth, RequireBearerAuth, RequireFlexibleAuth,
-        claude::{add_usage_info, apply_stop_sequences, check_overloaded, to_oai},
+        claude::{
+            CLIENT_TIMEOUT_HEADERS, DRY_RUN_HEADER, HIDE_THINKING_HEADER, IGNORED_PARAMS_HEADER,
+            add_usage_info, apply_response_rewrites, apply_stop_sequences, apply_stream_chunk_mode,
+            check_overloaded, check_tool_input, legacy_completions, restore_requested_model,
+            strip_thinking, to_oai, warn_ignored_params,
+        },
+        log_access, log_bodies, log_rejections, rate_limit, with_body_limit,
     },
     providers::claude::ClaudeProviders,
-    services::cookie_actor::CookieActorHandle,
+    services::{cookie_actor::CookieActorHandle, rate_limiter::RouteGroup},
 };
 
+/// Request headers clients and the admin UI send, allowed by CORS along
+/// with `CLIENT_TIMEOUT_HEADERS`
+const CORS_ALLOW_HEADERS: &[&str] = &[
+    "authorization",
+    "content-type",
+    "x-api-key",
+    "anthropic-version",
+    "anthropic-beta",
+    HIDE_THINKING_HEADER,
+    DRY_RUN_HEADER,
+];
+
+/// Response headers browsers may read, on top of the CORS safelisted ones
+const CORS_EXPOSE_HEADERS: &[&str] = &["retry-after", "content-disposition", IGNORED_PARAMS_HEADER];
+
+/// Built-in CORS header names followed by valid configured extras
+fn cors_headers(builtin: &[&'static str], extra: &[String]) -> Vec<HeaderName> {
+    let extra = extra.iter().filter_map(|name| {
+        HeaderName::try_from(name.trim())
+            .inspect_err(|_| warn!("Ignoring invalid CORS header name: {}", name))
+            .ok()
+    });
+    let mut headers = Vec::<HeaderName>::new();
+    for name in builtin
+        .iter()
+        .map(|name| HeaderName::from_static(name))
+        .chain(extra)
+    {
+        if !headers.contains(&name) {
+            headers.push(name);
+        }
+    }
+    headers
+}
+
+/// Wall-clock limit for a route group, answering 504 on expiry, `None` when
+/// `secs` is 0
+///
+/// The limit covers the handler until the response is ready, including
+/// retries and bootstrap, but not the streaming of an SSE body afterwards.
+fn timeout_layer(secs: u64) -> Option<TimeoutLayer> {
+    (secs > 0).then(|| {
+        TimeoutLayer::with_status_code(StatusCode::GATEWAY_TIMEOUT, Duration::from_secs(secs))
+    })
+}
+
 /// RouterBuilder for the application
 pub struct RouterBuilder {
     claude_providers: ClaudeProviders,
@@ -52,21 +108,41 @@ impl RouterBuilder {
             .route_claude_web_oai_endpoints()
             .route_claude_code_oai_endpoints()
             .setup_static_serving()
+            .with_access_log()
             .with_tower_trace()
+            .with_rejection_log()
             .with_cors()
     }
 
     /// Sets up routes for v1 endpoints
     fn route_claude_web_endpoints(mut self) -> Self {
         let router = Router::new()
-            .route("/v1/messages", post(api_claude_web))
+            .route(
+                "/v1/messages",
+                post(api_claude_web)
+                    .route_layer(from_fn_with_state(RouteGroup::ClaudeWeb, rate_limit)),
+            )
+            .route(
+                "/v1/messages/count_tokens",
+                post(api_claude_web_count_tokens)
+                    .route_layer(from_fn_with_state(RouteGroup::CountTokens, rate_limit)),
+            )
             .layer(
                 ServiceBuilder::new()
+                    .option_layer(timeout_layer(CLEWDR_CONFIG.load().request_timeout))
                     .layer(from_extractor::<RequireFlexibleAuth>())
                     .layer(CompressionLayer::new())
+                    .layer(from_fn(log_bodies))
+                    .layer(map_response(apply_stream_chunk_mode))
+                    .layer(map_response(warn_ignored_params))
                     .layer(map_response(add_usage_info))
+                    .layer(map_response(to_oai))
+                    .layer(map_response(restore_requested_model))
+                    .layer(map_response(strip_thinking))
+                    .layer(map_response(apply_response_rewrites))
                     .layer(map_response(apply_stop_sequences))
-                    .layer(map_response(check_overloaded)),
+                    .layer(map_response(check_overloaded))
+                    .layer(map_response(check_tool_input)),
             )
             .with_state(self.claude_providers.web());
         self.inner = self.inner.merge(router);
@@ -76,15 +152,29 @@ impl RouterBuilder {
     /// Sets up routes for v1 endpoints
     fn route_claude_code_endpoints(mut self) -> Self {
         let router = Router::new()
-            .route("/code/v1/messages", post(api_claude_code))
+            .route(
+                "/code/v1/messages",
+                post(api_claude_code)
+                    .route_layer(from_fn_with_state(RouteGroup::ClaudeCode, rate_limit)),
+            )
             .route(
                 "/code/v1/messages/count_tokens",
-                post(api_claude_code_count_tokens),
+                post(api_claude_code_count_tokens)
+                    .route_layer(from_fn_with_state(RouteGroup::CountTokens, rate_limit)),
             )
             .layer(
                 ServiceBuilder::new()
+                    .option_layer(timeout_layer(CLEWDR_CONFIG.load().request_timeout))
                     .layer(from_extractor::<RequireFlexibleAuth>())
-                    .layer(CompressionLayer::new()),
+                    .layer(CompressionLayer::new())
+                    .layer(from_fn(log_bodies))
+                    .layer(map_response(apply_stream_chunk_mode))
+                    .layer(map_response(warn_ignored_params))
+                    .layer(map_response(to_oai))
+                    .layer(map_response(restore_requested_model))
+                    .layer(map_response(strip_thinking))
+                    .layer(map_response(apply_response_rewrites))
+                    .layer(map_response(check_tool_input)),
             )
             .with_state(self.claude_providers.code());
         self.inner = self.inner.merge(router);
@@ -95,19 +185,49 @@ impl RouterBuilder {
     fn route_admin_endpoints(mut self) -> Self {
         let cookie_router = Router::new()
             .route("/cookies", get(api_get_cookies))
+            .route("/cookies/usage/reset", post(api_reset_cookie_usage))
             .route("/cookie", delete(api_delete_cookie).post(api_post_cookie))
             .with_state(self.cookie_actor_handle.to_owned());
         let admin_router = Router::new()
             .route("/auth", get(api_auth))
-            .route("/config", get(api_get_config).post(api_post_config));
+            .route("/config", get(api_get_config).post(api_post_config))
+            .route("/config/download", get(api_download_config))
+            .route("/config/upload", post(api_upload_config))
+            .route(
+                "/admin-tokens",
+                get(api_get_admin_tokens)
+                    .post(api_post_admin_token)
+                    .delete(api_delete_admin_token),
+            )
+            .route("/stats", get(api_get_stats))
+            .route("/proxy/test", post(api_test_proxy));
+        // validating a large pool outlasts the admin timeout, cutting it off
+        // would lose the summary of cookies already reclassified
+        let untimed_router = Router::new()
+            .route("/cookies/validate", post(api_validate_cookies))
+            .with_state(self.cookie_actor_handle.to_owned())
+            .layer(from_extractor::<RequireAdminAuth>());
+        let admin_timeout = timeout_layer(CLEWDR_CONFIG.load().admin_request_timeout);
         let router = Router::new()
             .nest(
                 "/api",
                 cookie_router
                     .merge(admin_router)
-                    .layer(from_extractor::<RequireAdminAuth>()),
+                    .layer(from_extractor::<RequireAdminAuth>())
+                    .layer(ServiceBuilder::new().option_layer(admin_timeout))
+                    .merge(untimed_router),
             )
-            .route("/api/version", get(api_version));
+            .route("/api/version", get(api_version))
+            .route("/health", get(api_health))
+            .route(
+                "/ready",
+                get(api_ready).with_state(self.cookie_actor_handle.to_owned()),
+            );
+        #[cfg(feature = "metrics")]
+        let router = router.route(
+            "/metrics",
+            get(api_metrics).with_state(self.cookie_actor_handle.to_owned()),
+        );
         self.inner = self.inner.merge(router);
         self
     }
@@ -115,15 +235,33 @@ impl RouterBuilder {
     /// Sets up routes for OpenAI compatible endpoints
     fn route_claude_web_oai_endpoints(mut self) -> Self {
         let router = Router::new()
-            .route("/v1/chat/completions", post(api_claude_web))
+            .route(
+                "/v1/chat/completions",
+                post(api_claude_web)
+                    .route_layer(from_fn_with_state(RouteGroup::ClaudeWeb, rate_limit)),
+            )
+            .route(
+                "/v1/completions",
+                post(api_claude_web)
+                    .route_layer(from_fn_with_state(RouteGroup::ClaudeWeb, rate_limit)),
+            )
             .route("/v1/models", get(api_get_models))
             .layer(
                 ServiceBuilder::new()
+                    .option_layer(timeout_layer(CLEWDR_CONFIG.load().request_timeout))
                     .layer(from_extractor::<RequireBearerAuth>())
                     .layer(CompressionLayer::new())
+                    .layer(from_fn(log_bodies))
+                    .layer(map_response(apply_stream_chunk_mode))
+                    .layer(map_response(warn_ignored_params))
+                    .layer(from_fn(legacy_completions))
                     .layer(map_response(to_oai))
+                    .layer(map_response(restore_requested_model))
+                    .layer(map_response(strip_thinking))
+                    .layer(map_response(apply_response_rewrites))
                     .layer(map_response(apply_stop_sequences))
-                    .layer(map_response(check_overloaded)),
+                    .layer(map_response(check_overloaded))
+                    .layer(map_response(check_tool_input)),
             )
             .with_state(self.claude_providers.web());
         self.inner = self.inner.merge(router);
@@ -133,13 +271,31 @@ impl RouterBuilder {
     /// Sets up routes for OpenAI compatible endpoints
     fn route_claude_code_oai_endpoints(mut self) -> Self {
         let router = Router::new()
-            .route("/code/v1/chat/completions", post(api_claude_code))
+            .route(
+                "/code/v1/chat/completions",
+                post(api_claude_code)
+                    .route_layer(from_fn_with_state(RouteGroup::ClaudeCode, rate_limit)),
+            )
+            .route(
+                "/code/v1/completions",
+                post(api_claude_code)
+                    .route_layer(from_fn_with_state(RouteGroup::ClaudeCode, rate_limit)),
+            )
             .route("/code/v1/models", get(api_get_models))
             .layer(
                 ServiceBuilder::new()
+                    .option_layer(timeout_layer(CLEWDR_CONFIG.load().request_timeout))
                     .layer(from_extractor::<RequireBearerAuth>())
                     .layer(CompressionLayer::new())
-                    .layer(map_response(to_oai)),
+                    .layer(from_fn(log_bodies))
+                    .layer(map_response(apply_stream_chunk_mode))
+                    .layer(map_response(warn_ignored_params))
+                    .layer(from_fn(legacy_completions))
+                    .layer(map_response(to_oai))
+                    .layer(map_response(restore_requested_model))
+                    .layer(map_response(strip_thinking))
+                    .layer(map_response(apply_response_rewrites))
+                    .layer(map_response(check_tool_input)),
             )
             .with_state(self.claude_providers.code());
         self.inner = self.inner.merge(router);
@@ -169,23 +325,35 @@ impl RouterBuilder {
     }
 
     /// Adds CORS support to the router
+    ///
+    /// The header lists are read once at startup, on top of the built-in
+    /// ones `cors_allow_headers` and `cors_expose_headers` add more.
     fn with_cors(mut self) -> Self {
-        use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
-        use http::header::HeaderName;
-
+        let config = CLEWDR_CONFIG.load();
         let cors = CorsLayer::new()
             .allow_origin(tower_http::cors::Any)
             .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
-            .allow_headers([
-                AUTHORIZATION,
-                CONTENT_TYPE,
-                HeaderName::from_static("x-api-key"),
-            ]);
+            .allow_headers(cors_headers(
+                &[CORS_ALLOW_HEADERS, CLIENT_TIMEOUT_HEADERS].concat(),
+                &config.cors_allow_headers,
+            ))
+            .expose_headers(cors_headers(
+                CORS_EXPOSE_HEADERS,
+                &config.cors_expose_headers,
+            ));
 
         self.inner = self.inner.layer(cors);
         self
     }
 
+    /// Logs an access entry for each request when `log_format` is `json`
+    fn with_access_log(mut self) -> Self {
+        if CLEWDR_CONFIG.load().log_format == LogFormat::Json {
+            self.inner = self.inner.layer(from_fn(log_access));
+        }
+        self
+    }
+
     fn with_tower_trace(mut self) -> Self {
         use tower_http::trace::TraceLayer;
 
@@ -195,9 +363,41 @@ impl RouterBuilder {
         self
     }
 
+    /// Logs requests ending in an error response to the rejection log
+    fn with_rejection_log(mut self) -> Self {
+        self.inner = self.inner.layer(from_fn(log_rejections));
+        self
+    }
+
+    /// Handle of the cookie actor the routes talk to
+    pub fn cookie_actor_handle(&self) -> CookieActorHandle {
+        self.cookie_actor_handle.to_owned()
+    }
+
     /// Returns the configured router
     /// Finalizes the router configuration for use with axum
     pub fn build(self) -> Router {
-        self.inner.layer(DefaultBodyLimit::max(32 * 1024 * 1024))
+        with_body_limit(self.inner, CLEWDR_CONFIG.load().max_body_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configured_cors_headers_extend_builtin_ones() {
+        let extra = [
+            "X-Custom".to_string(),
+            "x-api-key".to_string(),
+            "bad header".to_string(),
+        ];
+        let builtin = [CORS_ALLOW_HEADERS, CLIENT_TIMEOUT_HEADERS].concat();
+        let headers = cors_headers(&builtin, &extra);
+        assert_eq!(headers.len(), builtin.len() + 1);
+        assert!(headers.contains(&HeaderName::from_static("anthropic-beta")));
+        assert!(headers.contains(&HeaderName::from_static(DRY_RUN_HEADER)));
+        assert!(headers.contains(&HeaderName::from_static("x-stainless-timeout")));
+        assert!(headers.contains(&HeaderName::from_static("x-custom")));
     }
 }
@@ -1,21 +1,152 @@
-use std::collections::{HashSet, VecDeque};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
 
+use axum::{body::Body, response::Response};
 use chrono::Utc;
 use colored::Colorize;
+use futures::StreamExt;
 use moka::sync::Cache;
 use ractor::{Actor, ActorProcessingErr, ActorRef, RpcReplyPort};
 use serde::Serialize;
 use snafu::{GenerateImplicitData, Location};
+use tokio::time::{Instant, sleep};
 use tracing::{error, info, warn};
 
 use crate::{
-    config::{CLEWDR_CONFIG, ClewdrConfig, CookieStatus, Reason, UsageBreakdown, UselessCookie},
+    config::{
+        CLEWDR_CONFIG, ClewdrConfig, ClewdrCookie, CookieNeed, CookieStatus, Reason,
+        UsageBreakdown, UselessCookie, is_pro_capabilities,
+    },
     error::ClewdrError,
 };
 
 const INTERVAL: u64 = 300;
 const SESSION_WINDOW_SECS: i64 = 5 * 60 * 60; // 5h
 const WEEKLY_WINDOW_SECS: i64 = 7 * 24 * 60 * 60; // 7d
+/// How soon to look again for a cookie when every fitting one is at its
+/// `per_cookie_concurrency` limit
+const BUSY_RETRY: Duration = Duration::from_millis(200);
+
+/// Whether the valid cookie count was below `min_healthy_cookies` at the last check
+static POOL_LOW: AtomicBool = AtomicBool::new(false);
+/// Unix time of the last low cookie pool warning
+static LAST_LOW_WARNING: AtomicI64 = AtomicI64::new(0);
+
+/// Whether fewer valid cookies than `min_healthy_cookies` remain
+pub fn cookie_pool_low() -> bool {
+    POOL_LOW.load(Ordering::Relaxed)
+}
+
+/// Whether a low pool warning is due, repeated at most once per `INTERVAL`
+/// while the pool stays low
+fn low_warning_due(was_low: bool, last_warning: i64, now: i64) -> bool {
+    !was_low || now - last_warning >= INTERVAL as i64
+}
+
+/// How well a cookie fits `need`, lower is better, or `None` when it cannot
+/// serve it
+///
+/// Cookies whose plan is not known yet get the benefit of the doubt.
+fn fit(cookie: &CookieStatus, need: CookieNeed) -> Option<u8> {
+    if need == CookieNeed::Any || cookie.is_pro != Some(false) {
+        return Some(0);
+    }
+    (need == CookieNeed::PreferPro).then_some(1)
+}
+
+/// Best fit for `need` among `valid`, `None` when no cookie can serve it
+fn best_fit(valid: &VecDeque<CookieStatus>, need: CookieNeed) -> Option<u8> {
+    valid.iter().filter_map(|c| fit(c, need)).min()
+}
+
+/// A request in flight on a cookie, counted against `per_cookie_concurrency`
+/// until dropped
+#[derive(Debug)]
+pub struct CookieLease(Arc<AtomicUsize>);
+
+impl CookieLease {
+    fn take(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::AcqRel);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for CookieLease {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Keeps the cookie counted as in flight until the response body is fully
+/// sent or dropped
+pub fn hold_lease(lease: Option<Arc<CookieLease>>, response: Response) -> Response {
+    let Some(lease) = lease else {
+        return response;
+    };
+    response.map(|body| {
+        let stream = body.into_data_stream().map(move |chunk| {
+            let _lease = &lease;
+            chunk
+        });
+        Body::from_stream(stream)
+    })
+}
+
+/// Cookies with `max` requests in flight, `0` means unlimited
+///
+/// Counters no lease points to any more are dropped on the way.
+fn busy_cookies(
+    in_flight: &mut HashMap<ClewdrCookie, Arc<AtomicUsize>>,
+    max: usize,
+) -> HashSet<ClewdrCookie> {
+    in_flight.retain(|_, count| Arc::strong_count(count) > 1);
+    in_flight
+        .iter()
+        .filter(|(_, count)| max > 0 && count.load(Ordering::Acquire) >= max)
+        .map(|(cookie, _)| cookie.clone())
+        .collect()
+}
+
+/// Position of the first best fitting cookie in `valid` whose cooldown has
+/// passed and that is not `busy`, or how long until the earliest one may be
+fn next_eligible(
+    valid: &VecDeque<CookieStatus>,
+    last_dispatch: &HashMap<ClewdrCookie, Instant>,
+    busy: &HashSet<ClewdrCookie>,
+    cooldown: Duration,
+    now: Instant,
+    need: CookieNeed,
+) -> Result<usize, Duration> {
+    let best = best_fit(valid, need);
+    let fits = |c: &&CookieStatus| best.is_some() && fit(c, need) == best;
+    let remaining = |c: &CookieStatus| {
+        let cooling = last_dispatch.get(&c.cookie).map_or(Duration::ZERO, |&at| {
+            (at + cooldown).saturating_duration_since(now)
+        });
+        if busy.contains(&c.cookie) {
+            cooling.max(BUSY_RETRY)
+        } else {
+            cooling
+        }
+    };
+    valid
+        .iter()
+        .position(|c| fits(&c) && remaining(c).is_zero())
+        .ok_or_else(|| {
+            valid
+                .iter()
+                .filter(fits)
+                .map(remaining)
+                .min()
+                .unwrap_or_default()
+        })
+}
 
 #[derive(Debug, Serialize, Clone)]
 pub struct CookieStatusInfo {
@@ -33,12 +164,22 @@ enum CookieActorMessage {
     Submit(CookieStatus),
     /// Check for timed out Cookies
     CheckReset,
-    /// Request to get a Cookie
-    Request(Option<u64>, RpcReplyPort<Result<CookieStatus, ClewdrError>>),
+    /// Request to get a Cookie fitting a need
+    Request(
+        Option<u64>,
+        CookieNeed,
+        RpcReplyPort<Result<(CookieStatus, CookieLease), ClewdrError>>,
+    ),
     /// Get all Cookie status information
     GetStatus(RpcReplyPort<CookieStatusInfo>),
     /// Delete a Cookie
     Delete(CookieStatus, RpcReplyPort<Result<(), ClewdrError>>),
+    /// Record the capabilities of a Cookie's account
+    SetCapabilities(ClewdrCookie, Vec<String>),
+    /// Count a successful request and its tokens against a Cookie
+    RecordUse(ClewdrCookie, u64),
+    /// Zero the request counters of every Cookie
+    ResetUse,
 }
 
 /// CookieActor state - manages collections of cookies
@@ -48,6 +189,13 @@ struct CookieActorState {
     exhausted: HashSet<CookieStatus>,
     invalid: HashSet<UselessCookie>,
     moka: Cache<u64, CookieStatus>,
+    /// When each cookie was last handed out, for `per_cookie_rpm`
+    last_dispatch: HashMap<ClewdrCookie, Instant>,
+    /// Requests in flight on each cookie, for `per_cookie_concurrency`
+    in_flight: HashMap<ClewdrCookie, Arc<AtomicUsize>>,
+    /// Request counters changed since the last save, written on the next
+    /// save or timer tick rather than once per request
+    unsaved_use: bool,
 }
 
 /// Cookie actor that handles cookie distribution, collection, and status tracking using Ractor
@@ -55,7 +203,8 @@ struct CookieActor;
 
 impl CookieActor {
     /// Saves the current state of cookies to the configuration
-    fn save(state: &CookieActorState) {
+    fn save(state: &mut CookieActorState) {
+        state.unsaved_use = false;
         CLEWDR_CONFIG.rcu(|config| {
             let mut config = ClewdrConfig::clone(config);
             config.cookie_array = state
@@ -86,6 +235,29 @@ impl CookieActor {
             state.exhausted.len().to_string().yellow(),
             state.invalid.len().to_string().red(),
         );
+        Self::check_pool_health(state.valid.len());
+    }
+
+    /// Flags and warns about the pool when valid cookies fall below the minimum
+    fn check_pool_health(valid: usize) {
+        let min = CLEWDR_CONFIG.load().min_healthy_cookies;
+        let low = min > 0 && valid < min;
+        let was_low = POOL_LOW.swap(low, Ordering::Relaxed);
+        if !low {
+            if was_low {
+                info!("Valid cookies back to {}, minimum is {}", valid, min);
+            }
+            return;
+        }
+        let now = Utc::now().timestamp();
+        if low_warning_due(was_low, LAST_LOW_WARNING.load(Ordering::Relaxed), now) {
+            LAST_LOW_WARNING.store(now, Ordering::Relaxed);
+            warn!(
+                "Only {} valid cookies left, below the minimum of {}",
+                valid.to_string().red(),
+                min
+            );
+        }
     }
 
     /// Checks and resets cookies that have passed their reset time
@@ -172,34 +344,85 @@ impl CookieActor {
         changed
     }
 
-    /// Dispatches a cookie for use
+    /// Counts a new request in flight on `cookie`
+    fn lease(state: &mut CookieActorState, cookie: &ClewdrCookie) -> CookieLease {
+        CookieLease::take(state.in_flight.entry(cookie.clone()).or_default())
+    }
+
+    /// Dispatches a cookie for use, preferring the ones that best fit `need`
     fn dispatch(
         &self,
         state: &mut CookieActorState,
         hash: Option<u64>,
-    ) -> Result<CookieStatus, ClewdrError> {
+        need: CookieNeed,
+    ) -> Result<(CookieStatus, CookieLease), ClewdrError> {
         Self::reset(state);
+        let best = best_fit(&state.valid, need);
+        if best.is_none() && !state.valid.is_empty() {
+            return Err(ClewdrError::NoCapableCookie);
+        }
+        let config = CLEWDR_CONFIG.load();
+        let cooldown = config.cookie_cooldown();
+        let max_in_flight = config.per_cookie_concurrency;
+        let now = Instant::now();
+        state
+            .last_dispatch
+            .retain(|_, &mut at| now.duration_since(at) < cooldown);
+        let busy = busy_cookies(&mut state.in_flight, max_in_flight);
         if let Some(hash) = hash
             && let Some(cookie) = state.moka.get(&hash)
             && let Some(cookie) = state.valid.iter().find(|&c| c == &cookie)
+            && !state.last_dispatch.contains_key(&cookie.cookie)
+            && !busy.contains(&cookie.cookie)
+            && fit(cookie, need) == best
         {
             // renew moka cache
             state.moka.insert(hash, cookie.clone());
-            return Ok(cookie.clone());
+            let cookie = cookie.clone();
+            if !cooldown.is_zero() {
+                state.last_dispatch.insert(cookie.cookie.clone(), now);
+            }
+            let lease = Self::lease(state, &cookie.cookie);
+            return Ok((cookie, lease));
         }
-        let cookie = state
-            .valid
-            .pop_front()
-            .ok_or(ClewdrError::NoCookieAvailable)?;
+        if state.valid.is_empty() {
+            // with every cookie parked, tell the client when the first comes back
+            return Err(
+                match state.exhausted.iter().filter_map(|c| c.reset_time).min() {
+                    Some(reset_time) => ClewdrError::AllCookiesRateLimited { reset_time },
+                    None => ClewdrError::NoCookieAvailable,
+                },
+            );
+        }
+        let index = next_eligible(
+            &state.valid,
+            &state.last_dispatch,
+            &busy,
+            cooldown,
+            now,
+            need,
+        )
+        .map_err(|wait| ClewdrError::AllCookiesCoolingDown {
+            wait_ms: wait.as_millis().max(1) as u64,
+        })?;
+        let cookie = state.valid.remove(index).expect("index is in bounds");
         state.valid.push_back(cookie.clone());
         if let Some(hash) = hash {
             state.moka.insert(hash, cookie.clone());
         }
-        Ok(cookie)
+        if !cooldown.is_zero() {
+            state.last_dispatch.insert(cookie.cookie.clone(), now);
+        }
+        let lease = Self::lease(state, &cookie.cookie);
+        Ok((cookie, lease))
     }
 
     /// Collects a returned cookie and processes it based on the return reason
     fn collect(state: &mut CookieActorState, mut cookie: CookieStatus, reason: Option<Reason>) {
+        // request counters are kept here, the returned copy may predate some
+        if let Some(existing) = state.valid.iter().find(|c| **c == cookie) {
+            cookie.keep_use_of(existing);
+        }
         let Some(reason) = reason else {
             if let Some(existing) = state.valid.iter_mut().find(|c| **c == cookie) {
                 *existing = cookie;
@@ -274,6 +497,60 @@ impl CookieActor {
         Self::log(state);
     }
 
+    /// Records the capabilities found for a cookie and whether they make it
+    /// pro, saving only when they changed
+    fn set_capabilities(state: &mut CookieActorState, cookie: ClewdrCookie, caps: Vec<String>) {
+        let Some(existing) = state.valid.iter_mut().find(|c| c.cookie == cookie) else {
+            return;
+        };
+        if existing.capabilities != caps {
+            existing.is_pro = Some(is_pro_capabilities(&caps));
+            existing.capabilities = caps;
+            Self::save(state);
+        }
+    }
+
+    /// Counts a successful request against a cookie, parked or not,
+    /// returning whether it was found
+    fn record_use(
+        state: &mut CookieActorState,
+        cookie: &ClewdrCookie,
+        tokens: u64,
+        now: i64,
+    ) -> bool {
+        if let Some(existing) = state.valid.iter_mut().find(|c| c.cookie == *cookie) {
+            existing.record_use(tokens, now);
+            state.unsaved_use = true;
+            return true;
+        }
+        let Some(mut parked) = state
+            .exhausted
+            .iter()
+            .find(|c| c.cookie == *cookie)
+            .cloned()
+        else {
+            return false;
+        };
+        state.exhausted.remove(&parked);
+        parked.record_use(tokens, now);
+        state.exhausted.insert(parked);
+        state.unsaved_use = true;
+        true
+    }
+
+    /// Zeroes the request counters of every cookie
+    fn reset_use(state: &mut CookieActorState) {
+        state.valid.iter_mut().for_each(CookieStatus::reset_use);
+        state.exhausted = state
+            .exhausted
+            .drain()
+            .map(|mut c| {
+                c.reset_use();
+                c
+            })
+            .collect();
+    }
+
     /// Creates a report of all cookie statuses
     fn report(state: &CookieActorState) -> CookieStatusInfo {
         CookieStatusInfo {
@@ -343,6 +620,9 @@ impl Actor for CookieActor {
             exhausted,
             invalid,
             moka,
+            last_dispatch: HashMap::new(),
+            in_flight: HashMap::new(),
+            unsaved_use: false,
         };
 
         CookieActor::log(&state);
@@ -364,13 +644,14 @@ impl Actor for CookieActor {
             }
             CookieActorMessage::CheckReset => {
                 let changed = Self::refresh_usage_windows(state);
-                if changed {
+                if changed || state.unsaved_use {
                     Self::save(state);
                 }
                 Self::reset(state);
+                Self::check_pool_health(state.valid.len());
             }
-            CookieActorMessage::Request(cache_hash, reply_port) => {
-                let result = self.dispatch(state, cache_hash);
+            CookieActorMessage::Request(cache_hash, need, reply_port) => {
+                let result = self.dispatch(state, cache_hash, need);
                 reply_port.send(result)?;
             }
             CookieActorMessage::GetStatus(reply_port) => {
@@ -385,6 +666,17 @@ impl Actor for CookieActor {
                 let result = Self::delete(state, cookie.clone());
                 reply_port.send(result)?;
             }
+            CookieActorMessage::SetCapabilities(cookie, caps) => {
+                Self::set_capabilities(state, cookie, caps);
+            }
+            CookieActorMessage::RecordUse(cookie, tokens) => {
+                // saved later, see `unsaved_use`
+                Self::record_use(state, &cookie, tokens, Utc::now().timestamp());
+            }
+            CookieActorMessage::ResetUse => {
+                Self::reset_use(state);
+                Self::save(state);
+            }
         }
         Ok(())
     }
@@ -433,14 +725,38 @@ impl CookieActorHandle {
         });
     }
 
-    /// Request a cookie from the cookie actor
-    pub async fn request(&self, cache_hash: Option<u64>) -> Result<CookieStatus, ClewdrError> {
-        ractor::call!(self.actor_ref, CookieActorMessage::Request, cache_hash).map_err(|e| {
-            ClewdrError::RactorError {
+    /// Request a cookie fitting `need` from the cookie actor, along with the
+    /// lease counting the request as in flight on it
+    ///
+    /// While every cookie is cooling down or busy the request waits for the
+    /// first one to come free, for at most `cookie_cooldown_wait` seconds.
+    pub async fn request(
+        &self,
+        cache_hash: Option<u64>,
+        need: CookieNeed,
+    ) -> Result<(CookieStatus, CookieLease), ClewdrError> {
+        let max_wait = Duration::from_secs(CLEWDR_CONFIG.load().cookie_cooldown_wait);
+        let deadline = Instant::now() + max_wait;
+        loop {
+            let result = ractor::call!(
+                self.actor_ref,
+                CookieActorMessage::Request,
+                cache_hash,
+                need
+            )
+            .map_err(|e| ClewdrError::RactorError {
                 loc: Location::generate(),
                 msg: format!("Failed to communicate with CookieActor for request operation: {e}"),
+            })?;
+            let Err(ClewdrError::AllCookiesCoolingDown { wait_ms }) = result else {
+                return result;
+            };
+            let wait = Duration::from_millis(wait_ms);
+            if Instant::now() + wait > deadline {
+                return result;
             }
-        })?
+            sleep(wait).await;
+        }
     }
 
     /// Return a cookie to the cookie actor
@@ -467,6 +783,49 @@ impl CookieActorHandle {
         })
     }
 
+    /// Record the capabilities of a cookie's account
+    pub async fn set_capabilities(
+        &self,
+        cookie: ClewdrCookie,
+        capabilities: Vec<String>,
+    ) -> Result<(), ClewdrError> {
+        ractor::cast!(
+            self.actor_ref,
+            CookieActorMessage::SetCapabilities(cookie, capabilities)
+        )
+        .map_err(|e| ClewdrError::RactorError {
+            loc: Location::generate(),
+            msg: format!(
+                "Failed to communicate with CookieActor for set capabilities operation: {e}"
+            ),
+        })
+    }
+
+    /// Count a successful request and its tokens against a cookie
+    ///
+    /// Called once per request, after any retries, so that failed attempts
+    /// are not counted.
+    pub async fn record_use(&self, cookie: ClewdrCookie, tokens: u64) -> Result<(), ClewdrError> {
+        ractor::cast!(
+            self.actor_ref,
+            CookieActorMessage::RecordUse(cookie, tokens)
+        )
+        .map_err(|e| ClewdrError::RactorError {
+            loc: Location::generate(),
+            msg: format!("Failed to communicate with CookieActor for record use operation: {e}"),
+        })
+    }
+
+    /// Zero the request counters of every cookie
+    pub async fn reset_use(&self) -> Result<(), ClewdrError> {
+        ractor::cast!(self.actor_ref, CookieActorMessage::ResetUse).map_err(|e| {
+            ClewdrError::RactorError {
+                loc: Location::generate(),
+                msg: format!("Failed to communicate with CookieActor for reset use operation: {e}"),
+            }
+        })
+    }
+
     /// Get status information about all cookies
     pub async fn get_status(&self) -> Result<CookieStatusInfo, ClewdrError> {
         ractor::call!(self.actor_ref, CookieActorMessage::GetStatus).map_err(|e| {
@@ -479,6 +838,17 @@ impl CookieActorHandle {
         })
     }
 
+    /// Stops the cookie actor, saving its state on the way out
+    pub async fn stop(&self, timeout: Duration) -> Result<(), ClewdrError> {
+        self.actor_ref
+            .stop_and_wait(None, Some(timeout))
+            .await
+            .map_err(|e| ClewdrError::RactorError {
+                loc: Location::generate(),
+                msg: format!("Failed to stop CookieActor: {e}"),
+            })
+    }
+
     /// Delete a cookie from the cookie actor
     pub async fn delete_cookie(&self, cookie: CookieStatus) -> Result<(), ClewdrError> {
         ractor::call!(self.actor_ref, CookieActorMessage::Delete, cookie).map_err(|e| {
@@ -489,3 +859,271 @@ impl CookieActorHandle {
         })?
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn low_pool_warning_is_rate_limited() {
+        // first time the pool drops low, warn right away
+        assert!(low_warning_due(false, 0, 1_000));
+        // still low, stay quiet until the interval has passed
+        assert!(!low_warning_due(true, 1_000, 1_000 + INTERVAL as i64 - 1));
+        assert!(low_warning_due(true, 1_000, 1_000 + INTERVAL as i64));
+    }
+
+    #[tokio::test]
+    async fn parked_pool_reports_the_earliest_reset() {
+        use axum::{body, response::IntoResponse};
+        use http::header::RETRY_AFTER;
+
+        let now = Utc::now().timestamp();
+        let parked = |c: char, reset: i64| {
+            CookieStatus::new(
+                &format!("{}-ABCDEFAA", c.to_string().repeat(86)),
+                Some(reset),
+            )
+            .unwrap()
+        };
+        let mut state = CookieActorState {
+            valid: VecDeque::new(),
+            exhausted: HashSet::from([parked('a', now + 3600), parked('b', now + 600)]),
+            invalid: HashSet::new(),
+            moka: Cache::new(10),
+            last_dispatch: HashMap::new(),
+            in_flight: HashMap::new(),
+            unsaved_use: false,
+        };
+        let err = CookieActor
+            .dispatch(&mut state, None, CookieNeed::Any)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ClewdrError::AllCookiesRateLimited { reset_time } if reset_time == now + 600
+        ));
+
+        let resp = err.into_response();
+        assert_eq!(resp.status(), 429);
+        let retry_after = resp.headers()[RETRY_AFTER].to_str().unwrap().parse::<i64>();
+        assert!(retry_after.is_ok_and(|secs| (599..=600).contains(&secs)));
+        let bytes = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap();
+        assert_eq!(body["error"]["reset_time"], now + 600);
+        assert_eq!(body["error"]["type"], "all_cookies_rate_limited");
+    }
+
+    #[test]
+    fn cooling_cookies_are_skipped() {
+        let cookie = |c: char| {
+            CookieStatus::new(&format!("{}-ABCDEFAA", c.to_string().repeat(86)), None).unwrap()
+        };
+        let (a, b) = (cookie('a'), cookie('b'));
+        let valid = VecDeque::from([a.clone(), b.clone()]);
+        let cooldown = Duration::from_secs(6);
+        let now = Instant::now();
+
+        let mut last_dispatch = HashMap::new();
+        let busy = HashSet::new();
+        assert_eq!(
+            next_eligible(
+                &valid,
+                &last_dispatch,
+                &busy,
+                cooldown,
+                now,
+                CookieNeed::Any
+            ),
+            Ok(0)
+        );
+        last_dispatch.insert(a.cookie.clone(), now);
+        assert_eq!(
+            next_eligible(
+                &valid,
+                &last_dispatch,
+                &busy,
+                cooldown,
+                now,
+                CookieNeed::Any
+            ),
+            Ok(1)
+        );
+        last_dispatch.insert(b.cookie.clone(), now + Duration::from_secs(2));
+        assert_eq!(
+            next_eligible(
+                &valid,
+                &last_dispatch,
+                &busy,
+                cooldown,
+                now + Duration::from_secs(4),
+                CookieNeed::Any
+            ),
+            Err(Duration::from_secs(2))
+        );
+        assert_eq!(
+            next_eligible(
+                &valid,
+                &last_dispatch,
+                &busy,
+                cooldown,
+                now + Duration::from_secs(6),
+                CookieNeed::Any
+            ),
+            Ok(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn busy_cookies_are_skipped_until_their_response_is_sent() {
+        let cookie = |c: char| {
+            CookieStatus::new(&format!("{}-ABCDEFAA", c.to_string().repeat(86)), None).unwrap()
+        };
+        let (a, b) = (cookie('a'), cookie('b'));
+        let valid = VecDeque::from([a.clone(), b.clone()]);
+        let mut state = CookieActorState {
+            valid: valid.clone(),
+            exhausted: HashSet::new(),
+            invalid: HashSet::new(),
+            moka: Cache::new(10),
+            last_dispatch: HashMap::new(),
+            in_flight: HashMap::new(),
+            unsaved_use: false,
+        };
+        let pick = |busy: &HashSet<ClewdrCookie>| {
+            next_eligible(
+                &valid,
+                &HashMap::new(),
+                busy,
+                Duration::ZERO,
+                Instant::now(),
+                CookieNeed::Any,
+            )
+        };
+
+        let lease = CookieActor::lease(&mut state, &a.cookie);
+        assert!(busy_cookies(&mut state.in_flight, 0).is_empty());
+        let busy = busy_cookies(&mut state.in_flight, 1);
+        assert_eq!(busy, HashSet::from([a.cookie.clone()]));
+        assert_eq!(pick(&busy), Ok(1));
+
+        let other = CookieActor::lease(&mut state, &b.cookie);
+        let busy = busy_cookies(&mut state.in_flight, 1);
+        assert_eq!(pick(&busy), Err(BUSY_RETRY));
+        drop(other);
+        assert_eq!(busy_cookies(&mut state.in_flight, 1).len(), 1);
+        assert!(!state.in_flight.contains_key(&b.cookie));
+
+        // the response body keeps the cookie busy until it is sent
+        let response = hold_lease(Some(Arc::new(lease)), Response::new(Body::from("done")));
+        assert_eq!(busy_cookies(&mut state.in_flight, 1).len(), 1);
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(busy_cookies(&mut state.in_flight, 1).is_empty());
+        assert_eq!(pick(&HashSet::new()), Ok(0));
+    }
+
+    #[test]
+    fn pro_models_prefer_pro_cookies() {
+        let cookie = |c: char, is_pro: Option<bool>| {
+            let mut cookie =
+                CookieStatus::new(&format!("{}-ABCDEFAA", c.to_string().repeat(86)), None).unwrap();
+            cookie.is_pro = is_pro;
+            cookie
+        };
+        let opus = CookieNeed::for_model("claude-opus-4-6");
+        let long = CookieNeed::for_model("claude-sonnet-4-6-1M");
+        assert_eq!(opus, CookieNeed::PreferPro);
+        assert_eq!(long, CookieNeed::RequirePro);
+        assert_eq!(CookieNeed::for_model("claude-sonnet-4-6"), CookieNeed::Any);
+
+        let pick = |valid: &VecDeque<CookieStatus>, need| {
+            next_eligible(
+                valid,
+                &HashMap::new(),
+                &HashSet::new(),
+                Duration::ZERO,
+                Instant::now(),
+                need,
+            )
+        };
+        let mixed = VecDeque::from([cookie('a', Some(false)), cookie('b', Some(true))]);
+        assert_eq!(pick(&mixed, CookieNeed::Any), Ok(0));
+        assert_eq!(pick(&mixed, opus), Ok(1));
+        assert_eq!(pick(&mixed, long), Ok(1));
+        // a cookie not checked yet may well be pro
+        let unchecked = VecDeque::from([cookie('a', Some(false)), cookie('b', None)]);
+        assert_eq!(pick(&unchecked, long), Ok(1));
+
+        // free cookies are a fallback for Opus, but cannot serve 1M context
+        let mut state = CookieActorState {
+            valid: VecDeque::from([cookie('a', Some(false))]),
+            exhausted: HashSet::new(),
+            invalid: HashSet::new(),
+            moka: Cache::new(10),
+            last_dispatch: HashMap::new(),
+            in_flight: HashMap::new(),
+            unsaved_use: false,
+        };
+        assert!(CookieActor.dispatch(&mut state, None, opus).is_ok());
+        assert!(matches!(
+            CookieActor.dispatch(&mut state, None, long),
+            Err(ClewdrError::NoCapableCookie)
+        ));
+    }
+
+    #[test]
+    fn successful_requests_count_against_their_cookie() {
+        let cookie = |c: char| {
+            CookieStatus::new(&format!("{}-ABCDEFAA", c.to_string().repeat(86)), None).unwrap()
+        };
+        let (a, b, parked) = (cookie('a'), cookie('b'), cookie('c'));
+        let mut state = CookieActorState {
+            valid: VecDeque::from([a.clone(), b.clone()]),
+            exhausted: HashSet::from([parked.clone()]),
+            invalid: HashSet::new(),
+            moka: Cache::new(10),
+            last_dispatch: HashMap::new(),
+            in_flight: HashMap::new(),
+            unsaved_use: false,
+        };
+        let find = |state: &CookieActorState, c: &CookieStatus| {
+            state
+                .valid
+                .iter()
+                .chain(state.exhausted.iter())
+                .find(|s| *s == c)
+                .cloned()
+                .unwrap()
+        };
+
+        assert!(CookieActor::record_use(&mut state, &a.cookie, 120, 1_000));
+        // left for the next save instead of written per request
+        assert!(state.unsaved_use);
+        assert!(CookieActor::record_use(&mut state, &a.cookie, 30, 1_060));
+        assert!(CookieActor::record_use(
+            &mut state,
+            &parked.cookie,
+            10,
+            1_100
+        ));
+        let used = find(&state, &a);
+        assert_eq!(
+            (used.request_count, used.token_count, used.last_used),
+            (2, 150, Some(1_060))
+        );
+        assert_eq!(find(&state, &b).request_count, 0);
+        assert_eq!(find(&state, &parked).request_count, 1);
+
+        // a copy dispatched before those requests must not roll them back
+        let mut stale = a.clone();
+        stale.keep_use_of(&used);
+        assert_eq!(stale.request_count, 2);
+
+        CookieActor::reset_use(&mut state);
+        let used = find(&state, &a);
+        assert_eq!((used.request_count, used.token_count), (0, 0));
+        assert_eq!(used.last_used, Some(1_060));
+        assert_eq!(find(&state, &parked).token_count, 0);
+    }
+}
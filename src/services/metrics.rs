@@ -0,0 +1,157 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{LazyLock, Mutex},
+};
+
+use crate::services::{cookie_actor::CookieStatusInfo, stream_limiter::open_streams};
+
+/// Requests handled so far, by backend and model
+static REQUESTS: LazyLock<Mutex<BTreeMap<(&'static str, String), u64>>> =
+    LazyLock::new(Default::default);
+
+/// Counts a request sent to `backend` for `model`
+pub fn record_request(backend: &'static str, model: &str) {
+    if let Ok(mut requests) = REQUESTS.lock() {
+        *requests.entry((backend, model.to_string())).or_default() += 1;
+    }
+}
+
+/// Escapes a label value for the Prometheus text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// Writes the `HELP` and `TYPE` lines introducing a metric
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Renders the metrics in the Prometheus text exposition format
+fn render(
+    requests: &BTreeMap<(&'static str, String), u64>,
+    cookies: Option<&CookieStatusInfo>,
+    open_streams: usize,
+) -> String {
+    let mut out = String::new();
+    header(
+        &mut out,
+        "clewdr_requests_total",
+        "counter",
+        "Requests handled, by backend and model",
+    );
+    for ((backend, model), count) in requests {
+        let _ = writeln!(
+            out,
+            "clewdr_requests_total{{backend=\"{}\",model=\"{}\"}} {}",
+            backend,
+            escape_label(model),
+            count
+        );
+    }
+    if let Some(cookies) = cookies {
+        header(
+            &mut out,
+            "clewdr_cookies",
+            "gauge",
+            "Cookies in the pool, by state",
+        );
+        for (state, count) in [
+            ("valid", cookies.valid.len()),
+            ("exhausted", cookies.exhausted.len()),
+            ("invalid", cookies.invalid.len()),
+        ] {
+            let _ = writeln!(out, "clewdr_cookies{{state=\"{state}\"}} {count}");
+        }
+    }
+    header(
+        &mut out,
+        "clewdr_open_streams",
+        "gauge",
+        "Streaming responses currently open",
+    );
+    let _ = writeln!(out, "clewdr_open_streams {open_streams}");
+    out
+}
+
+/// Current metrics in the Prometheus text exposition format, cookie pool
+/// sizes are left out when the cookie actor could not be asked
+pub fn export(cookies: Option<&CookieStatusInfo>) -> String {
+    let requests = REQUESTS.lock().map(|r| r.clone()).unwrap_or_default();
+    render(&requests, cookies, open_streams())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks a sample line is `name{label="value",...} number`
+    fn parse_sample(line: &str) -> Option<(&str, f64)> {
+        let (series, value) = line.rsplit_once(' ')?;
+        let value = value.parse().ok()?;
+        let name = match series.split_once('{') {
+            Some((name, labels)) => {
+                let labels = labels.strip_suffix('}')?;
+                let mut rest = labels;
+                while !rest.is_empty() {
+                    let (key, after) = rest.split_once("=\"")?;
+                    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    {
+                        return None;
+                    }
+                    // find the closing quote, skipping escaped characters
+                    let mut chars = after.char_indices();
+                    let end = loop {
+                        match chars.next()? {
+                            (_, '\\') => {
+                                chars.next()?;
+                            }
+                            (i, '"') => break i,
+                            _ => {}
+                        }
+                    };
+                    rest = &after[end + 1..];
+                    rest = rest.strip_prefix(',').unwrap_or(rest);
+                }
+                name
+            }
+            None => series,
+        };
+        name.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+            .then_some((name, value))
+    }
+
+    #[test]
+    fn exposition_format_parses() {
+        let requests = BTreeMap::from([
+            (("claude_code", "claude-sonnet-4-5".to_string()), 3),
+            (("claude_web", "odd \"model\"\\name".to_string()), 1),
+        ]);
+        let cookies = CookieStatusInfo {
+            valid: vec![],
+            exhausted: vec![],
+            invalid: vec![],
+        };
+        let text = render(&requests, Some(&cookies), 2);
+
+        let mut samples = vec![];
+        for line in text.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                assert!(comment.starts_with("HELP ") || comment.starts_with("TYPE "));
+                continue;
+            }
+            samples.push(parse_sample(line).unwrap_or_else(|| panic!("bad line: {line}")));
+        }
+        assert_eq!(samples.len(), 6);
+        assert!(text.contains(
+            r#"clewdr_requests_total{backend="claude_web",model="odd \"model\"\\name"} 1"#
+        ));
+        assert!(text.contains("clewdr_cookies{state=\"valid\"} 0"));
+        assert_eq!(samples.last(), Some(&("clewdr_open_streams", 2.0)));
+    }
+}
@@ -1,3 +1,10 @@
 pub mod cookie_actor;
-#[cfg(feature = "portable")]
+pub mod dependencies;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod rate_limiter;
+pub mod shutdown;
+pub mod stream_coalescer;
+pub mod stream_limiter;
+#[cfg(all(feature = "portable", not(feature = "no-self-update")))]
 pub mod update;
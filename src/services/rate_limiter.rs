@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde_json::{Value, json};
+use strum::{EnumIter, IntoEnumIterator, IntoStaticStr};
+
+use crate::config::RateLimits;
+
+/// Length of a rate limit window
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Groups of routes with their own request rate limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, EnumIter, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum RouteGroup {
+    ClaudeWeb,
+    ClaudeCode,
+    CountTokens,
+}
+
+impl RouteGroup {
+    /// Requests per minute allowed for this group, `0` means unlimited
+    pub fn limit(self, limits: &RateLimits) -> u32 {
+        match self {
+            RouteGroup::ClaudeWeb => limits.claude_web,
+            RouteGroup::ClaudeCode => limits.claude_code,
+            RouteGroup::CountTokens => limits.count_tokens,
+        }
+    }
+}
+
+/// Requests counted in the current fixed window
+#[derive(Debug, Clone, Copy)]
+struct RateWindow {
+    started: Instant,
+    count: u32,
+}
+
+impl RateWindow {
+    fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            count: 0,
+        }
+    }
+
+    /// Counts a request if the limit allows it, otherwise returns how long
+    /// until the window resets
+    fn try_acquire(&mut self, limit: u32, now: Instant) -> Result<(), Duration> {
+        if now.duration_since(self.started) >= WINDOW {
+            *self = Self::new(now);
+        }
+        if limit > 0 && self.count >= limit {
+            return Err(WINDOW.saturating_sub(now.duration_since(self.started)));
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    fn current(&self, now: Instant) -> u32 {
+        if now.duration_since(self.started) >= WINDOW {
+            0
+        } else {
+            self.count
+        }
+    }
+}
+
+static WINDOWS: LazyLock<Mutex<HashMap<RouteGroup, RateWindow>>> = LazyLock::new(Default::default);
+
+/// Counts a request against `group`, returning how long to wait when the
+/// group's limit is exhausted
+pub fn try_acquire(group: RouteGroup, limit: u32) -> Result<(), Duration> {
+    let now = Instant::now();
+    let Ok(mut windows) = WINDOWS.lock() else {
+        return Ok(());
+    };
+    windows
+        .entry(group)
+        .or_insert_with(|| RateWindow::new(now))
+        .try_acquire(limit, now)
+}
+
+/// Requests in the current window and the configured limit of each group
+pub fn current_rates(limits: &RateLimits) -> Value {
+    let now = Instant::now();
+    let windows = WINDOWS.lock().map(|w| w.clone()).unwrap_or_default();
+    RouteGroup::iter()
+        .map(|group| {
+            let current = windows.get(&group).map_or(0, |w| w.current(now));
+            let name: &'static str = group.into();
+            (
+                name.to_string(),
+                json!({ "requests_per_minute": current, "limit": group.limit(limits) }),
+            )
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_limits_and_resets() {
+        let start = Instant::now();
+        let mut window = RateWindow::new(start);
+        assert!(window.try_acquire(2, start).is_ok());
+        assert!(window.try_acquire(2, start).is_ok());
+        let wait = window
+            .try_acquire(2, start + Duration::from_secs(15))
+            .unwrap_err();
+        assert_eq!(wait, Duration::from_secs(45));
+        assert_eq!(window.current(start), 2);
+
+        let later = start + WINDOW;
+        assert!(window.try_acquire(2, later).is_ok());
+        assert_eq!(window.current(later), 1);
+
+        // zero means unlimited
+        assert!((0..100).all(|_| window.try_acquire(0, later).is_ok()));
+    }
+}
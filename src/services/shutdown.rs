@@ -0,0 +1,123 @@
+use std::{future::Future, time::Duration};
+
+use axum::Router;
+use tokio::{net::TcpListener, sync::watch, time::sleep};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::CLEWDR_CONFIG,
+    error::ClewdrError,
+    services::{cookie_actor::CookieActorHandle, stream_limiter::open_streams},
+};
+
+/// Longest the cookie actor may take to stop
+const ACTOR_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves on SIGINT or SIGTERM, on Ctrl-C where there are no Unix signals
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.expect("Failed to install Ctrl-C handler"),
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Failed to install Ctrl-C handler");
+}
+
+/// Runs `server` to completion, or until `drain` has passed since `stopping`
+/// resolved, returning whether the server finished on its own
+///
+/// A zero `drain` waits however long the server takes.
+async fn drain_or_cut<E>(
+    server: impl Future<Output = Result<(), E>>,
+    stopping: impl Future<Output = ()>,
+    drain: Duration,
+) -> Result<bool, E> {
+    let deadline = async {
+        stopping.await;
+        if drain.is_zero() {
+            std::future::pending::<()>().await;
+        }
+        sleep(drain).await;
+    };
+    tokio::select! {
+        result = server => result.map(|_| true),
+        _ = deadline => Ok(false),
+    }
+}
+
+/// Serves `router` until a shutdown signal, then stops accepting connections
+/// and gives open requests `shutdown_drain_timeout` seconds to finish
+///
+/// Requests still open after that are cut.
+pub async fn serve_until_shutdown(
+    listener: TcpListener,
+    router: Router,
+) -> Result<(), ClewdrError> {
+    let (tx, rx) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!(
+            "Shutting down, waiting for {} open streams to finish",
+            open_streams()
+        );
+        let _ = tx.send(true);
+    });
+    let stopping = |mut rx: watch::Receiver<bool>| async move {
+        let _ = rx.wait_for(|stopping| *stopping).await;
+    };
+    let server = axum::serve(listener, router).with_graceful_shutdown(stopping(rx.clone()));
+    let drain = Duration::from_secs(CLEWDR_CONFIG.load().shutdown_drain_timeout);
+    if drain_or_cut(server.into_future(), stopping(rx), drain).await? {
+        info!("All connections drained");
+    } else {
+        warn!(
+            "Drain timeout elapsed, cutting {} open streams",
+            open_streams()
+        );
+    }
+    Ok(())
+}
+
+/// Stops the cookie actor and writes the config one last time, so cookie
+/// states and reset times survive the restart
+pub async fn flush_state(cookie_actor: CookieActorHandle) {
+    if let Err(e) = cookie_actor.stop(ACTOR_STOP_TIMEOUT).await {
+        warn!("Cookie actor did not stop cleanly: {}", e);
+    }
+    match CLEWDR_CONFIG.load().save().await {
+        Ok(()) => info!("State saved"),
+        Err(e) => error!("Failed to save state on shutdown: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn draining_is_cut_after_the_timeout() {
+        let drained = drain_or_cut(
+            async { Ok::<_, ()>(()) },
+            std::future::pending(),
+            Duration::ZERO,
+        )
+        .await;
+        assert_eq!(drained, Ok(true));
+
+        let drained = drain_or_cut(
+            std::future::pending::<Result<(), ()>>(),
+            async {},
+            Duration::from_millis(10),
+        )
+        .await;
+        assert_eq!(drained, Ok(false));
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{LazyLock, Mutex},
+};
+
+use axum::{body::Body, response::Response};
+use bytes::Bytes;
+use futures::StreamExt;
+use http::{HeaderMap, StatusCode};
+use tokio::sync::broadcast::{self, Receiver, Sender, error::RecvError};
+
+use crate::{error::ClewdrError, types::claude::CreateMessageParams};
+
+/// Chunks a follower may fall behind the upstream stream before it is cut off
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Upstream streams currently being set up, keyed by request hash
+static IN_FLIGHT: LazyLock<Mutex<HashMap<u64, Sender<Shared>>>> = LazyLock::new(Default::default);
+
+/// What the leading request fans out to every requester sharing its stream
+#[derive(Clone)]
+enum Shared {
+    Head(StatusCode, HeaderMap),
+    Chunk(Bytes),
+    Error(String),
+    /// The upstream request failed, followers send their own
+    Failed,
+}
+
+/// Hashes a request together with anything else that changes the upstream call
+pub fn coalesce_key(scope: impl Hash, params: &CreateMessageParams) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    scope.hash(&mut hasher);
+    serde_json::to_vec(params)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Removes the in-flight entry if the leader goes away before streaming
+struct LeaderGuard(Option<u64>);
+
+impl LeaderGuard {
+    fn finish(mut self) -> Option<Sender<Shared>> {
+        let key = self.0.take()?;
+        IN_FLIGHT.lock().ok()?.remove(&key)
+    }
+}
+
+impl Drop for LeaderGuard {
+    fn drop(&mut self) {
+        if let Some(key) = self.0.take()
+            && let Ok(mut in_flight) = IN_FLIGHT.lock()
+        {
+            in_flight.remove(&key);
+        }
+    }
+}
+
+/// Runs `upstream` once for all identical requests arriving while it is in
+/// flight, fanning the streamed body out to each of them
+///
+/// Only requesters that join before the upstream response arrives share it,
+/// later ones start their own stream. If the shared upstream call fails,
+/// followers fall back to calling `upstream` themselves.
+pub async fn coalesce<F, Fut>(key: u64, upstream: F) -> Result<Response, ClewdrError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Response, ClewdrError>>,
+{
+    let follower = {
+        let mut in_flight = IN_FLIGHT.lock().map_err(|_| ClewdrError::UnexpectedNone {
+            msg: "Stream coalescer lock poisoned",
+        })?;
+        match in_flight.get(&key) {
+            Some(tx) => Some(tx.subscribe()),
+            None => {
+                in_flight.insert(key, broadcast::channel(CHANNEL_CAPACITY).0);
+                None
+            }
+        }
+    };
+    if let Some(rx) = follower {
+        if let Some(response) = follow(rx).await {
+            return Ok(response);
+        }
+        return upstream().await;
+    }
+
+    let guard = LeaderGuard(Some(key));
+    let result = upstream().await;
+    let Some(tx) = guard.finish() else {
+        return result;
+    };
+    let response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            let _ = tx.send(Shared::Failed);
+            return Err(e);
+        }
+    };
+    let (parts, body) = response.into_parts();
+    let _ = tx.send(Shared::Head(parts.status, parts.headers.to_owned()));
+    let rx = tx.subscribe();
+    tokio::spawn(async move {
+        let mut body = body.into_data_stream();
+        while let Some(chunk) = body.next().await {
+            let shared = match chunk {
+                Ok(bytes) => Shared::Chunk(bytes),
+                Err(e) => Shared::Error(e.to_string()),
+            };
+            // every requester is gone, stop reading upstream
+            if tx.send(shared).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(Response::from_parts(parts, body_from(rx)))
+}
+
+/// Waits for the leader's response head, `None` if the leader failed
+async fn follow(mut rx: Receiver<Shared>) -> Option<Response> {
+    let Ok(Shared::Head(status, headers)) = rx.recv().await else {
+        return None;
+    };
+    let mut response = Response::new(body_from(rx));
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    Some(response)
+}
+
+fn body_from(rx: Receiver<Shared>) -> Body {
+    let stream = futures::stream::unfold(rx, async |mut rx| {
+        let item = match rx.recv().await {
+            Ok(Shared::Chunk(bytes)) => Ok(bytes),
+            Ok(Shared::Error(e)) => Err(std::io::Error::other(e)),
+            Ok(Shared::Head(..) | Shared::Failed) | Err(RecvError::Closed) => return None,
+            Err(RecvError::Lagged(n)) => Err(std::io::Error::other(format!(
+                "Fell {n} chunks behind the shared upstream stream"
+            ))),
+        };
+        Some((item, rx))
+    });
+    Body::from_stream(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use axum::body;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn identical_requests_share_one_upstream_call() {
+        let calls = &AtomicUsize::new(0);
+        let upstream = move || async move {
+            calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let chunks = ["data: a\n\n", "data: b\n\n"].map(Ok::<_, std::io::Error>);
+            Ok(Response::new(Body::from_stream(futures::stream::iter(
+                chunks,
+            ))))
+        };
+
+        let (first, second) = tokio::join!(coalesce(42, upstream), coalesce(42, upstream));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        for resp in [first.unwrap(), second.unwrap()] {
+            let bytes = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(bytes, "data: a\n\ndata: b\n\n");
+        }
+
+        // the key is free again once the shared stream started
+        let third = coalesce(42, upstream).await.unwrap();
+        body::to_bytes(third.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
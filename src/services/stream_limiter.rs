@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use axum::{body::Body, response::Response};
+use futures::StreamExt;
+
+/// Number of streaming responses currently open
+static OPEN_STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+/// A slot for one open streaming response, released on drop
+#[derive(Debug)]
+pub struct StreamPermit(());
+
+impl StreamPermit {
+    /// Takes a slot if fewer than `max` streams are open, `0` means unlimited
+    pub fn try_acquire(max: usize) -> Option<Self> {
+        OPEN_STREAMS
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (max == 0 || open < max).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| Self(()))
+    }
+
+    /// Keeps the slot taken until the response body is fully sent or dropped
+    pub fn hold(self, response: Response) -> Response {
+        response.map(|body| {
+            let stream = body.into_data_stream().map(move |chunk| {
+                let _permit = &self;
+                chunk
+            });
+            Body::from_stream(stream)
+        })
+    }
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        OPEN_STREAMS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Number of streaming responses currently open
+pub fn open_streams() -> usize {
+    OPEN_STREAMS.load(Ordering::Acquire)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits_are_bounded_and_released() {
+        let first = StreamPermit::try_acquire(2).unwrap();
+        let second = StreamPermit::try_acquire(2).unwrap();
+        assert!(StreamPermit::try_acquire(2).is_none());
+        drop(first);
+        let third = StreamPermit::try_acquire(2).unwrap();
+        assert!(StreamPermit::try_acquire(0).is_some());
+        drop((second, third));
+    }
+}
@@ -14,7 +14,7 @@ use zip::ZipArchive;
 
 use crate::{
     Args,
-    config::CLEWDR_CONFIG,
+    config::{CLEWDR_CONFIG, ClewdrConfig},
     error::{ClewdrError, WreqSnafu},
 };
 
@@ -30,6 +30,15 @@ struct GitHubAsset {
     browser_download_url: String,
 }
 
+/// Whether the startup update check may contact GitHub
+///
+/// Nothing is fetched when `check_update` is off and `--update` was not
+/// passed. Builds with the `no-self-update` feature go further and contain
+/// no updater at all, whatever the config says.
+fn update_check_enabled(force: bool, config: &ClewdrConfig) -> bool {
+    force || config.check_update
+}
+
 /// Updater for the ClewdR application
 /// Handles checking for updates and updating the application
 pub struct ClewdrUpdater {
@@ -55,12 +64,13 @@ impl ClewdrUpdater {
             .unwrap_or("Xerxes-2");
         let repo_name = env!("CARGO_PKG_NAME");
         let policy = wreq::redirect::Policy::default();
-        let client = wreq::Client::builder()
-            .redirect(policy)
-            .build()
-            .context(WreqSnafu {
-                msg: "Failed to create HTTP client",
-            })?;
+        let mut builder = wreq::Client::builder().redirect(policy);
+        if let Some(proxy) = CLEWDR_CONFIG.load().wreq_proxy.to_owned() {
+            builder = builder.proxy(proxy);
+        }
+        let client = builder.build().context(WreqSnafu {
+            msg: "Failed to create HTTP client",
+        })?;
 
         let user_agent = format!(
             "clewdr/{} (+https://github.com/{}/{})",
@@ -90,7 +100,7 @@ impl ClewdrUpdater {
         }
 
         let args: Args = clap::Parser::parse();
-        if !args.update && !CLEWDR_CONFIG.load().check_update {
+        if !update_check_enabled(args.update, &CLEWDR_CONFIG.load()) {
             return Ok(false);
         }
 
@@ -317,3 +327,17 @@ impl ClewdrUpdater {
         Ok(current < latest)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_check_skips_github() {
+        let mut config = ClewdrConfig::default();
+        assert!(update_check_enabled(false, &config));
+        config.check_update = false;
+        assert!(!update_check_enabled(false, &config));
+        assert!(update_check_enabled(true, &config));
+    }
+}
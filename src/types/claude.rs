@@ -2,6 +2,7 @@ use serde::{Deserialize, Serialize, de};
 use serde_json::Value;
 use serde_with::{DefaultOnError, serde_as};
 use tiktoken_rs::o200k_base;
+use tracing::debug;
 
 #[derive(Debug)]
 pub struct RequiredMessageParams {
@@ -246,6 +247,8 @@ pub struct Message {
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
+    /// OpenAI's o1-style `developer` role has no Claude counterpart, treat it as system
+    #[serde(alias = "developer")]
     System,
     User,
     #[default]
@@ -254,7 +257,7 @@ pub enum Role {
 
 /// Content of a message
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
-#[serde(untagged)]
+#[serde(untagged, from = "MessageContentRepr")]
 pub enum MessageContent {
     /// Simple text content
     Text { content: String },
@@ -262,6 +265,53 @@ pub enum MessageContent {
     Blocks { content: Vec<ContentBlock> },
 }
 
+/// Content part as sent by clients, some send plain strings instead of
+/// text blocks
+///
+/// `Text` comes first, `ContentBlock::Unknown` would take the string otherwise.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ContentPart {
+    Text(String),
+    Block(ContentBlock),
+}
+
+/// Accepted shapes of message content before normalization
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MessageContentRepr {
+    Text { content: String },
+    Parts { content: Vec<ContentPart> },
+}
+
+impl From<MessageContentRepr> for MessageContent {
+    fn from(repr: MessageContentRepr) -> Self {
+        match repr {
+            MessageContentRepr::Text { content } => MessageContent::Text { content },
+            MessageContentRepr::Parts { content } => {
+                let mut coerced = 0;
+                let content = content
+                    .into_iter()
+                    .map(|part| match part {
+                        ContentPart::Block(block) => block,
+                        ContentPart::Text(text) => {
+                            coerced += 1;
+                            ContentBlock::text(text)
+                        }
+                    })
+                    .collect();
+                if coerced > 0 {
+                    debug!(
+                        "Coerced {} plain string content parts to text blocks",
+                        coerced
+                    );
+                }
+                MessageContent::Blocks { content }
+            }
+        }
+    }
+}
+
 /// Content block in a message
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 #[serde(tag = "type")]
@@ -733,6 +783,14 @@ enum ToolChoiceTagged {
     },
     #[serde(rename = "none")]
     None,
+    /// OpenAI's way of naming a tool
+    #[serde(rename = "function")]
+    Function { function: FunctionName },
+}
+
+#[derive(Debug, Deserialize)]
+struct FunctionName {
+    name: String,
 }
 
 impl From<ToolChoiceTagged> for ToolChoice {
@@ -756,6 +814,10 @@ impl From<ToolChoiceTagged> for ToolChoice {
                 disable_parallel_tool_use,
             },
             ToolChoiceTagged::None => ToolChoice::None,
+            ToolChoiceTagged::Function { function } => ToolChoice::Tool {
+                name: function.name,
+                disable_parallel_tool_use: None,
+            },
         }
     }
 }
@@ -1069,4 +1131,34 @@ mod tests {
         let params: CreateMessageParams = serde_json::from_value(body).unwrap();
         assert!(matches!(params.tool_choice, Some(ToolChoice::Auto { .. })));
     }
+
+    #[test]
+    fn string_array_content_becomes_text_blocks() {
+        let message: Message = serde_json::from_value(json!({
+            "role": "user",
+            "content": ["a", "b"]
+        }))
+        .unwrap();
+        assert_eq!(
+            message,
+            Message::new_blocks(
+                Role::User,
+                vec![ContentBlock::text("a"), ContentBlock::text("b")]
+            )
+        );
+
+        // strings mixed with regular blocks keep their order
+        let message: Message = serde_json::from_value(json!({
+            "role": "user",
+            "content": ["a", { "type": "text", "text": "b" }]
+        }))
+        .unwrap();
+        assert_eq!(
+            message,
+            Message::new_blocks(
+                Role::User,
+                vec![ContentBlock::text("a"), ContentBlock::text("b")]
+            )
+        );
+    }
 }
@@ -27,6 +27,11 @@ impl Attachment {
             file_type: "txt".to_string(),
         }
     }
+
+    /// Number of characters in the attachment
+    pub fn char_count(&self) -> usize {
+        self.extracted_content.chars().count()
+    }
 }
 
 /// Request body to be sent to the Claude.ai
@@ -13,12 +13,13 @@ use wreq::Proxy;
 use crate::{
     claude_code_state::ClaudeCodeState,
     claude_web_state::ClaudeWebState,
+    config::CLAUDE_API_VERSION,
     error::{CheckClaudeErr, ClewdrError},
     types::claude::{
         ContentBlock, CountMessageTokensResponse, CreateMessageParams, CreateMessageResponse,
         Message, Role,
     },
-    utils::print_out_text,
+    utils::{print_out_text, with_idle_timeout},
 };
 
 /// Merges server-sent events (SSE) from a stream into a single string
@@ -83,6 +84,8 @@ impl ClaudeWebState {
             let mut input_tokens = self.usage.input_tokens as u64;
             let handle = self.cookie_actor_handle.clone();
             let cookie = self.cookie.clone();
+            let timeout_handle = handle.clone();
+            let timeout_cookie = cookie.clone();
             let enable_precise = crate::config::CLEWDR_CONFIG.load().enable_web_count_tokens;
             let last_params = self.last_params.clone();
             let endpoint = self.endpoint.clone();
@@ -145,7 +148,9 @@ impl ClaudeWebState {
                             })
                             .unwrap_or(crate::config::ModelFamily::Other);
                         c.add_and_bucket_usage(input_tokens, out, family);
+                        let used = c.cookie.clone();
                         let _ = handle.return_cookie(c, None).await;
+                        let _ = handle.record_use(used, input_tokens + out).await;
                     }
                 } else if let Some(mut c) = cookie.clone() {
                     // still persist input tokens to maintain parity
@@ -164,11 +169,24 @@ impl ClaudeWebState {
                         })
                         .unwrap_or(crate::config::ModelFamily::Other);
                     c.add_and_bucket_usage(input_tokens, 0, family);
+                    let used = c.cookie.clone();
                     let _ = handle.return_cookie(c, None).await;
+                    let _ = handle.record_use(used, input_tokens).await;
                 }
             };
             // normalize error type for axum SSE
             let stream = stream.map_err(|e: axum::Error| -> BoxError { e.into() });
+            let stream = with_idle_timeout(
+                stream,
+                crate::config::CLEWDR_CONFIG.load().stream_idle_duration(),
+                move || {
+                    if let Some(cookie) = timeout_cookie {
+                        tokio::spawn(async move {
+                            let _ = timeout_handle.return_cookie(cookie, None).await;
+                        });
+                    }
+                },
+            );
             return Ok(Sse::new(stream)
                 .keep_alive(Default::default())
                 .into_response());
@@ -207,6 +225,8 @@ impl ClaudeWebState {
         response.usage = Some(usage.clone());
         self.persist_usage_totals(usage.input_tokens as u64, output_tokens as u64)
             .await;
+        self.record_use(usage.input_tokens as u64 + output_tokens as u64)
+            .await;
         Ok(Json(response).into_response())
     }
 }
@@ -221,7 +241,7 @@ async fn bearer_count_tokens(
         .client
         .post(url.to_string())
         .bearer_auth(access_token)
-        .header("anthropic-version", "2023-06-01")
+        .header("anthropic-version", CLAUDE_API_VERSION)
         .json(body)
         .send()
         .await
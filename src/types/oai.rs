@@ -1,5 +1,6 @@
-use serde::{Deserialize, Serialize};
-use serde_json::{Value, json};
+use serde::{Deserialize, Deserializer, Serialize, de::Error as _};
+use serde_json::{Map, Value, json};
+use serde_with::{OneOrMany, formats::PreferMany, serde_as};
 use tiktoken_rs::o200k_base;
 
 use super::claude::{CreateMessageParams as ClaudeCreateMessageParams, *};
@@ -40,6 +41,193 @@ fn normalize_message(msg: Message) -> Option<Message> {
     })
 }
 
+/// Parses the JSON encoded arguments of an OpenAI call into a tool input
+fn call_input(call: &Value) -> Value {
+    call["arguments"]
+        .as_str()
+        .and_then(|args| serde_json::from_str::<Value>(args).ok())
+        .filter(Value::is_object)
+        .unwrap_or_else(|| json!({}))
+}
+
+/// Rewrites a message of OpenAI's function or tool calling in Claude terms
+///
+/// Assistant `tool_calls` become `tool_use` blocks keeping their ids, and
+/// `tool` role messages become the `tool_result` of the call they name.
+/// A legacy `function_call` gets an id made up from the message position, a
+/// `function` role message is the result of the latest open call of the same
+/// name. A legacy result with no such call is kept as plain user text, Claude
+/// rejects unmatched results.
+fn convert_function_calling(
+    mut msg: Value,
+    index: usize,
+    calls: &mut Vec<(String, String)>,
+) -> Value {
+    match msg["role"].as_str() {
+        Some("assistant") => {
+            let Some(m) = msg.as_object_mut() else {
+                return msg;
+            };
+            let mut uses = vec![];
+            if let Some(Value::Array(tool_calls)) = m.remove("tool_calls") {
+                for call in tool_calls {
+                    let function = &call["function"];
+                    let name = function["name"].as_str().unwrap_or_default();
+                    uses.push(json!({
+                        "type": "tool_use",
+                        "id": call["id"],
+                        "name": name,
+                        "input": call_input(function),
+                    }));
+                }
+            }
+            if let Some(call) = m.remove("function_call") {
+                let name = call["name"].as_str().unwrap_or_default().to_string();
+                let id = format!("toolu_function_{index}");
+                uses.push(json!({
+                    "type": "tool_use",
+                    "id": id,
+                    "name": name,
+                    "input": call_input(&call),
+                }));
+                calls.push((name, id));
+            }
+            if uses.is_empty() {
+                return msg;
+            }
+            let mut content = match msg["content"].take() {
+                Value::String(text) if !text.is_empty() => {
+                    vec![json!({"type": "text", "text": text})]
+                }
+                Value::Array(blocks) => blocks,
+                _ => vec![],
+            };
+            content.extend(uses);
+            msg["content"] = content.into();
+            msg
+        }
+        Some("tool") => {
+            let content = match msg["content"].take() {
+                Value::Null => Value::String(String::new()),
+                content => content,
+            };
+            json!({
+                "role": "user",
+                "content": [{
+                    "type": "tool_result",
+                    "tool_use_id": msg["tool_call_id"],
+                    "content": content,
+                }],
+            })
+        }
+        Some("function") => {
+            let content = match msg["content"].take() {
+                Value::Null => Value::String(String::new()),
+                content => content,
+            };
+            let name = msg["name"].as_str().unwrap_or_default();
+            let Some(pos) = calls.iter().rposition(|(call, _)| call == name) else {
+                return json!({"role": "user", "content": content});
+            };
+            let (_, id) = calls.remove(pos);
+            json!({
+                "role": "user",
+                "content": [{"type": "tool_result", "tool_use_id": id, "content": content}],
+            })
+        }
+        _ => msg,
+    }
+}
+
+/// Whether a converted message carries nothing but tool results
+fn is_tool_results(msg: &Value) -> bool {
+    msg["role"] == "user"
+        && msg["content"]
+            .as_array()
+            .is_some_and(|blocks| blocks.iter().all(|b| b["type"] == "tool_result"))
+}
+
+/// Deserializes OpenAI messages, accepting function and tool calling
+///
+/// OpenAI sends one message per tool result, Claude wants all results of a
+/// turn in one user message, so consecutive results are merged.
+fn deserialize_messages<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Message>, D::Error> {
+    let mut calls = vec![];
+    let mut messages: Vec<Value> = vec![];
+    for (i, msg) in Vec::<Value>::deserialize(d)?.into_iter().enumerate() {
+        let mut msg = convert_function_calling(msg, i, &mut calls);
+        if let Some(last) = messages.last_mut()
+            && is_tool_results(last)
+            && is_tool_results(&msg)
+            && let (Some(prev), Value::Array(results)) =
+                (last["content"].as_array_mut(), msg["content"].take())
+        {
+            prev.extend(results);
+            continue;
+        }
+        messages.push(msg);
+    }
+    messages
+        .into_iter()
+        .map(|msg| Message::deserialize(msg).map_err(D::Error::custom))
+        .collect()
+}
+
+/// Legacy function definition, superseded by tools
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Value>,
+}
+
+impl From<FunctionDefinition> for Tool {
+    fn from(f: FunctionDefinition) -> Self {
+        let mut tool = Map::new();
+        tool.insert("name".to_string(), f.name.into());
+        if let Some(description) = f.description {
+            tool.insert("description".to_string(), description.into());
+        }
+        let schema = f
+            .parameters
+            .unwrap_or_else(|| json!({"type": "object", "properties": {}}));
+        tool.insert("input_schema".to_string(), schema);
+        Tool::Raw(tool.into())
+    }
+}
+
+/// Converts an OpenAI `function` tool into a Claude custom tool, other tools
+/// are passed on as given
+fn convert_function_tool(tool: Tool) -> Tool {
+    match tool {
+        Tool::Raw(value) if value["type"] == "function" => {
+            serde_json::from_value::<FunctionDefinition>(value["function"].clone())
+                .map_or(Tool::Raw(value), Tool::from)
+        }
+        tool => tool,
+    }
+}
+
+/// Applies OpenAI's `parallel_tool_calls: false` to a tool choice
+fn disable_parallel_tool_use(choice: ToolChoice) -> ToolChoice {
+    let disable_parallel_tool_use = Some(true);
+    match choice {
+        ToolChoice::Auto { .. } => ToolChoice::Auto {
+            disable_parallel_tool_use,
+        },
+        ToolChoice::Any { .. } => ToolChoice::Any {
+            disable_parallel_tool_use,
+        },
+        ToolChoice::Tool { name, .. } => ToolChoice::Tool {
+            name,
+            disable_parallel_tool_use,
+        },
+        ToolChoice::None => ToolChoice::None,
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum Effort {
@@ -68,8 +256,27 @@ impl From<CreateMessageParams> for ClaudeCreateMessageParams {
         let system = (!systems.is_empty()).then(|| json!(systems));
         // normalize messages (convert ImageUrl to Image, skip empty messages)
         let messages = messages.into_iter().filter_map(normalize_message).collect();
+        let functions = params.functions.into_iter().flatten().map(Tool::from);
+        let tools = match params.tools {
+            Some(tools) => Some(
+                tools
+                    .into_iter()
+                    .map(convert_function_tool)
+                    .chain(functions)
+                    .collect(),
+            ),
+            None => Some(functions.collect::<Vec<_>>()).filter(|t| !t.is_empty()),
+        };
+        let tool_choice = match params.parallel_tool_calls {
+            Some(false) => Some(disable_parallel_tool_use(params.tool_choice.unwrap_or(
+                ToolChoice::Auto {
+                    disable_parallel_tool_use: None,
+                },
+            ))),
+            _ => params.tool_choice,
+        };
         Self {
-            max_tokens: (params.max_tokens.or(params.max_completion_tokens))
+            max_tokens: (params.max_completion_tokens.or(params.max_tokens))
                 .unwrap_or_else(default_max_tokens),
             system,
             messages,
@@ -85,8 +292,8 @@ impl From<CreateMessageParams> for ClaudeCreateMessageParams {
             stream: params.stream,
             top_k: params.top_k,
             top_p: params.top_p,
-            tools: params.tools,
-            tool_choice: params.tool_choice,
+            tools,
+            tool_choice,
             metadata: params.metadata,
             output_config: None,
             output_format: None,
@@ -96,14 +303,18 @@ impl From<CreateMessageParams> for ClaudeCreateMessageParams {
     }
 }
 
+#[serde_as]
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct CreateMessageParams {
-    /// Maximum number of tokens to generate
+    /// Maximum number of tokens to generate, takes precedence over `max_tokens`
     #[serde(skip_serializing_if = "Option::is_none")]
     pub max_completion_tokens: Option<u32>,
+    /// Deprecated by OpenAI in favor of `max_completion_tokens`
     #[serde(skip_serializing_if = "Option::is_none")]
     pub max_tokens: Option<u32>,
-    /// Input messages for the conversation
+    /// Input messages for the conversation, legacy function calling is
+    /// converted to tool use
+    #[serde(deserialize_with = "deserialize_messages")]
     pub messages: Vec<Message>,
     /// Model to use
     pub model: String,
@@ -116,8 +327,9 @@ pub struct CreateMessageParams {
     /// Temperature for response generation
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f32>,
-    /// Custom stop sequences
-    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Custom stop sequences, OpenAI accepts a single string or an array
+    #[serde_as(as = "Option<OneOrMany<_, PreferMany>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub stop: Option<Vec<String>>,
     /// Whether to stream the response
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -140,6 +352,12 @@ pub struct CreateMessageParams {
     /// How the model should use tools
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_choice: Option<ToolChoice>,
+    /// Whether the model may call several tools at once
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parallel_tool_calls: Option<bool>,
+    /// Legacy function definitions, added to the tools
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub functions: Option<Vec<FunctionDefinition>>,
     /// Request metadata
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<Metadata>,
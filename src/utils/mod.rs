@@ -1,12 +1,16 @@
-use axum::body::Body;
+use std::{io, time::Duration};
+
+use axum::{BoxError, body::Body};
 use colored::{ColoredString, Colorize};
+use futures::{Stream, StreamExt, pin_mut};
 use tokio::spawn;
-use tracing::error;
-use wreq::{Client, Proxy};
+use tracing::{error, info};
+use url::Url;
+use wreq::{Client, Proxy, RequestBuilder};
 use wreq_util::Emulation;
 
 use crate::{
-    config::{CLEWDR_CONFIG, LOG_DIR},
+    config::{CLEWDR_CONFIG, LOG_DIR, PROXY_HOPS_HEADER, Reason, is_official_endpoint},
     error::ClewdrError,
 };
 
@@ -65,6 +69,18 @@ pub fn build_http_client(proxy: Option<&Proxy>) -> Result<Client, wreq::Error> {
     builder.build()
 }
 
+/// Adds the proxy hop counter to a request for `url`
+///
+/// Only a custom endpoint or reverse proxy can be another ClewdR, Anthropic's
+/// own hosts are not sent the header as it would give the proxy away.
+pub fn with_proxy_hops(req: RequestBuilder, url: &str, hops: u32) -> RequestBuilder {
+    let custom = Url::parse(url).is_ok_and(|url| !is_official_endpoint(&url));
+    if !custom {
+        return req;
+    }
+    req.header(PROXY_HOPS_HEADER, hops + 1)
+}
+
 /// Timezone for the API
 pub const TIME_ZONE: &str = "America/New_York";
 
@@ -83,3 +99,311 @@ pub fn forward_response(in_: wreq::Response) -> Result<http::Response<Body>, Cle
 
     Ok(res.body(Body::from_stream(stream))?)
 }
+
+/// Fails a streamed body when upstream stays silent for longer than `idle`
+///
+/// `on_timeout` runs once when the timeout fires, before the error is yielded.
+pub fn with_idle_timeout<S, T, E>(
+    stream: S,
+    idle: Option<Duration>,
+    on_timeout: impl FnOnce(),
+) -> impl Stream<Item = Result<T, BoxError>>
+where
+    S: Stream<Item = Result<T, E>>,
+    E: Into<BoxError>,
+{
+    async_stream::stream! {
+        pin_mut!(stream);
+        loop {
+            let next = match idle {
+                Some(idle) => match tokio::time::timeout(idle, stream.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        error!("Upstream stream idle for more than {}s", idle.as_secs());
+                        on_timeout();
+                        yield Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("upstream stream idle for more than {}s", idle.as_secs()),
+                        )
+                        .into());
+                        break;
+                    }
+                },
+                None => stream.next().await,
+            };
+            let Some(item) = next else {
+                break;
+            };
+            yield item.map_err(Into::into);
+        }
+    }
+}
+
+/// Wait before retry `attempt` after a connection error, doubling from half
+/// a second up to 8 seconds
+pub fn retry_backoff(attempt: usize) -> Duration {
+    Duration::from_millis(500) * 2u32.pow(attempt.saturating_sub(1).min(4) as u32)
+}
+
+/// How a chat retry loop goes on after an attempt failed
+#[derive(Debug, PartialEq, Eq)]
+pub enum RetryAction {
+    /// Hand the cookie back, with the reason it failed if any, and try another
+    NextCookie(Option<Reason>),
+    /// Hand the cookie back untouched and try again after a pause
+    Backoff(Duration),
+    /// Give up on the request, handing the cookie back untouched when set
+    Fail { return_cookie: bool },
+}
+
+/// Decides how to go on after attempt `attempt` failed with `e`
+///
+/// A stuck upstream is not the cookie's fault, so a timed out cookie goes
+/// back to the pool as it was. The attempt is retried with another cookie
+/// unless the client set its own deadline, which has passed by then.
+pub fn after_failure(e: &ClewdrError, attempt: usize, client_deadline: bool) -> RetryAction {
+    match e {
+        ClewdrError::InvalidCookie { reason } => RetryAction::NextCookie(Some(reason.to_owned())),
+        // the network failed, not the cookie, try again after a pause
+        e if e.is_connection_error() => RetryAction::Backoff(retry_backoff(attempt + 1)),
+        ClewdrError::UpstreamTimeout { .. } if !client_deadline => RetryAction::NextCookie(None),
+        ClewdrError::UpstreamTimeout { .. } => RetryAction::Fail {
+            return_cookie: true,
+        },
+        _ => RetryAction::Fail {
+            return_cookie: false,
+        },
+    }
+}
+
+/// Runs chat attempts until one succeeds, going on after each failure as
+/// [`after_failure`] decides
+///
+/// Every attempt hands back the state holding its cookie, which goes to
+/// `return_cookie` when the cookie should be handed back to the pool.
+pub async fn retry_attempts<S, T, A, AF, R, RF>(
+    retries: usize,
+    client_deadline: bool,
+    mut attempt: A,
+    mut return_cookie: R,
+) -> Result<T, ClewdrError>
+where
+    A: FnMut(usize) -> AF,
+    AF: Future<Output = (S, Result<T, ClewdrError>)>,
+    R: FnMut(S, Option<Reason>) -> RF,
+    RF: Future<Output = ()>,
+{
+    for i in 0..retries + 1 {
+        if i > 0 {
+            info!("[RETRY] attempt: {}", i.to_string().green());
+        }
+        let (state, e) = match attempt(i).await {
+            (_, Ok(res)) => return Ok(res),
+            (state, Err(e)) => (state, e),
+        };
+        match after_failure(&e, i, client_deadline) {
+            RetryAction::NextCookie(reason) => return_cookie(state, reason).await,
+            RetryAction::Backoff(wait) => {
+                return_cookie(state, None).await;
+                tokio::time::sleep(wait).await;
+            }
+            RetryAction::Fail {
+                return_cookie: true,
+            } => {
+                return_cookie(state, None).await;
+                return Err(e);
+            }
+            RetryAction::Fail { .. } => return Err(e),
+        }
+    }
+    error!("Max retries exceeded");
+    Err(ClewdrError::TooManyRetries)
+}
+
+/// Fails a request when it takes longer than `limit` in total
+pub async fn with_total_timeout<T>(
+    fut: impl Future<Output = Result<T, ClewdrError>>,
+    limit: Option<Duration>,
+) -> Result<T, ClewdrError> {
+    let Some(limit) = limit else {
+        return fut.await;
+    };
+    tokio::time::timeout(limit, fut)
+        .await
+        .unwrap_or(Err(ClewdrError::UpstreamTimeout {
+            secs: limit.as_secs(),
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    };
+
+    use futures::{StreamExt, stream};
+    use snafu::ResultExt;
+
+    use super::*;
+    use crate::error::WreqSnafu;
+
+    #[tokio::test]
+    async fn idle_timeout_fires_on_stalled_stream() {
+        let stalled = stream::iter([Ok::<_, io::Error>(1)]).chain(stream::pending());
+        let fired = Arc::new(AtomicBool::new(false));
+        let flag = fired.clone();
+        let items = with_idle_timeout(stalled, Some(Duration::from_millis(50)), move || {
+            flag.store(true, Ordering::Relaxed)
+        })
+        .collect::<Vec<_>>()
+        .await;
+
+        assert_eq!(items.len(), 2);
+        assert_eq!(*items[0].as_ref().unwrap(), 1);
+        assert!(items[1].is_err());
+        assert!(fired.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_passes_steady_stream() {
+        let steady = stream::iter([Ok::<_, io::Error>(1), Ok(2), Ok(3)]);
+        let items = with_idle_timeout(steady, Some(Duration::from_millis(50)), || {
+            panic!("should not time out")
+        })
+        .map(Result::unwrap)
+        .collect::<Vec<_>>()
+        .await;
+
+        assert_eq!(items, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn stuck_upstream_times_out_and_keeps_the_cookie() {
+        // accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = vec![];
+            while let Ok((socket, _)) = listener.accept().await {
+                held.push(socket);
+            }
+        });
+        let client = build_http_client(None).unwrap();
+        let request = async {
+            client
+                .get(format!("http://{addr}/v1/messages"))
+                .send()
+                .await
+                .context(WreqSnafu {
+                    msg: "Failed to send chat message",
+                })
+        };
+        let err = with_total_timeout(request, Some(Duration::from_millis(100)))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ClewdrError::UpstreamTimeout { .. }));
+
+        assert_eq!(after_failure(&err, 0, false), RetryAction::NextCookie(None));
+        assert_eq!(
+            after_failure(&err, 0, true),
+            RetryAction::Fail {
+                return_cookie: true
+            }
+        );
+    }
+
+    #[test]
+    fn failed_attempts_are_classified() {
+        let banned = ClewdrError::InvalidCookie {
+            reason: Reason::Banned,
+        };
+        assert_eq!(
+            after_failure(&banned, 0, false),
+            RetryAction::NextCookie(Some(Reason::Banned))
+        );
+        let bad = ClewdrError::BadRequest { msg: "bad" };
+        assert_eq!(
+            after_failure(&bad, 0, false),
+            RetryAction::Fail {
+                return_cookie: false
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_failures_are_retried_until_an_attempt_succeeds() {
+        let attempts = AtomicUsize::new(0);
+        let returned = AtomicUsize::new(0);
+        let res = retry_attempts(
+            2,
+            false,
+            |i| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    let res = if i == 0 {
+                        Err(ClewdrError::from(io::Error::from(
+                            io::ErrorKind::ConnectionReset,
+                        )))
+                    } else {
+                        Ok("answer")
+                    };
+                    (i, res)
+                }
+            },
+            |state, reason| {
+                // the failed attempt's cookie goes back unpenalized
+                assert_eq!((state, reason), (0, None));
+                returned.fetch_add(1, Ordering::Relaxed);
+                async {}
+            },
+        )
+        .await;
+        assert_eq!(res.unwrap(), "answer");
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        assert_eq!(returned.load(Ordering::Relaxed), 1);
+
+        let res = retry_attempts(
+            2,
+            false,
+            |_| async { ((), Err::<(), _>(ClewdrError::BadRequest { msg: "bad" })) },
+            |_, _| async {
+                panic!("cookie returned after a request error");
+            },
+        )
+        .await;
+        assert!(matches!(res, Err(ClewdrError::BadRequest { .. })));
+    }
+
+    #[tokio::test]
+    async fn total_timeout_fails_slow_response() {
+        let slow = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok(())
+        };
+        let res = with_total_timeout(slow, Some(Duration::from_millis(50))).await;
+        assert!(matches!(res, Err(ClewdrError::UpstreamTimeout { .. })));
+
+        let fast = async { Ok(1) };
+        let res = with_total_timeout(fast, Some(Duration::from_millis(50))).await;
+        assert_eq!(res.unwrap(), 1);
+    }
+
+    #[test]
+    fn hop_counter_is_only_sent_to_custom_endpoints() {
+        let hops = |url: &str| {
+            let req = with_proxy_hops(Client::new().get(url), url, 1)
+                .build()
+                .unwrap();
+            req.headers()
+                .get(PROXY_HOPS_HEADER)
+                .map(|v| v.to_str().unwrap().to_owned())
+        };
+        assert_eq!(hops("https://api.anthropic.com/v1/messages"), None);
+        assert_eq!(hops("https://claude.ai/api/organizations"), None);
+        assert_eq!(
+            hops("http://127.0.0.1:8484/code/v1/messages").as_deref(),
+            Some("2")
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! Tests for OpenAI to Claude request conversion
+//!
+//! This test suite validates how OpenAI chat completion requests are
+//! normalized into Claude's messages format.
+
+#[cfg(test)]
+mod tests {
+    use clewdr::types::{
+        claude::{CreateMessageParams as ClaudeCreateMessageParams, Role},
+        oai::CreateMessageParams as OaiCreateMessageParams,
+    };
+    use serde_json::json;
+
+    #[test]
+    fn test_developer_role_becomes_system_prompt() {
+        let oai: OaiCreateMessageParams = serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5",
+            "messages": [
+                { "role": "developer", "content": "Answer in French." },
+                { "role": "user", "content": "Hello" }
+            ]
+        }))
+        .expect("developer role should be accepted");
+
+        let claude: ClaudeCreateMessageParams = oai.into();
+
+        assert_eq!(claude.messages.len(), 1);
+        assert_eq!(claude.messages[0].role, Role::User);
+        let system = claude.system.expect("system prompt should be set");
+        assert_eq!(system[0]["type"], "text");
+        assert_eq!(system[0]["text"], "Answer in French.");
+    }
+
+    #[test]
+    fn test_developer_and_system_roles_are_merged_in_order() {
+        let oai: OaiCreateMessageParams = serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5",
+            "messages": [
+                { "role": "system", "content": "You are helpful." },
+                { "role": "developer", "content": [{ "type": "text", "text": "Be brief." }] },
+                { "role": "user", "content": "Hi there" }
+            ]
+        }))
+        .unwrap();
+
+        let claude: ClaudeCreateMessageParams = oai.into();
+
+        let system = claude.system.unwrap();
+        let texts = system
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|b| b["text"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, vec!["You are helpful.", "Be brief."]);
+    }
+
+    #[test]
+    fn test_stop_accepts_string_or_array() {
+        let single: OaiCreateMessageParams = serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5",
+            "messages": [{ "role": "user", "content": "Hello" }],
+            "stop": "\n\nHuman:"
+        }))
+        .expect("a single stop string should be accepted");
+        let claude: ClaudeCreateMessageParams = single.into();
+        assert_eq!(claude.stop_sequences, Some(vec!["\n\nHuman:".to_string()]));
+
+        let many: OaiCreateMessageParams = serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5",
+            "messages": [{ "role": "user", "content": "Hello" }],
+            "stop": ["END", "STOP"]
+        }))
+        .unwrap();
+        let claude: ClaudeCreateMessageParams = many.into();
+        assert_eq!(
+            claude.stop_sequences,
+            Some(vec!["END".to_string(), "STOP".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_max_completion_tokens_sets_claude_max_tokens() {
+        let oai: OaiCreateMessageParams = serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5",
+            "messages": [{ "role": "user", "content": "Hello" }],
+            "max_completion_tokens": 1234
+        }))
+        .unwrap();
+        let claude: ClaudeCreateMessageParams = oai.into();
+        assert_eq!(claude.max_tokens, 1234);
+
+        let both: OaiCreateMessageParams = serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5",
+            "messages": [{ "role": "user", "content": "Hello" }],
+            "max_tokens": 100,
+            "max_completion_tokens": 2048
+        }))
+        .unwrap();
+        let claude: ClaudeCreateMessageParams = both.into();
+        assert_eq!(claude.max_tokens, 2048);
+    }
+
+    #[test]
+    fn test_legacy_function_role_becomes_tool_result() {
+        let oai: OaiCreateMessageParams = serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5",
+            "functions": [{
+                "name": "get_weather",
+                "description": "Current weather for a city",
+                "parameters": { "type": "object", "properties": { "city": { "type": "string" } } }
+            }],
+            "messages": [
+                { "role": "user", "content": "Weather in Paris?" },
+                {
+                    "role": "assistant",
+                    "content": null,
+                    "function_call": { "name": "get_weather", "arguments": "{\"city\": \"Paris\"}" }
+                },
+                { "role": "function", "name": "get_weather", "content": "18C, sunny" },
+                { "role": "function", "name": "unknown", "content": "stray output" }
+            ]
+        }))
+        .expect("legacy function calling should be accepted");
+
+        let claude: ClaudeCreateMessageParams = oai.into();
+        let messages = serde_json::to_value(&claude.messages).unwrap();
+
+        assert_eq!(messages[1]["role"], "assistant");
+        let tool_use = &messages[1]["content"][0];
+        assert_eq!(tool_use["type"], "tool_use");
+        assert_eq!(tool_use["name"], "get_weather");
+        assert_eq!(tool_use["input"], json!({ "city": "Paris" }));
+
+        assert_eq!(claude.messages[2].role, Role::User);
+        let result = &messages[2]["content"][0];
+        assert_eq!(result["type"], "tool_result");
+        assert_eq!(result["tool_use_id"], tool_use["id"]);
+        assert_eq!(result["content"], "18C, sunny");
+
+        // a result answering no call stays plain text
+        assert_eq!(
+            messages[3],
+            json!({ "role": "user", "content": "stray output" })
+        );
+
+        let tools = serde_json::to_value(claude.tools.unwrap()).unwrap();
+        assert_eq!(tools[0]["name"], "get_weather");
+        assert_eq!(
+            tools[0]["input_schema"]["properties"]["city"]["type"],
+            "string"
+        );
+    }
+
+    #[test]
+    fn test_tool_calls_become_tool_use_and_results() {
+        let oai: OaiCreateMessageParams = serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5",
+            "tools": [{
+                "type": "function",
+                "function": {
+                    "name": "get_weather",
+                    "description": "Current weather for a city",
+                    "parameters": { "type": "object", "properties": { "city": { "type": "string" } } }
+                }
+            }],
+            "tool_choice": { "type": "function", "function": { "name": "get_weather" } },
+            "parallel_tool_calls": false,
+            "messages": [
+                { "role": "user", "content": "Weather in Paris and Rome?" },
+                {
+                    "role": "assistant",
+                    "content": "",
+                    "tool_calls": [
+                        {
+                            "id": "call_1",
+                            "type": "function",
+                            "function": { "name": "get_weather", "arguments": "{\"city\": \"Paris\"}" }
+                        },
+                        {
+                            "id": "call_2",
+                            "type": "function",
+                            "function": { "name": "get_weather", "arguments": "{\"city\": \"Rome\"}" }
+                        }
+                    ]
+                },
+                { "role": "tool", "tool_call_id": "call_1", "content": "18C, sunny" },
+                { "role": "tool", "tool_call_id": "call_2", "content": "22C, cloudy" }
+            ]
+        }))
+        .expect("tool calling should be accepted");
+
+        let claude: ClaudeCreateMessageParams = oai.into();
+        let messages = serde_json::to_value(&claude.messages).unwrap();
+
+        // the empty text is dropped, the calls are kept
+        assert_eq!(claude.messages.len(), 3);
+        let uses = messages[1]["content"].as_array().unwrap();
+        assert_eq!(uses.len(), 2);
+        assert_eq!(uses[0]["type"], "tool_use");
+        assert_eq!(uses[0]["id"], "call_1");
+        assert_eq!(uses[1]["input"], json!({ "city": "Rome" }));
+
+        // both results land in one user message
+        assert_eq!(claude.messages[2].role, Role::User);
+        let results = messages[2]["content"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["type"], "tool_result");
+        assert_eq!(results[0]["tool_use_id"], "call_1");
+        assert_eq!(results[1]["tool_use_id"], "call_2");
+        assert_eq!(results[1]["content"], "22C, cloudy");
+
+        let tools = serde_json::to_value(claude.tools.unwrap()).unwrap();
+        assert_eq!(tools[0]["name"], "get_weather");
+        assert!(tools[0].get("function").is_none());
+        assert_eq!(
+            tools[0]["input_schema"]["properties"]["city"]["type"],
+            "string"
+        );
+
+        let choice = serde_json::to_value(claude.tool_choice.unwrap()).unwrap();
+        assert_eq!(
+            choice,
+            json!({ "type": "tool", "name": "get_weather", "disable_parallel_tool_use": true })
+        );
+    }
+}